@@ -0,0 +1,63 @@
+//! Compares evaluating an expression from its text every time with
+//! evaluating its postfix queue and with compiling it once and evaluating the
+//! compiled form, with and without reusing buffers through an `Evaluator`.
+//!
+//! Run with `cargo run --release --example compile_benchmark`.
+
+use clc::{evaluate_f64, evaluate_queue, Context, Evaluator, Expression};
+use std::time::Instant;
+
+const EXPRESSION: &str = "3 * x ^ 2 - 2 * x + sqrt(abs(x)) / (1 + x ^ 2)";
+const ITERATIONS: usize = 200_000;
+
+fn main() {
+    let mut context = Context::default_with_math_functions();
+
+    let start = Instant::now();
+    let mut sum = 0.0;
+    for i in 0..ITERATIONS {
+        context.set_variable("x", i as f64);
+        sum += evaluate_f64(EXPRESSION, &context).unwrap();
+    }
+    let interpreted = start.elapsed();
+
+    let expression = Expression::compile(EXPRESSION, &context).unwrap();
+    let start = Instant::now();
+    let mut queue_sum = 0.0;
+    for i in 0..ITERATIONS {
+        context.set_variable("x", i as f64);
+        queue_sum += evaluate_queue(&expression, &context).unwrap().to_f64();
+    }
+    let queued = start.elapsed();
+
+    let start = Instant::now();
+    let expression = Expression::compile(EXPRESSION, &context).unwrap();
+    let mut compiled_sum = 0.0;
+    for i in 0..ITERATIONS {
+        context.set_variable("x", i as f64);
+        compiled_sum += expression.evaluate(&context).unwrap();
+    }
+    let compiled = start.elapsed();
+
+    let start = Instant::now();
+    let mut evaluator = Evaluator::new();
+    let mut reused_sum = 0.0;
+    for i in 0..ITERATIONS {
+        context.set_variable("x", i as f64);
+        reused_sum += evaluator.eval_compiled(&expression, &context).unwrap();
+    }
+    let reused = start.elapsed();
+
+    assert_eq!(sum, queue_sum);
+    assert_eq!(sum, compiled_sum);
+    assert_eq!(sum, reused_sum);
+    println!("evaluate:            {:?}", interpreted);
+    println!("evaluate_queue:      {:?}", queued);
+    println!("compile + evaluate:  {:?}", compiled);
+    println!("Evaluator:           {:?}", reused);
+    println!(
+        "speedup:             {:.1}x over evaluate, {:.1}x over evaluate_queue",
+        interpreted.as_secs_f64() / compiled.as_secs_f64(),
+        queued.as_secs_f64() / compiled.as_secs_f64()
+    );
+}
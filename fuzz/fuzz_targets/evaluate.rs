@@ -0,0 +1,13 @@
+//! Evaluating arbitrary text may fail, but must not panic.
+
+#![no_main]
+
+use clc::Context;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let expression = String::from_utf8_lossy(data);
+    let _ = clc::evaluate(&expression, &Context::default());
+    let _ = clc::evaluate(&expression, &Context::default_with_math_functions());
+    let _ = clc::evaluate(&expression, &Context::default_with_bitwise_operators());
+});
@@ -0,0 +1,11 @@
+//! Tokenizing arbitrary text may fail, but must not panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let expression = String::from_utf8_lossy(data);
+    clc::tokenize(&expression).for_each(drop);
+    let _ = clc::tokenize_collect_errors(&expression);
+});
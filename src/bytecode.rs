@@ -0,0 +1,563 @@
+use crate::value::{apply_binary, apply_postfix, apply_prefix, condition};
+use crate::{branch_operator, factorial, from_integer, power, reduce, shift_amount, to_integer};
+use crate::{truth, Arity, Context, EvalError, EvaluationGuard, Function, Operator, OperatorKind};
+use crate::{Number, Token, Value};
+use std::collections::{HashMap, VecDeque};
+
+/// A built-in binary operator, resolved from its symbol ahead of time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum OpCode {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    FloorDivide,
+    Remainder,
+    Power,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    And,
+    Or,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+}
+
+impl OpCode {
+    pub(crate) fn from_symbol(symbol: &str) -> Option<Self> {
+        let opcode = match symbol {
+            "+" => Self::Add,
+            "-" => Self::Subtract,
+            "*" => Self::Multiply,
+            "/" => Self::Divide,
+            "//" => Self::FloorDivide,
+            "%" => Self::Remainder,
+            "^" => Self::Power,
+            "<" => Self::Less,
+            ">" => Self::Greater,
+            "<=" => Self::LessEqual,
+            ">=" => Self::GreaterEqual,
+            "==" => Self::Equal,
+            "!=" => Self::NotEqual,
+            "&&" => Self::And,
+            "||" => Self::Or,
+            "&" => Self::BitAnd,
+            "|" => Self::BitOr,
+            "^^" => Self::BitXor,
+            "<<" => Self::ShiftLeft,
+            ">>" => Self::ShiftRight,
+            _ => return None,
+        };
+        Some(opcode)
+    }
+
+    pub(crate) fn symbol(self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Subtract => "-",
+            Self::Multiply => "*",
+            Self::Divide => "/",
+            Self::FloorDivide => "//",
+            Self::Remainder => "%",
+            Self::Power => "^",
+            Self::Less => "<",
+            Self::Greater => ">",
+            Self::LessEqual => "<=",
+            Self::GreaterEqual => ">=",
+            Self::Equal => "==",
+            Self::NotEqual => "!=",
+            Self::And => "&&",
+            Self::Or => "||",
+            Self::BitAnd => "&",
+            Self::BitOr => "|",
+            Self::BitXor => "^^",
+            Self::ShiftLeft => "<<",
+            Self::ShiftRight => ">>",
+        }
+    }
+
+    pub(crate) fn apply(self, left: f64, right: f64) -> Result<f64, EvalError> {
+        let symbol = self.symbol();
+        let integer = |value| to_integer(symbol, value);
+        match self {
+            Self::Add => Ok(left + right),
+            Self::Subtract => Ok(left - right),
+            Self::Multiply => Ok(left * right),
+            Self::Divide => Ok(left / right),
+            Self::FloorDivide => Ok(left.div_euclid(right)),
+            Self::Remainder => Ok(left.rem_euclid(right)),
+            Self::Power => Ok(power(left, right)),
+            Self::Less => Ok(truth(left < right)),
+            Self::Greater => Ok(truth(left > right)),
+            Self::LessEqual => Ok(truth(left <= right)),
+            Self::GreaterEqual => Ok(truth(left >= right)),
+            Self::Equal => Ok(truth(left == right)),
+            Self::NotEqual => Ok(truth(left != right)),
+            Self::And => Ok(truth(left != 0.0 && right != 0.0)),
+            Self::Or => Ok(truth(left != 0.0 || right != 0.0)),
+            Self::BitAnd => from_integer(symbol, integer(left)? & integer(right)?),
+            Self::BitOr => from_integer(symbol, integer(left)? | integer(right)?),
+            Self::BitXor => from_integer(symbol, integer(left)? ^ integer(right)?),
+            Self::ShiftLeft => from_integer(symbol, integer(left)? << shift_amount(symbol, right)?),
+            Self::ShiftRight => {
+                from_integer(symbol, integer(left)? >> shift_amount(symbol, right)?)
+            }
+        }
+    }
+}
+
+/// A built-in prefix or postfix operator.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum UnaryOp {
+    /// Unary plus, which leaves the value as it is.
+    Identity,
+    Negate,
+    BitNot,
+    /// Logical not, which maps 0 to 1 and anything else to 0.
+    Not,
+    Factorial,
+    /// Postfix `%`, which divides by 100.
+    Percent,
+}
+
+impl UnaryOp {
+    pub(crate) fn prefix(symbol: &str) -> Option<Self> {
+        match symbol {
+            "+" => Some(Self::Identity),
+            "-" => Some(Self::Negate),
+            "~" => Some(Self::BitNot),
+            "!" => Some(Self::Not),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn postfix(symbol: &str) -> Option<Self> {
+        match symbol {
+            "!" => Some(Self::Factorial),
+            "%" => Some(Self::Percent),
+            _ => None,
+        }
+    }
+
+    fn of<N: Number>(operator: &Operator<N>) -> Option<Self> {
+        match operator.kind() {
+            OperatorKind::Prefix => Self::prefix(operator.symbol()),
+            OperatorKind::Postfix => Self::postfix(operator.symbol()),
+            OperatorKind::Infix => None,
+        }
+    }
+
+    pub(crate) fn symbol(self) -> &'static str {
+        match self {
+            Self::Identity => "+",
+            Self::Negate => "-",
+            Self::BitNot => "~",
+            Self::Not | Self::Factorial => "!",
+            Self::Percent => "%",
+        }
+    }
+
+    pub(crate) fn apply(self, value: f64) -> Result<f64, EvalError> {
+        match self {
+            Self::Identity => Ok(value),
+            Self::Negate => Ok(-value),
+            Self::BitNot => from_integer("~", !to_integer("~", value)?),
+            Self::Not => Ok(truth(value == 0.0)),
+            Self::Factorial => factorial("!", value),
+            Self::Percent => Ok(value / 100.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Instr<N> {
+    Const(Value<N>),
+    /// Pushes the value of a variable slot.
+    Load(usize),
+    Binary(OpCode),
+    Unary(UnaryOp),
+    /// A binary operator without an opcode, e.g. one defined by an
+    /// expression, or one spelled by an alias. Indexes `Program::operators`.
+    Operator(usize),
+    /// Like `Operator`, for prefix and postfix operators.
+    UnaryOperator(usize),
+    /// Calls `Program::functions[index]` with a number of arguments.
+    Call(usize, usize),
+    /// A call of a function that doesn't exist. Which error it is depends on
+    /// whether the variable slot of the same name is bound.
+    UnknownCall(usize),
+    /// The branches of an `if`, or of `&&` and `||` if the operator says so,
+    /// see `branch_operator`.
+    Branch(&'static str, Vec<Instr<N>>, Vec<Instr<N>>),
+    /// A `series` or `prod` that binds the variable slot while evaluating the
+    /// body.
+    Reduce(String, usize, Vec<Instr<N>>),
+    Assign(String),
+    /// An error that was found while compiling. It is only raised when the
+    /// instruction is reached, so errors are reported in the same order as
+    /// by `evaluate_queue`.
+    Fail(EvalError),
+}
+
+/// A postfix queue lowered to instructions, in which operators and functions
+/// are resolved once, so evaluating it needs no hashing and no string
+/// comparisons. Variables are numbered slots, which are bound to their values
+/// once per evaluation. Lazy variables that haven't been computed yet are
+/// only looked up when they are loaded.
+///
+/// Operators and functions are taken from the context the program is compiled
+/// in. Evaluating it otherwise gives the same results and errors as
+/// `evaluate_queue`.
+#[derive(Debug, Clone)]
+pub(crate) struct Program<N> {
+    instructions: Vec<Instr<N>>,
+    /// The variable name of each slot.
+    variables: Vec<String>,
+    functions: Vec<Function<N>>,
+    /// The operators of `Instr::Operator` and `Instr::UnaryOperator`, with the
+    /// symbol they are spelled with in the expression.
+    operators: Vec<(String, Operator<N>)>,
+}
+
+impl<N: Number> Program<N> {
+    pub(crate) fn compile(queue: &VecDeque<Token>, context: &Context<N>) -> Self {
+        let mut program = Self {
+            instructions: Vec::new(),
+            variables: Vec::new(),
+            functions: Vec::new(),
+            operators: Vec::new(),
+        };
+        program.instructions = program.lower(queue, context);
+        program
+    }
+
+    fn lower(&mut self, queue: &VecDeque<Token>, context: &Context<N>) -> Vec<Instr<N>> {
+        let mut instructions = Vec::new();
+        for token in queue {
+            if let Token::Identifier(name) | Token::Call(name, _) = token {
+                if context.is_arithmetic_only() {
+                    instructions.push(Instr::Fail(EvalError::FeatureDisabled(name.clone())));
+                    continue;
+                }
+            }
+
+            let instruction = match token {
+                Token::Number(value, text) => Instr::Const(Value::literal(*value, text, context)),
+                Token::Symbol(symbol) => match context.get_operator(symbol) {
+                    Some(operator) => match OpCode::from_symbol(operator.symbol()) {
+                        Some(opcode) if operator.body.is_none() && operator.symbol() == symbol => {
+                            Instr::Binary(opcode)
+                        }
+                        _ => Instr::Operator(self.add_operator(symbol, operator)),
+                    },
+                    None => Instr::Fail(EvalError::UnknownOperator(symbol.clone())),
+                },
+                Token::Prefix(symbol) => self.lower_unary(symbol, OperatorKind::Prefix, context),
+                Token::Postfix(symbol) => self.lower_unary(symbol, OperatorKind::Postfix, context),
+                Token::Branch(then, otherwise) => Instr::Branch(
+                    branch_operator(then, otherwise),
+                    self.lower(then, context),
+                    self.lower(otherwise, context),
+                ),
+                Token::Reduce(name, variable, body) => {
+                    let slot = self.slot(variable);
+                    Instr::Reduce(name.clone(), slot, self.lower(body, context))
+                }
+                Token::Call(name, args) => self.lower_call(name, *args, context),
+                Token::Identifier(name) if context.function_exists(name) => {
+                    let num_args = context
+                        .function_arities(name)
+                        .iter()
+                        .map(Arity::min)
+                        .min()
+                        .unwrap_or(0);
+                    self.lower_call(name, num_args, context)
+                }
+                Token::Identifier(name) => Instr::Load(self.slot(name)),
+                Token::Assign(name) => Instr::Assign(name.clone()),
+                Token::Whitespace(_) => continue,
+                Token::LeftParenthesis | Token::RightParenthesis => {
+                    Instr::Fail(EvalError::MismatchedParentheses)
+                }
+                Token::Separator => Instr::Fail(EvalError::UnexpectedToken(String::from(","))),
+            };
+            instructions.push(instruction);
+        }
+        instructions
+    }
+
+    fn lower_unary(&mut self, symbol: &str, kind: OperatorKind, context: &Context<N>) -> Instr<N> {
+        match context.get_operator_of_kind(symbol, kind) {
+            Some(operator) => match UnaryOp::of(operator) {
+                Some(op) if operator.body.is_none() => Instr::Unary(op),
+                _ => Instr::UnaryOperator(self.add_operator(symbol, operator)),
+            },
+            None => Instr::Fail(EvalError::UnknownOperator(String::from(symbol))),
+        }
+    }
+
+    fn lower_call(&mut self, name: &str, num_args: usize, context: &Context<N>) -> Instr<N> {
+        if !context.function_exists(name) {
+            return Instr::UnknownCall(self.slot(name));
+        }
+        match context.get_function(name, num_args) {
+            Some(function) => {
+                self.functions.push(function.clone());
+                Instr::Call(self.functions.len() - 1, num_args)
+            }
+            None => Instr::Fail(EvalError::WrongNumberOfArguments {
+                name: String::from(name),
+                expected: context.function_arities(name),
+                found: num_args,
+            }),
+        }
+    }
+
+    fn slot(&mut self, name: &str) -> usize {
+        match self.variables.iter().position(|variable| variable == name) {
+            Some(slot) => slot,
+            None => {
+                self.variables.push(String::from(name));
+                self.variables.len() - 1
+            }
+        }
+    }
+
+    fn add_operator(&mut self, symbol: &str, operator: &Operator<N>) -> usize {
+        self.operators
+            .push((String::from(symbol), operator.clone()));
+        self.operators.len() - 1
+    }
+
+    /// Evaluates the program, looking up variables in `bindings` before the
+    /// context.
+    pub(crate) fn run(
+        &self,
+        context: &Context<N>,
+        bindings: &HashMap<String, N>,
+    ) -> Result<Value<N>, EvalError> {
+        self.run_with_buffers(context, bindings, &mut Vec::new(), &mut Vec::new())
+    }
+
+    /// Like `run`, using `stack` and `slots` as buffers instead of
+    /// allocating new ones. Both are cleared first.
+    pub(crate) fn run_with_buffers(
+        &self,
+        context: &Context<N>,
+        bindings: &HashMap<String, N>,
+        stack: &mut Vec<Value<N>>,
+        slots: &mut Vec<Option<Value<N>>>,
+    ) -> Result<Value<N>, EvalError> {
+        slots.clear();
+        slots.extend(self.variables.iter().map(|name| match bindings.get(name) {
+            Some(&value) => Some(Value::Float(value)),
+            None => context.peek_variable(name).cloned(),
+        }));
+        stack.clear();
+        let _guard = EvaluationGuard::enter();
+        self.execute(&self.instructions, context, slots, stack)
+    }
+
+    /// Executes a block of instructions on top of `stack`. Branches are
+    /// nested blocks on the same stack; a block only sees the values it
+    /// pushed itself.
+    fn execute(
+        &self,
+        instructions: &[Instr<N>],
+        context: &Context<N>,
+        slots: &[Option<Value<N>>],
+        stack: &mut Vec<Value<N>>,
+    ) -> Result<Value<N>, EvalError> {
+        let base = stack.len();
+
+        for instruction in instructions {
+            match instruction {
+                Instr::Const(value) => stack.push(value.clone()),
+                Instr::Load(slot) => {
+                    let name = &self.variables[*slot];
+                    match slots[*slot].as_ref().or_else(|| context.get_variable(name)) {
+                        Some(value) => stack.push(value.clone()),
+                        None => return Err(EvalError::UnknownVariable(name.clone())),
+                    }
+                }
+                Instr::Binary(opcode) => {
+                    let symbol = opcode.symbol();
+                    let right = pop(stack, base, symbol)?;
+                    let left = pop(stack, base, symbol)?;
+                    stack.push(apply_binary(symbol, left, right, context)?);
+                }
+                Instr::Unary(op) => {
+                    let symbol = op.symbol();
+                    let value = pop(stack, base, symbol)?;
+                    stack.push(match op {
+                        UnaryOp::Factorial | UnaryOp::Percent => {
+                            apply_postfix(symbol, value, context)?
+                        }
+                        _ => apply_prefix(symbol, value, context)?,
+                    });
+                }
+                Instr::Operator(index) => {
+                    let (symbol, operator) = &self.operators[*index];
+                    let right = pop(stack, base, symbol)?;
+                    let left = pop(stack, base, symbol)?;
+                    stack.push(operator.evaluate(left, right, context)?);
+                }
+                Instr::UnaryOperator(index) => {
+                    let (symbol, operator) = &self.operators[*index];
+                    let value = pop(stack, base, symbol)?;
+                    stack.push(operator.evaluate_unary(value, context)?);
+                }
+                Instr::Call(index, num_args) => {
+                    let function = &self.functions[*index];
+                    let available = stack.len() - base;
+                    if available < *num_args {
+                        return Err(EvalError::WrongNumberOfArguments {
+                            name: String::from(function.name()),
+                            expected: vec![function.arity()],
+                            found: available,
+                        });
+                    }
+                    let start = stack.len() - num_args;
+                    let result = function.call(&stack[start..], context)?;
+                    stack.truncate(start);
+                    stack.push(result);
+                }
+                Instr::UnknownCall(slot) => {
+                    let name = self.variables[*slot].clone();
+                    return Err(if slots[*slot].is_some() || context.has_variable(&name) {
+                        EvalError::IdentifierUsedAsFunction(name)
+                    } else {
+                        EvalError::UnknownFunction(name)
+                    });
+                }
+                Instr::Branch(operator, then, otherwise) => {
+                    let value = pop(stack, base, "if")?;
+                    let branch = if condition(operator, value, context)? {
+                        then
+                    } else {
+                        otherwise
+                    };
+                    let value = self.execute(branch, context, slots, stack)?;
+                    stack.push(value);
+                }
+                Instr::Reduce(name, slot, body) => {
+                    let end = pop(stack, base, name)?;
+                    let start = pop(stack, base, name)?;
+                    let mut slots = slots.to_vec();
+                    let value = reduce(name, (start, end), context, |value| {
+                        slots[*slot] = Some(value);
+                        self.execute(body, context, &slots, stack)
+                    })?;
+                    stack.push(value);
+                }
+                Instr::Assign(name) => {
+                    return Err(if stack.len() > base {
+                        EvalError::AssignmentNotAllowed(name.clone())
+                    } else {
+                        EvalError::MissingOperand(String::from("="))
+                    });
+                }
+                Instr::Fail(error) => return Err(error.clone()),
+            }
+        }
+
+        match stack.len() - base {
+            0 => Err(EvalError::EmptyExpression),
+            1 => pop(stack, base, ""),
+            n => Err(EvalError::TooManyOperands(n - 1)),
+        }
+    }
+}
+
+/// Pops an operand of the operator `symbol` that was pushed after `base`.
+fn pop<N: Number>(
+    stack: &mut Vec<Value<N>>,
+    base: usize,
+    symbol: &str,
+) -> Result<Value<N>, EvalError> {
+    match stack.pop() {
+        Some(value) if stack.len() >= base => Ok(value),
+        _ => Err(EvalError::MissingOperand(String::from(symbol))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Program;
+    use crate::{collect_tokens, evaluate_queue, evaluate_queue_with, shunting_yard};
+    use crate::{Associativity, Context, EvalError, Function, OperatorKind, Value};
+    use std::collections::HashMap;
+
+    /// Checks that the program gives the same result as the postfix queue.
+    fn assert_same(expression: &str, context: &Context, bindings: &HashMap<String, f64>) {
+        let tokens = collect_tokens(expression, context).unwrap();
+        let queue = shunting_yard(tokens, context).unwrap();
+        let program = Program::compile(&queue, context);
+        assert_eq!(
+            program.run(context, bindings),
+            evaluate_queue_with(&queue, context, bindings),
+            "{}",
+            expression
+        );
+    }
+
+    #[test]
+    fn matches_evaluate_queue() {
+        let mut context = Context::default_with_bitwise_operators();
+        context.set_variable("x", 3.0);
+        context.add_function(Function::user_defined("sq", &["a"], "a * a", &context).unwrap());
+        context
+            .add_operator_binary_from_string("@", 10, Associativity::Left, "$0 * 10 + $1")
+            .unwrap();
+        context.add_postfix_operator("!", 13).unwrap();
+        context
+            .add_unary_operator_from_string("$", OperatorKind::Prefix, 13, "$0 + 1")
+            .unwrap();
+        let bindings = vec![(String::from("y"), 0.5)].into_iter().collect();
+
+        let expressions = [
+            "1 + 2 * x ^ 2 - -x",
+            "x ** 2 // 2 % 3",
+            "sq(x) @ 2",
+            "(x & 6) | 1 << 2 ^^ ~0",
+            "1 < x <= 3 && x != 2 || y",
+            "if(x > 2, 1, unknown)",
+            "if(x < 2, 1, unknown)",
+            "0 && unknown",
+            "x + y",
+            "unknown",
+            "y(2)",
+            "nope(2)",
+            "sq(1, 2)",
+            "-0.5 << 1",
+            "$x! - 3!!",
+            "0.5!",
+            "1 @ @",
+            "y = 2",
+        ];
+        for expression in &expressions {
+            assert_same(expression, &context, &bindings);
+        }
+    }
+
+    #[test]
+    fn functions_are_resolved_when_compiled() {
+        let mut context = Context::default();
+        let queue = shunting_yard(collect_tokens("f(2) + x", &context).unwrap(), &context).unwrap();
+        let program = Program::compile(&queue, &context);
+
+        context.add_function(Function::new("f", 1, |args, _| Ok(args[0] * 2.0)));
+        context.set_variable("x", 1.0);
+        assert_eq!(evaluate_queue(&queue, &context), Ok(Value::Float(5.0)));
+        assert_eq!(
+            program.run(&context, &HashMap::new()),
+            Err(EvalError::UnknownFunction(String::from("f")))
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! A cache of compiled expressions, see `Context::enable_expression_cache`.
+
+use crate::{Expression, Number};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+/// How often `evaluate` found an expression in the context's cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+/// Compiled expressions keyed by their text, which drops the least recently
+/// used one when it is full.
+#[derive(Debug)]
+pub(crate) struct ExpressionCache<N> {
+    capacity: usize,
+    expressions: HashMap<String, Arc<Expression<N>>>,
+    /// The keys of `expressions`, from least to most recently used.
+    order: VecDeque<String>,
+    stats: CacheStats,
+}
+
+impl<N: Number> ExpressionCache<N> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            expressions: HashMap::new(),
+            order: VecDeque::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, text: &str) -> Option<Arc<Expression<N>>> {
+        let expression = match self.expressions.get(text) {
+            Some(expression) => Arc::clone(expression),
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+        self.stats.hits += 1;
+        if let Some(index) = self.order.iter().position(|key| key == text) {
+            let key = self.order.remove(index).unwrap();
+            self.order.push_back(key);
+        }
+        Some(expression)
+    }
+
+    pub(crate) fn insert(&mut self, text: &str, expression: Arc<Expression<N>>) {
+        if self.capacity == 0 || self.expressions.contains_key(text) {
+            return;
+        }
+        if self.expressions.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.expressions.remove(&oldest);
+            }
+        }
+        self.expressions.insert(String::from(text), expression);
+        self.order.push_back(String::from(text));
+    }
+
+    /// Drops all expressions, but keeps the statistics.
+    pub(crate) fn clear(&mut self) {
+        self.expressions.clear();
+        self.order.clear();
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{evaluate, evaluate_f64, CacheStats, Context, Context64, EvalError, Function};
+
+    fn stats(hits: usize, misses: usize) -> CacheStats {
+        CacheStats { hits, misses }
+    }
+
+    #[test]
+    fn hits_and_misses() {
+        let mut context = Context::default();
+        assert_eq!(evaluate_f64("1 + 2", &context), Ok(3.0));
+        assert_eq!(context.cache_stats(), stats(0, 0));
+
+        context.enable_expression_cache(2);
+        context.set_variable("x", 1.0);
+        assert_eq!(evaluate_f64("x + 2", &context), Ok(3.0));
+        assert_eq!(evaluate_f64("x + 2", &context), Ok(3.0));
+        context.set_variable("x", 5.0);
+        assert_eq!(evaluate_f64("x + 2", &context), Ok(7.0));
+        assert_eq!(context.cache_stats(), stats(2, 1));
+
+        let unclosed = Err(EvalError::UnclosedParenthesis { position: 0 });
+        assert_eq!(evaluate("(2", &context), unclosed);
+        assert_eq!(evaluate("(2", &context), unclosed);
+        assert_eq!(context.cache_stats(), stats(2, 3));
+    }
+
+    #[test]
+    fn least_recently_used_is_dropped() {
+        let mut context = Context64::default();
+        context.enable_expression_cache(2);
+
+        for expression in &["1 + 1", "2 + 2", "1 + 1", "3 + 3", "1 + 1", "2 + 2"] {
+            evaluate(expression, &context).unwrap();
+        }
+        assert_eq!(context.cache_stats(), stats(2, 4));
+    }
+
+    #[test]
+    fn changes_to_functions_empty_the_cache() {
+        let mut context = Context::default();
+        context.enable_expression_cache(4);
+        context.add_function(Function::new("f", 1, |args, _| Ok(args[0] + 1.0)));
+
+        assert_eq!(evaluate_f64("f(1)", &context), Ok(2.0));
+        context.add_function(Function::new("f", 1, |args, _| Ok(args[0] + 10.0)));
+        assert_eq!(evaluate_f64("f(1)", &context), Ok(11.0));
+        context.add_macro("two", "2").unwrap();
+        assert_eq!(evaluate_f64("f(1)", &context), Ok(11.0));
+        assert_eq!(evaluate_f64("f(two)", &context), Ok(12.0));
+        assert_eq!(context.cache_stats(), stats(0, 4));
+    }
+
+    #[test]
+    fn new_variables_empty_the_cache_with_implicit_multiplication() {
+        let mut context = Context::default_with_math_functions();
+        context.set_implicit_multiplication(true);
+        context.enable_expression_cache(4);
+
+        assert_eq!(
+            evaluate("x(2)", &context),
+            Err(EvalError::UnknownFunction(String::from("x")))
+        );
+        context.set_variable("x", 5.0);
+        assert_eq!(evaluate_f64("x(2)", &context), Ok(10.0));
+        context.set_variable("x", 6.0);
+        assert_eq!(evaluate_f64("x(2)", &context), Ok(12.0));
+        assert_eq!(context.cache_stats(), stats(1, 2));
+    }
+}
@@ -0,0 +1,272 @@
+//! Symbolic differentiation of expressions.
+
+use crate::{Context, DiffError, Expr, ExprBuilder, Expression};
+
+impl Expr {
+    /// The derivative of the expression with respect to the variable `var`,
+    /// using the sum, product, quotient and chain rules. Angles are taken to
+    /// be in radians. Subexpressions that don't contain `var` are constants,
+    /// whatever they are.
+    ///
+    /// Supported are the operators `+`, `-`, `*`, `/`, `^`, prefix `-` and
+    /// `+`, the functions `sin`, `cos`, `tan`, `exp`, `ln` and `sqrt`, and
+    /// `if`, whose branches are differentiated. Anything else that contains
+    /// `var` fails with a `DiffError` that names it. The result isn't
+    /// simplified, see `Expr::simplify`.
+    pub fn derivative(&self, var: &str) -> Result<Self, DiffError> {
+        if !self.variables_used().contains(var) {
+            return Ok(number(0.0));
+        }
+
+        Ok(match self {
+            Self::Number(..) => number(0.0),
+            Self::Variable(name) => number(if name == var { 1.0 } else { 0.0 }),
+            Self::Prefix(symbol, operand) if symbol == "-" => negate(operand.derivative(var)?),
+            Self::Prefix(symbol, operand) if symbol == "+" => operand.derivative(var)?,
+            Self::Prefix(symbol, _) | Self::Postfix(symbol, _) => {
+                return Err(DiffError::UnsupportedOperator(symbol.clone()))
+            }
+            Self::Binary(symbol, left, right) => binary_derivative(symbol, left, right, var)?,
+            Self::Call(name, args) if name == "if" && args.len() == 3 => call(
+                "if",
+                vec![
+                    args[0].clone(),
+                    args[1].derivative(var)?,
+                    args[2].derivative(var)?,
+                ],
+            ),
+            Self::Call(name, args) if args.len() == 1 => {
+                let inner = args[0].derivative(var)?;
+                let u = args[0].clone();
+                let outer = match name.as_str() {
+                    "sin" => call("cos", vec![u]),
+                    "cos" => negate(call("sin", vec![u])),
+                    "tan" => binary(
+                        "/",
+                        number(1.0),
+                        binary("^", call("cos", vec![u]), number(2.0)),
+                    ),
+                    "exp" => call("exp", vec![u]),
+                    "ln" => binary("/", number(1.0), u),
+                    "sqrt" => binary(
+                        "/",
+                        number(1.0),
+                        binary("*", number(2.0), call("sqrt", vec![u])),
+                    ),
+                    _ => return Err(DiffError::UnsupportedFunction(name.clone())),
+                };
+                binary("*", outer, inner)
+            }
+            Self::Call(name, _) => return Err(DiffError::UnsupportedFunction(name.clone())),
+            Self::Assign(..) => return Err(DiffError::UnsupportedOperator(String::from("="))),
+        })
+    }
+
+    /// The derivative with respect to `var`, simplified in `context`. Fails
+    /// like `derivative`.
+    pub fn partial_derivative(&self, var: &str, context: &Context) -> Result<Self, DiffError> {
+        Ok(self.derivative(var)?.simplify(context))
+    }
+
+    /// The gradient, i.e. the partial derivatives with respect to each of
+    /// `vars` in that order.
+    pub fn grad(&self, vars: &[&str], context: &Context) -> Result<Vec<Self>, DiffError> {
+        vars.iter()
+            .map(|var| self.partial_derivative(var, context))
+            .collect()
+    }
+}
+
+fn binary_derivative(symbol: &str, u: &Expr, v: &Expr, var: &str) -> Result<Expr, DiffError> {
+    let depends = |expr: &Expr| expr.variables_used().contains(var);
+    let (du, dv) = (u.derivative(var)?, v.derivative(var)?);
+    let (u, v) = (u.clone(), v.clone());
+
+    Ok(match symbol {
+        "+" | "-" => binary(symbol, du, dv),
+        "*" if !depends(&u) => binary("*", u, dv),
+        "*" if !depends(&v) => binary("*", du, v),
+        "*" => binary("+", binary("*", du, v.clone()), binary("*", u, dv)),
+        "/" if !depends(&v) => binary("/", du, v),
+        "/" => binary(
+            "/",
+            binary("-", binary("*", du, v.clone()), binary("*", u, dv)),
+            binary("^", v, number(2.0)),
+        ),
+        "^" | "**" if !depends(&v) => {
+            let lowered = binary("^", u, binary("-", v.clone(), number(1.0)));
+            binary("*", binary("*", v, lowered), du)
+        }
+        "^" | "**" if !depends(&u) => {
+            let power = binary("^", u.clone(), v);
+            binary("*", binary("*", power, call("ln", vec![u])), dv)
+        }
+        "^" | "**" => {
+            // u^v = exp(v ln u), so its derivative is u^v (v' ln u + v u' / u).
+            let power = binary("^", u.clone(), v.clone());
+            let exponent = binary("*", dv, call("ln", vec![u.clone()]));
+            let base = binary("/", binary("*", v, du), u);
+            binary("*", power, binary("+", exponent, base))
+        }
+        _ => return Err(DiffError::UnsupportedOperator(String::from(symbol))),
+    })
+}
+
+fn number(value: f64) -> Expr {
+    ExprBuilder::number(value)
+}
+
+fn negate(expr: Expr) -> Expr {
+    Expr::Prefix(String::from("-"), Box::new(expr))
+}
+
+fn binary(symbol: &str, left: Expr, right: Expr) -> Expr {
+    Expr::Binary(String::from(symbol), Box::new(left), Box::new(right))
+}
+
+fn call(name: &str, args: Vec<Expr>) -> Expr {
+    Expr::Call(String::from(name), args)
+}
+
+impl Expression {
+    /// The derivative with respect to `var`, see `Expr::derivative`.
+    pub fn derivative(&self, var: &str) -> Result<Expression, DiffError> {
+        let expr = Expr::from_postfix(self).map_err(DiffError::InvalidExpression)?;
+        Ok(Expression::from(expr.derivative(var)?.to_postfix()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use crate::{
+        evaluate_f64, parse, postfix_to_infix, Context, DiffError, EvalError, Expression, Token,
+    };
+    use proptest::prelude::*;
+
+    fn derivative(expression: &str, context: &Context) -> Result<String, DiffError> {
+        let expression = Expression::compile(expression, context).unwrap();
+        let derivative = expression.derivative("x")?;
+        let infix = postfix_to_infix(&derivative, context).unwrap();
+        let simplified = parse(&infix, context).unwrap().simplify(context);
+        Ok(postfix_to_infix(&simplified.to_postfix(), context).unwrap())
+    }
+
+    #[test]
+    fn rules() {
+        let context = Context::default_with_math_functions();
+
+        assert_eq!(
+            derivative("x^2 + 3 * x", &context),
+            Ok(String::from("2 * x + 3"))
+        );
+        assert_eq!(derivative("5 - y", &context), Ok(String::from("0")));
+        assert_eq!(derivative("-x / y", &context), Ok(String::from("(-1) / y")));
+        assert_eq!(derivative("x * y", &context), Ok(String::from("y")));
+        assert_eq!(
+            derivative("sin(2 * x)", &context),
+            Ok(String::from("2 * cos(2 * x)"))
+        );
+        assert_eq!(derivative("ln(x)", &context), Ok(String::from("1 / x")));
+        assert_eq!(
+            derivative("if(x > 0, x^3, -x)", &context),
+            Ok(String::from("if(x > 0, 3 * x ^ 2, (-1))"))
+        );
+    }
+
+    #[test]
+    fn values() {
+        let mut context = Context::default_with_math_functions();
+        let slope = |expression: &str, x: f64, context: &mut Context| {
+            context.set_variable("x", x);
+            let derivative = derivative(expression, context).unwrap();
+            evaluate_f64(&derivative, context).unwrap()
+        };
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-12;
+
+        assert!(close(
+            slope("x * sin(x)", 1.0, &mut context),
+            1f64.sin() + 1f64.cos()
+        ));
+        assert!(close(
+            slope("exp(x) / x", 2.0, &mut context),
+            2f64.exp() / 4.0
+        ));
+        assert!(close(
+            slope("sqrt(x^2 + 1)", 1.0, &mut context),
+            0.5f64.sqrt()
+        ));
+        assert!(close(slope("2 ^ x", 3.0, &mut context), 8.0 * 2f64.ln()));
+        assert!(close(
+            slope("x ^ x", 2.0, &mut context),
+            4.0 * (2f64.ln() + 1.0)
+        ));
+        assert!(close(
+            slope("tan(x) - cos(x)", 0.5, &mut context),
+            1.0 / 0.5f64.cos().powi(2) + 0.5f64.sin()
+        ));
+    }
+
+    #[test]
+    fn unsupported() {
+        let context = Context::default_with_math_functions();
+
+        assert_eq!(
+            derivative("max(x, 1)", &context),
+            Err(DiffError::UnsupportedFunction(String::from("max")))
+        );
+        assert_eq!(
+            derivative("x!", &context),
+            Err(DiffError::UnsupportedOperator(String::from("!")))
+        );
+        assert_eq!(derivative("floor(y)", &context), Ok(String::from("0")));
+        let queue = VecDeque::from(vec![Token::Symbol(String::from("+"))]);
+        assert_eq!(
+            Expression::from(queue).derivative("x").unwrap_err(),
+            DiffError::InvalidExpression(EvalError::MissingOperand(String::from("+")))
+        );
+    }
+
+    #[test]
+    fn gradient() {
+        let context = Context::default_with_math_functions();
+        let expr = parse("x^2 + y^2", &context).unwrap();
+        let grad = expr.grad(&["x", "y"], &context).unwrap();
+        let grad: Vec<String> = grad.iter().map(|expr| expr.to_string()).collect();
+        assert_eq!(grad, vec!["2 * x", "2 * y"]);
+
+        assert_eq!(
+            parse("max(x, y)", &context)
+                .unwrap()
+                .grad(&["x", "y"], &context),
+            Err(DiffError::UnsupportedFunction(String::from("max")))
+        );
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(20))]
+
+        #[test]
+        fn gradient_matches_differences(x in -2.0..2.0f64, y in -2.0..2.0f64) {
+            let mut context = Context::default_with_math_functions();
+            let expr = parse("x^2 * y + sin(x * y)", &context).unwrap();
+            let grad = expr.grad(&["x", "y"], &context).unwrap();
+            let point = [x, y];
+            let h = 1e-6;
+            for (i, var) in ["x", "y"].iter().enumerate() {
+                let mut at = |offset: f64| {
+                    for (j, name) in ["x", "y"].iter().enumerate() {
+                        let shift = if i == j { offset } else { 0.0 };
+                        context.set_variable(name, point[j] + shift);
+                    }
+                    expr.evaluate(&context).unwrap()
+                };
+                let numeric = (at(h) - at(-h)) / (2.0 * h);
+                at(0.0);
+                let exact = grad[i].evaluate(&context).unwrap();
+                prop_assert!((exact - numeric).abs() < 1e-6, "d/d{} at {:?}", var, point);
+            }
+        }
+    }
+}
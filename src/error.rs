@@ -0,0 +1,446 @@
+use crate::{Arity, Value};
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    MalformedNumber(String),
+    NumberOutOfRange(String),
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedNumber(text) => write!(f, "Malformed number {}", text),
+            Self::NumberOutOfRange(text) => write!(f, "Number {} is out of range", text),
+        }
+    }
+}
+
+impl Error for LexError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    Lex(LexError),
+    Operator(OperatorError),
+    UnknownOperator(String),
+    UnknownVariable(String),
+    UnknownFunction(String),
+    UnexpectedToken(String),
+    MismatchedParentheses,
+    /// An opening parenthesis at a byte offset is never closed.
+    UnclosedParenthesis {
+        position: usize,
+    },
+    /// A closing parenthesis at a byte offset has no opening partner.
+    UnexpectedClosingParenthesis {
+        position: usize,
+    },
+    MissingOperand(String),
+    WrongNumberOfArguments {
+        name: String,
+        expected: Vec<Arity>,
+        found: usize,
+    },
+    EmptyExpression,
+    TooManyOperands(usize),
+    RecursionLimitExceeded,
+    /// A `series` or `prod` would iterate over more values than the context
+    /// allows, see `Context::set_max_iterations`.
+    IterationLimitExceeded,
+    /// An identifier was used in a context that only allows arithmetic.
+    FeatureDisabled(String),
+    /// The left side of `=` is something other than a variable.
+    InvalidAssignmentTarget(String),
+    /// An assignment was evaluated without a mutable context.
+    AssignmentNotAllowed(String),
+    /// A variable was followed by parentheses as if it were a function, like
+    /// `x(2)`.
+    IdentifierUsedAsFunction(String),
+    /// An operator can't be applied to a value, like a bitwise operator to a
+    /// fraction.
+    InvalidOperand {
+        operator: String,
+        value: f64,
+    },
+    /// Two non-associative operators of the same precedence follow each other,
+    /// like in `a < b < c`. The positions are byte offsets into the
+    /// expression.
+    ChainedNonAssociative {
+        first: String,
+        first_position: usize,
+        second: String,
+        second_position: usize,
+    },
+    /// A macro pattern isn't a name, optionally followed by a parenthesized
+    /// list of parameter names.
+    InvalidMacroPattern(String),
+    /// The result of an operator is too large to be represented exactly.
+    ResultOutOfRange {
+        operator: String,
+        value: f64,
+    },
+    /// The step of a table is zero or goes away from the end of the range.
+    InvalidStep(f64),
+    /// Evaluating a table or an integrand failed at one of its values.
+    AtSample {
+        value: f64,
+        error: Box<EvalError>,
+    },
+    /// An integrand evaluated to infinity or NaN.
+    NonFiniteValue(f64),
+    /// An expression evaluated to infinity or NaN, see `evaluate_checked`.
+    NonFiniteResult {
+        value: f64,
+        expression: String,
+    },
+    /// A bound of an integral is infinite or NaN.
+    InvalidBound(f64),
+    /// The tolerance of an integral isn't positive and finite.
+    InvalidTolerance(f64),
+    /// An integral didn't reach its tolerance. Holds the estimate that was
+    /// reached and its estimated error.
+    NotConverged {
+        value: f64,
+        error: f64,
+    },
+    /// Reading the input failed. Holds the message of the underlying error.
+    Io(String),
+    /// An operation on integers has a result that isn't an integer, see
+    /// `IntegerMode::Strict`.
+    NotAnInteger {
+        operator: String,
+        value: f64,
+    },
+    /// An operator, function or condition got a value of the wrong type, see
+    /// `Context::set_strict_truth_values`.
+    TypeMismatch {
+        operator: String,
+        value: Value,
+    },
+    /// An integer that doesn't fit into an `i64` was combined with a float,
+    /// see `IntegerMode::Strict`.
+    #[cfg(feature = "bigint")]
+    PrecisionLoss {
+        operator: String,
+        value: Value,
+    },
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Lex(error) => error.fmt(f),
+            Self::Operator(error) => error.fmt(f),
+            Self::UnknownOperator(symbol) => write!(f, "Unknown operator {}", symbol),
+            Self::UnknownVariable(name) => write!(f, "Unknown variable {}", name),
+            Self::UnknownFunction(name) => write!(f, "Unknown function {}", name),
+            Self::UnexpectedToken(token) => write!(f, "Unexpected {}", token),
+            Self::MismatchedParentheses => write!(f, "Mismatched parentheses"),
+            Self::UnclosedParenthesis { position } => {
+                write!(f, "Parenthesis at position {} is never closed", position)
+            }
+            Self::UnexpectedClosingParenthesis { position } => write!(
+                f,
+                "Closing parenthesis at position {} has no opening parenthesis",
+                position
+            ),
+            Self::MissingOperand(symbol) => write!(f, "Missing operand for {}", symbol),
+            Self::WrongNumberOfArguments {
+                name,
+                expected,
+                found,
+            } => {
+                let expected: Vec<String> = expected
+                    .iter()
+                    .map(|arity| match arity {
+                        Arity::Exact(n) => n.to_string(),
+                        Arity::AtLeast(n) => format!("at least {}", n),
+                    })
+                    .collect();
+                write!(
+                    f,
+                    "{} takes {} arguments, but got {}",
+                    name,
+                    expected.join(" or "),
+                    found
+                )
+            }
+            Self::EmptyExpression => write!(f, "Empty expression"),
+            Self::TooManyOperands(extra) => {
+                write!(f, "{} operands too many, an operator is missing", extra)
+            }
+            Self::RecursionLimitExceeded => write!(f, "Recursion limit exceeded"),
+            Self::IterationLimitExceeded => write!(f, "Iteration limit exceeded"),
+            Self::FeatureDisabled(name) => write!(f, "{} is not allowed here", name),
+            Self::InvalidAssignmentTarget(target) => write!(f, "Can't assign to {}", target),
+            Self::AssignmentNotAllowed(name) => {
+                write!(f, "Assigning to {} is not allowed here", name)
+            }
+            Self::IdentifierUsedAsFunction(name) => {
+                write!(f, "{} is a variable, not a function", name)
+            }
+            Self::InvalidOperand { operator, value } => {
+                write!(f, "{} can't be applied to {}", operator, value)
+            }
+            Self::ChainedNonAssociative {
+                first,
+                first_position,
+                second,
+                second_position,
+            } => write!(
+                f,
+                "{} at position {} can't be chained with {} at position {} without parentheses",
+                first, first_position, second, second_position
+            ),
+            Self::InvalidMacroPattern(pattern) => write!(f, "Invalid macro pattern {}", pattern),
+            Self::ResultOutOfRange { operator, value } => {
+                write!(f, "Result {} of {} is out of range", value, operator)
+            }
+            Self::InvalidStep(step) => write!(f, "Invalid step {}", step),
+            Self::AtSample { value, error } => write!(f, "{} at {}", error, value),
+            Self::NonFiniteValue(value) => write!(f, "Non-finite value {}", value),
+            Self::NonFiniteResult { value, expression } => {
+                write!(f, "{} evaluates to {}", expression, value)
+            }
+            Self::InvalidBound(bound) => write!(f, "Invalid bound {}", bound),
+            Self::InvalidTolerance(tolerance) => write!(f, "Invalid tolerance {}", tolerance),
+            Self::NotConverged { value, error } => {
+                write!(
+                    f,
+                    "Did not converge, reached {} with error {}",
+                    value, error
+                )
+            }
+            Self::Io(message) => write!(f, "Reading input failed: {}", message),
+            Self::NotAnInteger { operator, value } => {
+                write!(f, "Result {} of {} is not an integer", value, operator)
+            }
+            Self::TypeMismatch { operator, value } => write!(
+                f,
+                "{} can't be applied to {} {}",
+                operator,
+                value.type_name(),
+                value
+            ),
+            #[cfg(feature = "bigint")]
+            Self::PrecisionLoss { operator, value } => {
+                write!(f, "{} would round {} to a float", operator, value)
+            }
+        }
+    }
+}
+
+/// Has no `source`, because the messages of wrapped errors are already part
+/// of its own, and error reports that walk the chain would repeat them.
+impl Error for EvalError {}
+
+/// Why `Context::add_function_checked` refused to add a function.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FunctionValidationError {
+    ReturnedNan {
+        inputs: Vec<f64>,
+    },
+    ReturnedInfinity {
+        inputs: Vec<f64>,
+    },
+    /// Calling the function with the test inputs failed.
+    Failed {
+        inputs: Vec<f64>,
+        error: EvalError,
+    },
+}
+
+impl fmt::Display for FunctionValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inputs = |inputs: &[f64]| {
+            let inputs: Vec<String> = inputs.iter().map(ToString::to_string).collect();
+            inputs.join(", ")
+        };
+        match self {
+            Self::ReturnedNan { inputs: values } => {
+                write!(f, "Returned NaN for ({})", inputs(values))
+            }
+            Self::ReturnedInfinity { inputs: values } => {
+                write!(f, "Returned infinity for ({})", inputs(values))
+            }
+            Self::Failed {
+                inputs: values,
+                error,
+            } => write!(f, "Failed for ({}): {}", inputs(values), error),
+        }
+    }
+}
+
+impl Error for FunctionValidationError {}
+
+/// Why `Context::add_operator` or `Context::add_operator_alias` refused to add
+/// an operator.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OperatorError {
+    /// The symbol contains characters that are reserved for other tokens.
+    InvalidSymbol(String),
+    /// There already is an operator or alias with this symbol.
+    AlreadyDefined(String),
+    /// The operator an alias refers to doesn't exist.
+    UnknownTarget(String),
+    /// An infix operator was given where only prefix and postfix operators
+    /// are allowed.
+    NotUnary(String),
+}
+
+impl fmt::Display for OperatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidSymbol(symbol) => write!(f, "Invalid operator symbol {}", symbol),
+            Self::AlreadyDefined(symbol) => write!(f, "Operator {} is already defined", symbol),
+            Self::UnknownTarget(symbol) => write!(f, "Unknown operator {}", symbol),
+            Self::NotUnary(symbol) => write!(f, "Operator {} is not unary", symbol),
+        }
+    }
+}
+
+impl Error for OperatorError {}
+
+/// Why `solve` couldn't find a root.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SolveError {
+    /// Evaluating the expression failed, see `EvalError::AtSample`.
+    Eval(EvalError),
+    /// The derivative is zero at a value, and no values with opposite signs
+    /// are known.
+    ZeroDerivative(f64),
+    /// The steps kept moving away from zero. Holds the last value.
+    Diverged(f64),
+    /// The maximum number of iterations was reached. Holds the last value
+    /// and the expression's value there.
+    NotConverged { value: f64, residual: f64 },
+    /// The expression has the same sign at both ends of the bracket.
+    InvalidBracket { start: f64, end: f64 },
+}
+
+impl fmt::Display for SolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Eval(error) => write!(f, "{}", error),
+            Self::ZeroDerivative(value) => write!(f, "Zero derivative at {}", value),
+            Self::Diverged(value) => write!(f, "Diverged at {}", value),
+            Self::NotConverged { value, residual } => {
+                write!(
+                    f,
+                    "Did not converge, reached {} with value {}",
+                    value, residual
+                )
+            }
+            Self::InvalidBracket { start, end } => {
+                write!(f, "No sign change between {} and {}", start, end)
+            }
+        }
+    }
+}
+
+impl Error for SolveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Eval(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Why `Expression::derivative` couldn't differentiate an expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffError {
+    /// The expression calls a function without a known derivative.
+    UnsupportedFunction(String),
+    /// The expression uses an operator without a known derivative.
+    UnsupportedOperator(String),
+    /// The expression isn't a valid postfix queue.
+    InvalidExpression(EvalError),
+}
+
+impl fmt::Display for DiffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedFunction(name) => write!(f, "Cannot differentiate function {}", name),
+            Self::UnsupportedOperator(symbol) => {
+                write!(f, "Cannot differentiate operator {}", symbol)
+            }
+            Self::InvalidExpression(error) => write!(f, "Invalid expression: {}", error),
+        }
+    }
+}
+
+impl Error for DiffError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::InvalidExpression(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{evaluate, Arity, Context64, EvalError, FunctionValidationError, LexError};
+    use std::error::Error;
+
+    #[test]
+    fn messages() {
+        let context = Context64::default();
+        let message = |expression| evaluate(expression, &context).unwrap_err().to_string();
+
+        assert_eq!(message("1 + x"), "Unknown variable x");
+        assert_eq!(message("2 *"), "Missing operand for *");
+        assert_eq!(
+            message("(1 + 2"),
+            "Parenthesis at position 0 is never closed"
+        );
+        assert_eq!(message("0.5 & 1"), "Unknown operator &");
+        assert_eq!(
+            EvalError::WrongNumberOfArguments {
+                name: String::from("log"),
+                expected: vec![Arity::Exact(1), Arity::AtLeast(3)],
+                found: 2,
+            }
+            .to_string(),
+            "log takes 1 or at least 3 arguments, but got 2"
+        );
+    }
+
+    #[test]
+    fn sources() {
+        let lex = LexError::MalformedNumber(String::from("1.2.3"));
+        let error = EvalError::Lex(lex.clone());
+
+        assert_eq!(error.to_string(), "Malformed number 1.2.3");
+        assert!(error.source().is_none());
+        assert!(EvalError::EmptyExpression.source().is_none());
+
+        let error = EvalError::AtSample {
+            value: 2.0,
+            error: Box::new(error),
+        };
+        assert_eq!(error.to_string(), "Malformed number 1.2.3 at 2");
+        assert!(error.source().is_none());
+    }
+
+    #[test]
+    fn function_validation_messages() {
+        let error = FunctionValidationError::ReturnedNan {
+            inputs: vec![-1.0, 0.5],
+        };
+        assert_eq!(error.to_string(), "Returned NaN for (-1, 0.5)");
+        assert_eq!(
+            FunctionValidationError::ReturnedInfinity { inputs: vec![0.0] }.to_string(),
+            "Returned infinity for (0)"
+        );
+
+        let error = FunctionValidationError::Failed {
+            inputs: vec![1.0],
+            error: EvalError::UnknownVariable(String::from("x")),
+        };
+        assert_eq!(error.to_string(), "Failed for (1): Unknown variable x");
+        assert!(error.source().is_none());
+    }
+}
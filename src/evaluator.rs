@@ -0,0 +1,58 @@
+use crate::{evaluate_queue, Context, EvalError, Expression, Number, Value};
+use std::collections::HashMap;
+
+/// Keeps the buffers that evaluation needs between calls, so that evaluating
+/// compiled expressions over and over doesn't allocate once the buffers have
+/// grown large enough. Calls of user defined functions still allocate.
+#[derive(Debug, Default)]
+pub struct Evaluator<N = f64> {
+    stack: Vec<Value<N>>,
+    slots: Vec<Option<Value<N>>>,
+}
+
+impl<N: Number> Evaluator<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compiles and evaluates an expression. Only evaluation reuses the
+    /// buffers; parsing allocates as usual.
+    pub fn eval(&mut self, expression: &str, context: &Context<N>) -> Result<N, EvalError> {
+        let expression = Expression::compile(expression, context)?;
+        self.eval_compiled(&expression, context)
+    }
+
+    /// Evaluates an expression, which only reuses the buffers if it was
+    /// compiled with `Expression::compile`.
+    pub fn eval_compiled(
+        &mut self,
+        expression: &Expression<N>,
+        context: &Context<N>,
+    ) -> Result<N, EvalError> {
+        match expression.program() {
+            Some(program) => {
+                program.run_with_buffers(context, &HashMap::new(), &mut self.stack, &mut self.slots)
+            }
+            None => evaluate_queue(expression, context),
+        }
+        .map(|value| value.to_number())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Context, Evaluator, Expression};
+
+    #[test]
+    fn reused_between_expressions() {
+        let mut context = Context::default();
+        context.set_variable("x", 2.0);
+        let mut evaluator = Evaluator::new();
+
+        assert_eq!(evaluator.eval("x * (1 + x)", &context), Ok(6.0));
+        assert!(evaluator.eval("x +", &context).is_err());
+        assert_eq!(evaluator.eval("x", &context), Ok(2.0));
+        let composed = Expression::variable("x") + Expression::number(1.0);
+        assert_eq!(evaluator.eval_compiled(&composed, &context), Ok(3.0));
+    }
+}
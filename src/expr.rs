@@ -0,0 +1,1343 @@
+use crate::macros::expand_macros;
+use crate::{
+    evaluate_queue, is_chained_comparison, is_logical_operator, is_postfix_operator, is_reducer,
+    is_variable_not_function, logical_branch, pop_operand, Associativity, Context, EvalError,
+    Operator, Token, Tokens,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::FromStr;
+
+/// An expression parsed into a tree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// A number literal, holding its value and the text it was parsed from,
+    /// like `Token::Number`. Numbers that weren't parsed, like the values of
+    /// folded constants, are written as `f64::to_string` writes them.
+    Number(f64, String),
+    Variable(String),
+    /// A prefix operator applied to an operand, like `-x`.
+    Prefix(String, Box<Expr>),
+    /// A postfix operator applied to an operand, like `x!`.
+    Postfix(String, Box<Expr>),
+    /// An infix operator applied to a left and right operand.
+    Binary(String, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    /// An assignment of a value to a variable, like `x = 2`.
+    Assign(String, Box<Expr>),
+}
+
+impl Expr {
+    /// Builds the tree of a postfix queue, the inverse of `to_postfix`.
+    /// Branches become calls of `if`, including the ones that `&&` and `||`
+    /// are converted to.
+    pub(crate) fn from_postfix(queue: &VecDeque<Token>) -> Result<Self, EvalError> {
+        let mut stack: Vec<Self> = Vec::new();
+        for token in queue {
+            let expr = match token {
+                Token::Number(value, text) => Self::Number(*value, text.clone()),
+                Token::Identifier(name) => Self::Variable(name.clone()),
+                Token::Symbol(symbol) => {
+                    let right = pop_operand(&mut stack, symbol)?;
+                    let left = pop_operand(&mut stack, symbol)?;
+                    Self::Binary(symbol.clone(), Box::new(left), Box::new(right))
+                }
+                Token::Prefix(symbol) => {
+                    Self::Prefix(symbol.clone(), Box::new(pop_operand(&mut stack, symbol)?))
+                }
+                Token::Postfix(symbol) => {
+                    Self::Postfix(symbol.clone(), Box::new(pop_operand(&mut stack, symbol)?))
+                }
+                Token::Call(name, num_args) => {
+                    if stack.len() < *num_args {
+                        return Err(EvalError::MissingOperand(name.clone()));
+                    }
+                    Self::Call(name.clone(), stack.split_off(stack.len() - num_args))
+                }
+                Token::Branch(then, otherwise) => {
+                    let condition = pop_operand(&mut stack, "if")?;
+                    let args = vec![
+                        condition,
+                        Self::from_postfix(then)?,
+                        Self::from_postfix(otherwise)?,
+                    ];
+                    Self::Call(String::from("if"), args)
+                }
+                Token::Reduce(name, variable, body) => {
+                    let end = pop_operand(&mut stack, name)?;
+                    let start = pop_operand(&mut stack, name)?;
+                    let variable = Self::Variable(variable.clone());
+                    let args = vec![variable, start, end, Self::from_postfix(body)?];
+                    Self::Call(name.clone(), args)
+                }
+                Token::Assign(name) => {
+                    Self::Assign(name.clone(), Box::new(pop_operand(&mut stack, "=")?))
+                }
+                Token::Whitespace(_) => continue,
+                Token::LeftParenthesis | Token::RightParenthesis => {
+                    return Err(EvalError::MismatchedParentheses)
+                }
+                Token::Separator => return Err(EvalError::UnexpectedToken(String::from(","))),
+            };
+            stack.push(expr);
+        }
+
+        match stack.len() {
+            0 => Err(EvalError::EmptyExpression),
+            1 => Ok(stack.remove(0)),
+            n => Err(EvalError::TooManyOperands(n - 1)),
+        }
+    }
+
+    /// Converts the tree to a postfix queue that can be evaluated with
+    /// `evaluate_queue`.
+    pub fn to_postfix(&self) -> VecDeque<Token> {
+        let mut queue = VecDeque::new();
+        self.push_postfix(&mut queue);
+        queue
+    }
+
+    pub fn evaluate(&self, context: &Context) -> Result<f64, EvalError> {
+        evaluate_queue(self.to_postfix(), context).map(|value| value.to_f64())
+    }
+
+    /// The names of all variables the expression reads. Variables that are
+    /// only assigned to aren't included.
+    pub fn variables_used(&self) -> HashSet<String> {
+        let mut names = HashSet::new();
+        self.visit(&mut |expr| {
+            if let Self::Variable(name) = expr {
+                names.insert(name.clone());
+            }
+        });
+        names
+    }
+
+    /// The names of all functions the expression calls with parentheses.
+    /// `if` isn't included, since it is a special form rather than a function.
+    pub fn functions_called(&self) -> HashSet<String> {
+        let mut names = HashSet::new();
+        self.visit(&mut |expr| {
+            if let Self::Call(name, _) = expr {
+                if name != "if" {
+                    names.insert(name.clone());
+                }
+            }
+        });
+        names
+    }
+
+    /// Whether the expression has the same value whenever it is evaluated in
+    /// `context`, because it reads no variables and doesn't assign any.
+    /// Identifiers that name a function in the context are calls rather than
+    /// variables, and functions are assumed to depend only on their arguments.
+    pub fn is_constant(&self, context: &Context) -> bool {
+        match self {
+            Self::Number(..) => true,
+            Self::Variable(name) => context.function_exists(name),
+            Self::Prefix(_, operand) | Self::Postfix(_, operand) => operand.is_constant(context),
+            Self::Binary(_, left, right) => left.is_constant(context) && right.is_constant(context),
+            Self::Call(_, args) => args.iter().all(|arg| arg.is_constant(context)),
+            Self::Assign(..) => false,
+        }
+    }
+
+    /// Replaces each constant subexpression, see `is_constant`, with its
+    /// value. Fails if a constant subexpression fails to evaluate, unless it
+    /// is only evaluated conditionally, like a branch of `if` or the right
+    /// operand of `&&`, in which case it is kept as it is.
+    pub fn fold_constants(&self, context: &Context) -> Result<Self, EvalError> {
+        self.fold(context, false)
+    }
+
+    fn fold(&self, context: &Context, conditional: bool) -> Result<Self, EvalError> {
+        if self.is_constant(context) {
+            match self.evaluate(context) {
+                Ok(value) => return Ok(ExprBuilder::number(value)),
+                Err(error) if !conditional => return Err(error),
+                Err(_) => (),
+            }
+        }
+
+        let fold = |expr: &Self, conditional| expr.fold(context, conditional).map(Box::new);
+        Ok(match self {
+            Self::Number(..) | Self::Variable(_) => self.clone(),
+            Self::Prefix(symbol, operand) => {
+                Self::Prefix(symbol.clone(), fold(operand, conditional)?)
+            }
+            Self::Postfix(symbol, operand) => {
+                Self::Postfix(symbol.clone(), fold(operand, conditional)?)
+            }
+            Self::Binary(symbol, left, right) => {
+                let right_conditional = conditional || is_logical_operator(symbol);
+                Self::Binary(
+                    symbol.clone(),
+                    fold(left, conditional)?,
+                    fold(right, right_conditional)?,
+                )
+            }
+            Self::Call(name, args) => {
+                let is_if = name == "if" && args.len() == 3;
+                let args = args
+                    .iter()
+                    .enumerate()
+                    .map(|(index, arg)| arg.fold(context, conditional || (is_if && index > 0)))
+                    .collect::<Result<_, _>>()?;
+                Self::Call(name.clone(), args)
+            }
+            Self::Assign(name, value) => Self::Assign(name.clone(), fold(value, conditional)?),
+        })
+    }
+
+    /// Simplifies the expression without changing its value or its errors:
+    /// constant subexpressions are folded as in `fold_constants`, operations
+    /// with an identity element like `x * 1`, `x + 0` and `x ^ 1` are reduced
+    /// to `x`, and so are double negations `--x` and unary plus `+x`.
+    /// Operators defined by expressions are left alone. `x * 0` isn't reduced
+    /// to 0, since that would hide the error if `x` is unknown.
+    ///
+    /// If one operand of a commutative operator is a number, the operands are
+    /// put in a canonical order, sorted by how they are written, so that
+    /// `x * 2` and `2 * x` simplify to the same `Expr`. Other operands keep
+    /// their order, since both could fail and the left one's error is the one
+    /// that's reported.
+    pub fn simplify(&self, context: &Context) -> Self {
+        let simplify = |expr: &Self| Box::new(expr.simplify(context));
+        let simplified = match self {
+            Self::Number(..) | Self::Variable(_) => self.clone(),
+            Self::Prefix(symbol, operand) => {
+                let operand = simplify(operand);
+                let is_negation =
+                    |symbol| builtin_symbol(context.get_prefix_operator(symbol)) == Some("-");
+                let is_plus = builtin_symbol(context.get_prefix_operator(symbol)) == Some("+");
+                match *operand {
+                    _ if is_plus => *operand,
+                    Self::Prefix(ref inner, ref value)
+                        if is_negation(symbol) && is_negation(inner) =>
+                    {
+                        (**value).clone()
+                    }
+                    _ => Self::Prefix(symbol.clone(), operand),
+                }
+            }
+            Self::Postfix(symbol, operand) => Self::Postfix(symbol.clone(), simplify(operand)),
+            Self::Binary(symbol, left, right) => {
+                let (left, right) = (simplify(left), simplify(right));
+                let is = |expr: &Self, constant: f64| matches!(expr, Self::Number(value, _) if *value == constant);
+                let is_commutative = |symbol| {
+                    context
+                        .get_operator(symbol)
+                        .is_some_and(Operator::is_commutative)
+                };
+                match builtin_symbol(context.get_operator(symbol)) {
+                    Some("+") if is(&left, 0.0) => *right,
+                    Some("+") | Some("-") if is(&right, 0.0) => *left,
+                    Some("*") if is(&left, 1.0) => *right,
+                    Some("*") | Some("/") | Some("^") if is(&right, 1.0) => *left,
+                    _ if is_commutative(symbol)
+                        && (matches!(*left, Self::Number(..))
+                            || matches!(*right, Self::Number(..)))
+                        && left.to_string() > right.to_string() =>
+                    {
+                        Self::Binary(symbol.clone(), right, left)
+                    }
+                    _ => Self::Binary(symbol.clone(), left, right),
+                }
+            }
+            Self::Call(name, args) => Self::Call(
+                name.clone(),
+                args.iter().map(|arg| arg.simplify(context)).collect(),
+            ),
+            Self::Assign(name, value) => Self::Assign(name.clone(), simplify(value)),
+        };
+
+        if simplified.is_constant(context) {
+            if let Ok(value) = simplified.evaluate(context) {
+                return ExprBuilder::number(value);
+            }
+        }
+        simplified
+    }
+
+    /// Evaluates every subexpression that doesn't read one of `unknowns` and
+    /// keeps the rest as it is, see `evaluate_symbolic`.
+    fn evaluate_partially(&self, context: &Context, unknowns: &[&str]) -> Self {
+        let variables = self.variables_used();
+        if !unknowns.iter().any(|&name| variables.contains(name)) {
+            if let Ok(value) = self.evaluate(context) {
+                return ExprBuilder::number(value);
+            }
+        }
+
+        let partial = |expr: &Self| Box::new(expr.evaluate_partially(context, unknowns));
+        match self {
+            Self::Number(..) | Self::Variable(_) => self.clone(),
+            Self::Prefix(symbol, operand) => Self::Prefix(symbol.clone(), partial(operand)),
+            Self::Postfix(symbol, operand) => Self::Postfix(symbol.clone(), partial(operand)),
+            Self::Binary(symbol, left, right) => {
+                Self::Binary(symbol.clone(), partial(left), partial(right))
+            }
+            Self::Call(name, args) => Self::Call(
+                name.clone(),
+                args.iter()
+                    .map(|arg| arg.evaluate_partially(context, unknowns))
+                    .collect(),
+            ),
+            Self::Assign(name, value) => Self::Assign(name.clone(), partial(value)),
+        }
+    }
+
+    /// Calls `f` for the expression and each of its subexpressions.
+    fn visit(&self, f: &mut impl FnMut(&Expr)) {
+        f(self);
+        match self {
+            Self::Number(..) | Self::Variable(_) => (),
+            Self::Prefix(_, operand) | Self::Postfix(_, operand) => operand.visit(f),
+            Self::Binary(_, left, right) => {
+                left.visit(f);
+                right.visit(f);
+            }
+            Self::Call(_, args) => args.iter().for_each(|arg| arg.visit(f)),
+            Self::Assign(_, value) => value.visit(f),
+        }
+    }
+
+    /// Counts how often each operator and function is applied, keyed by the
+    /// operator's symbol or the function's name. Assignments aren't counted.
+    pub fn count_operations(&self) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        self.count_operations_into(&mut counts);
+        counts
+    }
+
+    fn count_operations_into(&self, counts: &mut HashMap<String, usize>) {
+        match self {
+            Self::Number(..) | Self::Variable(_) => (),
+            Self::Prefix(symbol, operand) | Self::Postfix(symbol, operand) => {
+                *counts.entry(symbol.clone()).or_insert(0) += 1;
+                operand.count_operations_into(counts);
+            }
+            Self::Binary(symbol, left, right) => {
+                *counts.entry(symbol.clone()).or_insert(0) += 1;
+                left.count_operations_into(counts);
+                right.count_operations_into(counts);
+            }
+            Self::Call(name, args) => {
+                *counts.entry(name.clone()).or_insert(0) += 1;
+                for arg in args {
+                    arg.count_operations_into(counts);
+                }
+            }
+            Self::Assign(_, value) => value.count_operations_into(counts),
+        }
+    }
+
+    fn push_postfix(&self, queue: &mut VecDeque<Token>) {
+        match self {
+            Self::Number(value, text) => queue.push_back(Token::Number(*value, text.clone())),
+            Self::Variable(name) => queue.push_back(Token::Identifier(name.clone())),
+            Self::Prefix(symbol, operand) => {
+                operand.push_postfix(queue);
+                queue.push_back(Token::Prefix(symbol.clone()));
+            }
+            Self::Postfix(symbol, operand) => {
+                operand.push_postfix(queue);
+                queue.push_back(Token::Postfix(symbol.clone()));
+            }
+            Self::Binary(symbol, left, right) if is_logical_operator(symbol) => {
+                left.push_postfix(queue);
+                queue.push_back(logical_branch(symbol, right.to_postfix()));
+            }
+            Self::Binary(symbol, left, right) => {
+                left.push_postfix(queue);
+                right.push_postfix(queue);
+                queue.push_back(Token::Symbol(symbol.clone()));
+            }
+            Self::Call(name, args) if name == "if" && args.len() == 3 => {
+                args[0].push_postfix(queue);
+                queue.push_back(Token::Branch(args[1].to_postfix(), args[2].to_postfix()));
+            }
+            Self::Call(name, args) if is_reducer(name) && args.len() == 4 => match &args[0] {
+                Self::Variable(variable) => {
+                    args[1].push_postfix(queue);
+                    args[2].push_postfix(queue);
+                    let body = args[3].to_postfix();
+                    queue.push_back(Token::Reduce(name.clone(), variable.clone(), body));
+                }
+                _ => {
+                    for arg in args {
+                        arg.push_postfix(queue);
+                    }
+                    queue.push_back(Token::Call(name.clone(), args.len()));
+                }
+            },
+            Self::Call(name, args) => {
+                for arg in args {
+                    arg.push_postfix(queue);
+                }
+                queue.push_back(Token::Call(name.clone(), args.len()));
+            }
+            Self::Assign(name, value) => {
+                value.push_postfix(queue);
+                queue.push_back(Token::Assign(name.clone()));
+            }
+        }
+    }
+}
+
+/// Constructs `Expr` trees without parsing, giving the same trees as parsing
+/// the equivalent expression, e.g.
+/// `ExprBuilder::add(ExprBuilder::number(1.0), ExprBuilder::variable("x"))`
+/// for `1 + x`. Operators use the symbols of the default context.
+pub struct ExprBuilder;
+
+impl ExprBuilder {
+    pub fn number(value: f64) -> Expr {
+        Expr::Number(value, value.to_string())
+    }
+
+    pub fn variable(name: &str) -> Expr {
+        Expr::Variable(String::from(name))
+    }
+
+    pub fn add(left: Expr, right: Expr) -> Expr {
+        Self::binary("+", left, right)
+    }
+
+    pub fn sub(left: Expr, right: Expr) -> Expr {
+        Self::binary("-", left, right)
+    }
+
+    pub fn mul(left: Expr, right: Expr) -> Expr {
+        Self::binary("*", left, right)
+    }
+
+    pub fn div(left: Expr, right: Expr) -> Expr {
+        Self::binary("/", left, right)
+    }
+
+    pub fn pow(base: Expr, exponent: Expr) -> Expr {
+        Self::binary("^", base, exponent)
+    }
+
+    pub fn call(name: &str, args: Vec<Expr>) -> Expr {
+        Expr::Call(String::from(name), args)
+    }
+
+    fn binary(symbol: &str, left: Expr, right: Expr) -> Expr {
+        Expr::Binary(String::from(symbol), Box::new(left), Box::new(right))
+    }
+}
+
+/// The symbol of an operator that isn't defined by an expression, with
+/// aliases resolved.
+fn builtin_symbol(operator: Option<&Operator>) -> Option<&str> {
+    operator
+        .filter(|operator| operator.body.is_none())
+        .map(Operator::symbol)
+}
+
+impl Expr {
+    /// Writes the expression as an operand, in parentheses unless it is a
+    /// single value or call. Prefix operations are parenthesized as well,
+    /// since `-2 ^ 2` would be read as `-(2 ^ 2)`.
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Prefix(..) | Self::Binary(..) | Self::Assign(..) => write!(f, "({})", self),
+            Self::Number(value, _) if value.is_sign_negative() => write!(f, "({})", self),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+/// Writes the expression in infix notation, with operators spelled as they
+/// were parsed, so aliases like `**` are kept. Nested operations are always
+/// parenthesized, regardless of precedence.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(value, _) => write!(f, "{}", value),
+            Self::Variable(name) => write!(f, "{}", name),
+            Self::Prefix(symbol, operand) => {
+                write!(f, "{}", symbol)?;
+                operand.fmt_operand(f)
+            }
+            Self::Postfix(symbol, operand) => {
+                operand.fmt_operand(f)?;
+                write!(f, "{}", symbol)
+            }
+            Self::Binary(symbol, left, right) => {
+                left.fmt_operand(f)?;
+                write!(f, " {} ", symbol)?;
+                right.fmt_operand(f)
+            }
+            Self::Call(name, args) => {
+                write!(f, "{}(", name)?;
+                for (index, arg) in args.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Self::Assign(name, value) => write!(f, "{} = {}", name, value),
+        }
+    }
+}
+
+/// Parses an expression with the operators of the default context, so that
+/// `"x ^ 2 + 1".parse::<Expr>()` works.
+impl FromStr for Expr {
+    type Err = EvalError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        parse(expression, &Context::default())
+    }
+}
+
+impl TryFrom<&str> for Expr {
+    type Error = EvalError;
+
+    fn try_from(expression: &str) -> Result<Self, Self::Error> {
+        expression.parse()
+    }
+}
+
+/// Evaluates as much of an expression as possible without the variables in
+/// `unknowns` and returns the rest as a tree. The unknowns stay variables even
+/// if the context has a value for them. A subexpression that fails to
+/// evaluate is kept as well, so that evaluating the result later gives the
+/// same error.
+///
+/// With `x = 3` in the context, `x ^ 2 + y` with the unknown `y` becomes
+/// `9 + y`.
+pub fn evaluate_symbolic(
+    expression: &str,
+    context: &Context,
+    unknowns: &[&str],
+) -> Result<Expr, EvalError> {
+    Ok(parse(expression, context)?.evaluate_partially(context, unknowns))
+}
+
+/// Parses an expression into a tree.
+///
+/// Unlike `shunting_yard`, this fails at the first token that doesn't fit,
+/// e.g. with `EvalError::UnexpectedToken("2")` for `1 2`. Macros are expanded
+/// first, like in `evaluate`.
+pub fn parse(expression: &str, context: &Context) -> Result<Expr, EvalError> {
+    let expression = expand_macros(expression, context);
+    let mut tokens = context.tokenize(&expression).peekable();
+    let expr = parse_pratt(&mut tokens, context, 0)?;
+    match next_token(&mut tokens)? {
+        None => Ok(expr),
+        Some(Token::RightParenthesis) => Err(EvalError::MismatchedParentheses),
+        Some(token) => Err(EvalError::UnexpectedToken(token.to_string())),
+    }
+}
+
+/// Parses a list of expressions separated by top-level argument separators,
+/// like `x + 1, x - 1, x ^ 2`. Separators within calls separate arguments as
+/// usual.
+pub fn parse_expression_list(expression: &str, context: &Context) -> Result<Vec<Expr>, EvalError> {
+    let expression = expand_macros(expression, context);
+    let mut tokens = context.tokenize(&expression).peekable();
+    let mut exprs = vec![parse_pratt(&mut tokens, context, 0)?];
+    loop {
+        match next_token(&mut tokens)? {
+            None => return Ok(exprs),
+            Some(Token::Separator) => exprs.push(parse_pratt(&mut tokens, context, 0)?),
+            Some(Token::RightParenthesis) => return Err(EvalError::MismatchedParentheses),
+            Some(token) => return Err(EvalError::UnexpectedToken(token.to_string())),
+        }
+    }
+}
+
+/// Evaluates each expression of a list, see `parse_expression_list`.
+pub fn evaluate_expression_list(
+    expression: &str,
+    context: &Context,
+) -> Result<Vec<f64>, EvalError> {
+    parse_expression_list(expression, context)?
+        .iter()
+        .map(|expr| evaluate_queue(expr.to_postfix(), context).map(|value| value.to_f64()))
+        .collect()
+}
+
+/// Parses tokens into a tree until it reaches an infix operator that binds
+/// less tightly than `min_bp`, a closing parenthesis, an argument separator or
+/// the end of the input, none of which are consumed.
+///
+/// Binding powers are derived from operator precedences, which are clamped to
+/// `0..=126` for this. Two chained non-associative operators fail with
+/// `EvalError::ChainedNonAssociative`, as in `shunting_yard`, with positions
+/// counted from the first token of `tokens`.
+pub fn parse_pratt(
+    tokens: &mut Peekable<Tokens>,
+    context: &Context,
+    min_bp: u8,
+) -> Result<Expr, EvalError> {
+    let end = remaining_len(tokens);
+    parse_bp(tokens, context, min_bp).map_err(|error| match error {
+        EvalError::ChainedNonAssociative {
+            first,
+            first_position,
+            second,
+            second_position,
+        } => EvalError::ChainedNonAssociative {
+            first,
+            first_position: end - first_position,
+            second,
+            second_position: end - second_position,
+        },
+        error => error,
+    })
+}
+
+/// Does the work of `parse_pratt`, except that the positions of
+/// `EvalError::ChainedNonAssociative` are counted from the end of the input,
+/// since the start of `tokens` isn't known here.
+fn parse_bp(
+    tokens: &mut Peekable<Tokens>,
+    context: &Context,
+    min_bp: u8,
+) -> Result<Expr, EvalError> {
+    let mut left = parse_operand(tokens, context, min_bp)?;
+    // The precedence, symbol and position of the last non-associative
+    // operator on this level.
+    let mut non_associative: Option<(i32, String, usize)> = None;
+    // The right operand of the last comparison on this level, if it can be
+    // chained, as in `a < b < c`.
+    let mut comparison: Option<Expr> = None;
+
+    loop {
+        let symbol = match peek_token(tokens)? {
+            Some(Token::Symbol(symbol)) => symbol.clone(),
+            Some(Token::Number(..)) | Some(Token::Identifier(_)) | Some(Token::LeftParenthesis)
+                if context.implicit_multiplication() =>
+            {
+                let operator = context.implicit_operator()?;
+                let (left_bp, right_bp) = if context.implicit_mul_binds_tighter() {
+                    // Binds its left operand away from `*` and `/`, but not
+                    // its right one, so `1/2x*3` is `(1/(2x))*3`.
+                    let bp = binding_power(operator.precedence()) + 1;
+                    (bp, bp)
+                } else {
+                    infix_binding_power(operator.precedence(), operator.associativity())
+                };
+                if left_bp < min_bp {
+                    break;
+                }
+                comparison = None;
+                let right = parse_bp(tokens, context, right_bp)?;
+                left = Expr::Binary(String::from("*"), Box::new(left), Box::new(right));
+                continue;
+            }
+            _ => break,
+        };
+        if let Some(operator) = context.get_postfix_operator(&symbol) {
+            if is_postfix_operator(&symbol, peek_second_token(tokens)?.as_ref(), context) {
+                if binding_power(operator.precedence()) < min_bp {
+                    break;
+                }
+                tokens.next();
+                left = Expr::Postfix(symbol, Box::new(left));
+                continue;
+            }
+        }
+        let operator = context
+            .get_operator(&symbol)
+            .ok_or_else(|| EvalError::UnknownOperator(symbol.clone()))?;
+        let (left_bp, right_bp) =
+            infix_binding_power(operator.precedence(), operator.associativity());
+        if left_bp < min_bp {
+            break;
+        }
+        let chained = is_chained_comparison(operator.symbol());
+        let middle = comparison.take().filter(|_| chained);
+        if operator.associativity() == Associativity::None {
+            let position = remaining_len(tokens);
+            match non_associative {
+                Some((precedence, first, first_position))
+                    if precedence == operator.precedence() && middle.is_none() =>
+                {
+                    return Err(EvalError::ChainedNonAssociative {
+                        first,
+                        first_position,
+                        second: symbol,
+                        second_position: position,
+                    });
+                }
+                _ => non_associative = Some((operator.precedence(), symbol.clone(), position)),
+            }
+        }
+        tokens.next();
+
+        if symbol == "=" {
+            return Err(EvalError::InvalidAssignmentTarget(describe(&left)));
+        }
+        expect_operand(tokens, &symbol)?;
+        let mut right = parse_bp(tokens, context, right_bp)?;
+        if context.percent_of()
+            && matches!(operator.symbol(), "+" | "-")
+            && matches!(&right, Expr::Postfix(postfix, _) if postfix == "%")
+        {
+            right = Expr::Binary(String::from("*"), Box::new(left.clone()), Box::new(right));
+        }
+        if chained {
+            comparison = Some(right.clone());
+        }
+        left = match middle {
+            Some(middle) => {
+                let next = Expr::Binary(symbol, Box::new(middle), Box::new(right));
+                Expr::Binary(String::from("&&"), Box::new(left), Box::new(next))
+            }
+            None => Expr::Binary(symbol, Box::new(left), Box::new(right)),
+        };
+    }
+
+    Ok(left)
+}
+
+fn parse_operand(
+    tokens: &mut Peekable<Tokens>,
+    context: &Context,
+    min_bp: u8,
+) -> Result<Expr, EvalError> {
+    let token = match next_token(tokens)? {
+        Some(token) => token,
+        None => return Err(EvalError::EmptyExpression),
+    };
+
+    match token {
+        Token::Number(value, text) => Ok(Expr::Number(value, text)),
+        Token::Identifier(name) => {
+            if context.is_arithmetic_only() {
+                return Err(EvalError::FeatureDisabled(name));
+            }
+            match peek_token(tokens)? {
+                Some(Token::LeftParenthesis) if !context.is_implicit_product(&name) => {
+                    tokens.next();
+                    parse_call(tokens, context, name)
+                }
+                Some(Token::Symbol(symbol))
+                    if symbol == "=" && context.get_operator("=").is_some() && min_bp == 0 =>
+                {
+                    if context.function_exists(&name) {
+                        return Err(EvalError::InvalidAssignmentTarget(name));
+                    }
+                    tokens.next();
+                    expect_operand(tokens, "=")?;
+                    let value = parse_bp(tokens, context, 0)?;
+                    Ok(Expr::Assign(name, Box::new(value)))
+                }
+                _ if context.is_paren_free_call(&name) => {
+                    expect_operand(tokens, &name)?;
+                    let arg = parse_bp(tokens, context, u8::MAX)?;
+                    Ok(Expr::Call(name, vec![arg]))
+                }
+                _ => Ok(Expr::Variable(name)),
+            }
+        }
+        Token::LeftParenthesis => {
+            let expr = parse_bp(tokens, context, 0)?;
+            match next_token(tokens)? {
+                Some(Token::RightParenthesis) => Ok(expr),
+                Some(token) => Err(EvalError::UnexpectedToken(token.to_string())),
+                None => Err(EvalError::MismatchedParentheses),
+            }
+        }
+        Token::Symbol(symbol) => match context.get_prefix_operator(&symbol) {
+            Some(operator) => {
+                let right_bp = prefix_binding_power(operator.precedence());
+                expect_operand(tokens, &symbol)?;
+                let operand = parse_bp(tokens, context, right_bp)?;
+                Ok(Expr::Prefix(symbol, Box::new(operand)))
+            }
+            None if context.get_operator(&symbol).is_some() => {
+                Err(EvalError::MissingOperand(symbol))
+            }
+            None => Err(EvalError::UnknownOperator(symbol)),
+        },
+        token => Err(EvalError::UnexpectedToken(token.to_string())),
+    }
+}
+
+/// Parses the arguments of a call after its opening parenthesis.
+fn parse_call(
+    tokens: &mut Peekable<Tokens>,
+    context: &Context,
+    name: String,
+) -> Result<Expr, EvalError> {
+    if is_variable_not_function(&name, context) {
+        return Err(EvalError::IdentifierUsedAsFunction(name));
+    }
+
+    let mut args = Vec::new();
+    if let Some(Token::RightParenthesis) = peek_token(tokens)? {
+        tokens.next();
+        return Ok(Expr::Call(name, args));
+    }
+
+    loop {
+        args.push(parse_bp(tokens, context, 0)?);
+        match next_token(tokens)? {
+            Some(Token::Separator) => (),
+            Some(Token::RightParenthesis) => return Ok(Expr::Call(name, args)),
+            Some(token) => return Err(EvalError::UnexpectedToken(token.to_string())),
+            None => return Err(EvalError::MismatchedParentheses),
+        }
+    }
+}
+
+/// Fails with `EvalError::MissingOperand` if the operator `symbol` isn't
+/// followed by anything that could start an operand.
+fn expect_operand(tokens: &mut Peekable<Tokens>, symbol: &str) -> Result<(), EvalError> {
+    match peek_token(tokens)? {
+        None | Some(Token::RightParenthesis) | Some(Token::Separator) => {
+            Err(EvalError::MissingOperand(String::from(symbol)))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn binding_power(precedence: i32) -> u8 {
+    precedence.clamp(0, 126) as u8 * 2 + 2
+}
+
+fn infix_binding_power(precedence: i32, associativity: Associativity) -> (u8, u8) {
+    let bp = binding_power(precedence);
+    match associativity {
+        Associativity::Left | Associativity::None => (bp, bp + 1),
+        Associativity::Right => (bp + 1, bp),
+    }
+}
+
+fn prefix_binding_power(precedence: i32) -> u8 {
+    binding_power(precedence) + 1
+}
+
+/// The text used to refer to an expression in error messages.
+fn describe(expr: &Expr) -> String {
+    match expr {
+        Expr::Number(value, _) => value.to_string(),
+        Expr::Variable(name) | Expr::Call(name, _) => name.clone(),
+        Expr::Prefix(symbol, _) | Expr::Postfix(symbol, _) | Expr::Binary(symbol, ..) => {
+            symbol.clone()
+        }
+        Expr::Assign(..) => String::from("="),
+    }
+}
+
+fn skip_whitespace(tokens: &mut Peekable<Tokens>) {
+    while let Some(Ok(Token::Whitespace(_))) = tokens.peek() {
+        tokens.next();
+    }
+}
+
+/// The length of the input that is left, including the next token.
+fn remaining_len(tokens: &Peekable<Tokens>) -> usize {
+    tokens
+        .clone()
+        .map(|token| token.map_or(0, |token| token.len()))
+        .sum()
+}
+
+/// Returns the token after the next one, without consuming either.
+fn peek_second_token(tokens: &mut Peekable<Tokens>) -> Result<Option<Token>, EvalError> {
+    let mut ahead = tokens.clone();
+    next_token(&mut ahead)?;
+    next_token(&mut ahead)
+}
+
+fn peek_token<'a>(tokens: &'a mut Peekable<Tokens>) -> Result<Option<&'a Token>, EvalError> {
+    skip_whitespace(tokens);
+    match tokens.peek() {
+        Some(Ok(token)) => Ok(Some(token)),
+        Some(Err(error)) => Err(EvalError::Lex(error.clone())),
+        None => Ok(None),
+    }
+}
+
+fn next_token(tokens: &mut Peekable<Tokens>) -> Result<Option<Token>, EvalError> {
+    skip_whitespace(tokens);
+    tokens.next().transpose().map_err(EvalError::Lex)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{evaluate, evaluate_expression_list, evaluate_f64, evaluate_queue};
+    use crate::{evaluate_symbolic, parse, parse_expression_list};
+    use crate::{Associativity, Context, EvalError, Expr, ExprBuilder, Function};
+    use proptest::prelude::*;
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+
+    fn var(name: &str) -> Box<Expr> {
+        Box::new(Expr::Variable(String::from(name)))
+    }
+
+    fn binary(symbol: &str, left: Box<Expr>, right: Box<Expr>) -> Box<Expr> {
+        Box::new(Expr::Binary(String::from(symbol), left, right))
+    }
+
+    #[test]
+    fn tree() {
+        let context = Context::default_with_math_functions();
+
+        assert_eq!(
+            parse("a - b - c", &context),
+            Ok(*binary("-", binary("-", var("a"), var("b")), var("c")))
+        );
+        assert_eq!(
+            parse("a ^ b ^ c", &context),
+            Ok(*binary("^", var("a"), binary("^", var("b"), var("c"))))
+        );
+        assert_eq!(
+            parse("-a ^ 2", &context),
+            Ok(Expr::Prefix(
+                String::from("-"),
+                binary("^", var("a"), Box::new(ExprBuilder::number(2.0)))
+            ))
+        );
+        assert_eq!(
+            parse("x = max(a, b * c)", &context),
+            Ok(Expr::Assign(
+                String::from("x"),
+                Box::new(Expr::Call(
+                    String::from("max"),
+                    vec![*var("a"), *binary("*", var("b"), var("c"))]
+                ))
+            ))
+        );
+    }
+
+    #[test]
+    fn same_results_as_shunting_yard() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", 3.0);
+
+        for expression in &[
+            "1 + 2 * 3",
+            "(1 + 2) * 3",
+            "2 ^ 3 ^ 2",
+            "10 - 4 - 3",
+            "-2 ^ 2",
+            "2 * -x",
+            "max(1, 2 + 3, x) - min(4, 5)",
+            "log(8, 2) + sqrt(16)",
+            "-(x - 5) / 2",
+            "if(x - 3, 1 / 0, max(if(0, 1, 2), 3))",
+        ] {
+            let queue = parse(expression, &context).unwrap().to_postfix();
+            assert_eq!(
+                evaluate_queue(&queue, &context),
+                evaluate(expression, &context),
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", 3.0);
+
+        let error = |expression| parse(expression, &context).unwrap_err();
+        assert_eq!(error("1 2"), EvalError::UnexpectedToken(String::from("2")));
+        assert_eq!(error("(1 + 2"), EvalError::MismatchedParentheses);
+        assert_eq!(error("1 + 2)"), EvalError::MismatchedParentheses);
+        assert_eq!(error("1 +"), EvalError::MissingOperand(String::from("+")));
+        assert_eq!(error("(1 *)"), EvalError::MissingOperand(String::from("*")));
+        assert_eq!(error("* 2"), EvalError::MissingOperand(String::from("*")));
+        assert_eq!(
+            error("1 $ 2"),
+            EvalError::UnknownOperator(String::from("$"))
+        );
+        assert_eq!(error(""), EvalError::EmptyExpression);
+        assert_eq!(
+            error("2 = 3"),
+            EvalError::InvalidAssignmentTarget(String::from("2"))
+        );
+        assert_eq!(
+            error("x(2)"),
+            EvalError::IdentifierUsedAsFunction(String::from("x"))
+        );
+    }
+
+    #[test]
+    fn non_associative_operators() {
+        let mut context = Context::default();
+        context.add_operator("<>", 5, Associativity::None).unwrap();
+        context.add_operator("><", 5, Associativity::None).unwrap();
+
+        for &(expression, first, first_position, second, second_position) in &[
+            ("1 <> 2 <> 3", "<>", 2, "<>", 7),
+            ("1 <> 2 >< 3", "<>", 2, "><", 7),
+            ("x + (1<>2><3)", "<>", 6, "><", 9),
+            ("1 == 1 != 3", "==", 2, "!=", 7),
+        ] {
+            let expected = Err(EvalError::ChainedNonAssociative {
+                first: String::from(first),
+                first_position,
+                second: String::from(second),
+                second_position,
+            });
+            assert_eq!(
+                parse(expression, &context).map(|_| ()),
+                expected,
+                "{}",
+                expression
+            );
+            assert_eq!(
+                evaluate_f64(expression, &context),
+                expected.map(|()| 0.0),
+                "{}",
+                expression
+            );
+        }
+        assert!(parse("(1 <> 2) + 1 <> 3", &context).is_ok());
+    }
+
+    #[test]
+    fn count_operations() {
+        let context = Context::default_with_math_functions();
+        let counts = parse("sin(x)^2 + cos(x)^2", &context)
+            .unwrap()
+            .count_operations();
+
+        let expected: HashMap<String, usize> = [("^", 2), ("+", 1), ("sin", 1), ("cos", 1)]
+            .iter()
+            .map(|&(name, count)| (String::from(name), count))
+            .collect();
+        assert_eq!(counts, expected);
+
+        let counts = parse("y = -max(1, -x)", &context)
+            .unwrap()
+            .count_operations();
+        assert_eq!(counts.get("-"), Some(&2));
+        assert_eq!(counts.get("max"), Some(&1));
+        assert_eq!(counts.get("="), None);
+    }
+
+    #[test]
+    fn paren_free_calls() {
+        let mut context = Context::default_with_math_functions();
+        context.set_paren_free_calls(true);
+
+        for expression in &[
+            "sqrt 16 + 1",
+            "2 * sqrt 3 ^ 2",
+            "abs -3 - 1",
+            "sqrt sqrt 16",
+        ] {
+            let queue = parse(expression, &context).unwrap().to_postfix();
+            assert_eq!(
+                evaluate_queue(&queue, &context),
+                evaluate(expression, &context),
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn implicit_multiplication() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", 4.0);
+        context.set_implicit_multiplication(true);
+
+        for &tighter in &[false, true] {
+            context.set_implicit_mul_binds_tighter(tighter);
+            for expression in &["1/2x", "1/2x*3", "2x^2", "-2x", "(2)(3)x", "x(x + 1)"] {
+                let queue = parse(expression, &context).unwrap().to_postfix();
+                assert_eq!(
+                    evaluate_queue(&queue, &context),
+                    evaluate(expression, &context),
+                    "{}",
+                    expression
+                );
+            }
+        }
+        assert_eq!(
+            parse("2 sqrt(x)", &context).unwrap().to_string(),
+            "2 * sqrt(x)"
+        );
+    }
+
+    #[test]
+    fn display() {
+        let context = Context::default_with_math_functions();
+        let display = |expression| parse(expression, &context).unwrap().to_string();
+
+        assert_eq!(display("2 ** 3 ** 2"), "2 ** (3 ** 2)");
+        assert_eq!(display("2^3*2"), "(2 ^ 3) * 2");
+        assert_eq!(display("-(a + b) * max(1, -x)"), "(-(a + b)) * max(1, -x)");
+        assert_eq!(display("(-2) ^ 2 + (-x)!"), "((-2) ^ 2) + (-x)!");
+        assert_eq!(display("y = 0.5 * x"), "y = 0.5 * x");
+        assert_eq!(
+            Expr::Binary(
+                String::from("^"),
+                Box::new(ExprBuilder::number(-3.0)),
+                var("x")
+            )
+            .to_string(),
+            "(-3) ^ x"
+        );
+    }
+
+    #[test]
+    fn expression_lists() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", 3.0);
+
+        assert_eq!(
+            evaluate_expression_list("x + 1, x - 1, x ^ 2", &context),
+            Ok(vec![4.0, 2.0, 9.0])
+        );
+        assert_eq!(
+            evaluate_expression_list("max(1, x), (2)", &context),
+            Ok(vec![3.0, 2.0])
+        );
+        assert_eq!(evaluate_expression_list("x", &context), Ok(vec![3.0]));
+        assert_eq!(
+            parse_expression_list("a, b", &context),
+            Ok(vec![*var("a"), *var("b")])
+        );
+        assert_eq!(
+            evaluate_expression_list("1, , 2", &context),
+            Err(EvalError::UnexpectedToken(String::from(",")))
+        );
+        assert_eq!(
+            evaluate_expression_list("1, 2)", &context),
+            Err(EvalError::MismatchedParentheses)
+        );
+    }
+
+    #[test]
+    fn from_str() {
+        let mut context = Context::default();
+        context.set_variable("x", 3.0);
+
+        let expr: Expr = "x ^ 2 + 1".parse().unwrap();
+        assert_eq!(expr.evaluate(&context), Ok(10.0));
+        assert_eq!(
+            Expr::try_from("2 * (1 + 1)").unwrap().evaluate(&context),
+            Ok(4.0)
+        );
+        assert_eq!(
+            "1 +".parse::<Expr>(),
+            Err(EvalError::MissingOperand(String::from("+")))
+        );
+    }
+
+    #[test]
+    fn variables_and_functions_used() {
+        let context = Context::default_with_math_functions();
+        let expr = parse("y = if(x > z, max(x, 2), -sqrt(x))", &context).unwrap();
+        let names = |names: &[&str]| names.iter().map(|&name| String::from(name)).collect();
+
+        assert_eq!(expr.variables_used(), names(&["x", "z"]));
+        assert_eq!(expr.functions_called(), names(&["max", "sqrt"]));
+        assert!(parse("1 + 2", &context)
+            .unwrap()
+            .variables_used()
+            .is_empty());
+    }
+
+    #[test]
+    fn constant_expressions() {
+        let mut context = Context::default_with_math_functions();
+        context.add_function(Function::new("two", 0, |_, _| Ok(2.0)));
+        let expr = |text: &str| parse(text, &context).unwrap();
+
+        assert!(expr("2 + 3 * sin(0)").is_constant(&context));
+        assert!(expr("3 * two").is_constant(&context));
+        assert!(expr("max(1, two)!").is_constant(&context));
+        assert!(!expr("2 * x").is_constant(&context));
+        assert!(!expr("if(1, 2, x)").is_constant(&context));
+        assert!(!expr("y = 2").is_constant(&context));
+    }
+
+    #[test]
+    fn constant_folding() {
+        let mut context = Context::default_with_math_functions();
+        context.add_function(Function::new("two", 0, |_, _| Ok(2.0)));
+        let expr = |text: &str, context: &Context| parse(text, context).unwrap();
+        let fold = |text: &str, context: &Context| expr(text, context).fold_constants(context);
+
+        assert_eq!(fold("2 + 3 * 4", &context), Ok(ExprBuilder::number(14.0)));
+        assert_eq!(
+            fold("x * (2 + 3) + max(1, two)", &context),
+            Ok(expr("x * 5 + 2", &context))
+        );
+        assert_eq!(
+            fold("y = x ^ (two - 1)", &context),
+            Ok(expr("y = x ^ 1", &context))
+        );
+        assert_eq!(
+            fold("x + (0 - 1)!", &context),
+            Err(EvalError::InvalidOperand {
+                operator: String::from("!"),
+                value: -1.0,
+            })
+        );
+
+        let folded = fold("if(x > 0, x, (1 - 2)!) + (x < 0 && (0 - 3)!)", &context).unwrap();
+        let failing =
+            |value| Expr::Postfix(String::from("!"), Box::new(ExprBuilder::number(value)));
+        let (branch, logical) = match &folded {
+            Expr::Binary(_, left, right) => (left.as_ref(), right.as_ref()),
+            _ => panic!("expected a sum, found {}", folded),
+        };
+        assert!(matches!(branch, Expr::Call(_, args) if args[2] == failing(-1.0)));
+        assert!(matches!(logical, Expr::Binary(_, _, right) if **right == failing(-3.0)));
+
+        context.set_variable("x", 2.0);
+        assert_eq!(folded.evaluate(&context), Ok(2.0));
+    }
+
+    #[test]
+    fn simplification() {
+        let mut context = Context::default_with_math_functions();
+        context
+            .add_operator_binary_from_string("@", 11, Associativity::Left, "$0 + $1")
+            .unwrap();
+        let simplify = |text: &str| parse(text, &context).unwrap().simplify(&context);
+        let expr = |text: &str| parse(text, &context).unwrap();
+
+        assert_eq!(simplify("2 * 3 + x"), expr("6 + x"));
+        assert_eq!(simplify("(x * 1 + 0) ** (3 - 2)"), expr("x"));
+        assert_eq!(simplify("1 * x / 1 - 0 + (0 + y)"), expr("x + y"));
+        assert_eq!(simplify("--x + ---y"), expr("x + -y"));
+        assert_eq!(simplify("-+-x * +y"), expr("x * y"));
+        assert_eq!(simplify("max(x, 2 ^ 2) * 0"), expr("0 * max(x, 4)"));
+        assert_eq!(simplify("x @ 1"), expr("x @ 1"));
+        assert_eq!(simplify("x * 2"), simplify("2 * x"));
+        assert_eq!(simplify("(x + 1) + 2"), expr("(1 + x) + 2"));
+        assert_eq!(simplify("y + x"), expr("y + x"));
+        assert_eq!(simplify("(b * a) * (2 + c)"), expr("(b * a) * (2 + c)"));
+        assert_eq!(
+            simplify("y + x").evaluate(&context),
+            Err(EvalError::UnknownVariable(String::from("y")))
+        );
+        assert_eq!(simplify("y - x"), expr("y - x"));
+        assert_eq!(simplify("y = x ^ 1 + 2 * 2").to_string(), "y = 4 + x");
+        assert_eq!(
+            simplify("if(x, y, (0 - 1)!) + 0"),
+            Expr::Call(
+                String::from("if"),
+                vec![
+                    expr("x"),
+                    expr("y"),
+                    Expr::Postfix(String::from("!"), Box::new(ExprBuilder::number(-1.0))),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn symbolic_evaluation() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", 3.0);
+        context.set_variable("y", 1.0);
+        let symbolic = |text: &str, unknowns: &[&str]| evaluate_symbolic(text, &context, unknowns);
+
+        assert_eq!(
+            symbolic("x^2 + y", &["y"]),
+            Ok(Expr::Binary(
+                String::from("+"),
+                Box::new(ExprBuilder::number(9.0)),
+                Box::new(Expr::Variable(String::from("y")))
+            ))
+        );
+        assert_eq!(symbolic("x^2 + y", &[]), Ok(ExprBuilder::number(10.0)));
+        assert_eq!(
+            symbolic("max(x, 2) * z - sqrt(x + 1) / z", &["z"]),
+            parse("3 * z - 2 / z", &context)
+        );
+        assert_eq!(symbolic("w + 2 * x", &["z"]), parse("w + 6", &context));
+        assert_eq!(
+            symbolic("x + ", &["z"]),
+            Err(EvalError::MissingOperand(String::from("+")))
+        );
+    }
+
+    #[test]
+    fn builder() {
+        let context = Context::default_with_math_functions();
+        let (x, y) = (ExprBuilder::variable("x"), ExprBuilder::variable("y"));
+        let expr = ExprBuilder::div(
+            ExprBuilder::add(
+                ExprBuilder::number(1.0),
+                ExprBuilder::mul(
+                    ExprBuilder::number(2.0),
+                    ExprBuilder::pow(x.clone(), ExprBuilder::number(3.0)),
+                ),
+            ),
+            ExprBuilder::call(
+                "max",
+                vec![ExprBuilder::sub(x, y), ExprBuilder::number(0.5)],
+            ),
+        );
+
+        assert_eq!(
+            Ok(expr.clone()),
+            parse("(1 + 2 * x ^ 3) / max(x - y, 0.5)", &context)
+        );
+        assert_eq!(expr.to_string(), "(1 + (2 * (x ^ 3))) / max(x - y, 0.5)");
+    }
+
+    /// Random expressions of numbers, the variables `x` and `y`, the
+    /// arithmetic operators and a few functions.
+    fn expressions() -> impl Strategy<Value = Expr> {
+        let leaf = prop_oneof![
+            (0..100u8).prop_map(|value| ExprBuilder::number(f64::from(value) / 8.0)),
+            prop_oneof![Just("x"), Just("y")].prop_map(|name| Expr::Variable(String::from(name))),
+        ];
+        leaf.prop_recursive(5, 64, 2, |operand| {
+            let symbol = prop_oneof![Just("+"), Just("-"), Just("*"), Just("/"), Just("^")];
+            prop_oneof![
+                operand
+                    .clone()
+                    .prop_map(|operand| Expr::Prefix(String::from("-"), Box::new(operand))),
+                (prop_oneof![Just("sqrt"), Just("abs")], operand.clone())
+                    .prop_map(|(name, arg)| Expr::Call(String::from(name), vec![arg])),
+                (operand.clone(), operand.clone())
+                    .prop_map(|(a, b)| Expr::Call(String::from("max"), vec![a, b])),
+                (symbol, operand.clone(), operand).prop_map(|(symbol, left, right)| {
+                    Expr::Binary(String::from(symbol), Box::new(left), Box::new(right))
+                }),
+            ]
+        })
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(500))]
+
+        #[test]
+        fn generated_expressions(expr in expressions()) {
+            let mut context = Context::default_with_math_functions();
+            context.set_variable("x", 1.5);
+            context.set_variable("y", -0.75);
+            let text = expr.to_string();
+
+            prop_assert_eq!(parse(&text, &context), Ok(expr.clone()), "{}", text);
+            match (evaluate_f64(&text, &context), expr.evaluate(&context)) {
+                (Ok(a), Ok(b)) => prop_assert!(
+                    a == b
+                        || (a.is_nan() && b.is_nan())
+                        || (a - b).abs() <= f64::EPSILON * a.abs().max(1.0),
+                    "{}: {} != {}",
+                    text,
+                    a,
+                    b
+                ),
+                (a, b) => prop_assert_eq!(a, b, "{}", text),
+            }
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use crate::bytecode::Program;
+use crate::{collect_tokens, evaluate_queue, evaluate_queue_with, shunting_yard};
+use crate::{Context, EvalError, Number, Token, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::{Add, Deref, Div, Mul, Sub};
+
+/// A postfix queue that can be built up with arithmetic operators instead of
+/// being parsed from a string.
+///
+/// An expression that is evaluated many times should be compiled once with
+/// `Expression::compile`, which skips tokenizing and reordering on every
+/// evaluation and resolves its operators and functions in the given context
+/// ahead of time. Variables are still looked up when it is evaluated, so their
+/// values can change between evaluations.
+///
+/// `Expression::variable("x") + Expression::number(2.0)` is the queue
+/// `x 2 +`. Operators are applied by their symbol, so they have to exist in
+/// the context the expression is evaluated in.
+#[derive(Debug, Clone)]
+pub struct Expression<N = f64> {
+    queue: VecDeque<Token>,
+    /// The lowered form of a compiled expression. Expressions that are built
+    /// up or changed afterwards evaluate their queue instead.
+    program: Option<Program<N>>,
+}
+
+impl<N: Number> Expression<N> {
+    pub fn number(value: N) -> Self {
+        Self::from(VecDeque::from(vec![Token::Number(
+            value.into_f64(),
+            value.to_string(),
+        )]))
+    }
+
+    pub fn variable(name: &str) -> Self {
+        Self::from(VecDeque::from(vec![Token::Identifier(String::from(name))]))
+    }
+
+    /// Parses an expression into postfix order for repeated evaluation.
+    pub fn compile(expression: &str, context: &Context<N>) -> Result<Self, EvalError> {
+        let tokens = collect_tokens(expression, context)?;
+        let queue = shunting_yard(tokens, context)?;
+        let program = Program::compile(&queue, context);
+        Ok(Self {
+            queue,
+            program: Some(program),
+        })
+    }
+
+    pub fn evaluate(&self, context: &Context<N>) -> Result<N, EvalError> {
+        self.evaluate_value(context).map(|value| value.to_number())
+    }
+
+    /// Evaluates the expression without converting the result to a number.
+    pub(crate) fn evaluate_value(&self, context: &Context<N>) -> Result<Value<N>, EvalError> {
+        match &self.program {
+            Some(program) => program.run(context, &HashMap::new()),
+            None => evaluate_queue(&self.queue, context),
+        }
+    }
+
+    /// Evaluates the expression with variables from `bindings`, see
+    /// `evaluate_with`.
+    pub fn evaluate_with(
+        &self,
+        context: &Context<N>,
+        bindings: &HashMap<String, N>,
+    ) -> Result<N, EvalError> {
+        match &self.program {
+            Some(program) => program.run(context, bindings),
+            None => evaluate_queue_with(&self.queue, context, bindings),
+        }
+        .map(|value| value.to_number())
+    }
+
+    /// Evaluates the expression once for each row of bindings, see
+    /// `evaluate_with`. The results are in the same order as the rows.
+    ///
+    /// With the `parallel` feature, the rows are split between as many
+    /// threads as there are CPUs.
+    pub fn eval_batch(
+        &self,
+        context: &Context<N>,
+        rows: &[HashMap<String, N>],
+    ) -> Vec<Result<N, EvalError>> {
+        #[cfg(feature = "parallel")]
+        {
+            let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+            let chunk_size = rows.len().div_ceil(threads).max(1);
+            std::thread::scope(|scope| {
+                let chunks: Vec<_> = rows
+                    .chunks(chunk_size)
+                    .map(|chunk| scope.spawn(move || self.eval_rows(context, chunk)))
+                    .collect();
+                chunks
+                    .into_iter()
+                    .flat_map(|chunk| {
+                        chunk
+                            .join()
+                            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                    })
+                    .collect()
+            })
+        }
+        #[cfg(not(feature = "parallel"))]
+        self.eval_rows(context, rows)
+    }
+
+    fn eval_rows(
+        &self,
+        context: &Context<N>,
+        rows: &[HashMap<String, N>],
+    ) -> Vec<Result<N, EvalError>> {
+        rows.iter()
+            .map(|bindings| self.evaluate_with(context, bindings))
+            .collect()
+    }
+
+    /// Replaces variables with their values from `bindings`, e.g. to
+    /// specialize a formula for some of its parameters. Names that are
+    /// functions in `context`, variables that the expression assigns to and
+    /// variables that aren't in `bindings` stay. A compiled expression is
+    /// compiled again in `context`.
+    pub fn substitute(&self, context: &Context<N>, bindings: &HashMap<String, N>) -> Self {
+        self.substitute_with(context, |name| {
+            bindings.get(name).map(|&value| Self::number(value).queue)
+        })
+    }
+
+    /// Like `substitute`, but replaces variables with whole expressions.
+    pub fn substitute_expressions(
+        &self,
+        context: &Context<N>,
+        replacements: &HashMap<String, Expression<N>>,
+    ) -> Self {
+        self.substitute_with(context, |name| {
+            replacements
+                .get(name)
+                .map(|replacement| replacement.queue.clone())
+        })
+    }
+
+    fn substitute_with(
+        &self,
+        context: &Context<N>,
+        replacement: impl Fn(&str) -> Option<VecDeque<Token>>,
+    ) -> Self {
+        let mut assigned = HashSet::new();
+        collect_assigned(&self.queue, &mut assigned);
+        let replacement = |name: &str| {
+            if context.function_exists(name) || assigned.contains(name) {
+                None
+            } else {
+                replacement(name)
+            }
+        };
+        let queue = replace_variables(&self.queue, &replacement);
+        let program = self
+            .program
+            .as_ref()
+            .map(|_| Program::compile(&queue, context));
+        Self { queue, program }
+    }
+
+    /// Evaluates the expression with `variable` set to `value` in `bindings`,
+    /// as a function of that variable. Fails with `EvalError::AtSample` if
+    /// the evaluation fails or the result isn't finite.
+    pub(crate) fn evaluate_at(
+        &self,
+        context: &Context<N>,
+        bindings: &mut HashMap<String, N>,
+        variable: &str,
+        value: N,
+    ) -> Result<N, EvalError> {
+        bindings.insert(String::from(variable), value);
+        let at_sample = |error| EvalError::AtSample {
+            value: value.into_f64(),
+            error: Box::new(error),
+        };
+        let result = self.evaluate_with(context, bindings).map_err(at_sample)?;
+        if !result.is_finite() {
+            return Err(at_sample(EvalError::NonFiniteValue(result.into_f64())));
+        }
+        Ok(result)
+    }
+
+    pub(crate) fn program(&self) -> Option<&Program<N>> {
+        self.program.as_ref()
+    }
+
+    /// Appends `other` and the operator that combines both.
+    fn combine(mut self, symbol: &str, other: Self) -> Self {
+        self.queue.extend(other.queue);
+        self.queue.push_back(Token::Symbol(String::from(symbol)));
+        Self::from(self.queue)
+    }
+}
+
+fn collect_assigned(queue: &VecDeque<Token>, assigned: &mut HashSet<String>) {
+    for token in queue {
+        match token {
+            Token::Assign(name) => {
+                assigned.insert(name.clone());
+            }
+            Token::Branch(then, otherwise) => {
+                collect_assigned(then, assigned);
+                collect_assigned(otherwise, assigned);
+            }
+            Token::Reduce(_, _, body) => collect_assigned(body, assigned),
+            _ => (),
+        }
+    }
+}
+
+/// Copies a queue, putting the tokens from `replacement` in place of each
+/// variable it returns some for, also inside of branches.
+fn replace_variables(
+    queue: &VecDeque<Token>,
+    replacement: &dyn Fn(&str) -> Option<VecDeque<Token>>,
+) -> VecDeque<Token> {
+    let mut result = VecDeque::with_capacity(queue.len());
+    for token in queue {
+        match token {
+            Token::Identifier(name) => match replacement(name) {
+                Some(tokens) => result.extend(tokens),
+                None => result.push_back(token.clone()),
+            },
+            Token::Branch(then, otherwise) => result.push_back(Token::Branch(
+                replace_variables(then, replacement),
+                replace_variables(otherwise, replacement),
+            )),
+            Token::Reduce(name, variable, body) => {
+                let unbound = |name: &str| {
+                    if name == variable {
+                        None
+                    } else {
+                        replacement(name)
+                    }
+                };
+                let body = replace_variables(body, &unbound);
+                result.push_back(Token::Reduce(name.clone(), variable.clone(), body));
+            }
+            _ => result.push_back(token.clone()),
+        }
+    }
+    result
+}
+
+impl<N> From<VecDeque<Token>> for Expression<N> {
+    fn from(queue: VecDeque<Token>) -> Self {
+        Self {
+            queue,
+            program: None,
+        }
+    }
+}
+
+impl<N> Deref for Expression<N> {
+    type Target = VecDeque<Token>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.queue
+    }
+}
+
+impl<'a, N> IntoIterator for &'a Expression<N> {
+    type Item = &'a Token;
+    type IntoIter = std::collections::vec_deque::Iter<'a, Token>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.queue.iter()
+    }
+}
+
+impl<N: Number> Add for Expression<N> {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        self.combine("+", other)
+    }
+}
+
+impl<N: Number> Sub for Expression<N> {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        self.combine("-", other)
+    }
+}
+
+impl<N: Number> Mul for Expression<N> {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        self.combine("*", other)
+    }
+}
+
+impl<N: Number> Div for Expression<N> {
+    type Output = Self;
+
+    fn div(self, other: Self) -> Self {
+        self.combine("/", other)
+    }
+}
+
+impl<N: Number> Add for &Expression<N> {
+    type Output = Expression<N>;
+
+    fn add(self, other: Self) -> Expression<N> {
+        self.clone() + other.clone()
+    }
+}
+
+impl<N: Number> Sub for &Expression<N> {
+    type Output = Expression<N>;
+
+    fn sub(self, other: Self) -> Expression<N> {
+        self.clone() - other.clone()
+    }
+}
+
+impl<N: Number> Mul for &Expression<N> {
+    type Output = Expression<N>;
+
+    fn mul(self, other: Self) -> Expression<N> {
+        self.clone() * other.clone()
+    }
+}
+
+impl<N: Number> Div for &Expression<N> {
+    type Output = Expression<N>;
+
+    fn div(self, other: Self) -> Expression<N> {
+        self.clone() / other.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse, postfix_to_infix, Context, EvalError, Expression, Function, Token};
+    use std::collections::HashMap;
+
+    #[test]
+    fn composition() {
+        let mut context = Context::default();
+        context.set_variable("x", 4.0);
+
+        let sum = Expression::variable("x") + Expression::number(2.0);
+        let text: Vec<String> = sum.iter().map(Token::to_string).collect();
+        assert_eq!(text, vec!["x", "2", "+"]);
+        assert_eq!(sum.evaluate(&context), Ok(6.0));
+
+        let half = Expression::number(0.5);
+        let product = &sum * &half;
+        assert_eq!(product.evaluate(&context), Ok(3.0));
+        assert_eq!((&product - &sum).evaluate(&context), Ok(-3.0));
+        assert_eq!((&sum / &half).evaluate(&context), Ok(12.0));
+        assert_eq!(sum.len(), 3);
+    }
+
+    #[test]
+    fn from_parsed_queue() {
+        let context = Context::default();
+        let parsed = Expression::from(parse("2 ^ 3", &context).unwrap().to_postfix());
+
+        assert_eq!(
+            (parsed + Expression::number(1.0)).evaluate(&context),
+            Ok(9.0)
+        );
+    }
+
+    #[test]
+    fn compiled() {
+        let mut context = Context::default_with_math_functions();
+        let expression = Expression::compile("x ^ 2 + max(x, 1)", &context).unwrap();
+
+        for &x in &[0.0f64, 2.0, -3.0] {
+            context.set_variable("x", x);
+            assert_eq!(expression.evaluate(&context), Ok(x * x + x.max(1.0)));
+        }
+        let doubled = &expression * &Expression::number(2.0);
+        assert_eq!(doubled.evaluate(&context), Ok(20.0));
+        assert!(Expression::compile("(x", &context).is_err());
+    }
+
+    #[test]
+    fn batches() {
+        let context = Context::default_with_math_functions();
+        let expression = Expression::compile("sqrt(x) + y", &context).unwrap();
+        let rows: Vec<HashMap<String, f64>> = (0..1000)
+            .map(|i| {
+                let mut row = HashMap::new();
+                row.insert(String::from("x"), f64::from(i * i));
+                if i != 500 {
+                    row.insert(String::from("y"), 1.0);
+                }
+                row
+            })
+            .collect();
+
+        let results = expression.eval_batch(&context, &rows);
+        assert_eq!(results.len(), rows.len());
+        for (i, result) in results.into_iter().enumerate() {
+            match i {
+                500 => assert_eq!(result, Err(EvalError::UnknownVariable(String::from("y")))),
+                _ => assert_eq!(result, Ok(i as f64 + 1.0)),
+            }
+        }
+        assert!(expression.eval_batch(&context, &[]).is_empty());
+    }
+
+    #[test]
+    fn substitution() {
+        let mut context = Context::default_with_math_functions();
+        context.add_function(Function::new("c", 0, |_, _| Ok(7.0)));
+        let formula =
+            Expression::compile("a * x^2 + b * x + c + if(x > 1, b, 0)", &context).unwrap();
+        let mut bindings = HashMap::new();
+        bindings.insert(String::from("a"), 1.0);
+        bindings.insert(String::from("b"), -3.0);
+        bindings.insert(String::from("c"), 2.0);
+
+        let specialized = formula.substitute(&context, &bindings);
+        let infix = postfix_to_infix(&specialized, &context).unwrap();
+        assert_eq!(infix, "1 * x ^ 2 + (-3) * x + c + if(x > 1, (-3), 0)");
+        assert_eq!(
+            parse(&infix, &context)
+                .unwrap()
+                .simplify(&context)
+                .to_string(),
+            "(((x ^ 2) + ((-3) * x)) + 7) + if(x > 1, -3, 0)"
+        );
+
+        context.set_variable("x", 2.0);
+        assert_eq!(specialized.evaluate(&context), Ok(2.0));
+        assert!(specialized.program().is_some());
+
+        let assigning = Expression::compile("a = 2", &context).unwrap();
+        let unchanged = assigning.substitute(&context, &bindings);
+        assert_eq!(
+            unchanged.iter().collect::<Vec<_>>(),
+            assigning.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Expression::variable("d")
+                .substitute(&context, &bindings)
+                .evaluate(&context),
+            Err(EvalError::UnknownVariable(String::from("d")))
+        );
+    }
+
+    #[test]
+    fn expression_substitution() {
+        let context = Context::default();
+        let mut replacements = HashMap::new();
+        replacements.insert(
+            String::from("y"),
+            Expression::variable("x") + Expression::number(1.0),
+        );
+
+        let expression = (Expression::variable("y") * Expression::variable("y"))
+            .substitute_expressions(&context, &replacements);
+        assert_eq!(
+            postfix_to_infix(&expression, &context),
+            Ok(String::from("(x + 1) * (x + 1)"))
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use crate::{evaluate, Context, EvalError};
+
+/// A context in which floats are `f64`, which is what `Context` is unless
+/// another number type is given.
+pub type Context64 = Context<f64>;
+
+/// A context in which floats are `f32`. Integers and truth values are the
+/// same as in a `Context64`, and so are the operators and functions.
+pub type Context32 = Context<f32>;
+
+/// Evaluates an expression with `f32` arithmetic. Numbers are parsed as
+/// `f32` directly, rather than being rounded from an `f64`.
+pub fn evaluate_f32(expression: &str, context: &Context32) -> Result<f32, EvalError> {
+    evaluate(expression, context).map(|value| value.to_number())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{evaluate, evaluate_f32, Context32, EvalError, Function, Value};
+
+    #[test]
+    fn arithmetic() {
+        let mut context = Context32::default();
+        context.set_variable("x", 1.5f32);
+
+        assert_eq!(evaluate_f32("2 * x + 1", &context), Ok(4.0));
+        assert_eq!(evaluate_f32("-x ^ 2", &context), Ok(-2.25));
+        assert_eq!(evaluate_f32("-+-x", &context), Ok(1.5));
+        assert_eq!(evaluate_f32("7 // 2 + 7 % 2", &context), Ok(4.0));
+        assert_eq!(evaluate_f32("0.1", &context), Ok(0.1f32));
+        assert_eq!(evaluate_f32("0.1 + 0.2", &context), Ok(0.1f32 + 0.2f32));
+        assert_eq!(evaluate_f32("1e39", &context), Ok(f32::INFINITY));
+        assert_eq!(evaluate("x < 2", &context), Ok(Value::Bool(true)));
+        assert_eq!(evaluate("7 // 2", &context), Ok(Value::Int(3)));
+    }
+
+    #[test]
+    fn functions() {
+        let mut context = Context32::default_with_math_functions();
+        context.add_function(Function::new("half", 1, |args, _| Ok(args[0] / 2.0)));
+        context.add_function(Function::user_defined("sq", &["a"], "a * a", &context).unwrap());
+
+        assert_eq!(evaluate_f32("sqrt(2)", &context), Ok(2f32.sqrt()));
+        assert_eq!(evaluate_f32("half(0.1)", &context), Ok(0.1f32 / 2.0));
+        assert_eq!(evaluate_f32("sq(0.1)", &context), Ok(0.1f32 * 0.1f32));
+        assert_eq!(evaluate_f32("max(1, 2.5, 2)", &context), Ok(2.5));
+    }
+
+    #[test]
+    fn errors() {
+        let context = Context32::default();
+
+        assert_eq!(
+            evaluate_f32("y + 1", &context),
+            Err(EvalError::UnknownVariable(String::from("y")))
+        );
+        assert_eq!(
+            evaluate_f32("sqrt(4)", &context),
+            Err(EvalError::UnknownFunction(String::from("sqrt")))
+        );
+    }
+}
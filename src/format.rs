@@ -0,0 +1,435 @@
+use crate::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Notation {
+    /// Plain digits without an exponent, e.g. `12345.6`.
+    Decimal,
+    /// One digit before the decimal point and an exponent, e.g. `1.23456e4`.
+    Scientific,
+    /// Like scientific notation, but the exponent is always a multiple of 3,
+    /// e.g. `12.3456e3`.
+    Engineering,
+    /// Decimal notation for values in `[1e-4, 1e15]` and zero, scientific
+    /// notation for everything else.
+    Auto,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumberFormatOptions {
+    /// The number of digits after the decimal point. Takes precedence over
+    /// `sig_figs`. In scientific and engineering notation this applies to the
+    /// mantissa.
+    pub decimal_places: Option<usize>,
+    /// The number of significant digits to round to.
+    pub sig_figs: Option<usize>,
+    pub notation: Notation,
+}
+
+impl Default for NumberFormatOptions {
+    fn default() -> Self {
+        Self {
+            decimal_places: None,
+            sig_figs: None,
+            notation: Notation::Auto,
+        }
+    }
+}
+
+pub fn format_result(value: f64, opts: &NumberFormatOptions) -> String {
+    if !value.is_finite() {
+        return format!("{}", value);
+    }
+
+    let split = |decimals| split_scientific(value, decimals);
+    match opts.notation {
+        Notation::Decimal => format_decimal(value, opts),
+        Notation::Scientific => format_scientific(split, opts),
+        Notation::Engineering => format_engineering(split, opts),
+        Notation::Auto => {
+            let magnitude = value.abs();
+            if value == 0.0 || (1e-4..=1e15).contains(&magnitude) {
+                format_decimal(value, opts)
+            } else {
+                format_scientific(split, opts)
+            }
+        }
+    }
+}
+
+/// Formats a result of `evaluate` like `format_result`. Integers are
+/// formatted from their exact digits rather than as a float, so integers
+/// beyond 2^53 keep all of their digits unless `opts` rounds them.
+pub fn format_value(value: &Value, opts: &NumberFormatOptions) -> String {
+    match value {
+        Value::Float(value) => format_result(*value, opts),
+        Value::Bool(value) => value.to_string(),
+        _ => format_integer(&value.to_string(), opts),
+    }
+}
+
+/// Formats an integer given as an optional `-` followed by its digits.
+fn format_integer(text: &str, opts: &NumberFormatOptions) -> String {
+    let split = |decimals| split_integer(text, decimals);
+    let decimal = || match (opts.decimal_places, opts.sig_figs) {
+        (Some(0), _) | (None, None) => String::from(text),
+        (Some(decimal_places), _) => format!("{}.{}", text, "0".repeat(decimal_places)),
+        (None, Some(sig_figs)) => {
+            let (mantissa, exponent) = split(Some(sig_figs.max(1) - 1));
+            shift_point(&mantissa, exponent)
+        }
+    };
+    match opts.notation {
+        Notation::Decimal => decimal(),
+        Notation::Scientific => format_scientific(split, opts),
+        Notation::Engineering => format_engineering(split, opts),
+        Notation::Auto => {
+            let digits = text.trim_start_matches('-');
+            if digits.len() <= 15 || digits == "1000000000000000" {
+                decimal()
+            } else {
+                format_scientific(split, opts)
+            }
+        }
+    }
+}
+
+fn format_decimal(value: f64, opts: &NumberFormatOptions) -> String {
+    match (opts.decimal_places, opts.sig_figs) {
+        (Some(decimal_places), _) => format!("{:.*}", decimal_places, value),
+        (None, Some(sig_figs)) => {
+            let (mantissa, exponent) = split_scientific(value, Some(sig_figs.max(1) - 1));
+            shift_point(&mantissa, exponent)
+        }
+        (None, None) => format!("{}", value),
+    }
+}
+
+/// `split` splits the value into the mantissa and exponent of its
+/// scientific notation, like `split_scientific`.
+fn format_scientific(
+    split: impl Fn(Option<usize>) -> (String, i32),
+    opts: &NumberFormatOptions,
+) -> String {
+    let decimals = opts
+        .decimal_places
+        .or_else(|| opts.sig_figs.map(|sig_figs| sig_figs.max(1) - 1));
+    let (mantissa, exponent) = split(decimals);
+    format!("{}e{}", mantissa, exponent)
+}
+
+fn format_engineering(
+    split: impl Fn(Option<usize>) -> (String, i32),
+    opts: &NumberFormatOptions,
+) -> String {
+    let (_, mut exponent) = split(None);
+
+    // Rounding can carry into the next power of ten, which in turn can change
+    // how many digits end up before the decimal point, so try at most twice.
+    for _ in 0..2 {
+        let shift = exponent.rem_euclid(3);
+        let decimals = match (opts.decimal_places, opts.sig_figs) {
+            (Some(decimal_places), _) => Some(decimal_places + shift as usize),
+            (None, Some(sig_figs)) => Some(sig_figs.max(1) - 1),
+            (None, None) => None,
+        };
+
+        let (mantissa, rounded_exponent) = split(decimals);
+        if rounded_exponent == exponent {
+            return format!("{}e{}", shift_point(&mantissa, shift), exponent - shift);
+        }
+        exponent = rounded_exponent;
+    }
+
+    let shift = exponent.rem_euclid(3);
+    let (mantissa, _) = split(None);
+    format!("{}e{}", shift_point(&mantissa, shift), exponent - shift)
+}
+
+/// Splits a value into the mantissa and exponent of its scientific notation,
+/// optionally rounding the mantissa to a number of decimals.
+fn split_scientific(value: f64, decimals: Option<usize>) -> (String, i32) {
+    let formatted = match decimals {
+        Some(decimals) => format!("{:.*e}", decimals, value),
+        None => format!("{:e}", value),
+    };
+    let (mantissa, exponent) = formatted.split_at(formatted.find('e').unwrap());
+    (String::from(mantissa), exponent[1..].parse().unwrap())
+}
+
+/// Like `split_scientific`, but for an integer given as an optional `-`
+/// followed by its digits. Without `decimals`, the mantissa has as many
+/// digits as needed to be exact. Ties are rounded to even, like floats are.
+fn split_integer(text: &str, decimals: Option<usize>) -> (String, i32) {
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", text),
+    };
+    let mut exponent = digits.len() as i32 - 1;
+    let significant = digits.trim_end_matches('0');
+    let mut kept: Vec<u8> = match decimals {
+        None => significant.bytes().collect(),
+        Some(decimals) => (0..=decimals)
+            .map(|index| *digits.as_bytes().get(index).unwrap_or(&b'0'))
+            .collect(),
+    };
+    if kept.is_empty() {
+        kept.push(b'0');
+    }
+
+    if let Some(decimals) = decimals {
+        let rest = digits.get(decimals + 1..).unwrap_or("");
+        let round_up = match rest.bytes().next() {
+            Some(b'5') if rest[1..].trim_end_matches('0').is_empty() => {
+                (kept[decimals] - b'0') % 2 == 1
+            }
+            Some(digit) => digit >= b'5',
+            None => false,
+        };
+        if round_up {
+            let carried = kept
+                .iter()
+                .rposition(|&digit| digit != b'9')
+                .map_or(0, |index| {
+                    kept[index] += 1;
+                    index + 1
+                });
+            for digit in &mut kept[carried..] {
+                *digit = b'0';
+            }
+            if carried == 0 {
+                kept.insert(0, b'1');
+                kept.pop();
+                exponent += 1;
+            }
+        }
+    }
+
+    let (first, fraction) = kept.split_at(1);
+    let mut mantissa = format!("{}{}", sign, first[0] as char);
+    if !fraction.is_empty() {
+        mantissa.push('.');
+        mantissa.extend(fraction.iter().map(|&digit| digit as char));
+    }
+    (mantissa, exponent)
+}
+
+/// Moves the decimal point of a mantissa like `-1.234` by `shift` places to
+/// the right (or to the left for negative values), padding with zeros.
+fn shift_point(mantissa: &str, shift: i32) -> String {
+    let (sign, unsigned) = match mantissa.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", mantissa),
+    };
+    let (integer, fraction) = match unsigned.find('.') {
+        Some(index) => (&unsigned[..index], &unsigned[index + 1..]),
+        None => (unsigned, ""),
+    };
+    let digits = format!("{}{}", integer, fraction);
+    let point = integer.len() as i32 + shift;
+
+    if point <= 0 {
+        format!("{}0.{}{}", sign, "0".repeat(-point as usize), digits)
+    } else if point as usize >= digits.len() {
+        format!(
+            "{}{}{}",
+            sign,
+            digits,
+            "0".repeat(point as usize - digits.len())
+        )
+    } else {
+        let (integer, fraction) = digits.split_at(point as usize);
+        format!("{}{}.{}", sign, integer, fraction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{format_result, format_value, Notation, NumberFormatOptions, Value};
+
+    fn format(
+        value: f64,
+        decimal_places: Option<usize>,
+        sig_figs: Option<usize>,
+        notation: Notation,
+    ) -> String {
+        let opts = NumberFormatOptions {
+            decimal_places,
+            sig_figs,
+            notation,
+        };
+        format_result(value, &opts)
+    }
+
+    #[test]
+    fn decimal() {
+        assert_eq!(
+            format(1234.5678, None, None, Notation::Decimal),
+            "1234.5678"
+        );
+        assert_eq!(
+            format(1234.5678, Some(2), None, Notation::Decimal),
+            "1234.57"
+        );
+        assert_eq!(format(1234.5678, None, Some(3), Notation::Decimal), "1230");
+        assert_eq!(
+            format(0.00123456, None, Some(2), Notation::Decimal),
+            "0.0012"
+        );
+        assert_eq!(format(-9.996, None, Some(3), Notation::Decimal), "-10.0");
+        assert_eq!(format(2.5, Some(1), Some(5), Notation::Decimal), "2.5");
+    }
+
+    #[test]
+    fn scientific() {
+        assert_eq!(
+            format(1234.5678, None, None, Notation::Scientific),
+            "1.2345678e3"
+        );
+        assert_eq!(
+            format(1234.5678, Some(2), None, Notation::Scientific),
+            "1.23e3"
+        );
+        assert_eq!(
+            format(0.00012, None, Some(3), Notation::Scientific),
+            "1.20e-4"
+        );
+        assert_eq!(format(-5.0, None, None, Notation::Scientific), "-5e0");
+    }
+
+    #[test]
+    fn engineering() {
+        assert_eq!(
+            format(1234.5678, None, None, Notation::Engineering),
+            "1.2345678e3"
+        );
+        assert_eq!(
+            format(12345.0, None, None, Notation::Engineering),
+            "12.345e3"
+        );
+        assert_eq!(
+            format(123456.0, None, Some(2), Notation::Engineering),
+            "120e3"
+        );
+        assert_eq!(format(0.00047, None, None, Notation::Engineering), "470e-6");
+        assert_eq!(
+            format(999.96, Some(1), None, Notation::Engineering),
+            "1.0e3"
+        );
+        assert_eq!(
+            format(-0.05, Some(2), None, Notation::Engineering),
+            "-50.00e-3"
+        );
+    }
+
+    #[test]
+    fn auto() {
+        assert_eq!(format(0.0, None, None, Notation::Auto), "0");
+        assert_eq!(format(0.5, None, None, Notation::Auto), "0.5");
+        assert_eq!(format(1e15, None, None, Notation::Auto), "1000000000000000");
+        assert_eq!(format(2e15, None, None, Notation::Auto), "2e15");
+        assert_eq!(format(0.00001, None, None, Notation::Auto), "1e-5");
+        assert_eq!(format(-0.00002, None, Some(2), Notation::Auto), "-2.0e-5");
+    }
+
+    #[test]
+    fn non_finite() {
+        let opts = NumberFormatOptions::default();
+        assert_eq!(format_result(f64::NAN, &opts), "NaN");
+        assert_eq!(format_result(f64::INFINITY, &opts), "inf");
+        assert_eq!(format_result(f64::NEG_INFINITY, &opts), "-inf");
+    }
+
+    #[test]
+    fn values() {
+        let notations = [
+            Notation::Decimal,
+            Notation::Scientific,
+            Notation::Engineering,
+            Notation::Auto,
+        ];
+        // Integers that are exact as floats are formatted like them.
+        for &value in &[
+            0,
+            7,
+            -42,
+            125,
+            135,
+            995,
+            999_999,
+            -98_765_432_109,
+            1_000_000_000_000_000,
+            4_503_599_627_370_497,
+        ] {
+            for &decimal_places in &[None, Some(0), Some(2)] {
+                for &sig_figs in &[None, Some(1), Some(2), Some(4)] {
+                    for &notation in &notations {
+                        let opts = NumberFormatOptions {
+                            decimal_places,
+                            sig_figs,
+                            notation,
+                        };
+                        assert_eq!(
+                            format_value(&Value::Int(value), &opts),
+                            format_result(value as f64, &opts),
+                            "{} {:?}",
+                            value,
+                            opts
+                        );
+                    }
+                }
+            }
+        }
+
+        let opts = NumberFormatOptions::default();
+        assert_eq!(
+            format_value(&Value::Int(i64::MAX), &opts),
+            "9.223372036854775807e18"
+        );
+        assert_eq!(format_value(&Value::Float(0.5), &opts), "0.5");
+        assert_eq!(format_value(&Value::Bool(true), &opts), "true");
+    }
+
+    #[cfg(feature = "bigint")]
+    #[test]
+    fn big_values() {
+        let digits = "1606938044258990275541962092341162602522202993782792835301376";
+        let value = Value::Big(digits.parse().unwrap());
+        let negative = Value::Big(format!("-{}", digits).parse().unwrap());
+        let format = |value: &Value, decimal_places, sig_figs, notation| {
+            let opts = NumberFormatOptions {
+                decimal_places,
+                sig_figs,
+                notation,
+            };
+            format_value(value, &opts)
+        };
+
+        assert_eq!(format(&value, None, None, Notation::Decimal), digits);
+        assert_eq!(
+            format(&negative, None, None, Notation::Decimal),
+            format!("-{}", digits)
+        );
+        assert_eq!(
+            format(&value, None, None, Notation::Auto),
+            "1.606938044258990275541962092341162602522202993782792835301376e60"
+        );
+        assert_eq!(format(&value, None, Some(3), Notation::Auto), "1.61e60");
+        assert_eq!(
+            format(&negative, Some(1), None, Notation::Engineering),
+            "-1.6e60"
+        );
+        assert_eq!(
+            format(&value, None, Some(5), Notation::Decimal),
+            format!("16069{}", "0".repeat(56))
+        );
+        assert_eq!(
+            format(
+                &Value::Big("99960000000000000000".parse().unwrap()),
+                None,
+                Some(3),
+                Notation::Scientific
+            ),
+            "1.00e20"
+        );
+    }
+}
@@ -0,0 +1,221 @@
+use crate::value::{number, Number};
+use crate::{collect_tokens, evaluate_scoped, shunting_yard, Context, EvalError, Token, Value};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+
+/// The implementation of a native function. It receives the arguments and the
+/// context the function is called in. It has to be `Send` and `Sync` so that
+/// contexts can be shared between threads.
+pub type NativeFn<N = f64> =
+    dyn Fn(&[Value<N>], &Context<N>) -> Result<Value<N>, EvalError> + Send + Sync;
+
+/// How many arguments of a function made with `Function::new` are converted
+/// to numbers without allocating.
+const ARGUMENT_BUFFER: usize = 8;
+
+/// The number of arguments a function accepts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, num_args: usize) -> bool {
+        match *self {
+            Self::Exact(n) => num_args == n,
+            Self::AtLeast(n) => num_args >= n,
+        }
+    }
+
+    /// The smallest number of arguments that is accepted.
+    pub fn min(&self) -> usize {
+        match *self {
+            Self::Exact(n) => n,
+            Self::AtLeast(n) => n,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Function<N = f64> {
+    NativeFunction {
+        name: String,
+        arity: Arity,
+        execute: Arc<NativeFn<N>>,
+    },
+    /// A function defined by an expression, which is compiled to postfix
+    /// order once and evaluated with its parameters bound as variables.
+    UserDefined {
+        name: String,
+        params: Vec<String>,
+        body: VecDeque<Token>,
+    },
+}
+
+impl<N: Number> Function<N> {
+    /// Creates a function of numbers, which gets the values of its arguments
+    /// as the number type of the context and gives a float, see `evaluate`.
+    pub fn new<F>(name: &str, num_args: usize, execute: F) -> Self
+    where
+        F: Fn(&[N], &Context<N>) -> Result<N, EvalError> + Send + Sync + 'static,
+    {
+        Self::with_values(name, Arity::Exact(num_args), numeric(name, execute))
+    }
+
+    /// Like `new`, for a function that accepts `min_args` or more arguments.
+    pub fn variadic<F>(name: &str, min_args: usize, execute: F) -> Self
+    where
+        F: Fn(&[N], &Context<N>) -> Result<N, EvalError> + Send + Sync + 'static,
+    {
+        Self::with_values(name, Arity::AtLeast(min_args), numeric(name, execute))
+    }
+
+    /// Creates a function that gets its arguments as values, e.g. to give an
+    /// integer or a truth value.
+    pub fn with_values<F>(name: &str, arity: Arity, execute: F) -> Self
+    where
+        F: Fn(&[Value<N>], &Context<N>) -> Result<Value<N>, EvalError> + Send + Sync + 'static,
+    {
+        Self::NativeFunction {
+            name: String::from(name),
+            arity,
+            execute: Arc::new(execute),
+        }
+    }
+
+    pub fn user_defined(
+        name: &str,
+        params: &[&str],
+        body: &str,
+        context: &Context<N>,
+    ) -> Result<Self, EvalError> {
+        let body = shunting_yard(collect_tokens(body, context)?, context)?;
+        Ok(Self::UserDefined {
+            name: String::from(name),
+            params: params.iter().map(|&param| String::from(param)).collect(),
+            body,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::NativeFunction { name, .. } => name,
+            Self::UserDefined { name, .. } => name,
+        }
+    }
+
+    /// A copy of the function under another name, which shares the
+    /// implementation of a native function.
+    pub(crate) fn renamed(&self, new_name: &str) -> Self {
+        let mut function = self.clone();
+        match &mut function {
+            Self::NativeFunction { name, .. } | Self::UserDefined { name, .. } => {
+                *name = String::from(new_name)
+            }
+        }
+        function
+    }
+
+    pub fn arity(&self) -> Arity {
+        match self {
+            Self::NativeFunction { arity, .. } => *arity,
+            Self::UserDefined { params, .. } => Arity::Exact(params.len()),
+        }
+    }
+
+    /// Pops `num_args` arguments off `stack` and pushes the result.
+    pub fn apply(
+        &self,
+        stack: &mut Vec<Value<N>>,
+        num_args: usize,
+        context: &Context<N>,
+    ) -> Result<(), EvalError> {
+        let arity = self.arity();
+        if !arity.accepts(num_args) || stack.len() < num_args {
+            return Err(EvalError::WrongNumberOfArguments {
+                name: String::from(self.name()),
+                expected: vec![arity],
+                found: num_args.min(stack.len()),
+            });
+        }
+
+        let start = stack.len() - num_args;
+        let result = self.call(&stack[start..], context)?;
+        stack.truncate(start);
+        stack.push(result);
+        Ok(())
+    }
+
+    /// Calls the function with arguments it accepts.
+    pub(crate) fn call(
+        &self,
+        args: &[Value<N>],
+        context: &Context<N>,
+    ) -> Result<Value<N>, EvalError> {
+        match self {
+            Self::NativeFunction { execute, .. } => execute(args, context),
+            Self::UserDefined { name, params, body } => match context.cached_call(name, args) {
+                Some(result) => Ok(result),
+                None => {
+                    let locals: HashMap<String, Value<N>> =
+                        params.iter().cloned().zip(args.iter().cloned()).collect();
+                    context.enter_call()?;
+                    let result = evaluate_scoped(body, context, &locals, None);
+                    if let Ok(result) = &result {
+                        context.cache_call(name, args, result.clone());
+                    }
+                    context.leave_call();
+                    result
+                }
+            },
+        }
+    }
+}
+
+/// Wraps a function of numbers for `Function::new`, converting the arguments
+/// on the stack unless there are more than `ARGUMENT_BUFFER`.
+fn numeric<N, F>(
+    name: &str,
+    execute: F,
+) -> impl Fn(&[Value<N>], &Context<N>) -> Result<Value<N>, EvalError>
+where
+    N: Number,
+    F: Fn(&[N], &Context<N>) -> Result<N, EvalError>,
+{
+    let name = String::from(name);
+    move |args, context| {
+        let mut buffer = [N::zero(); ARGUMENT_BUFFER];
+        let mut spilled = Vec::new();
+        let numbers = match buffer.get_mut(..args.len()) {
+            Some(numbers) => numbers,
+            None => {
+                spilled.resize(args.len(), N::zero());
+                &mut spilled[..]
+            }
+        };
+        for (converted, arg) in numbers.iter_mut().zip(args) {
+            *converted = number(&name, arg, context)?;
+        }
+        execute(numbers, context).map(Value::Float)
+    }
+}
+
+impl<N> fmt::Debug for Function<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NativeFunction { name, arity, .. } => f
+                .debug_struct("NativeFunction")
+                .field("name", name)
+                .field("arity", arity)
+                .finish(),
+            Self::UserDefined { name, params, body } => f
+                .debug_struct("UserDefined")
+                .field("name", name)
+                .field("params", params)
+                .field("body", body)
+                .finish(),
+        }
+    }
+}
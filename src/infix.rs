@@ -0,0 +1,243 @@
+//! Converting postfix queues back to infix notation.
+
+use crate::{branch_operator, pop_operand, Associativity, Context, EvalError, Operator, Token};
+use std::collections::VecDeque;
+
+/// The precedence of anything that never needs parentheses, like numbers and
+/// calls.
+const ATOM: i32 = i32::MAX;
+
+/// Writes a postfix queue, as returned by `shunting_yard`, in infix notation.
+/// Operands are only parenthesized where the precedence and associativity of
+/// the operators in `context` require it, so `2 + 3 * 4` stays as it is and
+/// `(2 + 3) * 4` keeps its parentheses.
+///
+/// `if` and the logical operators `&&` and `||` are branches in the queue,
+/// which are written as `&&` and `||` again where they have their shape and as
+/// calls of `if` otherwise.
+pub fn postfix_to_infix(queue: &VecDeque<Token>, context: &Context) -> Result<String, EvalError> {
+    infix(queue, context).map(|(text, _)| text)
+}
+
+/// Converts a block of tokens to infix notation and returns the precedence
+/// of its outermost operation.
+fn infix<'a>(
+    tokens: impl IntoIterator<Item = &'a Token>,
+    context: &Context,
+) -> Result<(String, i32), EvalError> {
+    let mut stack: Vec<(String, i32)> = Vec::new();
+    let separator = format!("{} ", context.number_locale().argument_separator());
+
+    for token in tokens {
+        let entry = match token {
+            Token::Number(value, text) if *value < 0.0 => (format!("({})", text), ATOM),
+            Token::Number(_, text) => (text.clone(), ATOM),
+            Token::Identifier(name) => (name.clone(), ATOM),
+            Token::Symbol(symbol) => {
+                let operator = context
+                    .get_operator(symbol)
+                    .ok_or_else(|| EvalError::UnknownOperator(symbol.clone()))?;
+                let precedence = operator.precedence();
+                let (right, right_precedence) = pop_operand(&mut stack, symbol)?;
+                let (left, left_precedence) = pop_operand(&mut stack, symbol)?;
+                let associativity = operator.associativity();
+                let left = wrap(
+                    left,
+                    left_precedence < precedence
+                        || (left_precedence == precedence && associativity != Associativity::Left),
+                );
+                let right = wrap(
+                    right,
+                    right_precedence < precedence
+                        || (right_precedence == precedence
+                            && associativity != Associativity::Right),
+                );
+                (format!("{} {} {}", left, symbol, right), precedence)
+            }
+            Token::Prefix(symbol) => {
+                let precedence = unary_precedence(context.get_prefix_operator(symbol), symbol)?;
+                let (operand, operand_precedence) = pop_operand(&mut stack, symbol)?;
+                let operand = wrap(operand, operand_precedence < precedence);
+                (format!("{}{}", symbol, operand), precedence)
+            }
+            Token::Postfix(symbol) => {
+                let precedence = unary_precedence(context.get_postfix_operator(symbol), symbol)?;
+                let (operand, operand_precedence) = pop_operand(&mut stack, symbol)?;
+                let operand = wrap(operand, operand_precedence < precedence);
+                (format!("{}{}", operand, symbol), precedence)
+            }
+            Token::Call(name, num_args) => {
+                if stack.len() < *num_args {
+                    return Err(EvalError::MissingOperand(name.clone()));
+                }
+                let args: Vec<String> = stack
+                    .drain(stack.len() - num_args..)
+                    .map(|(arg, _)| arg)
+                    .collect();
+                (format!("{}({})", name, args.join(&separator)), ATOM)
+            }
+            Token::Branch(then, otherwise) => {
+                let (condition, condition_precedence) = pop_operand(&mut stack, "if")?;
+                match logical_operator(then, otherwise, context) {
+                    Some((symbol, precedence, right)) => {
+                        let (right, right_precedence) = infix(right, context)?;
+                        let condition = wrap(condition, condition_precedence < precedence);
+                        let right = wrap(right, right_precedence <= precedence);
+                        (format!("{} {} {}", condition, symbol, right), precedence)
+                    }
+                    None => {
+                        let (then, _) = infix(then, context)?;
+                        let (otherwise, _) = infix(otherwise, context)?;
+                        let args = [condition, then, otherwise].join(&separator);
+                        (format!("if({})", args), ATOM)
+                    }
+                }
+            }
+            Token::Reduce(name, variable, body) => {
+                let (end, _) = pop_operand(&mut stack, name)?;
+                let (start, _) = pop_operand(&mut stack, name)?;
+                let (body, _) = infix(body, context)?;
+                let args = [variable.clone(), start, end, body].join(&separator);
+                (format!("{}({})", name, args), ATOM)
+            }
+            Token::Assign(name) => {
+                let (value, _) = pop_operand(&mut stack, "=")?;
+                (format!("{} = {}", name, value), i32::MIN)
+            }
+            Token::Whitespace(_) => continue,
+            Token::LeftParenthesis | Token::RightParenthesis => {
+                return Err(EvalError::MismatchedParentheses)
+            }
+            Token::Separator => return Err(EvalError::UnexpectedToken(String::from(","))),
+        };
+        stack.push(entry);
+    }
+
+    match stack.len() {
+        0 => Err(EvalError::EmptyExpression),
+        1 => Ok(stack.remove(0)),
+        n => Err(EvalError::TooManyOperands(n - 1)),
+    }
+}
+
+fn unary_precedence(operator: Option<&Operator>, symbol: &str) -> Result<i32, EvalError> {
+    operator
+        .map(Operator::precedence)
+        .ok_or_else(|| EvalError::UnknownOperator(String::from(symbol)))
+}
+
+fn wrap(text: String, parenthesize: bool) -> String {
+    if parenthesize {
+        format!("({})", text)
+    } else {
+        text
+    }
+}
+
+/// Recognizes the branches that `logical_branch` makes for `&&` and `||` and
+/// returns the operator, its precedence and its right operand.
+fn logical_operator<'a>(
+    then: &'a VecDeque<Token>,
+    otherwise: &'a VecDeque<Token>,
+    context: &Context,
+) -> Option<(&'static str, i32, impl Iterator<Item = &'a Token>)> {
+    let (symbol, right, constant) = match branch_operator(then, otherwise) {
+        "&&" => ("&&", then, "true"),
+        "||" => ("||", otherwise, "false"),
+        _ => return None,
+    };
+    let len = right.len();
+    match (len.checked_sub(2).map(|i| &right[i]), right.back()) {
+        (Some(Token::Number(_, text)), Some(Token::Symbol(inner)))
+            if text == constant && inner == symbol => {}
+        _ => return None,
+    }
+    let precedence = context.get_operator(symbol)?.precedence();
+    Some((symbol, precedence, right.iter().take(len - 2)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        collect_tokens, evaluate, lex, postfix_to_infix, shunting_yard, Context, EvalError,
+    };
+    use std::collections::VecDeque;
+
+    fn infix(expression: &str, context: &Context) -> String {
+        let queue = shunting_yard(collect_tokens(expression, context).unwrap(), context).unwrap();
+        postfix_to_infix(&queue, context).unwrap()
+    }
+
+    #[test]
+    fn minimal_parentheses() {
+        let context = Context::default_with_math_functions();
+
+        assert_eq!(infix("2+3*4", &context), "2 + 3 * 4");
+        assert_eq!(infix("(2+3)*4", &context), "(2 + 3) * 4");
+        assert_eq!(infix("((1 - 2)) - 3", &context), "1 - 2 - 3");
+        assert_eq!(infix("1 - (2 - 3)", &context), "1 - (2 - 3)");
+        assert_eq!(infix("2 ^ 3 ^ 2", &context), "2 ^ 3 ^ 2");
+        assert_eq!(infix("(2 ^ 3) ^ 2", &context), "(2 ^ 3) ^ 2");
+        assert_eq!(infix("-2 ^ 2", &context), "-2 ^ 2");
+        assert_eq!(infix("(-2) ^ 2", &context), "(-2) ^ 2");
+        assert_eq!(infix("(-3)! + -(3!)", &context), "(-3)! + -3!");
+        assert_eq!(
+            infix("max(1, (2 + 3)) * sqrt(4)", &context),
+            "max(1, 2 + 3) * sqrt(4)"
+        );
+    }
+
+    #[test]
+    fn branches_and_assignments() {
+        let context = Context::default();
+
+        assert_eq!(infix("x = (1 + 2)", &context), "x = 1 + 2");
+        assert_eq!(
+            infix("if(x > 1, 2, 3) + 1", &context),
+            "if(x > 1, 2, 3) + 1"
+        );
+        assert_eq!(infix("a && (b || c)", &context), "a && (b || c)");
+        assert_eq!(infix("(a && b) || c", &context), "a && b || c");
+        assert_eq!(infix("!(a == 1) && b", &context), "!(a == 1) && b");
+    }
+
+    #[test]
+    fn same_results() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", 3.0);
+
+        for &expression in &[
+            "2 * (x - 1) / (4 - x)",
+            "-(x + 1) ^ 2 ^ (1 / 2)",
+            "max(x, 2) % (3 - 1)!",
+            "0 < x < 4 || x == 1 && x",
+            "if(x, 1, 2) * -x",
+        ] {
+            assert_eq!(
+                evaluate(&infix(expression, &context), &context),
+                evaluate(expression, &context),
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let context = Context::default();
+        let queue = |tokens: &str| -> VecDeque<_> { lex(tokens).unwrap().into_iter().collect() };
+
+        assert_eq!(
+            postfix_to_infix(&queue("1 +"), &context),
+            Err(EvalError::MissingOperand(String::from("+")))
+        );
+        assert_eq!(
+            postfix_to_infix(&queue("1 2"), &context),
+            Err(EvalError::TooManyOperands(1))
+        );
+        assert_eq!(
+            postfix_to_infix(&VecDeque::new(), &context),
+            Err(EvalError::EmptyExpression)
+        );
+    }
+}
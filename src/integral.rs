@@ -0,0 +1,261 @@
+//! Numerical integration with adaptive Simpson quadrature.
+
+use crate::{Context, EvalError, Expression};
+use std::collections::HashMap;
+
+/// The absolute error that `integrate` aims for.
+const DEFAULT_TOLERANCE: f64 = 1e-10;
+
+/// How often an interval may be halved. Intervals that reach this depth are
+/// accepted as they are.
+const MAX_DEPTH: u32 = 50;
+
+/// How often an interval is halved at least, so that integrands that happen
+/// to look flat at the first few points aren't taken for flat.
+const MIN_DEPTH: u32 = 4;
+
+/// How often the integrand may be evaluated. Once that many evaluations are
+/// used up, intervals are no longer halved.
+const MAX_EVALUATIONS: usize = 1_000_000;
+
+/// A definite integral and an estimate of its absolute error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Integral {
+    pub value: f64,
+    pub error: f64,
+}
+
+/// Integrates `expression` over `variable` from `start` to `end`, see
+/// `integrate_with_tolerance`, aiming for an absolute error of 1e-10.
+pub fn integrate(
+    expression: &Expression,
+    variable: &str,
+    start: f64,
+    end: f64,
+    context: &Context,
+) -> Result<Integral, EvalError> {
+    integrate_with_tolerance(expression, variable, start, end, DEFAULT_TOLERANCE, context)
+}
+
+/// Integrates `expression` over `variable` from `start` to `end` with
+/// adaptive Simpson quadrature, halving intervals until the estimated
+/// absolute error is at most `tolerance`. If `start` is greater than `end`,
+/// the sign of the result is flipped.
+///
+/// The integrand is evaluated at both ends, so a singularity there, as in
+/// `1 / sqrt(x)` at 0, fails rather than converging slowly. Evaluation
+/// errors, infinity and NaN fail with `EvalError::AtSample`. If the
+/// tolerance can't be met within a fixed depth and number of evaluations,
+/// this fails with `EvalError::NotConverged`, which holds the estimate that
+/// was reached.
+pub fn integrate_with_tolerance(
+    expression: &Expression,
+    variable: &str,
+    start: f64,
+    end: f64,
+    tolerance: f64,
+    context: &Context,
+) -> Result<Integral, EvalError> {
+    if !(tolerance > 0.0 && tolerance.is_finite()) {
+        return Err(EvalError::InvalidTolerance(tolerance));
+    }
+    for &bound in &[start, end] {
+        if !bound.is_finite() {
+            return Err(EvalError::InvalidBound(bound));
+        }
+    }
+    if start > end {
+        let integral =
+            integrate_with_tolerance(expression, variable, end, start, tolerance, context)?;
+        return Ok(Integral {
+            value: -integral.value,
+            ..integral
+        });
+    }
+
+    let mut integrand = Integrand {
+        expression,
+        variable,
+        context,
+        bindings: HashMap::new(),
+        evaluations: 0,
+    };
+    let (f_start, f_end) = (integrand.at(start)?, integrand.at(end)?);
+    let whole = integrand.simpson(start, f_start, end, f_end)?;
+    let mut estimate = Estimate {
+        error: 0.0,
+        exhausted: false,
+    };
+    let value = integrand.adapt(
+        (start, f_start),
+        (end, f_end),
+        whole,
+        tolerance,
+        0,
+        &mut estimate,
+    )?;
+
+    if estimate.exhausted && estimate.error > tolerance {
+        return Err(EvalError::NotConverged {
+            value,
+            error: estimate.error,
+        });
+    }
+    Ok(Integral {
+        value,
+        error: estimate.error,
+    })
+}
+
+struct Integrand<'a> {
+    expression: &'a Expression,
+    variable: &'a str,
+    context: &'a Context,
+    bindings: HashMap<String, f64>,
+    evaluations: usize,
+}
+
+/// The error accumulated over all accepted intervals, and whether any of them
+/// was accepted without meeting its tolerance.
+struct Estimate {
+    error: f64,
+    exhausted: bool,
+}
+
+/// A point of the integrand and its value.
+type Point = (f64, f64);
+
+impl Integrand<'_> {
+    fn at(&mut self, value: f64) -> Result<f64, EvalError> {
+        self.evaluations += 1;
+        self.expression
+            .evaluate_at(self.context, &mut self.bindings, self.variable, value)
+    }
+
+    /// Simpson's rule on an interval, returning the midpoint along with the
+    /// estimate.
+    fn simpson(
+        &mut self,
+        start: f64,
+        f_start: f64,
+        end: f64,
+        f_end: f64,
+    ) -> Result<(Point, f64), EvalError> {
+        let middle = (start + end) / 2.0;
+        let f_middle = self.at(middle)?;
+        let area = (end - start) / 6.0 * (f_start + 4.0 * f_middle + f_end);
+        Ok(((middle, f_middle), area))
+    }
+
+    /// Refines the estimate `whole` of the interval from `start` to `end` by
+    /// comparing it to the estimates of its halves.
+    fn adapt(
+        &mut self,
+        start: Point,
+        end: Point,
+        (middle, whole): (Point, f64),
+        tolerance: f64,
+        depth: u32,
+        estimate: &mut Estimate,
+    ) -> Result<f64, EvalError> {
+        let left = self.simpson(start.0, start.1, middle.0, middle.1)?;
+        let right = self.simpson(middle.0, middle.1, end.0, end.1)?;
+        let delta = left.1 + right.1 - whole;
+        // The halves are more accurate than the whole by about a factor of
+        // 16, so the difference is about 15 times their error.
+        let error = delta.abs() / 15.0;
+
+        let converged = depth >= MIN_DEPTH && error <= tolerance;
+        let exhausted = depth >= MAX_DEPTH || self.evaluations >= MAX_EVALUATIONS;
+        if converged || exhausted {
+            estimate.error += error;
+            estimate.exhausted |= !converged;
+            return Ok(left.1 + right.1 + delta / 15.0);
+        }
+
+        let left = self.adapt(start, middle, left, tolerance / 2.0, depth + 1, estimate)?;
+        let right = self.adapt(middle, end, right, tolerance / 2.0, depth + 1, estimate)?;
+        Ok(left + right)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{integrate, integrate_with_tolerance, Context, EvalError, Expression};
+    use std::f64::consts::PI;
+
+    #[test]
+    fn integrals() {
+        let context = Context::default_with_math_functions();
+        let integral = |expression, start, end| {
+            let expression = Expression::compile(expression, &context).unwrap();
+            integrate(&expression, "x", start, end, &context).unwrap()
+        };
+
+        let square = integral("x^2", 0.0, 1.0);
+        assert!((square.value - 1.0 / 3.0).abs() < 1e-9);
+        assert!(square.error < 1e-10);
+        assert!((integral("sin(x)", 0.0, PI).value - 2.0).abs() < 1e-9);
+        assert!((integral("sin(x)", 0.0, 2.0 * PI).value).abs() < 1e-9);
+        assert!((integral("exp(-x^2)", -6.0, 6.0).value - PI.sqrt()).abs() < 1e-9);
+        assert!((integral("x^2", 1.0, 0.0).value + 1.0 / 3.0).abs() < 1e-9);
+        assert_eq!(integral("x", 2.0, 2.0).value, 0.0);
+        assert!((integral("1 / sqrt(x)", 1e-8, 1.0).value - 2.0 + 2e-4).abs() < 1e-8);
+    }
+
+    #[test]
+    fn tolerances() {
+        let context = Context::default_with_math_functions();
+        let expression = Expression::compile("sqrt(x)", &context).unwrap();
+
+        let coarse = integrate_with_tolerance(&expression, "x", 0.0, 1.0, 1e-3, &context).unwrap();
+        assert!((coarse.value - 2.0 / 3.0).abs() < 1e-3);
+        assert!(coarse.error <= 1e-3);
+        assert_eq!(
+            integrate_with_tolerance(&expression, "x", 0.0, 1.0, 0.0, &context),
+            Err(EvalError::InvalidTolerance(0.0))
+        );
+        assert_eq!(
+            integrate(&expression, "x", 0.0, f64::INFINITY, &context),
+            Err(EvalError::InvalidBound(f64::INFINITY))
+        );
+    }
+
+    #[test]
+    fn failures() {
+        let context = Context::default_with_math_functions();
+        let integral = |expression, start, end| {
+            let expression = Expression::compile(expression, &context).unwrap();
+            integrate(&expression, "x", start, end, &context)
+        };
+
+        assert_eq!(
+            integral("1 / sqrt(x)", 0.0, 1.0),
+            Err(EvalError::AtSample {
+                value: 0.0,
+                error: Box::new(EvalError::NonFiniteValue(f64::INFINITY)),
+            })
+        );
+        assert_eq!(
+            integral("(x - 2)!", 0.0, 4.0),
+            Err(EvalError::AtSample {
+                value: 0.0,
+                error: Box::new(EvalError::InvalidOperand {
+                    operator: String::from("!"),
+                    value: -2.0,
+                }),
+            })
+        );
+        assert_eq!(
+            integral("x + y", 0.0, 1.0),
+            Err(EvalError::AtSample {
+                value: 0.0,
+                error: Box::new(EvalError::UnknownVariable(String::from("y"))),
+            })
+        );
+        assert!(matches!(
+            integral("sin(1 / x)", 1e-6, 1.0),
+            Err(EvalError::NotConverged { .. })
+        ));
+    }
+}
@@ -0,0 +1,371 @@
+use crate::{collect_tokens, pop_operand, shunting_yard, Context, EvalError, Token};
+use core::f64::consts::{FRAC_PI_2, PI, TAU};
+use std::collections::HashMap;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+/// A closed range of real numbers `[lo, hi]`.
+///
+/// All operations round their bounds outward, so the exact result of an
+/// operation on any numbers within the operands is always contained in the
+/// resulting interval.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    pub lo: f64,
+    pub hi: f64,
+}
+
+impl Interval {
+    pub fn new(lo: f64, hi: f64) -> Self {
+        Self { lo, hi }
+    }
+
+    /// An interval that only contains `value`.
+    pub fn point(value: f64) -> Self {
+        Self::new(value, value)
+    }
+
+    /// The interval of all real numbers.
+    pub fn entire() -> Self {
+        Self::new(f64::NEG_INFINITY, f64::INFINITY)
+    }
+
+    /// The result of an operation that isn't defined anywhere in its operands.
+    fn undefined() -> Self {
+        Self::new(f64::NAN, f64::NAN)
+    }
+
+    pub fn contains(&self, value: f64) -> bool {
+        self.lo <= value && value <= self.hi
+    }
+
+    pub fn width(&self) -> f64 {
+        self.hi - self.lo
+    }
+
+    /// Widens the interval by one unit in the last place on both sides to
+    /// make up for rounding errors in the computation of its bounds.
+    fn outward(self) -> Self {
+        Self::new(self.lo.next_down(), self.hi.next_up())
+    }
+
+    fn hull(values: &[f64]) -> Self {
+        let lo = values.iter().copied().fold(f64::INFINITY, f64::min);
+        let hi = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        Self::new(lo, hi)
+    }
+
+    /// Raises the interval to the power of `exponent`.
+    ///
+    /// Negative bases are only allowed for a single integer exponent. For any
+    /// other exponent, the negative part of the base is ignored, like `sqrt`
+    /// does.
+    pub fn pow(self, exponent: Self) -> Self {
+        if exponent.lo == exponent.hi && exponent.lo.fract() == 0.0 {
+            return self.powi(exponent.lo);
+        }
+
+        let base = Self::new(self.lo.max(0.0), self.hi);
+        if base.hi < 0.0 {
+            return Self::undefined();
+        }
+        // For a positive base, x ^ y is monotonic in both x and y, so the
+        // bounds are found in the corners.
+        Self::hull(&[
+            base.lo.powf(exponent.lo),
+            base.lo.powf(exponent.hi),
+            base.hi.powf(exponent.lo),
+            base.hi.powf(exponent.hi),
+        ])
+        .outward()
+    }
+
+    fn powi(self, n: f64) -> Self {
+        if n == 0.0 {
+            return Self::point(1.0);
+        }
+        if n < 0.0 {
+            return Self::point(1.0) / self.powi(-n);
+        }
+
+        let lo = self.lo.powf(n);
+        let hi = self.hi.powf(n);
+        if n % 2.0 == 1.0 {
+            Self::new(lo, hi).outward()
+        } else if self.contains(0.0) {
+            Self::new(0.0, lo.max(hi)).outward()
+        } else {
+            Self::hull(&[lo, hi]).outward()
+        }
+    }
+
+    /// The square root of the non-negative part of the interval.
+    pub fn sqrt(self) -> Self {
+        if self.hi < 0.0 {
+            return Self::undefined();
+        }
+        Self::new(self.lo.max(0.0).sqrt(), self.hi.sqrt()).outward()
+    }
+
+    pub fn sin(self) -> Self {
+        self.periodic(f64::sin, FRAC_PI_2)
+    }
+
+    pub fn cos(self) -> Self {
+        self.periodic(f64::cos, 0.0)
+    }
+
+    /// Applies `f`, a sine wave with its maxima at `peak + 2kπ` and its minima
+    /// at `peak + π + 2kπ`.
+    fn periodic(self, f: fn(f64) -> f64, peak: f64) -> Self {
+        if self.width() >= TAU {
+            return Self::new(-1.0, 1.0);
+        }
+
+        let contains_phase = |phase: f64| {
+            let k = ((self.lo - phase) / TAU).ceil();
+            phase + k * TAU <= self.hi
+        };
+        let mut result = Self::hull(&[f(self.lo), f(self.hi)]).outward();
+        if contains_phase(peak) {
+            result.hi = 1.0;
+        }
+        if contains_phase(peak + PI) {
+            result.lo = -1.0;
+        }
+        Self::new(result.lo.max(-1.0), result.hi.min(1.0))
+    }
+}
+
+impl Add for Interval {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self::new(self.lo + other.lo, self.hi + other.hi).outward()
+    }
+}
+
+impl Neg for Interval {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self::new(-self.hi, -self.lo)
+    }
+}
+
+impl Sub for Interval {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self::new(self.lo - other.hi, self.hi - other.lo).outward()
+    }
+}
+
+impl Mul for Interval {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        Self::hull(&[
+            self.lo * other.lo,
+            self.lo * other.hi,
+            self.hi * other.lo,
+            self.hi * other.hi,
+        ])
+        .outward()
+    }
+}
+
+impl Div for Interval {
+    type Output = Self;
+
+    /// If `other` contains zero, the quotient can be arbitrarily large, so the
+    /// result is the entire real line.
+    fn div(self, other: Self) -> Self {
+        if other.contains(0.0) {
+            return Self::entire();
+        }
+        Self::hull(&[
+            self.lo / other.lo,
+            self.lo / other.hi,
+            self.hi / other.lo,
+            self.hi / other.hi,
+        ])
+        .outward()
+    }
+}
+
+/// A context for `evaluate_interval`, in which variables are intervals.
+///
+/// The operators `+`, `-`, `*`, `/` and `^`, unary minus and the functions
+/// `sqrt`, `sin` and `cos` are available.
+#[derive(Debug)]
+pub struct IntervalContext {
+    variables: HashMap<String, Interval>,
+    context: Context,
+}
+
+impl IntervalContext {
+    pub fn new() -> Self {
+        Self {
+            variables: HashMap::new(),
+            context: Context::default(),
+        }
+    }
+
+    pub fn set_variable(&mut self, name: &str, value: Interval) {
+        self.variables.insert(String::from(name), value);
+    }
+
+    pub fn get_variable(&self, name: &str) -> Option<&Interval> {
+        self.variables.get(name)
+    }
+}
+
+impl Default for IntervalContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Evaluates an expression with interval arithmetic. The result is guaranteed
+/// to contain the value of the expression for any choice of variable values
+/// within their intervals.
+pub fn evaluate_interval(
+    expression: &str,
+    context: &IntervalContext,
+) -> Result<Interval, EvalError> {
+    let tokens = collect_tokens(expression, &context.context)?;
+    let queue = shunting_yard(tokens, &context.context)?;
+    let mut stack: Vec<Interval> = Vec::new();
+
+    for token in &queue {
+        match token {
+            Token::Number(value, _) => stack.push(Interval::point(*value)),
+            Token::Identifier(name) => match context.get_variable(name) {
+                Some(&value) => stack.push(value),
+                None => return Err(EvalError::UnknownVariable(name.clone())),
+            },
+            Token::Symbol(symbol) => {
+                let right = pop_operand(&mut stack, symbol)?;
+                let left = pop_operand(&mut stack, symbol)?;
+                let result = match symbol.as_str() {
+                    "+" => left + right,
+                    "-" => left - right,
+                    "*" => left * right,
+                    "/" => left / right,
+                    "^" => left.pow(right),
+                    _ => return Err(EvalError::UnknownOperator(symbol.clone())),
+                };
+                stack.push(result);
+            }
+            Token::Call(name, args) => {
+                let function: fn(Interval) -> Interval = match name.as_str() {
+                    "sqrt" => Interval::sqrt,
+                    "sin" => Interval::sin,
+                    "cos" => Interval::cos,
+                    _ => return Err(EvalError::UnknownFunction(name.clone())),
+                };
+                if *args != 1 || stack.is_empty() {
+                    return Err(EvalError::WrongNumberOfArguments {
+                        name: name.clone(),
+                        expected: vec![crate::Arity::Exact(1)],
+                        found: (*args).min(stack.len()),
+                    });
+                }
+                let argument = pop_operand(&mut stack, name)?;
+                stack.push(function(argument));
+            }
+            Token::Assign(name) => return Err(EvalError::AssignmentNotAllowed(name.clone())),
+            Token::Prefix(symbol) if symbol == "-" => {
+                let value = pop_operand(&mut stack, symbol)?;
+                stack.push(-value);
+            }
+            Token::Prefix(symbol) if symbol == "+" => {
+                let value = pop_operand(&mut stack, symbol)?;
+                stack.push(value);
+            }
+            Token::Prefix(symbol) | Token::Postfix(symbol) => {
+                return Err(EvalError::UnknownOperator(symbol.clone()))
+            }
+            Token::Branch(..) => return Err(EvalError::UnknownFunction(String::from("if"))),
+            Token::Reduce(name, ..) => return Err(EvalError::UnknownFunction(name.clone())),
+            Token::Whitespace(_) => (),
+            Token::LeftParenthesis | Token::RightParenthesis => {
+                return Err(EvalError::MismatchedParentheses)
+            }
+            Token::Separator => return Err(EvalError::UnexpectedToken(String::from(","))),
+        }
+    }
+
+    match stack.len() {
+        0 => Err(EvalError::EmptyExpression),
+        1 => Ok(stack[0]),
+        n => Err(EvalError::TooManyOperands(n - 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{evaluate_interval, EvalError, Interval, IntervalContext};
+    use core::f64::consts::{FRAC_PI_2, PI, TAU};
+
+    fn eval(expression: &str, variables: &[(&str, f64, f64)]) -> Interval {
+        let mut context = IntervalContext::new();
+        for &(name, lo, hi) in variables {
+            context.set_variable(name, Interval::new(lo, hi));
+        }
+        evaluate_interval(expression, &context).unwrap()
+    }
+
+    fn assert_encloses(interval: Interval, lo: f64, hi: f64) {
+        assert!(interval.lo <= lo && interval.hi >= hi, "{:?}", interval);
+        assert!(
+            interval.lo > lo - 1e-9 && interval.hi < hi + 1e-9,
+            "{:?}",
+            interval
+        );
+    }
+
+    #[test]
+    fn arithmetic() {
+        let vars = [("x", 1.0, 2.0), ("y", -3.0, 4.0)];
+
+        assert_encloses(eval("x + y", &vars), -2.0, 6.0);
+        assert_encloses(eval("x - y", &vars), -3.0, 5.0);
+        assert_encloses(eval("-+-x", &vars), 1.0, 2.0);
+        assert_encloses(eval("x * y", &vars), -6.0, 8.0);
+        assert_encloses(eval("y / x", &vars), -3.0, 4.0);
+        assert_eq!(eval("x / y", &vars), Interval::entire());
+    }
+
+    #[test]
+    fn power() {
+        assert_encloses(eval("x ^ 2", &[("x", -3.0, 2.0)]), 0.0, 9.0);
+        assert_encloses(eval("x ^ 3", &[("x", -3.0, 2.0)]), -27.0, 8.0);
+        assert_encloses(eval("x ^ -1", &[("x", 2.0, 4.0)]), 0.25, 0.5);
+        assert_encloses(eval("-x ^ 2", &[("x", -3.0, 2.0)]), -9.0, 0.0);
+        assert_encloses(eval("2 ^ x", &[("x", -1.0, 3.0)]), 0.5, 8.0);
+    }
+
+    #[test]
+    fn functions() {
+        assert_encloses(eval("sqrt(x)", &[("x", -1.0, 4.0)]), 0.0, 2.0);
+        assert_eq!(eval("sin(x)", &[("x", 0.0, TAU)]), Interval::new(-1.0, 1.0));
+        assert_encloses(eval("sin(x)", &[("x", 0.0, FRAC_PI_2)]), 0.0, 1.0);
+        assert_encloses(eval("cos(x)", &[("x", 0.0, PI)]), -1.0, 1.0);
+        assert_encloses(eval("cos(x)", &[("x", 0.5, 1.0)]), 1f64.cos(), 0.5f64.cos());
+    }
+
+    #[test]
+    fn errors() {
+        let context = IntervalContext::new();
+
+        assert_eq!(
+            evaluate_interval("x + 1", &context),
+            Err(EvalError::UnknownVariable(String::from("x")))
+        );
+        assert_eq!(
+            evaluate_interval("exp(1)", &context),
+            Err(EvalError::UnknownFunction(String::from("exp")))
+        );
+    }
+}
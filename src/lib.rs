@@ -1,248 +1,4764 @@
+use bytecode::{OpCode, UnaryOp};
+use cache::ExpressionCache;
 use itertools::Itertools;
-use std::collections::HashMap;
+use macros::{expand_macros, Macro};
+use std::any::Any;
+use std::borrow::Borrow;
+use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::BufRead;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use value::{apply_binary, apply_postfix, apply_prefix};
 
+mod bytecode;
+mod cache;
+mod derivative;
+mod error;
+mod evaluator;
+mod expr;
+mod expression;
+mod float32;
+mod format;
+mod function;
+mod infix;
+mod integral;
+mod interval;
+mod linter;
+mod macros;
+mod number_theory;
 mod parsing;
-pub use parsing::{tokenize, Token};
+mod solve;
+mod statistics;
+mod table;
+mod value;
+pub use cache::CacheStats;
+pub use error::{
+    DiffError, EvalError, FunctionValidationError, LexError, OperatorError, SolveError,
+};
+pub use evaluator::Evaluator;
+pub use expr::{
+    evaluate_expression_list, evaluate_symbolic, parse, parse_expression_list, parse_pratt, Expr,
+    ExprBuilder,
+};
+pub use expression::Expression;
+pub use float32::{evaluate_f32, Context32, Context64};
+pub use format::{format_result, format_value, Notation, NumberFormatOptions};
+pub use function::{Arity, Function};
+pub use infix::postfix_to_infix;
+pub use integral::{integrate, integrate_with_tolerance, Integral};
+pub use interval::{evaluate_interval, Interval, IntervalContext};
+pub use linter::{lint, Lint, LintKind};
+pub use macros::preprocess;
+#[cfg(feature = "bigint")]
+pub use num_bigint::{BigInt, ParseBigIntError};
+pub use parsing::{
+    lex, tokenize, tokenize_collect_errors, tokenize_with_locale, NumberLocale, Token, Tokens,
+};
+pub use solve::{solve, solve_with, SolveOptions};
+pub use table::{table, table_or_nan};
+#[cfg(feature = "bigint")]
+pub use value::BIGINT_MAX_BITS;
+pub use value::{IntegerMode, Number, Value};
 
-#[derive(Debug, PartialEq)]
+/// The unit of angles passed to and returned from trigonometric functions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrigMode {
+    Radians,
+    Degrees,
+    /// 400 gradians make a full turn.
+    Gradians,
+}
+
+/// How far apart two values may be for `==` to consider them equal, see
+/// `Context::set_equality_tolerance`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    /// The largest difference relative to the larger magnitude of the two
+    /// values, e.g. `1e-9`.
+    pub relative: f64,
+    /// The largest difference regardless of the values, which matters for
+    /// values close to zero, where a relative tolerance is tiny.
+    pub absolute: f64,
+}
+
+impl Tolerance {
+    /// Whether `a` and `b` are equal or differ by at most the relative or the
+    /// absolute tolerance. NaN is never close to anything, and an infinity is
+    /// only close to itself.
+    pub fn is_close(&self, a: f64, b: f64) -> bool {
+        if !a.is_finite() || !b.is_finite() {
+            return a == b;
+        }
+        a == b || (a - b).abs() <= self.absolute.max(self.relative * a.abs().max(b.abs()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Associativity {
     Left,
     Right,
+    /// The operator can't be chained with itself or other non-associative
+    /// operators of the same precedence, e.g. `a < b < c` is an error.
+    None,
 }
 
-#[derive(Debug)]
-pub struct Operator {
+/// Where an operator is written relative to its operands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperatorKind {
+    /// Between two operands, like `+` in `1 + 2`.
+    Infix,
+    /// In front of its single operand, like `-` in `-x`.
+    Prefix,
+    /// After its single operand, like `!` in `5!`.
+    Postfix,
+}
+
+#[derive(Debug, Clone)]
+pub struct Operator<N = f64> {
     symbol: String,
     precedence: i32,
     associativity: Associativity,
+    kind: OperatorKind,
+    /// The postfix queue of an operator defined by an expression, in which
+    /// the operands are the variables `$0` and `$1`.
+    body: Option<VecDeque<Token>>,
+    is_commutative: bool,
+    /// Operators hold no numbers, but they are applied to values of the
+    /// number type of their context.
+    number: PhantomData<fn() -> N>,
 }
 
-impl Operator {
+impl<N: Number> Operator<N> {
     pub fn new(symbol: &str, precedence: i32, associativity: Associativity) -> Self {
         Self {
             symbol: String::from(symbol),
             precedence,
             associativity,
+            kind: OperatorKind::Infix,
+            body: None,
+            is_commutative: false,
+            number: PhantomData,
+        }
+    }
+
+    fn unary(symbol: &str, precedence: i32, kind: OperatorKind) -> Self {
+        // Prefix operators apply right to left, postfix operators left to
+        // right.
+        let associativity = match kind {
+            OperatorKind::Postfix => Associativity::Left,
+            _ => Associativity::Right,
+        };
+        Self {
+            kind,
+            ..Self::new(symbol, precedence, associativity)
+        }
+    }
+
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    pub fn precedence(&self) -> i32 {
+        self.precedence
+    }
+
+    pub fn associativity(&self) -> Associativity {
+        self.associativity
+    }
+
+    pub fn kind(&self) -> OperatorKind {
+        self.kind
+    }
+
+    /// Whether the operands may be swapped without changing the result, as
+    /// for `+` and `*`. Operators aren't commutative unless marked with
+    /// `Context::set_operator_commutative`.
+    pub fn is_commutative(&self) -> bool {
+        self.is_commutative
+    }
+
+    pub fn apply(&self, left: N, right: N) -> Result<N, EvalError> {
+        match OpCode::from_symbol(&self.symbol) {
+            Some(opcode) => opcode
+                .apply(left.into_f64(), right.into_f64())
+                .map(N::from_f64),
+            None => Err(EvalError::UnknownOperator(self.symbol.clone())),
+        }
+    }
+
+    /// Applies the operator in a context, evaluating its body if it was
+    /// defined by an expression.
+    pub(crate) fn evaluate(
+        &self,
+        left: Value<N>,
+        right: Value<N>,
+        context: &Context<N>,
+    ) -> Result<Value<N>, EvalError> {
+        match &self.body {
+            Some(body) => evaluate_operator_body(body, vec![left, right], context),
+            None => apply_binary(&self.symbol, left, right, context),
+        }
+    }
+
+    /// Like `evaluate`, for prefix and postfix operators.
+    pub(crate) fn evaluate_unary(
+        &self,
+        value: Value<N>,
+        context: &Context<N>,
+    ) -> Result<Value<N>, EvalError> {
+        match (&self.body, self.kind) {
+            (Some(body), _) => evaluate_operator_body(body, vec![value], context),
+            (None, OperatorKind::Postfix) => apply_postfix(&self.symbol, value, context),
+            (None, _) => apply_prefix(&self.symbol, value, context),
+        }
+    }
+
+    /// Applies the operator as a prefix operator to a single value.
+    pub fn apply_prefix(&self, value: N) -> Result<N, EvalError> {
+        match UnaryOp::prefix(&self.symbol) {
+            Some(op) => op.apply(value.into_f64()).map(N::from_f64),
+            None => Err(EvalError::UnknownOperator(self.symbol.clone())),
+        }
+    }
+
+    /// Applies the operator as a postfix operator to a single value.
+    pub fn apply_postfix(&self, value: N) -> Result<N, EvalError> {
+        match UnaryOp::postfix(&self.symbol) {
+            Some(op) => op.apply(value.into_f64()).map(N::from_f64),
+            None => Err(EvalError::UnknownOperator(self.symbol.clone())),
+        }
+    }
+}
+
+/// Evaluates the body of an operator defined by an expression, in which the
+/// operands are the variables `$0` and `$1`.
+fn evaluate_operator_body<N: Number>(
+    body: &VecDeque<Token>,
+    operands: Vec<Value<N>>,
+    context: &Context<N>,
+) -> Result<Value<N>, EvalError> {
+    let operands: HashMap<String, Value<N>> = operands
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| (format!("${}", index), value))
+        .collect();
+    context.enter_call()?;
+    let result = evaluate_scoped(body, context, &operands, None);
+    context.leave_call();
+    result
+}
+
+/// The factorial of a non-negative integer. Results beyond `170!` are
+/// infinite.
+fn factorial(operator: &str, value: f64) -> Result<f64, EvalError> {
+    if value.fract() != 0.0 || value < 0.0 {
+        return Err(EvalError::InvalidOperand {
+            operator: String::from(operator),
+            value,
+        });
+    }
+    Ok((2..=value.min(171.0) as u32).map(f64::from).product())
+}
+
+/// Raises `base` to the power of `exponent`. If both are integers and the
+/// exponent isn't negative, the power is computed exactly with integers, as
+/// long as it fits into an `i128`, and only rounded once at the end. `powf`
+/// doesn't guarantee that, e.g. it gets `10 ^ 23` wrong.
+///
+/// A negative base with a fractional exponent has a real result if the
+/// exponent is a fraction with an odd denominator, like the cube root
+/// `(-8) ^ (1/3)`, which is -2. Other fractional exponents, like in
+/// `(-4) ^ 0.5`, give NaN.
+fn power<N: Number>(base: N, exponent: N) -> N {
+    let is_integer = |value: N| value.fract().is_zero();
+    if is_integer(base)
+        && is_integer(exponent)
+        && base.into_f64().abs() <= i128::MAX as f64 / 2.0
+        && (0.0..=f64::from(u32::MAX)).contains(&exponent.into_f64())
+    {
+        if let Some(result) = (base.into_f64() as i128).checked_pow(exponent.into_f64() as u32) {
+            return num_traits::cast(result).unwrap_or_else(N::infinity);
+        }
+    }
+    if base < N::zero() && exponent.is_finite() && !is_integer(exponent) {
+        if let Some(numerator) = odd_root_numerator(exponent.into_f64()) {
+            let magnitude = (-base).powf(exponent);
+            return if numerator % 2.0 == 0.0 {
+                magnitude
+            } else {
+                -magnitude
+            };
+        }
+    }
+    base.powf(exponent)
+}
+
+/// The largest denominator that `odd_root_numerator` looks for.
+const MAX_ROOT_DENOMINATOR: i32 = 99;
+
+/// If `exponent` is close to a fraction `p/q` in lowest terms with an odd `q`
+/// of at most `MAX_ROOT_DENOMINATOR`, returns `p`. Exponents like `1/3` can't
+/// be represented exactly, so a small relative error is allowed.
+fn odd_root_numerator(exponent: f64) -> Option<f64> {
+    (3..=MAX_ROOT_DENOMINATOR)
+        .step_by(2)
+        .find_map(|denominator| {
+            let numerator = exponent * f64::from(denominator);
+            let rounded = numerator.round();
+            ((numerator - rounded).abs() <= 1e-9 * rounded.abs().max(1.0)).then_some(rounded)
+        })
+}
+
+/// The value of a comparison, 1 for true and 0 for false.
+fn truth(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// The largest magnitude of an integer for bitwise operations. Every integer
+/// up to 2^53 can be represented exactly as an `f64`.
+const INTEGER_LIMIT: i64 = 1 << 53;
+
+/// Converts a value to an integer for bitwise operations, failing if it has a
+/// fractional part or is larger than 2^53 in magnitude.
+fn to_integer(operator: &str, value: f64) -> Result<i64, EvalError> {
+    let limit = INTEGER_LIMIT as f64;
+    if value.fract() != 0.0 || !(-limit..=limit).contains(&value) {
+        return Err(EvalError::InvalidOperand {
+            operator: String::from(operator),
+            value,
+        });
+    }
+    Ok(value as i64)
+}
+
+/// Converts the result of a bitwise operation back, failing if it is larger
+/// than 2^53 in magnitude and would lose bits.
+fn from_integer(operator: &str, value: i64) -> Result<f64, EvalError> {
+    if !(-INTEGER_LIMIT..=INTEGER_LIMIT).contains(&value) {
+        return Err(EvalError::ResultOutOfRange {
+            operator: String::from(operator),
+            value: value as f64,
+        });
+    }
+    Ok(value as f64)
+}
+
+/// Converts the right operand of a shift, which has to be an integer from 0
+/// to 63.
+fn shift_amount(operator: &str, value: f64) -> Result<u32, EvalError> {
+    if value.fract() != 0.0 || !(0.0..64.0).contains(&value) {
+        return Err(EvalError::InvalidOperand {
+            operator: String::from(operator),
+            value,
+        });
+    }
+    Ok(value as u32)
+}
+
+/// Identifies a call of a user defined function by the address of the
+/// context, the function name and the arguments, written with `Debug`, which
+/// keeps their type and every bit of floats.
+type CallKey = (usize, String, Vec<String>);
+
+thread_local! {
+    /// How deeply user defined functions are nested in the evaluation running
+    /// on this thread. It is kept out of `Context` so that a context can be
+    /// shared between threads that evaluate independently of each other.
+    static CALL_DEPTH: Cell<usize> = const { Cell::new(0) };
+
+    /// Results of user defined function calls. Like the call depth, they are
+    /// kept per thread. They are cleared whenever the outermost call returns.
+    /// The values are of the number type of the context, which is part of
+    /// the key.
+    static CALL_CACHE: RefCell<HashMap<CallKey, Box<dyn Any>>> =
+        RefCell::new(HashMap::new());
+
+    /// How many evaluations are running on this thread, counting nested ones
+    /// like the bodies of user defined functions and branches.
+    static EVALUATION_DEPTH: Cell<usize> = const { Cell::new(0) };
+
+    /// How many values the `series` and `prod` of the outermost evaluation on
+    /// this thread have iterated over, see `charge_iterations`.
+    static ITERATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Marks an evaluation as running on this thread. When the outermost one
+/// ends, its iterations are forgotten.
+pub(crate) struct EvaluationGuard;
+
+impl EvaluationGuard {
+    pub(crate) fn enter() -> Self {
+        EVALUATION_DEPTH.with(|depth| depth.set(depth.get() + 1));
+        Self
+    }
+}
+
+impl Drop for EvaluationGuard {
+    fn drop(&mut self) {
+        let depth = EVALUATION_DEPTH.with(|depth| {
+            depth.set(depth.get() - 1);
+            depth.get()
+        });
+        if depth == 0 {
+            ITERATIONS.with(|iterations| iterations.set(0));
         }
     }
 }
 
+/// Counts `count` more iterations of a `series` or `prod` against the budget
+/// of `Context::max_iterations`, which all of them in an evaluation share, so
+/// that nesting them can't multiply it.
+pub(crate) fn charge_iterations<N: Number>(
+    count: f64,
+    context: &Context<N>,
+) -> Result<(), EvalError> {
+    let iterations = ITERATIONS.with(Cell::get) as f64 + count;
+    if iterations > context.max_iterations as f64 {
+        return Err(EvalError::IterationLimitExceeded);
+    }
+    ITERATIONS.with(|cell| cell.set(iterations as usize));
+    Ok(())
+}
+
+/// A variable whose value is computed when it is first read.
+struct LazyVariable<N> {
+    compute: Box<dyn Fn() -> N + Send + Sync>,
+    value: OnceLock<Value<N>>,
+}
+
+impl<N: fmt::Debug> fmt::Debug for LazyVariable<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LazyVariable")
+            .field("value", &self.value.get())
+            .finish()
+    }
+}
+
+/// The operators, functions and variables that expressions are evaluated
+/// with. Floats are of the type `N`, see `Context64` and `Context32`.
 #[derive(Debug)]
-pub struct Context {
-    variables: HashMap<String, f64>,
-    operators: HashMap<String, Operator>,
+pub struct Context<N = f64> {
+    variables: HashMap<String, Value<N>>,
+    lazy_variables: HashMap<String, LazyVariable<N>>,
+    operators: HashMap<String, Operator<N>>,
+    /// Alternative symbols for operators, mapping each alias to its target.
+    operator_aliases: HashMap<String, String>,
+    prefix_operators: HashMap<String, Operator<N>>,
+    postfix_operators: HashMap<String, Operator<N>>,
+    functions: HashMap<String, Vec<Function<N>>>,
+    max_depth: usize,
+    max_iterations: usize,
+    arithmetic_only: bool,
+    number_locale: NumberLocale,
+    trig_mode: TrigMode,
+    equality_tolerance: Option<Tolerance>,
+    integer_mode: IntegerMode,
+    strict_truth_values: bool,
+    paren_free_calls: bool,
+    percent_of: bool,
+    implicit_multiplication: bool,
+    implicit_mul_binds_tighter: bool,
+    auto_close_parens: bool,
+    memoize_user_functions: bool,
+    macros: HashMap<String, Macro>,
+    expression_cache: Option<Mutex<ExpressionCache<N>>>,
 }
 
-impl Context {
+impl<N: Number> Context<N> {
     pub fn new() -> Self {
         Self {
             variables: HashMap::new(),
+            lazy_variables: HashMap::new(),
             operators: HashMap::new(),
+            operator_aliases: HashMap::new(),
+            prefix_operators: HashMap::new(),
+            postfix_operators: HashMap::new(),
+            functions: HashMap::new(),
+            max_depth: 256,
+            max_iterations: 1_000_000,
+            arithmetic_only: false,
+            number_locale: NumberLocale::default(),
+            trig_mode: TrigMode::Radians,
+            equality_tolerance: None,
+            integer_mode: IntegerMode::default(),
+            strict_truth_values: false,
+            paren_free_calls: false,
+            percent_of: false,
+            implicit_multiplication: false,
+            implicit_mul_binds_tighter: false,
+            auto_close_parens: false,
+            memoize_user_functions: false,
+            macros: HashMap::new(),
+            expression_cache: None,
         }
     }
 
-    pub fn set_variable(&mut self, name: &str, value: f64) {
-        self.variables.insert(String::from(name), value);
+    /// Creates a context for untrusted input that only allows number literals
+    /// and the four basic arithmetic operators, including unary minus. Any
+    /// identifier, be it a variable or a function, is rejected with
+    /// `EvalError::FeatureDisabled`.
+    pub fn arithmetic_only() -> Self {
+        let mut context = Self::new();
+        context.insert_operator("+", 10, Associativity::Left);
+        context.insert_operator("-", 10, Associativity::Left);
+        context.insert_operator("*", 11, Associativity::Left);
+        context.insert_operator("/", 11, Associativity::Left);
+        context.insert_prefix_operator("-", 11);
+        context.arithmetic_only = true;
+        context
     }
 
-    pub fn get_variable(&self, name: &str) -> Option<&f64> {
-        self.variables.get(name)
+    pub fn is_arithmetic_only(&self) -> bool {
+        self.arithmetic_only
     }
 
-    pub fn add_operator(&mut self, symbol: &str, precedence: i32, associativity: Associativity) {
-        let operator = Operator::new(symbol, precedence, associativity);
-        self.operators.insert(String::from(symbol), operator);
+    /// Sets how number literals and argument separators are read. See
+    /// `NumberLocale` for how ambiguous input is resolved.
+    pub fn set_number_locale(&mut self, locale: NumberLocale) {
+        self.invalidate_expression_cache();
+        self.number_locale = locale;
     }
 
-    pub fn get_operator(&self, symbol: &str) -> Option<&Operator> {
-        self.operators.get(symbol)
+    pub fn number_locale(&self) -> NumberLocale {
+        self.number_locale
     }
-}
 
-impl Default for Context {
-    fn default() -> Self {
-        let mut context = Self::new();
-        context.add_operator("+", 2, Associativity::Left);
-        context.add_operator("-", 2, Associativity::Left);
-        context.add_operator("*", 3, Associativity::Left);
-        context.add_operator("/", 3, Associativity::Left);
-        context.add_operator("^", 4, Associativity::Right);
-        context
+    /// Adds a macro that is expanded in expressions before they are
+    /// tokenized, see `preprocess`. The pattern is a name, optionally followed
+    /// by parameter names in parentheses, like `sq(x)`. A macro with the same
+    /// name replaces an existing one.
+    pub fn add_macro(&mut self, pattern: &str, replacement: &str) -> Result<(), EvalError> {
+        let (name, definition) = Macro::new(pattern, replacement)?;
+        self.invalidate_expression_cache();
+        self.macros.insert(name, definition);
+        Ok(())
     }
-}
 
-fn move_operators(
-    operator: &Operator,
-    stack: &mut Vec<Token>,
-    queue: &mut VecDeque<Token>,
-    context: &Context,
-) {
-    while let Some(Token::Symbol(other_symbol)) = stack.last() {
-        if let Some(other_operator) = context.get_operator(other_symbol) {
-            if other_operator.precedence > operator.precedence
-                || (other_operator.precedence == operator.precedence
-                    && operator.associativity == Associativity::Left)
-            {
-                if let Some(t) = stack.pop() {
-                    queue.push_back(t);
-                }
-            } else {
-                break;
-            }
+    pub(crate) fn get_macro(&self, name: &str) -> Option<&Macro> {
+        self.macros.get(name)
+    }
+
+    /// Allows calling functions that take one argument without parentheses,
+    /// like `sin 30` or `sqrt 16 + 1`. The function applies to the next
+    /// operand only, e.g. a number, variable, parenthesized expression or
+    /// call, so `sqrt 16 + 1` is 5. Disabled by default, since `f x` could
+    /// just as well be meant as a multiplication.
+    pub fn set_paren_free_calls(&mut self, enabled: bool) {
+        self.invalidate_expression_cache();
+        self.paren_free_calls = enabled;
+    }
+
+    pub fn paren_free_calls(&self) -> bool {
+        self.paren_free_calls
+    }
+
+    /// Makes a percentage that is the right operand of `+` or `-` a
+    /// percentage of the left operand, as on a pocket calculator: `200 + 10%`
+    /// is 220 and `200 - 10%` is 180. Disabled by default, in which case they
+    /// are 200.1 and 199.9. Percentages need a postfix `%` operator, see
+    /// `add_postfix_operator`.
+    ///
+    /// Only the whole right operand counts. `200 + 10% * 2` is 200.2 and
+    /// `100 * 10%` is 10, since the percentage is an operand of `*`, while
+    /// `200 + (10%)` is 220 again. The left operand is evaluated twice.
+    pub fn set_percent_of(&mut self, enabled: bool) {
+        self.invalidate_expression_cache();
+        self.percent_of = enabled;
+    }
+
+    pub fn percent_of(&self) -> bool {
+        self.percent_of
+    }
+
+    /// Reads an operand directly following another one as a multiplication,
+    /// so `2x`, `2(x + 1)`, `(a)(b)` and `x(y)` for a variable `x` multiply.
+    /// Disabled by default.
+    pub fn set_implicit_multiplication(&mut self, enabled: bool) {
+        self.invalidate_expression_cache();
+        self.implicit_multiplication = enabled;
+    }
+
+    pub fn implicit_multiplication(&self) -> bool {
+        self.implicit_multiplication
+    }
+
+    /// Makes implicit multiplication bind tighter than explicit `*` and `/`,
+    /// so `1/2x` is `1/(2x)` rather than `(1/2)x`. Operators with a higher
+    /// precedence than `*` still bind tighter, so `2x^2` is `2(x^2)` either
+    /// way. Disabled by default.
+    pub fn set_implicit_mul_binds_tighter(&mut self, enabled: bool) {
+        self.invalidate_expression_cache();
+        self.implicit_mul_binds_tighter = enabled;
+    }
+
+    pub fn implicit_mul_binds_tighter(&self) -> bool {
+        self.implicit_mul_binds_tighter
+    }
+
+    /// The operator that is inserted for implicit multiplication. It has the
+    /// precedence of `*`, but is right associative if it binds tighter, so
+    /// it doesn't take the operand of a `*` or `/` before it.
+    pub(crate) fn implicit_operator(&self) -> Result<Operator<N>, EvalError> {
+        let operator = self
+            .get_operator("*")
+            .ok_or_else(|| EvalError::UnknownOperator(String::from("*")))?;
+        let associativity = if self.implicit_mul_binds_tighter {
+            Associativity::Right
         } else {
-            break;
+            operator.associativity
+        };
+        Ok(Operator::new("*", operator.precedence, associativity))
+    }
+
+    /// Whether `name`, followed by a parenthesis, is a variable multiplied
+    /// with the parenthesized expression rather than a call.
+    pub(crate) fn is_implicit_product(&self, name: &str) -> bool {
+        self.implicit_multiplication && is_variable_not_function(name, self)
+    }
+
+    /// Treats parentheses that are still open at the end of an expression as
+    /// closed, so `(2 + 3) * (4 - 1` and `max(1, 2` are accepted. Disabled by
+    /// default, in which case these fail with
+    /// `EvalError::UnclosedParenthesis`. Closing parentheses without a
+    /// partner are always an error.
+    pub fn set_auto_close_parens(&mut self, enabled: bool) {
+        self.invalidate_expression_cache();
+        self.auto_close_parens = enabled;
+    }
+
+    pub fn auto_close_parens(&self) -> bool {
+        self.auto_close_parens
+    }
+
+    /// Whether `name` is a function that can be called without parentheses.
+    pub(crate) fn is_paren_free_call(&self, name: &str) -> bool {
+        self.paren_free_calls && self.get_function(name, 1).is_some()
+    }
+
+    /// Sets the unit of angles for functions that take or return them, like
+    /// `sin` and `asin`. The default is radians.
+    pub fn set_trig_mode(&mut self, mode: TrigMode) {
+        self.trig_mode = mode;
+    }
+
+    pub fn trig_mode(&self) -> TrigMode {
+        self.trig_mode
+    }
+
+    /// Makes `==` and `!=` treat values as equal if they are within a
+    /// tolerance, so `0.1 + 0.2 == 0.3` is true. `None`, the default,
+    /// compares exactly.
+    pub fn set_equality_tolerance(&mut self, tolerance: Option<Tolerance>) {
+        self.equality_tolerance = tolerance;
+    }
+
+    pub fn equality_tolerance(&self) -> Option<Tolerance> {
+        self.equality_tolerance
+    }
+
+    /// Sets what happens if an operation on two integers has no exact
+    /// integer result, see `IntegerMode`.
+    pub fn set_integer_mode(&mut self, mode: IntegerMode) {
+        self.integer_mode = mode;
+    }
+
+    pub fn integer_mode(&self) -> IntegerMode {
+        self.integer_mode
+    }
+
+    /// Only allows truth values with `&&`, `||`, `!`, `==`, `!=` and as the
+    /// condition of `if`, which in turn require one, so `(1 < 2) + 1` and
+    /// `if(1, 2, 3)` fail with `EvalError::TypeMismatch`. Disabled by
+    /// default, in which case truth values are the numbers 1 and 0 and
+    /// numbers other than 0 are true.
+    pub fn set_strict_truth_values(&mut self, enabled: bool) {
+        self.strict_truth_values = enabled;
+    }
+
+    pub fn strict_truth_values(&self) -> bool {
+        self.strict_truth_values
+    }
+
+    /// Like `set_trig_mode`, for building a context in one expression, e.g.
+    /// `Context::default_with_math_functions().with_trig_mode(TrigMode::Degrees)`.
+    pub fn with_trig_mode(mut self, mode: TrigMode) -> Self {
+        self.set_trig_mode(mode);
+        self
+    }
+
+    /// Converts an angle in the context's trig mode to radians.
+    pub fn angle_to_radians(&self, angle: N) -> N {
+        match self.trig_mode {
+            TrigMode::Radians => angle,
+            TrigMode::Degrees => angle.to_radians(),
+            TrigMode::Gradians => angle * N::from_f64(std::f64::consts::PI / 200.0),
         }
     }
-}
 
-pub fn shunting_yard(tokens: Vec<Token>, context: &Context) -> VecDeque<Token> {
-    let mut queue: VecDeque<Token> = VecDeque::new();
-    let mut stack: Vec<Token> = Vec::new();
+    /// Converts an angle in radians to the context's trig mode.
+    pub fn radians_to_angle(&self, radians: N) -> N {
+        match self.trig_mode {
+            TrigMode::Radians => radians,
+            TrigMode::Degrees => radians.to_degrees(),
+            TrigMode::Gradians => radians * N::from_f64(200.0 / std::f64::consts::PI),
+        }
+    }
 
-    for token in tokens {
-        match token {
-            Token::Number(_) => queue.push_back(token),
-            Token::Identifier(_) => queue.push_back(token),
-            Token::Symbol(ref symbol) => {
-                if let Some(operator) = context.get_operator(&symbol) {
-                    move_operators(&operator, &mut stack, &mut queue, context);
-                    stack.push(token);
-                } else {
-                    panic!("Unknown operator {}", symbol);
-                }
-            }
-            Token::Whitespace(_) => (),
-            Token::LeftParenthesis => stack.push(token),
-            Token::RightParenthesis => {
-                while let Some(token) = stack.last() {
-                    if let Token::LeftParenthesis = token {
-                        stack.pop();
-                        break;
-                    } else if let Some(token) = stack.pop() {
-                        queue.push_back(token);
-                    } else {
-                        panic!("Mismatched parentheses.");
-                    }
-                }
+    /// Adds the bitwise operators `&`, `|`, `^^` (xor), `<<`, `>>` and the
+    /// prefix `~` (not), as well as the functions `xor` and `bitnot`. Their
+    /// precedences follow C: shifts bind tighter than comparisons, which bind
+    /// tighter than `&`, `^^` and `|`.
+    ///
+    /// Operands must be integers of at most 2^53 in magnitude, so that no
+    /// bits are lost, and are computed as `i64`. Shift amounts must be
+    /// in `0..64`.
+    ///
+    /// These are opt-in because `^` already means exponentiation.
+    pub fn default_with_bitwise_operators() -> Self {
+        let mut context = Self::default();
+        context.insert_operator("|", 4, Associativity::Left);
+        context.insert_operator("^^", 5, Associativity::Left);
+        context.insert_operator("&", 6, Associativity::Left);
+        context.insert_operator("<<", 9, Associativity::Left);
+        context.insert_operator(">>", 9, Associativity::Left);
+        context.insert_prefix_operator("~", 13);
+        context.add_function(Function::new("xor", 2, |args: &[N], _| {
+            from_integer(
+                "xor",
+                to_integer("xor", args[0].into_f64())? ^ to_integer("xor", args[1].into_f64())?,
+            )
+            .map(N::from_f64)
+        }));
+        context.add_function(Function::new("bitnot", 1, |args: &[N], _| {
+            from_integer("bitnot", !to_integer("bitnot", args[0].into_f64())?).map(N::from_f64)
+        }));
+        context
+    }
+
+    pub fn default_with_math_functions() -> Self {
+        let mut context = Self::default();
+        // The factorial of non-negative integers, binding tighter than `^`.
+        context.insert_postfix_operator("!", 13);
+        context.add_function(Function::new("sqrt", 1, |args: &[N], _| Ok(args[0].sqrt())));
+        context.add_function(Function::new("abs", 1, |args: &[N], _| Ok(args[0].abs())));
+        context.add_function(Function::new("pow", 2, |args: &[N], _| {
+            Ok(power(args[0], args[1]))
+        }));
+        context.add_function(Function::new("exp", 1, |args: &[N], _| Ok(args[0].exp())));
+        context.add_function(Function::new("ln", 1, |args: &[N], _| Ok(args[0].ln())));
+        // The remainder with the sign of the dividend, unlike `%`.
+        context.add_function(Function::new("rem", 2, |args: &[N], _| {
+            Ok(args[0] % args[1])
+        }));
+        context.add_function(Function::new("log", 1, |args: &[N], _| Ok(args[0].ln())));
+        context.add_function(Function::new("log", 2, |args: &[N], _| {
+            Ok(args[0].log(args[1]))
+        }));
+        context.add_function(Function::new("sin", 1, |args: &[N], context| {
+            Ok(context.angle_to_radians(args[0]).sin())
+        }));
+        context.add_function(Function::new("cos", 1, |args: &[N], context| {
+            Ok(context.angle_to_radians(args[0]).cos())
+        }));
+        context.add_function(Function::new("tan", 1, |args: &[N], context| {
+            Ok(context.angle_to_radians(args[0]).tan())
+        }));
+        context.add_function(Function::new("asin", 1, |args: &[N], context| {
+            Ok(context.radians_to_angle(args[0].asin()))
+        }));
+        context.add_function(Function::new("acos", 1, |args: &[N], context| {
+            Ok(context.radians_to_angle(args[0].acos()))
+        }));
+        context.add_function(Function::new("atan", 1, |args: &[N], context| {
+            Ok(context.radians_to_angle(args[0].atan()))
+        }));
+        context.add_function(Function::new("floor", 1, |args: &[N], _| {
+            Ok(args[0].floor())
+        }));
+        context.add_function(Function::new("ceil", 1, |args: &[N], _| Ok(args[0].ceil())));
+        context.add_function(Function::new("round", 1, |args: &[N], _| {
+            Ok(args[0].round())
+        }));
+        context.add_function(Function::new("round", 2, |args: &[N], _| {
+            let factor = N::from_f64(10.0).powf(args[1].trunc());
+            Ok((args[0] * factor).round() / factor)
+        }));
+        context.add_function(Function::variadic("min", 1, |args: &[N], _| {
+            Ok(args.iter().cloned().fold(N::infinity(), N::min))
+        }));
+        context.add_function(Function::variadic("max", 1, |args: &[N], _| {
+            Ok(args.iter().cloned().fold(N::neg_infinity(), N::max))
+        }));
+        context
+    }
+
+    /// Adds the functions `gcd`, `lcm` and `fact` (factorial). Their
+    /// arguments must be non-negative integers of at most 2^53.
+    pub fn default_with_number_theory_functions() -> Self {
+        let mut context = Self::default();
+        context.add_function(Function::new("gcd", 2, |args: &[N], _| {
+            number_theory::gcd(args[0].into_f64(), args[1].into_f64()).map(N::from_f64)
+        }));
+        context.add_function(Function::new("lcm", 2, |args: &[N], _| {
+            number_theory::lcm(args[0].into_f64(), args[1].into_f64()).map(N::from_f64)
+        }));
+        context.add_function(Function::new("fact", 1, |args: &[N], _| {
+            number_theory::fact(args[0].into_f64()).map(N::from_f64)
+        }));
+        context
+    }
+
+    /// Adds the variadic functions `sum`, `product`, `mean`, `median`,
+    /// `variance` and `stddev`. `variance` and `stddev` are those of a
+    /// sample, dividing by `n - 1`, so they need at least two arguments;
+    /// `mean` and `median` need at least one.
+    pub fn default_with_statistical_functions() -> Self {
+        let mut context = Self::default();
+        context.add_function(Function::variadic("sum", 0, |args: &[N], _| {
+            Ok(statistics::sum(args))
+        }));
+        context.add_function(Function::variadic("product", 0, |args: &[N], _| {
+            Ok(statistics::product(args))
+        }));
+        context.add_function(Function::variadic("mean", 1, |args: &[N], _| {
+            Ok(statistics::mean(args))
+        }));
+        context.add_function(Function::variadic("median", 1, |args: &[N], _| {
+            Ok(statistics::median(args))
+        }));
+        context.add_function(Function::variadic("variance", 2, |args: &[N], _| {
+            Ok(statistics::variance(args))
+        }));
+        context.add_function(Function::variadic("stddev", 2, |args: &[N], _| {
+            Ok(statistics::stddev(args))
+        }));
+        context
+    }
+
+    /// Makes `evaluate` keep up to `capacity` compiled expressions, keyed by
+    /// their text, so that evaluating the same text again skips parsing and
+    /// compiling. When the cache is full, the least recently used expression
+    /// is dropped. Changing operators, functions, macros or how expressions
+    /// are read empties the cache. Changing the value of a variable doesn't,
+    /// since values are only read during evaluation, but adding or removing a
+    /// variable does while implicit multiplication is enabled, because whether
+    /// `x(2)` is a call or a product depends on whether `x` is a variable.
+    pub fn enable_expression_cache(&mut self, capacity: usize) {
+        self.expression_cache = Some(Mutex::new(ExpressionCache::new(capacity)));
+    }
+
+    /// How often `evaluate` found an expression in the cache, see
+    /// `enable_expression_cache`. All zero if the cache isn't enabled.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.expression_cache
+            .as_ref()
+            .map_or_else(CacheStats::default, |cache| {
+                cache.lock().unwrap_or_else(PoisonError::into_inner).stats()
+            })
+    }
+
+    fn invalidate_expression_cache(&mut self) {
+        if let Some(cache) = &mut self.expression_cache {
+            cache
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+    }
+
+    /// Empties the expression cache if the parse of cached expressions may
+    /// depend on which variables exist, see `is_implicit_product`.
+    fn variables_added_or_removed(&mut self) {
+        if self.implicit_multiplication {
+            self.invalidate_expression_cache();
+        }
+    }
+
+    /// Evaluates an expression with a compiled form from the cache, compiling
+    /// and caching it first if needed. The cache isn't locked during
+    /// compilation or evaluation, which may evaluate other expressions.
+    fn evaluate_cached(
+        &self,
+        cache: &Mutex<ExpressionCache<N>>,
+        expression: &str,
+    ) -> Result<Value<N>, EvalError> {
+        let lock = || cache.lock().unwrap_or_else(PoisonError::into_inner);
+        let cached = lock().get(expression);
+        let compiled = match cached {
+            Some(compiled) => compiled,
+            None => {
+                let compiled = Arc::new(Expression::compile(expression, self)?);
+                lock().insert(expression, Arc::clone(&compiled));
+                compiled
             }
+        };
+        compiled.evaluate_value(self)
+    }
+
+    /// Sets a variable to a float, integer or truth value, like
+    /// `set_variable("x", 2.5)` or `set_variable("n", 3i64)`.
+    pub fn set_variable(&mut self, name: &str, value: impl Into<Value<N>>) {
+        if !self.has_variable(name) {
+            self.variables_added_or_removed();
         }
+        self.variables.insert(String::from(name), value.into());
     }
 
-    while let Some(token) = stack.pop() {
-        queue.push_back(token);
+    pub fn get_variable(&self, name: &str) -> Option<&Value<N>> {
+        self.variables.get(name).or_else(|| {
+            let lazy = self.lazy_variables.get(name)?;
+            Some(lazy.value.get_or_init(|| Value::Float((lazy.compute)())))
+        })
     }
 
-    queue
-}
+    /// Adds a variable whose value is computed by `compute` the first time it
+    /// is read, e.g. by an expression that uses it, and then kept. Expressions
+    /// that don't use the variable, or only in a branch that isn't taken,
+    /// never compute it. A variable of the same name set with `set_variable`
+    /// takes precedence. The value is a float.
+    pub fn set_lazy_variable<F>(&mut self, name: &str, compute: F)
+    where
+        F: Fn() -> N + Send + Sync + 'static,
+    {
+        if !self.has_variable(name) {
+            self.variables_added_or_removed();
+        }
+        self.variables.remove(name);
+        let variable = LazyVariable {
+            compute: Box::new(compute),
+            value: OnceLock::new(),
+        };
+        self.lazy_variables.insert(String::from(name), variable);
+    }
 
-pub fn evaluate_queue(queue: &VecDeque<Token>, context: &Context) -> f64 {
-    let mut stack: Vec<f64> = Vec::new();
+    /// Returns a variable unless it is lazy and hasn't been computed yet.
+    pub(crate) fn peek_variable(&self, name: &str) -> Option<&Value<N>> {
+        self.variables.get(name).or_else(|| {
+            let lazy = self.lazy_variables.get(name)?;
+            lazy.value.get()
+        })
+    }
 
-    for token in queue {
-        match token {
-            Token::Number(num) => stack.push(num.parse().unwrap()),
-            Token::Symbol(s) if s == "+" => {
-                let right = stack.pop().unwrap();
-                let left = stack.pop().unwrap();
-                stack.push(left + right);
-            }
-            Token::Symbol(s) if s == "-" => {
-                let right = stack.pop().unwrap();
-                let left = stack.pop().unwrap();
-                stack.push(left - right);
-            }
-            Token::Symbol(s) if s == "*" => {
-                let right = stack.pop().unwrap();
-                let left = stack.pop().unwrap();
-                stack.push(left * right);
-            }
-            Token::Symbol(s) if s == "/" => {
-                let right = stack.pop().unwrap();
-                let left = stack.pop().unwrap();
-                stack.push(left / right);
-            }
-            Token::Symbol(s) if s == "^" => {
-                let right = stack.pop().unwrap();
-                let left = stack.pop().unwrap();
-                stack.push(left.powf(right));
-            }
-            Token::Identifier(name) => {
-                let variable = context.get_variable(name);
-                if let Some(&value) = variable {
-                    stack.push(value);
-                } else {
-                    panic!("Variable {} does not exist", name);
+    /// The variables set with `set_variable`, in no particular order. Lazy
+    /// variables aren't included.
+    pub fn variables(&self) -> impl Iterator<Item = (&str, &Value<N>)> + '_ {
+        self.variables
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// Whether a variable exists, without computing it if it is lazy.
+    pub fn has_variable(&self, name: &str) -> bool {
+        self.variables.contains_key(name) || self.lazy_variables.contains_key(name)
+    }
+
+    /// Calls `f` with the context and afterwards restores the variables as
+    /// they were before, so variables that `f` sets or changes are only
+    /// visible within it. They are also restored if `f` panics. Everything
+    /// else that `f` changes, like functions, stays.
+    pub fn with_scope<T>(&mut self, f: impl FnOnce(&mut Context<N>) -> T) -> T {
+        let variables = self.variables.clone();
+        let guard = ScopeGuard {
+            context: self,
+            variables: Some(variables),
+        };
+        f(guard.context)
+    }
+
+    /// Adds an operator. Fails if the symbol can't be told apart from other
+    /// tokens, i.e. if it is empty or contains whitespace, digits, letters,
+    /// parentheses, `,` or `;`, or if the symbol is already taken. Use
+    /// `remove_operator` first to replace an operator.
+    pub fn add_operator(
+        &mut self,
+        symbol: &str,
+        precedence: i32,
+        associativity: Associativity,
+    ) -> Result<(), OperatorError> {
+        self.check_operator_symbol(symbol)?;
+        self.insert_operator(symbol, precedence, associativity);
+        Ok(())
+    }
+
+    /// Adds an operator that evaluates `body`, an expression in which `$0`
+    /// and `$1` are the left and right operand, e.g.
+    /// `max($0, $1) + min($0, $1)`. The symbol has to be valid, as for
+    /// `add_operator`. The body is parsed right away with the operators and
+    /// functions that exist at that point, but it may also use the new
+    /// operator itself.
+    pub fn add_operator_binary_from_string(
+        &mut self,
+        symbol: &str,
+        precedence: i32,
+        associativity: Associativity,
+        body: &str,
+    ) -> Result<(), EvalError> {
+        self.check_operator_symbol(symbol)
+            .map_err(EvalError::Operator)?;
+        self.insert_operator(symbol, precedence, associativity);
+
+        let body = collect_tokens(body, self).and_then(|tokens| {
+            let tokens = substitute_operands(tokens);
+            shunting_yard(tokens, self)
+        });
+        match body {
+            Ok(body) => {
+                if let Some(operator) = self.operators.get_mut(symbol) {
+                    operator.body = Some(body);
                 }
+                Ok(())
+            }
+            Err(error) => {
+                self.operators.remove(symbol);
+                Err(error)
             }
-            _ => (),
         }
     }
 
-    stack.pop().unwrap()
-}
-
-pub fn evaluate(expression: &str, context: &Context) -> f64 {
-    let tokens: Vec<Token> = tokenize(expression).try_collect().unwrap();
+    fn insert_operator(&mut self, symbol: &str, precedence: i32, associativity: Associativity) {
+        let operator = Operator::new(symbol, precedence, associativity);
+        self.invalidate_expression_cache();
+        self.operators.insert(String::from(symbol), operator);
+    }
 
-    let queue = shunting_yard(tokens, context);
-    evaluate_queue(&queue, context)
-}
+    fn check_operator_symbol(&self, symbol: &str) -> Result<(), OperatorError> {
+        check_symbol_characters(symbol)?;
+        if self.operators.contains_key(symbol) || self.operator_aliases.contains_key(symbol) {
+            return Err(OperatorError::AlreadyDefined(String::from(symbol)));
+        }
+        Ok(())
+    }
 
-#[cfg(test)]
-mod tests {
-    use crate::evaluate;
-    use crate::Context;
-    use core::f64::consts::PI;
+    /// Adds an operator that is written in front of its single operand, like
+    /// `~` in `~5`. Prefix operators may share their symbol with an infix
+    /// operator; which one is meant is decided by whether an operand is
+    /// expected at that point. The same characters as for `add_operator` are
+    /// reserved.
+    pub fn add_prefix_operator(
+        &mut self,
+        symbol: &str,
+        precedence: i32,
+    ) -> Result<(), OperatorError> {
+        check_symbol_characters(symbol)?;
+        if self.prefix_operators.contains_key(symbol) {
+            return Err(OperatorError::AlreadyDefined(String::from(symbol)));
+        }
+        self.insert_prefix_operator(symbol, precedence);
+        Ok(())
+    }
 
-    fn calc(expression: &str) -> f64 {
-        let context = Context::default();
-        evaluate(expression, &context)
+    fn insert_prefix_operator(&mut self, symbol: &str, precedence: i32) {
+        let operator = Operator::unary(symbol, precedence, OperatorKind::Prefix);
+        self.invalidate_expression_cache();
+        self.prefix_operators.insert(String::from(symbol), operator);
     }
 
-    #[test]
-    fn addition() {
-        assert_eq!(calc("4 + 9"), 4.0 + 9.0);
+    pub fn get_prefix_operator(&self, symbol: &str) -> Option<&Operator<N>> {
+        self.prefix_operators.get(symbol)
     }
 
-    #[test]
-    fn subtraction() {
-        assert_eq!(calc("25 - 3"), 25.0 - 3.0);
+    /// Adds an operator that is written after its single operand, like `!`
+    /// in `5!`. Postfix operators may share their symbol with an infix
+    /// operator; then the symbol is only read as the postfix operator if
+    /// what follows can't start an operand, e.g. at the end of the
+    /// expression, before a `)` or before an operator like `-` that can be
+    /// infix as well. Spacing doesn't matter, so with a postfix `%`, both
+    /// `10% - 3` and `10 % -3` are 0.1 - 3, and a remainder by a negative
+    /// number has to be written as `10 % (-3)`. The same characters as for
+    /// `add_operator` are reserved.
+    ///
+    /// Postfix operators without a body are built in: `!` is the factorial
+    /// and `%` divides by 100. Any other symbol fails with
+    /// `EvalError::UnknownOperator` when it is evaluated.
+    pub fn add_postfix_operator(
+        &mut self,
+        symbol: &str,
+        precedence: i32,
+    ) -> Result<(), OperatorError> {
+        check_symbol_characters(symbol)?;
+        if self.postfix_operators.contains_key(symbol) {
+            return Err(OperatorError::AlreadyDefined(String::from(symbol)));
+        }
+        self.insert_postfix_operator(symbol, precedence);
+        Ok(())
     }
 
-    #[test]
-    fn multiplication() {
-        assert_eq!(calc("2 * 3"), 2.0 * 3.0);
+    fn insert_postfix_operator(&mut self, symbol: &str, precedence: i32) {
+        let operator = Operator::unary(symbol, precedence, OperatorKind::Postfix);
+        self.invalidate_expression_cache();
+        self.postfix_operators
+            .insert(String::from(symbol), operator);
     }
 
-    #[test]
-    fn division() {
-        assert_eq!(calc("10 / 2"), 10.0 / 2.0);
+    pub fn get_postfix_operator(&self, symbol: &str) -> Option<&Operator<N>> {
+        self.postfix_operators.get(symbol)
     }
 
-    #[test]
-    fn exponentiation() {
-        assert_eq!(calc("2^10"), f64::powf(2.0, 10.0));
+    /// Returns the operator of the given kind for a symbol. Infix operators
+    /// follow aliases, see `get_operator`.
+    pub fn get_operator_of_kind(&self, symbol: &str, kind: OperatorKind) -> Option<&Operator<N>> {
+        match kind {
+            OperatorKind::Infix => self.get_operator(symbol),
+            OperatorKind::Prefix => self.get_prefix_operator(symbol),
+            OperatorKind::Postfix => self.get_postfix_operator(symbol),
+        }
     }
 
-    #[test]
-    fn variables() {
-        let mut context = Context::default();
-        context.set_variable("pi", PI);
+    /// Adds a prefix or postfix operator that evaluates `body`, an expression
+    /// in which `$0` is the operand, e.g. `$0 * $0` for a square. The symbol
+    /// has to be valid, as for `add_operator`, and not taken by an operator of
+    /// the same kind. Infix operators are added with
+    /// `add_operator_binary_from_string` instead, since they also need an
+    /// associativity.
+    pub fn add_unary_operator_from_string(
+        &mut self,
+        symbol: &str,
+        kind: OperatorKind,
+        precedence: i32,
+        body: &str,
+    ) -> Result<(), EvalError> {
+        match kind {
+            OperatorKind::Prefix => self.add_prefix_operator(symbol, precedence),
+            OperatorKind::Postfix => self.add_postfix_operator(symbol, precedence),
+            OperatorKind::Infix => Err(OperatorError::NotUnary(String::from(symbol))),
+        }
+        .map_err(EvalError::Operator)?;
 
-        assert_eq!(evaluate("2 * pi", &context), 2.0 * PI);
+        let body = collect_tokens(body, self).and_then(|tokens| {
+            let tokens = substitute_operands(tokens);
+            shunting_yard(tokens, self)
+        });
+        let operators = match kind {
+            OperatorKind::Postfix => &mut self.postfix_operators,
+            _ => &mut self.prefix_operators,
+        };
+        match body {
+            Ok(body) => {
+                if let Some(operator) = operators.get_mut(symbol) {
+                    operator.body = Some(body);
+                }
+                Ok(())
+            }
+            Err(error) => {
+                operators.remove(symbol);
+                Err(error)
+            }
+        }
     }
 
-    #[test]
-    fn precedence() {
-        assert_eq!(calc("2 + 3 * 4"), 2.0 + 3.0 * 4.0);
+    /// Sets whether unary minus binds tighter than `^`. By default it
+    /// doesn't, so `-2 ^ 2` is `-4` as in mathematical notation. Some
+    /// calculators evaluate it as `(-2) ^ 2` instead, which is `4`.
+    /// Parenthesized expressions like `(-2) ^ 2` aren't affected.
+    pub fn set_unary_minus_binds_tighter_than_power(&mut self, tighter: bool) {
+        let power = self.get_operator("^").map_or(12, Operator::precedence);
+        let precedence = if tighter { power + 1 } else { power - 1 };
+        self.insert_prefix_operator("-", precedence);
+    }
+
+    /// Removes an operator and returns it. Aliases of the operator stay and
+    /// start working again once an operator with the same symbol is added.
+    pub fn remove_operator(&mut self, symbol: &str) -> Option<Operator<N>> {
+        self.invalidate_expression_cache();
+        self.operators.remove(symbol)
+    }
+
+    /// Marks an infix operator as commutative or not, see
+    /// `Operator::is_commutative`. Fails if there is no such operator.
+    pub fn set_operator_commutative(
+        &mut self,
+        symbol: &str,
+        commutative: bool,
+    ) -> Result<(), OperatorError> {
+        let operator = self
+            .operators
+            .get_mut(symbol)
+            .ok_or_else(|| OperatorError::UnknownTarget(String::from(symbol)))?;
+        operator.is_commutative = commutative;
+        Ok(())
+    }
+
+    /// All registered operators, in no particular order. Aliases aren't
+    /// included, see `operator_aliases`.
+    pub fn operators(&self) -> impl Iterator<Item = &Operator<N>> {
+        self.operators.values()
+    }
+
+    /// All operator aliases and the symbols of the operators they stand
+    /// for, in no particular order.
+    pub fn operator_aliases(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.operator_aliases
+            .iter()
+            .map(|(alias, target)| (alias.as_str(), target.as_str()))
+    }
+
+    /// Returns the operator for a symbol, following aliases.
+    pub fn get_operator(&self, symbol: &str) -> Option<&Operator<N>> {
+        let symbol = self
+            .operator_aliases
+            .get(symbol)
+            .map_or(symbol, String::as_str);
+        self.operators.get(symbol)
+    }
+
+    /// Makes `alias` another symbol for the operator `target`, e.g. `**` for
+    /// `^`. The alias shares the target's precedence, associativity and
+    /// operation. The alias has to be a valid operator symbol that isn't
+    /// taken yet, see `add_operator`.
+    pub fn add_operator_alias(&mut self, alias: &str, target: &str) -> Result<(), OperatorError> {
+        self.check_operator_symbol(alias)?;
+        if !self.operators.contains_key(target) {
+            return Err(OperatorError::UnknownTarget(String::from(target)));
+        }
+        self.invalidate_expression_cache();
+        self.operator_aliases
+            .insert(String::from(alias), String::from(target));
+        Ok(())
+    }
+
+    /// Splits an expression into tokens according to the context's number
+    /// locale. Symbols of registered operators and aliases that are longer
+    /// than one character, like `**`, are kept together, preferring the longest
+    /// match.
+    pub fn tokenize<'a>(&self, expression: &'a str) -> Tokens<'a> {
+        let symbols = self
+            .operators
+            .keys()
+            .chain(self.operator_aliases.keys())
+            .chain(self.prefix_operators.keys())
+            .chain(self.postfix_operators.keys())
+            .filter(|symbol| symbol.chars().count() > 1)
+            .cloned()
+            .collect();
+        tokenize_with_locale(expression, self.number_locale).with_symbols(symbols)
+    }
+
+    /// Makes `alias` another name for all overloads of the function
+    /// `original`, replacing any function that was called `alias`. Functions
+    /// that are added to `original` later don't affect the alias.
+    pub fn set_function_alias(&mut self, alias: &str, original: &str) -> Result<(), EvalError> {
+        let overloads = self
+            .functions
+            .get(original)
+            .ok_or_else(|| EvalError::UnknownFunction(String::from(original)))?
+            .iter()
+            .map(|function| function.renamed(alias))
+            .collect();
+        self.invalidate_expression_cache();
+        self.functions.insert(String::from(alias), overloads);
+        Ok(())
+    }
+
+    /// Adds a function. Functions with the same name but different arities
+    /// are kept side by side as overloads; a function with the same name and
+    /// arity as an existing one replaces it.
+    pub fn add_function(&mut self, function: Function<N>) {
+        self.invalidate_expression_cache();
+        let overloads = self
+            .functions
+            .entry(String::from(function.name()))
+            .or_default();
+        match overloads
+            .iter_mut()
+            .find(|overload| overload.arity() == function.arity())
+        {
+            Some(overload) => *overload = function,
+            None => overloads.push(function),
+        }
+    }
+
+    /// Adds a function after calling it once with each of `test_inputs`. If
+    /// any call fails or returns NaN or infinity, the function isn't added.
+    pub fn add_function_checked(
+        &mut self,
+        function: Function<N>,
+        test_inputs: &[&[f64]],
+    ) -> Result<(), FunctionValidationError> {
+        for &inputs in test_inputs {
+            let mut stack: Vec<Value<N>> = inputs
+                .iter()
+                .map(|&input| Value::Float(N::from_f64(input)))
+                .collect();
+            let result = function
+                .apply(&mut stack, inputs.len(), self)
+                .map(|()| stack[stack.len() - 1].to_f64());
+            let inputs = inputs.to_vec();
+            match result {
+                Err(error) => return Err(FunctionValidationError::Failed { inputs, error }),
+                Ok(value) if value.is_nan() => {
+                    return Err(FunctionValidationError::ReturnedNan { inputs })
+                }
+                Ok(value) if value.is_infinite() => {
+                    return Err(FunctionValidationError::ReturnedInfinity { inputs })
+                }
+                Ok(_) => (),
+            }
+        }
+
+        self.add_function(function);
+        Ok(())
+    }
+
+    /// Returns the overload of a function that accepts `num_args` arguments.
+    /// An overload that takes exactly `num_args` arguments is preferred over a
+    /// variadic one.
+    pub fn get_function(&self, name: &str, num_args: usize) -> Option<&Function<N>> {
+        let overloads = self.functions.get(name)?;
+        overloads
+            .iter()
+            .find(|function| function.arity() == Arity::Exact(num_args))
+            .or_else(|| {
+                overloads
+                    .iter()
+                    .find(|function| function.arity().accepts(num_args))
+            })
+    }
+
+    /// Returns the arities of all overloads of a function.
+    pub fn function_arities(&self, name: &str) -> Vec<Arity> {
+        self.functions
+            .get(name)
+            .map(|overloads| overloads.iter().map(Function::arity).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn function_exists(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    /// Checks that an expression parses and that every variable and function
+    /// it uses exists, with functions called with a number of arguments they
+    /// accept, without evaluating it. Variables assigned in the expression
+    /// count as existing after the assignment.
+    pub fn validate(&self, expression: &str) -> Result<(), EvalError> {
+        let queue = shunting_yard(collect_tokens(expression, self)?, self)?;
+        self.validate_queue(&queue, &mut HashSet::new())
+    }
+
+    fn validate_queue(
+        &self,
+        queue: &VecDeque<Token>,
+        assigned: &mut HashSet<String>,
+    ) -> Result<(), EvalError> {
+        for token in queue {
+            match token {
+                Token::Identifier(name) => {
+                    // A function used as an operand is called without
+                    // arguments.
+                    if self.function_exists(name) {
+                        if self.get_function(name, 0).is_none() {
+                            return Err(EvalError::WrongNumberOfArguments {
+                                name: name.clone(),
+                                expected: self.function_arities(name),
+                                found: 0,
+                            });
+                        }
+                    } else if !self.has_variable(name) && !assigned.contains(name) {
+                        return Err(EvalError::UnknownVariable(name.clone()));
+                    }
+                }
+                Token::Call(name, num_args) => {
+                    if is_variable_not_function(name, self) {
+                        return Err(EvalError::IdentifierUsedAsFunction(name.clone()));
+                    }
+                    if !self.function_exists(name) {
+                        return Err(EvalError::UnknownFunction(name.clone()));
+                    }
+                    if self.get_function(name, *num_args).is_none() {
+                        return Err(EvalError::WrongNumberOfArguments {
+                            name: name.clone(),
+                            expected: self.function_arities(name),
+                            found: *num_args,
+                        });
+                    }
+                }
+                Token::Assign(name) => {
+                    assigned.insert(name.clone());
+                }
+                Token::Branch(then, otherwise) => {
+                    self.validate_queue(then, assigned)?;
+                    self.validate_queue(otherwise, assigned)?;
+                }
+                Token::Reduce(_, variable, body) => {
+                    let mut bound = assigned.clone();
+                    bound.insert(variable.clone());
+                    self.validate_queue(body, &mut bound)?;
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets how deeply user defined functions may call each other before
+    /// evaluation fails with `EvalError::RecursionLimitExceeded`.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Sets how many values the `series` and `prod` of an evaluation may
+    /// iterate over before it fails with `EvalError::IterationLimitExceeded`.
+    /// They share the limit, so that nesting them can't multiply it. Defaults
+    /// to a million.
+    pub fn set_max_iterations(&mut self, max_iterations: usize) {
+        self.max_iterations = max_iterations;
+    }
+
+    pub fn max_iterations(&self) -> usize {
+        self.max_iterations
+    }
+
+    /// The number of user defined function calls currently being evaluated
+    /// on this thread.
+    pub fn current_depth(&self) -> usize {
+        CALL_DEPTH.with(Cell::get)
+    }
+
+    fn enter_call(&self) -> Result<(), EvalError> {
+        let depth = self.current_depth();
+        if depth >= self.max_depth {
+            return Err(EvalError::RecursionLimitExceeded);
+        }
+        CALL_DEPTH.with(|call_depth| call_depth.set(depth + 1));
+        Ok(())
+    }
+
+    fn leave_call(&self) {
+        let depth = CALL_DEPTH.with(|call_depth| {
+            call_depth.set(call_depth.get() - 1);
+            call_depth.get()
+        });
+        if depth == 0 {
+            CALL_CACHE.with(|cache| cache.borrow_mut().clear());
+        }
+    }
+
+    /// Makes calls of user defined functions reuse the result of earlier
+    /// calls with the same arguments, which turns naive recursive definitions
+    /// like Fibonacci from exponential into linear time. Results are only kept
+    /// until the outermost call returns. This assumes that functions always
+    /// return the same result for the same arguments, so it should not be
+    /// enabled if they use variables that change, e.g. with assignments.
+    /// Disabled by default.
+    pub fn set_memoize_user_functions(&mut self, enabled: bool) {
+        self.memoize_user_functions = enabled;
+    }
+
+    pub fn memoize_user_functions(&self) -> bool {
+        self.memoize_user_functions
+    }
+
+    fn call_key(&self, name: &str, args: &[Value<N>]) -> CallKey {
+        let address = self as *const Self as usize;
+        let args = args.iter().map(|arg| format!("{:?}", arg)).collect();
+        (address, String::from(name), args)
+    }
+
+    pub(crate) fn cached_call(&self, name: &str, args: &[Value<N>]) -> Option<Value<N>> {
+        if !self.memoize_user_functions {
+            return None;
+        }
+        let key = self.call_key(name, args);
+        CALL_CACHE.with(|cache| {
+            let cache = cache.borrow();
+            cache.get(&key)?.downcast_ref::<Value<N>>().cloned()
+        })
+    }
+
+    pub(crate) fn cache_call(&self, name: &str, args: &[Value<N>], result: Value<N>) {
+        if self.memoize_user_functions {
+            let key = self.call_key(name, args);
+            CALL_CACHE.with(|cache| cache.borrow_mut().insert(key, Box::new(result)));
+        }
+    }
+
+    /// Calls the overload of a function matching `num_args`, taking the
+    /// arguments from `stack`.
+    fn call_function(
+        &self,
+        name: &str,
+        num_args: usize,
+        stack: &mut Vec<Value<N>>,
+    ) -> Result<(), EvalError> {
+        if !self.function_exists(name) {
+            return Err(EvalError::UnknownFunction(String::from(name)));
+        }
+
+        match self.get_function(name, num_args) {
+            Some(function) => function.apply(stack, num_args, self),
+            None => Err(EvalError::WrongNumberOfArguments {
+                name: String::from(name),
+                expected: self.function_arities(name),
+                found: num_args,
+            }),
+        }
+    }
+}
+
+/// Restores the variables of a context when it is dropped, see
+/// `Context::with_scope`.
+struct ScopeGuard<'a, N: Number> {
+    context: &'a mut Context<N>,
+    variables: Option<HashMap<String, Value<N>>>,
+}
+
+impl<N: Number> Drop for ScopeGuard<'_, N> {
+    fn drop(&mut self) {
+        if let Some(variables) = self.variables.take() {
+            if variables.len() != self.context.variables.len() {
+                self.context.variables_added_or_removed();
+            }
+            self.context.variables = variables;
+        }
+    }
+}
+
+impl<N: Number> Default for Context<N> {
+    /// The default operators leave gaps in their precedences so that other
+    /// operators can be slotted in between.
+    ///
+    /// The comparisons `<`, `>`, `<=`, `>=`, `==` and `!=` evaluate to a
+    /// `Value::Bool`, which `evaluate_f64` gives as 1 for true and 0 for
+    /// false. They are non-associative, so `a == b == c` is an error, but
+    /// `a < b <= c` is a chained comparison, see `shunting_yard`.
+    /// `==` compares exactly unless the context has an equality tolerance,
+    /// see `set_equality_tolerance`.
+    ///
+    /// `**` is an alias for `^`. `+` and `*` are commutative. Prefix `+`
+    /// leaves its operand as it is, and prefix operators can be repeated, as
+    /// in `-+-3`.
+    ///
+    /// `%` is the Euclidean remainder, which is never negative, so `-7 % 3` is
+    /// 2. Like division by zero, which results in infinity rather than an
+    /// error, `x % 0` results in NaN. `//` is the matching Euclidean division,
+    /// so that `a == (a // b) * b + a % b`. For positive divisors, that's
+    /// floor division, so `-7 // 2` is -4. If `%` is added as a postfix
+    /// operator as well, `7 % -3` becomes 0.07 - 3, see
+    /// `add_postfix_operator`.
+    ///
+    /// The logical operators `&&`, `||` and prefix `!` and the function `not`
+    /// treat 0 as false and anything else as true. `&&` and `||` short-circuit:
+    /// their right operand is only evaluated if the left one doesn't already
+    /// decide the result.
+    fn default() -> Self {
+        let mut context = Self::new();
+        context.insert_operator("=", 1, Associativity::Right);
+        context.insert_operator("||", 2, Associativity::Left);
+        context.insert_operator("&&", 3, Associativity::Left);
+        context.insert_operator("==", 7, Associativity::None);
+        context.insert_operator("!=", 7, Associativity::None);
+        context.insert_operator("<", 8, Associativity::None);
+        context.insert_operator(">", 8, Associativity::None);
+        context.insert_operator("<=", 8, Associativity::None);
+        context.insert_operator(">=", 8, Associativity::None);
+        context.insert_operator("+", 10, Associativity::Left);
+        context.insert_operator("-", 10, Associativity::Left);
+        context.insert_operator("*", 11, Associativity::Left);
+        context.insert_operator("/", 11, Associativity::Left);
+        context.insert_operator("//", 11, Associativity::Left);
+        context.insert_operator("%", 11, Associativity::Left);
+        context.insert_operator("^", 12, Associativity::Right);
+        context.insert_prefix_operator("+", 11);
+        context.insert_prefix_operator("-", 11);
+        context.insert_prefix_operator("!", 11);
+        for symbol in &["+", "*"] {
+            if let Some(operator) = context.operators.get_mut(*symbol) {
+                operator.is_commutative = true;
+            }
+        }
+        context
+            .operator_aliases
+            .insert(String::from("**"), String::from("^"));
+        context.add_function(Function::new("not", 1, |args: &[N], _| {
+            Ok(N::from_f64(truth(args[0].is_zero())))
+        }));
+        context
+    }
+}
+
+/// Whether an operator on the stack of the shunting-yard algorithm has to be
+/// moved to the queue before the incoming operator is pushed, i.e. whether
+/// it takes its right operand first.
+///
+/// That is the case if the stacked operator has a higher precedence, or the
+/// same precedence and the incoming operator is left-associative, so that
+/// `1 - 2 - 3` is `(1 - 2) - 3` and `2 ^ 3 ^ 2` is `2 ^ (3 ^ 2)`.
+/// Non-associative operators of the same precedence, like `<` in
+/// `a < b < c`, are never popped; `shunting_yard` reports them as an error
+/// instead.
+pub fn should_pop<N: Number>(stacked: &Operator<N>, incoming: &Operator<N>) -> bool {
+    stacked.precedence > incoming.precedence
+        || (stacked.precedence == incoming.precedence
+            && incoming.associativity == Associativity::Left
+            && stacked.associativity != Associativity::None)
+}
+
+/// Moves operators that bind at least as tightly as `operator` from the stack
+/// to the queue. Stack entries hold the byte offset of their token.
+fn move_operators<N: Number>(
+    operator: &Operator<N>,
+    position: usize,
+    stack: &mut Vec<(Token, usize)>,
+    queue: &mut VecDeque<Token>,
+    context: &Context<N>,
+) -> Result<(), EvalError> {
+    loop {
+        // Calls without parentheses bind tighter than any operator.
+        if let Some((Token::Call(..), _)) = stack.last() {
+            if let Some((token, _)) = stack.pop() {
+                queue.push_back(token);
+            }
+            continue;
+        }
+
+        let (other_operator, other_position) = match stack.last() {
+            Some((Token::Symbol(symbol), position)) => (context.get_operator(symbol), *position),
+            Some((Token::Prefix(symbol), position)) => {
+                (context.get_prefix_operator(symbol), *position)
+            }
+            Some((Token::Assign(_), position)) => (context.get_operator("="), *position),
+            _ => break,
+        };
+        let other_operator = match other_operator {
+            Some(other_operator) => other_operator,
+            None => break,
+        };
+
+        let same_precedence = other_operator.precedence == operator.precedence;
+        if same_precedence
+            && is_chained_comparison(&other_operator.symbol)
+            && is_chained_comparison(&operator.symbol)
+        {
+            // The middle operand of `a < b < c` is copied for the second
+            // comparison, which `short_circuit` later finds after the marker.
+            let start = operand_start(queue, queue.len(), context).unwrap_or(queue.len());
+            let middle: Vec<Token> = queue.range(start..).cloned().collect();
+            if let Some((token, _)) = stack.pop() {
+                queue.push_back(token);
+            }
+            queue.push_back(Token::Separator);
+            queue.extend(middle);
+            break;
+        }
+        if same_precedence
+            && (operator.associativity == Associativity::None
+                || other_operator.associativity == Associativity::None)
+        {
+            return Err(EvalError::ChainedNonAssociative {
+                first: other_operator.symbol.clone(),
+                first_position: other_position,
+                second: operator.symbol.clone(),
+                second_position: position,
+            });
+        }
+        if should_pop(other_operator, operator) {
+            if let Some((token, _)) = stack.pop() {
+                queue.push_back(token);
+            }
+        } else {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Converts a list of tokens from infix to postfix order.
+///
+/// An identifier directly followed by a left parenthesis is a function call,
+/// even if no such function exists yet. This allows user defined functions to
+/// call themselves. Calls end up in the queue as `Token::Call`, together with
+/// the number of arguments that were passed. Unless implicit multiplication is
+/// enabled, see `Context::set_implicit_multiplication`, this fails with
+/// `EvalError::IdentifierUsedAsFunction` if the identifier is a variable
+/// rather than a function, as in `x(y)`.
+///
+/// `if(condition, then, else)` is a special form rather than a function: only
+/// one of `then` and `else` is evaluated, depending on whether `condition` is
+/// true, i.e. anything but zero. The two branches end up in the queue as a
+/// `Token::Branch` after the condition.
+///
+/// `series(k, a, b, body)` and `prod(k, a, b, body)` are special forms as
+/// well, if the first of their four arguments is a variable name. They add up
+/// or multiply the values of `body` with `k` bound to each integer from `a` to
+/// `b`, which gives 0 or 1 if `b` is less than `a`. `k` shadows a variable of
+/// the same name only within `body`. The bounds have to be integers, and may
+/// span at most `Context::max_iterations` values. The body ends up in the
+/// queue as a `Token::Reduce` after the bounds. The series isn't called `sum`
+/// so that it doesn't take over calls of the variadic `sum` of
+/// `default_with_statistical_functions`.
+///
+/// An identifier followed by `=` at the start of an expression is the target
+/// of an assignment, which ends up in the queue as `Token::Assign` after the
+/// assigned value.
+///
+/// Two operators with `Associativity::None` and the same precedence in a row
+/// fail with `EvalError::ChainedNonAssociative`, except for chains of the
+/// comparisons `<`, `<=`, `>` and `>=`. Like in Python, `a < b < c` means
+/// `a < b && b < c`. Middle operands are evaluated twice.
+///
+/// The right operand of `&&` and `||` ends up in a `Token::Branch`, like the
+/// branches of an `if`, so that it is only evaluated when needed.
+pub fn shunting_yard<N: Number>(
+    mut tokens: Vec<Token>,
+    context: &Context<N>,
+) -> Result<VecDeque<Token>, EvalError> {
+    if context.auto_close_parens {
+        close_parentheses(&mut tokens);
+    }
+    check_parentheses_balance(&tokens)?;
+
+    let mut queue: VecDeque<Token> = VecDeque::new();
+    let mut stack: Vec<(Token, usize)> = Vec::new();
+    // One entry per open parenthesis, which is only `Some` if the parenthesis
+    // belongs to a function call.
+    let mut parentheses: Vec<Option<OpenCall>> = Vec::new();
+    let mut offset = 0;
+    let mut tokens = tokens
+        .into_iter()
+        .map(|token| {
+            let position = offset;
+            offset += token.len();
+            (token, position)
+        })
+        .filter(|(token, _)| !matches!(token, Token::Whitespace(_)))
+        .peekable();
+    // Whether the current token is the first one of a (sub-)expression.
+    let mut at_start = true;
+    // Whether the current token has to start an operand, which decides
+    // whether a symbol is a prefix or an infix operator.
+    let mut expect_operand = true;
+    let mut previous = String::new();
+
+    while let Some((token, position)) = tokens.next() {
+        if let Token::Identifier(name) = &token {
+            if context.is_arithmetic_only() {
+                return Err(EvalError::FeatureDisabled(name.clone()));
+            }
+        }
+
+        let was_at_start = at_start;
+        at_start = matches!(token, Token::LeftParenthesis | Token::Separator);
+        let was_expecting_operand = expect_operand;
+        expect_operand = matches!(
+            token,
+            Token::Symbol(_) | Token::LeftParenthesis | Token::Separator
+        );
+        let text = token.to_string();
+
+        if context.implicit_multiplication
+            && !was_expecting_operand
+            && starts_implicit_product(&token, stack.last().map(|(top, _)| top))
+        {
+            let operator = context.implicit_operator()?;
+            move_operators(&operator, position, &mut stack, &mut queue, context)?;
+            stack.push((Token::Symbol(String::from("*")), position));
+        }
+
+        match token {
+            Token::RightParenthesis | Token::Separator => (),
+            _ => {
+                if let Some(Some(call)) = parentheses.last_mut() {
+                    call.args = call.args.max(1);
+                }
+            }
+        }
+
+        match token {
+            Token::Number(..) => queue.push_back(token),
+            Token::Identifier(ref name) => match tokens.peek() {
+                Some((Token::LeftParenthesis, _)) if !context.is_implicit_product(name) => {
+                    stack.push((token, position))
+                }
+                Some((Token::Symbol(symbol), _))
+                    if symbol == "=" && context.get_operator("=").is_some() =>
+                {
+                    if !was_at_start || context.function_exists(name) {
+                        return Err(EvalError::InvalidAssignmentTarget(name.clone()));
+                    }
+                    tokens.next();
+                    stack.push((Token::Assign(name.clone()), position));
+                    at_start = true;
+                    expect_operand = true;
+                }
+                _ if context.is_paren_free_call(name) => {
+                    stack.push((Token::Call(name.clone(), 1), position));
+                    expect_operand = true;
+                }
+                _ => queue.push_back(token),
+            },
+            Token::Symbol(ref symbol) if symbol == "=" && context.get_operator("=").is_some() => {
+                return Err(EvalError::InvalidAssignmentTarget(previous));
+            }
+            Token::Symbol(ref symbol)
+                if !was_expecting_operand
+                    && is_postfix_operator(
+                        symbol,
+                        tokens.peek().map(|(next, _)| next),
+                        context,
+                    ) =>
+            {
+                if let Some(operator) = context.get_postfix_operator(symbol) {
+                    move_operators(operator, position, &mut stack, &mut queue, context)?;
+                }
+                queue.push_back(Token::Postfix(symbol.clone()));
+                expect_operand = false;
+            }
+            Token::Symbol(ref symbol)
+                if was_expecting_operand && context.get_prefix_operator(symbol).is_some() =>
+            {
+                stack.push((Token::Prefix(symbol.clone()), position));
+            }
+            Token::Symbol(ref symbol) => {
+                if let Some(operator) = context.get_operator(symbol) {
+                    move_operators(operator, position, &mut stack, &mut queue, context)?;
+                    stack.push((token, position));
+                } else {
+                    return Err(EvalError::UnknownOperator(symbol.clone()));
+                }
+            }
+            Token::Whitespace(_) => (),
+            Token::LeftParenthesis => {
+                if let Some((Token::Identifier(_), _)) = stack.last() {
+                    parentheses.push(Some(OpenCall {
+                        args: 0,
+                        starts: vec![queue.len()],
+                    }));
+                } else {
+                    parentheses.push(None);
+                }
+                stack.push((token, position));
+            }
+            Token::RightParenthesis => {
+                move_to_parenthesis(&mut stack, &mut queue)?;
+                stack.pop();
+                if let Some(Some(call)) = parentheses.pop() {
+                    if let Some((Token::Identifier(name), _)) = stack.pop() {
+                        if name == "if" {
+                            push_branch(call, &mut queue)?;
+                        } else if is_reduction(&name, &call, &queue) {
+                            push_reduction(name, call, &mut queue);
+                        } else if is_variable_not_function(&name, context) {
+                            return Err(EvalError::IdentifierUsedAsFunction(name));
+                        } else {
+                            queue.push_back(Token::Call(name, call.args));
+                        }
+                    }
+                }
+            }
+            Token::Separator => {
+                move_to_parenthesis(&mut stack, &mut queue)?;
+                match parentheses.last_mut() {
+                    Some(Some(call)) => {
+                        call.args += 1;
+                        call.starts.push(queue.len());
+                    }
+                    _ => return Err(EvalError::UnexpectedToken(String::from(","))),
+                }
+            }
+            Token::Call(..)
+            | Token::Assign(_)
+            | Token::Prefix(_)
+            | Token::Postfix(_)
+            | Token::Branch(..)
+            | Token::Reduce(..) => return Err(EvalError::UnexpectedToken(token.to_string())),
+        }
+
+        previous = text;
+    }
+
+    while let Some((token, _)) = stack.pop() {
+        if let Token::LeftParenthesis = token {
+            return Err(EvalError::MismatchedParentheses);
+        }
+        queue.push_back(token);
+    }
+
+    let queue = if context.percent_of {
+        percent_of(queue, context)
+    } else {
+        queue
+    };
+    Ok(short_circuit(queue, context))
+}
+
+/// Whether `token`, following an operand, starts another operand that is
+/// multiplied with it. A parenthesis after a function name on the stack
+/// opens a call instead.
+fn starts_implicit_product(token: &Token, stack_top: Option<&Token>) -> bool {
+    match token {
+        Token::Number(..) | Token::Identifier(_) => true,
+        Token::LeftParenthesis => !matches!(stack_top, Some(Token::Identifier(_))),
+        _ => false,
+    }
+}
+
+fn check_symbol_characters(symbol: &str) -> Result<(), OperatorError> {
+    let is_reserved = |c: char| c.is_whitespace() || c.is_alphanumeric() || "(),;".contains(c);
+    if symbol.is_empty() || symbol.chars().any(is_reserved) {
+        return Err(OperatorError::InvalidSymbol(String::from(symbol)));
+    }
+    Ok(())
+}
+
+/// Whether `symbol`, following an operand, is a postfix operator. If it is
+/// an infix operator as well, it is only read as postfix if the token after
+/// it can't start an operand. A symbol that is both prefix and infix, like
+/// `-`, counts as infix there regardless of spacing, so `10% - 3` and
+/// `10 % -3` are both 0.1 - 3.
+pub(crate) fn is_postfix_operator<N: Number>(
+    symbol: &str,
+    next: Option<&Token>,
+    context: &Context<N>,
+) -> bool {
+    let starts_operand = |token: &Token| match token {
+        Token::Number(..) | Token::Identifier(_) | Token::LeftParenthesis => true,
+        Token::Symbol(symbol) => {
+            context.get_prefix_operator(symbol).is_some() && context.get_operator(symbol).is_none()
+        }
+        _ => false,
+    };
+    context.get_postfix_operator(symbol).is_some()
+        && (context.get_operator(symbol).is_none() || !next.is_some_and(starts_operand))
+}
+
+pub(crate) fn is_variable_not_function<N: Number>(name: &str, context: &Context<N>) -> bool {
+    !context.function_exists(name) && context.has_variable(name)
+}
+
+/// Checks that every parenthesis in a list of tokens, as produced by the
+/// lexer, has a partner. Fails with the byte offset of the first closing
+/// parenthesis that has nothing to close, or otherwise of the first opening
+/// parenthesis that is never closed.
+pub fn check_parentheses_balance(tokens: &[Token]) -> Result<(), EvalError> {
+    let mut open = Vec::new();
+    let mut position = 0;
+    for token in tokens {
+        match token {
+            Token::LeftParenthesis => open.push(position),
+            Token::RightParenthesis if open.pop().is_none() => {
+                return Err(EvalError::UnexpectedClosingParenthesis { position });
+            }
+            _ => (),
+        }
+        position += token.len();
+    }
+
+    match open.first() {
+        Some(&position) => Err(EvalError::UnclosedParenthesis { position }),
+        None => Ok(()),
+    }
+}
+
+/// Appends a closing parenthesis for each opening one that is never closed.
+fn close_parentheses(tokens: &mut Vec<Token>) {
+    let mut open = 0;
+    for token in tokens.iter() {
+        match token {
+            Token::LeftParenthesis => open += 1,
+            Token::RightParenthesis if open > 0 => open -= 1,
+            _ => (),
+        }
+    }
+    tokens.extend((0..open).map(|_| Token::RightParenthesis));
+}
+
+/// An open parenthesis that belongs to a function call.
+struct OpenCall {
+    /// The number of arguments seen so far.
+    args: usize,
+    /// Where each argument starts in the queue.
+    starts: Vec<usize>,
+}
+
+/// Turns each `$` directly followed by `0` or `1` into the identifier of an
+/// operand in the body of an operator.
+fn substitute_operands(tokens: Vec<Token>) -> Vec<Token> {
+    let mut result = Vec::with_capacity(tokens.len());
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        if let Token::Symbol(symbol) = &token {
+            if let (true, Some(Token::Number(_, text))) = (symbol == "$", tokens.peek()) {
+                if text == "0" || text == "1" {
+                    result.push(Token::Identifier(format!("${}", text)));
+                    tokens.next();
+                    continue;
+                }
+            }
+        }
+        result.push(token);
+    }
+    result
+}
+
+/// Replaces the last two arguments of an `if` in the queue with a
+/// `Token::Branch` holding both, so that only one of them is evaluated.
+fn push_branch(call: OpenCall, queue: &mut VecDeque<Token>) -> Result<(), EvalError> {
+    if call.args != 3 {
+        return Err(EvalError::WrongNumberOfArguments {
+            name: String::from("if"),
+            expected: vec![Arity::Exact(3)],
+            found: call.args,
+        });
+    }
+    let otherwise = queue.split_off(call.starts[2]);
+    let then = queue.split_off(call.starts[1]);
+    queue.push_back(Token::Branch(then, otherwise));
+    Ok(())
+}
+
+/// Whether a call is a `series` or `prod` over a bound variable, i.e. has four
+/// arguments of which the first is a single identifier.
+fn is_reduction(name: &str, call: &OpenCall, queue: &VecDeque<Token>) -> bool {
+    is_reducer(name)
+        && call.args == 4
+        && call.starts[1] == call.starts[0] + 1
+        && matches!(queue[call.starts[0]], Token::Identifier(_))
+}
+
+pub(crate) fn is_reducer(name: &str) -> bool {
+    name == "series" || name == "prod"
+}
+
+/// Replaces the arguments of a `series` or `prod` in the queue with its bounds
+/// and a `Token::Reduce` holding the bound variable and the body.
+fn push_reduction(name: String, call: OpenCall, queue: &mut VecDeque<Token>) {
+    let body = queue.split_off(call.starts[3]);
+    let variable = match queue.remove(call.starts[0]) {
+        Some(Token::Identifier(variable)) => variable,
+        _ => unreachable!("checked by is_reduction"),
+    };
+    queue.push_back(Token::Reduce(name, variable, body));
+}
+
+/// Evaluates a `series` or `prod` by calling `body` with each integer from
+/// `start` to `end`. The bounds have to be integers, of which floats without
+/// a fractional part are fine as well. If `end` is less than `start`, the
+/// result is 0 or 1.
+pub(crate) fn reduce<N: Number>(
+    name: &str,
+    (start, end): (Value<N>, Value<N>),
+    context: &Context<N>,
+    mut body: impl FnMut(Value<N>) -> Result<Value<N>, EvalError>,
+) -> Result<Value<N>, EvalError> {
+    let invalid = |value| EvalError::InvalidOperand {
+        operator: String::from(name),
+        value,
+    };
+    let (start, end) = (
+        value::number(name, &start, context)?.into_f64(),
+        value::number(name, &end, context)?.into_f64(),
+    );
+    for &value in &[start, end] {
+        if value.fract() != 0.0 || !value.is_finite() {
+            return Err(invalid(value));
+        }
+    }
+    charge_iterations((end - start + 1.0).max(0.0), context)?;
+
+    let range = i64::MIN as f64..i64::MAX as f64;
+    let (start, end) = match (range.contains(&start), range.contains(&end)) {
+        (true, true) => (start as i64, end as i64),
+        (false, _) => return Err(invalid(start)),
+        (_, false) => return Err(invalid(end)),
+    };
+
+    let (mut result, symbol) = match name {
+        "prod" => (Value::Int(1), "*"),
+        _ => (Value::Int(0), "+"),
+    };
+    for index in start..=end {
+        result = apply_binary(symbol, result, body(Value::Int(index))?, context)?;
+    }
+    Ok(result)
+}
+
+/// Replaces each `&&` and `||` in a postfix queue and its branches with a
+/// branch that holds the operator's right operand.
+///
+/// The second comparison of a chain like `a < b < c` is preceded by a
+/// `Token::Separator` in the queue, followed by a copy of `b`. The comparison
+/// is combined with the first one with `&&`.
+fn short_circuit<N: Number>(queue: VecDeque<Token>, context: &Context<N>) -> VecDeque<Token> {
+    let mut output = VecDeque::new();
+    for token in queue {
+        match token {
+            Token::Symbol(symbol) => {
+                let operator = context
+                    .get_operator(&symbol)
+                    .map_or(symbol.as_str(), Operator::symbol);
+                let right_start = operand_start(&output, output.len(), context);
+                let left_start =
+                    right_start.and_then(|start| operand_start(&output, start, context));
+                match (right_start, left_start) {
+                    (Some(start), _) if is_logical_operator(operator) => {
+                        let right = output.split_off(start);
+                        output.push_back(logical_branch(operator, right));
+                    }
+                    (_, Some(start))
+                        if start > 0 && matches!(output[start - 1], Token::Separator) =>
+                    {
+                        let mut comparison = output.split_off(start);
+                        output.pop_back();
+                        comparison.push_back(Token::Symbol(symbol));
+                        output.push_back(logical_branch("&&", comparison));
+                    }
+                    _ => output.push_back(Token::Symbol(symbol)),
+                }
+            }
+            Token::Branch(then, otherwise) => output.push_back(Token::Branch(
+                short_circuit(then, context),
+                short_circuit(otherwise, context),
+            )),
+            Token::Reduce(name, variable, body) => {
+                output.push_back(Token::Reduce(name, variable, short_circuit(body, context)))
+            }
+            token => output.push_back(token),
+        }
+    }
+    output
+}
+
+/// Multiplies each percentage that is the right operand of `+` or `-` in a
+/// postfix queue and its branches with a copy of the left operand, see
+/// `Context::set_percent_of`.
+fn percent_of<N: Number>(queue: VecDeque<Token>, context: &Context<N>) -> VecDeque<Token> {
+    let mut output = VecDeque::new();
+    for token in queue {
+        match token {
+            Token::Symbol(symbol) => {
+                let operator = context
+                    .get_operator(&symbol)
+                    .map_or(symbol.as_str(), Operator::symbol);
+                let is_percentage =
+                    matches!(output.back(), Some(Token::Postfix(last)) if last == "%");
+                if matches!(operator, "+" | "-") && is_percentage {
+                    let right_start = operand_start(&output, output.len(), context);
+                    let left_start =
+                        right_start.and_then(|start| operand_start(&output, start, context));
+                    if let (Some(right), Some(left)) = (right_start, left_start) {
+                        let base: Vec<Token> = output.range(left..right).cloned().collect();
+                        let percentage = output.split_off(right);
+                        output.extend(base);
+                        output.extend(percentage);
+                        output.push_back(Token::Symbol(String::from("*")));
+                    }
+                }
+                output.push_back(Token::Symbol(symbol));
+            }
+            Token::Branch(then, otherwise) => output.push_back(Token::Branch(
+                percent_of(then, context),
+                percent_of(otherwise, context),
+            )),
+            Token::Reduce(name, variable, body) => {
+                output.push_back(Token::Reduce(name, variable, percent_of(body, context)))
+            }
+            token => output.push_back(token),
+        }
+    }
+    output
+}
+
+/// Finds where the operand that ends before `end` in a postfix queue
+/// starts, by walking back until the tokens add up to a single value.
+fn operand_start<N: Number>(
+    queue: &VecDeque<Token>,
+    end: usize,
+    context: &Context<N>,
+) -> Option<usize> {
+    let mut values = 0;
+    for (index, token) in queue.range(..end).enumerate().rev() {
+        values += match token {
+            Token::Number(..) => 1,
+            Token::Identifier(name) => {
+                let args = context.function_arities(name).iter().map(Arity::min).min();
+                1 - args.unwrap_or(0) as isize
+            }
+            Token::Symbol(_) => -1,
+            Token::Call(_, args) => 1 - *args as isize,
+            Token::Reduce(..) => -1,
+            _ => 0,
+        };
+        if values == 1 {
+            return Some(index);
+        }
+    }
+    None
+}
+
+pub(crate) fn is_chained_comparison(symbol: &str) -> bool {
+    matches!(symbol, "<" | "<=" | ">" | ">=")
+}
+
+pub(crate) fn is_logical_operator(symbol: &str) -> bool {
+    symbol == "&&" || symbol == "||"
+}
+
+/// The branch that replaces the logical operator `symbol`, which only
+/// evaluates `right` if the left operand, the condition of the branch,
+/// doesn't decide the result on its own. Either way, the result is a truth
+/// value: `right` is combined with the constant that doesn't change it, as
+/// in `right && true`, and the other branch is the constant `true` or
+/// `false`. The constants are 1 and 0, written `true` and `false`.
+pub(crate) fn logical_branch(symbol: &str, mut right: VecDeque<Token>) -> Token {
+    let constant = |value: bool| Token::Number(f64::from(u8::from(value)), value.to_string());
+    let is_and = symbol == "&&";
+    right.push_back(constant(is_and));
+    right.push_back(Token::Symbol(String::from(symbol)));
+    let other = VecDeque::from(vec![constant(!is_and)]);
+    if is_and {
+        Token::Branch(right, other)
+    } else {
+        Token::Branch(other, right)
+    }
+}
+
+/// The operator that the branch of a queue stands for, which is `&&` or `||`
+/// for the branches of `logical_branch` and `if` for others.
+pub(crate) fn branch_operator(then: &VecDeque<Token>, otherwise: &VecDeque<Token>) -> &'static str {
+    let is_constant = |block: &VecDeque<Token>, text: &str| {
+        block.len() == 1
+            && matches!(block.front(), Some(Token::Number(_, constant)) if constant == text)
+    };
+    if is_constant(otherwise, "false") {
+        "&&"
+    } else if is_constant(then, "true") {
+        "||"
+    } else {
+        "if"
+    }
+}
+
+/// Moves operators to the queue until the innermost open parenthesis is on
+/// top of the stack.
+fn move_to_parenthesis(
+    stack: &mut Vec<(Token, usize)>,
+    queue: &mut VecDeque<Token>,
+) -> Result<(), EvalError> {
+    loop {
+        match stack.last() {
+            Some((Token::LeftParenthesis, _)) => return Ok(()),
+            Some(_) => {
+                if let Some((token, _)) = stack.pop() {
+                    queue.push_back(token);
+                }
+            }
+            None => return Err(EvalError::MismatchedParentheses),
+        }
+    }
+}
+
+pub(crate) fn pop_operand<T>(stack: &mut Vec<T>, symbol: &str) -> Result<T, EvalError> {
+    stack
+        .pop()
+        .ok_or_else(|| EvalError::MissingOperand(String::from(symbol)))
+}
+
+/// Evaluates tokens in postfix order, such as the output of `shunting_yard`.
+///
+/// The tokens can be given by value or by reference, so both `queue` and
+/// `&queue` work, as does any other iterator over tokens.
+pub fn evaluate_queue<N: Number, I>(queue: I, context: &Context<N>) -> Result<Value<N>, EvalError>
+where
+    I: IntoIterator,
+    I::Item: Borrow<Token>,
+{
+    evaluate_scoped(queue, context, &HashMap::new(), None)
+}
+
+/// Like `evaluate_with`, for an expression that is already in postfix order.
+pub fn evaluate_queue_with<N: Number, I>(
+    queue: I,
+    context: &Context<N>,
+    bindings: &HashMap<String, N>,
+) -> Result<Value<N>, EvalError>
+where
+    I: IntoIterator,
+    I::Item: Borrow<Token>,
+{
+    let bindings = bindings
+        .iter()
+        .map(|(name, &value)| (name.clone(), Value::Float(value)))
+        .collect();
+    evaluate_scoped(queue, context, &bindings, None)
+}
+
+/// Evaluates a postfix queue, looking up variables in `locals` and then in
+/// `assignments` before falling back to the context.
+///
+/// Assignments are only allowed if `assignments` is given, in which case the
+/// assigned values are collected there instead of changing the context.
+pub(crate) fn evaluate_scoped<N: Number, I>(
+    queue: I,
+    context: &Context<N>,
+    locals: &HashMap<String, Value<N>>,
+    mut assignments: Option<&mut HashMap<String, Value<N>>>,
+) -> Result<Value<N>, EvalError>
+where
+    I: IntoIterator,
+    I::Item: Borrow<Token>,
+{
+    let _guard = EvaluationGuard::enter();
+    let mut stack: Vec<Value<N>> = Vec::new();
+
+    for token in queue {
+        let token = token.borrow();
+        if let Token::Identifier(name) | Token::Call(name, _) = token {
+            if context.is_arithmetic_only() {
+                return Err(EvalError::FeatureDisabled(name.clone()));
+            }
+        }
+
+        match token {
+            Token::Number(value, text) => stack.push(Value::literal(*value, text, context)),
+            Token::Symbol(_) | Token::Prefix(_) | Token::Postfix(_) => {
+                evaluate_operator(token, &mut stack, context)?
+            }
+            Token::Branch(then, otherwise) => {
+                let operator = branch_operator(then, otherwise);
+                let condition =
+                    value::condition(operator, pop_operand(&mut stack, "if")?, context)?;
+                let branch = if condition { then } else { otherwise };
+                let value = evaluate_scoped(branch, context, locals, assignments.as_deref_mut())?;
+                stack.push(value);
+            }
+            Token::Reduce(name, variable, body) => {
+                let assignments = assignments.as_deref_mut();
+                evaluate_reduction(
+                    name,
+                    variable,
+                    body,
+                    &mut stack,
+                    context,
+                    locals,
+                    assignments,
+                )?;
+            }
+            Token::Call(name, args) => {
+                let is_local = locals.contains_key(name)
+                    || assignments
+                        .as_ref()
+                        .is_some_and(|assignments| assignments.contains_key(name));
+                if is_variable_not_function(name, context)
+                    || (is_local && !context.function_exists(name))
+                {
+                    return Err(EvalError::IdentifierUsedAsFunction(name.clone()));
+                }
+                context.call_function(name, *args, &mut stack)?
+            }
+            Token::Identifier(name) => {
+                if context.function_exists(name) {
+                    let num_args = context
+                        .function_arities(name)
+                        .iter()
+                        .map(Arity::min)
+                        .min()
+                        .unwrap_or(0);
+                    context.call_function(name, num_args, &mut stack)?;
+                } else if let Some(value) = locals.get(name) {
+                    stack.push(value.clone());
+                } else if let Some(value) = assignments
+                    .as_ref()
+                    .and_then(|assignments| assignments.get(name))
+                {
+                    stack.push(value.clone());
+                } else if let Some(value) = context.get_variable(name) {
+                    stack.push(value.clone());
+                } else {
+                    return Err(EvalError::UnknownVariable(name.clone()));
+                }
+            }
+            Token::Assign(name) => {
+                let value = stack
+                    .last()
+                    .cloned()
+                    .ok_or_else(|| EvalError::MissingOperand(String::from("=")))?;
+                match assignments.as_mut() {
+                    Some(assignments) => assignments.insert(name.clone(), value),
+                    None => return Err(EvalError::AssignmentNotAllowed(name.clone())),
+                };
+            }
+            Token::Whitespace(_) => (),
+            Token::LeftParenthesis | Token::RightParenthesis => {
+                return Err(EvalError::MismatchedParentheses)
+            }
+            Token::Separator => return Err(EvalError::UnexpectedToken(String::from(","))),
+        }
+    }
+
+    match stack.len() {
+        0 => Err(EvalError::EmptyExpression),
+        1 => Ok(stack.remove(0)),
+        n => Err(EvalError::TooManyOperands(n - 1)),
+    }
+}
+
+/// Applies the operator of a `Token::Symbol`, `Token::Prefix` or
+/// `Token::Postfix` to the operands on top of `stack`. Kept out of
+/// `evaluate_scoped` for the same reason as `evaluate_reduction`.
+fn evaluate_operator<N: Number>(
+    token: &Token,
+    stack: &mut Vec<Value<N>>,
+    context: &Context<N>,
+) -> Result<(), EvalError> {
+    let unknown = |symbol: &String| EvalError::UnknownOperator(symbol.clone());
+    let value = match token {
+        Token::Symbol(symbol) => {
+            let operator = context
+                .get_operator(symbol)
+                .ok_or_else(|| unknown(symbol))?;
+            let right = pop_operand(stack, symbol)?;
+            let left = pop_operand(stack, symbol)?;
+            operator.evaluate(left, right, context)?
+        }
+        Token::Prefix(symbol) => {
+            let operator = context
+                .get_prefix_operator(symbol)
+                .ok_or_else(|| unknown(symbol))?;
+            operator.evaluate_unary(pop_operand(stack, symbol)?, context)?
+        }
+        Token::Postfix(symbol) => {
+            let operator = context
+                .get_postfix_operator(symbol)
+                .ok_or_else(|| unknown(symbol))?;
+            operator.evaluate_unary(pop_operand(stack, symbol)?, context)?
+        }
+        _ => return Err(EvalError::UnexpectedToken(token.to_string())),
+    };
+    stack.push(value);
+    Ok(())
+}
+
+/// Evaluates the `Token::Reduce` of a `series` or `prod` on top of `stack`,
+/// with the bound variable added to `locals`. Kept out of `evaluate_scoped` so
+/// that its stack frame, of which recursive user defined functions pile up
+/// many, stays small.
+fn evaluate_reduction<N: Number>(
+    name: &str,
+    variable: &str,
+    body: &VecDeque<Token>,
+    stack: &mut Vec<Value<N>>,
+    context: &Context<N>,
+    locals: &HashMap<String, Value<N>>,
+    mut assignments: Option<&mut HashMap<String, Value<N>>>,
+) -> Result<(), EvalError> {
+    let end = pop_operand(stack, name)?;
+    let start = pop_operand(stack, name)?;
+    let mut locals = locals.clone();
+    let value = reduce(name, (start, end), context, |value| {
+        locals.insert(String::from(variable), value);
+        evaluate_scoped(body, context, &locals, assignments.as_deref_mut())
+    })?;
+    stack.push(value);
+    Ok(())
+}
+
+/// Tokenizes an expression after expanding the context's macros. Positions in
+/// errors refer to the expanded expression.
+pub(crate) fn collect_tokens<N: Number>(
+    expression: &str,
+    context: &Context<N>,
+) -> Result<Vec<Token>, EvalError> {
+    context
+        .tokenize(&expand_macros(expression, context))
+        .try_collect()
+        .map_err(EvalError::Lex)
+}
+
+/// Evaluates an expression to a float, an integer or a truth value. Floats
+/// are of the number type of the context, `f64` for a `Context64` and `f32`
+/// for a `Context32`.
+///
+/// Number literals without decimals or an exponent, like `3` but not `3.0`
+/// or `1e3`, are integers, unless they are larger than 2^53. `+`, `-`, `*`,
+/// `//`, `%` and `^` of two integers and the factorial `!` of an integer give
+/// an integer, unless the result overflows or, for `^`, the exponent is
+/// negative. `/` of two integers gives an integer only if the division is
+/// exact, so `10 / 2` is `Int(5)` but `10 / 4` is `Float(2.5)`.
+/// `Context::set_integer_mode` turns these cases into errors instead.
+/// Anything else involving a float gives a float.
+///
+/// With the `bigint` feature, integers don't overflow at `i64` but become a
+/// `Value::Big` with up to `BIGINT_MAX_BITS` bits, so `2 ^ 200` and `50!` are
+/// exact. Larger integer literals are read from their digits rather than as
+/// floats, as long as they are below `f64::MAX`. Combined with a float, a
+/// `Value::Big` is rounded to the nearest float, which loses all but its 53
+/// most significant bits, or fails with `EvalError::PrecisionLoss` in
+/// `IntegerMode::Strict`.
+///
+/// Comparisons, `&&`, `||` and `!` give a `Bool`. Other operators take truth
+/// values as the integers 1 and 0, and conditions take numbers other than 0
+/// as true, unless `Context::set_strict_truth_values` makes both an error.
+///
+/// Functions made with `Function::new` or `Function::variadic` get the
+/// values of their arguments as numbers and give a float, while those made with
+/// `Function::with_values` get the values themselves. Variables have the
+/// value they were set to. `evaluate_f64` gives the result as a number.
+pub fn evaluate<N: Number>(expression: &str, context: &Context<N>) -> Result<Value<N>, EvalError> {
+    if let Some(cache) = &context.expression_cache {
+        return context.evaluate_cached(cache, expression);
+    }
+    let queue = shunting_yard(collect_tokens(expression, context)?, context)?;
+    evaluate_queue(&queue, context)
+}
+
+/// Like `evaluate`, with the result as a number, see `Value::to_f64`.
+pub fn evaluate_f64(expression: &str, context: &Context) -> Result<f64, EvalError> {
+    evaluate(expression, context).map(|value| value.to_f64())
+}
+
+/// Like `evaluate_f64`, but fails with `EvalError::NonFiniteResult` if the
+/// result is infinite or NaN, as for `1 / 0` or `sqrt(-1)`.
+pub fn evaluate_checked(expression: &str, context: &Context) -> Result<f64, EvalError> {
+    let value = evaluate_f64(expression, context)?;
+    if !value.is_finite() {
+        return Err(EvalError::NonFiniteResult {
+            value,
+            expression: String::from(expression),
+        });
+    }
+    Ok(value)
+}
+
+/// Like `evaluate_f64`, but also returns the postfix queue the expression was
+/// converted to, e.g. for showing how it was read.
+pub fn evaluate_explained(
+    expression: &str,
+    context: &Context,
+) -> Result<(f64, VecDeque<Token>), EvalError> {
+    let tokens = collect_tokens(expression, context)?;
+
+    let queue = shunting_yard(tokens, context)?;
+    let value = evaluate_queue(&queue, context)?;
+    Ok((value.to_f64(), queue))
+}
+
+/// Evaluates tokens that were produced without `tokenize`, e.g. by another
+/// lexer. Macros aren't expanded, since they apply to text.
+pub fn evaluate_tokens(tokens: Vec<Token>, context: &Context) -> Result<f64, EvalError> {
+    let queue = shunting_yard(tokens, context)?;
+    evaluate_queue(queue, context).map(|value| value.to_f64())
+}
+
+/// The variables and functions an expression refers to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Dependencies {
+    pub variables: HashSet<String>,
+    pub functions: HashSet<String>,
+}
+
+impl Dependencies {
+    fn collect(&mut self, queue: &VecDeque<Token>, context: &Context) {
+        for token in queue {
+            match token {
+                Token::Identifier(name) if context.function_exists(name) => {
+                    self.functions.insert(name.clone());
+                }
+                Token::Identifier(name) => {
+                    self.variables.insert(name.clone());
+                }
+                Token::Call(name, _) => {
+                    self.functions.insert(name.clone());
+                }
+                Token::Branch(then, otherwise) => {
+                    self.collect(then, context);
+                    self.collect(otherwise, context);
+                }
+                Token::Reduce(_, variable, body) => {
+                    let mut body_dependencies = Self::default();
+                    body_dependencies.collect(body, context);
+                    body_dependencies.variables.remove(variable);
+                    self.variables.extend(body_dependencies.variables);
+                    self.functions.extend(body_dependencies.functions);
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Finds the variables and functions an expression refers to, without
+/// evaluating it, so they don't need to exist. Identifiers followed by
+/// parentheses are functions, other identifiers are functions if the context
+/// has a function with that name and variables otherwise. Variables that are
+/// only assigned to aren't included.
+pub fn identifiers(expression: &str, context: &Context) -> Result<Dependencies, EvalError> {
+    let queue = shunting_yard(collect_tokens(expression, context)?, context)?;
+    let mut dependencies = Dependencies::default();
+    dependencies.collect(&queue, context);
+    Ok(dependencies)
+}
+
+/// Evaluates an expression with variables from `bindings`, which take
+/// precedence over the context's variables. Neither is changed, so a shared
+/// context can be used to evaluate the same expression for many values.
+pub fn evaluate_with(
+    expression: &str,
+    context: &Context,
+    bindings: &HashMap<String, f64>,
+) -> Result<f64, EvalError> {
+    let tokens = collect_tokens(expression, context)?;
+
+    let queue = shunting_yard(tokens, context)?;
+    evaluate_queue_with(&queue, context, bindings).map(|value| value.to_f64())
+}
+
+/// Evaluates an expression that may assign to variables, like `x = 2 + 3`.
+///
+/// Assignments only take effect if the whole expression evaluates without an
+/// error.
+pub fn evaluate_mut(expression: &str, context: &mut Context) -> Result<f64, EvalError> {
+    let tokens = collect_tokens(expression, context)?;
+    evaluate_statement(tokens, context).map(|value| value.to_f64())
+}
+
+/// Evaluates a program of statements separated by `;`, like
+/// `r = 2.5; area = pi * r ^ 2; area * 10`, and returns the value of the last
+/// one.
+///
+/// Each statement is evaluated like `evaluate_mut`, so an error stops the
+/// program but keeps the assignments of the statements before it. Empty
+/// statements, e.g. from `;;` or a trailing `;`, are skipped. If every
+/// statement is empty, the result is `EvalError::EmptyExpression`.
+pub fn evaluate_program(program: &str, context: &mut Context) -> Result<f64, EvalError> {
+    let mut tokens = collect_tokens(program, context)?;
+    if context.auto_close_parens {
+        close_parentheses(&mut tokens);
+    }
+    check_parentheses_balance(&tokens)?;
+    let separator_is_semicolon = context.number_locale.argument_separator() == ';';
+
+    let mut statements = vec![Vec::new()];
+    let mut depth = 0usize;
+    for token in tokens {
+        match &token {
+            Token::LeftParenthesis => depth += 1,
+            Token::RightParenthesis => depth = depth.saturating_sub(1),
+            Token::Symbol(symbol) if depth == 0 && symbol == ";" => {
+                statements.push(Vec::new());
+                continue;
+            }
+            Token::Separator if depth == 0 && separator_is_semicolon => {
+                statements.push(Vec::new());
+                continue;
+            }
+            _ => (),
+        }
+        statements.last_mut().unwrap().push(token);
+    }
+
+    let mut result = Err(EvalError::EmptyExpression);
+    for statement in statements {
+        if statement
+            .iter()
+            .all(|token| matches!(token, Token::Whitespace(_)))
+        {
+            continue;
+        }
+        result = Ok(evaluate_statement(statement, context)?.to_f64());
+    }
+    result
+}
+
+fn evaluate_statement(tokens: Vec<Token>, context: &mut Context) -> Result<Value, EvalError> {
+    let queue = shunting_yard(tokens, context)?;
+
+    let mut assignments = HashMap::new();
+    let result = evaluate_scoped(&queue, context, &HashMap::new(), Some(&mut assignments))?;
+    for (name, value) in assignments {
+        context.set_variable(&name, value);
+    }
+    Ok(result)
+}
+
+/// Lazily evaluates every line read from `reader` as a separate expression.
+///
+/// There is exactly one result per line, so blank lines yield
+/// `EvalError::EmptyExpression` and results can be matched to line numbers.
+pub fn evaluate_lines<'a, R: BufRead + 'a>(
+    reader: R,
+    context: &'a Context,
+) -> impl Iterator<Item = Result<f64, EvalError>> + 'a {
+    reader.lines().map(move |line| {
+        let line = line.map_err(|err| EvalError::Io(err.to_string()))?;
+        evaluate_f64(&line, context)
+    })
+}
+
+/// Evaluates an expression written in reverse polish notation, e.g. `3 4 + 2 *`.
+///
+/// Functions take exactly as many values off the stack as they declare.
+/// Variadic and overloaded functions take the minimum number of arguments they
+/// accept.
+pub fn evaluate_rpn(expression: &str, context: &Context) -> Result<f64, EvalError> {
+    let tokens = collect_tokens(expression, context)?;
+    evaluate_queue(tokens, context).map(|value| value.to_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{check_parentheses_balance, evaluate, evaluate_explained, evaluate_lines};
+    use crate::{evaluate_checked, evaluate_mut, lint, should_pop, FunctionValidationError};
+    use crate::{evaluate_f64, evaluate_tokens, Context64, Operator, Tolerance, Value};
+    use crate::{evaluate_program, evaluate_rpn, lex, tokenize, tokenize_collect_errors, Token};
+    use crate::{evaluate_queue, evaluate_queue_with, evaluate_with, parse, shunting_yard};
+    use crate::{identifiers, Arity, Context, Dependencies, EvalError, Expression, Function};
+    use crate::{Associativity, LexError, NumberLocale, OperatorError, OperatorKind, TrigMode};
+    use core::f64::consts::{E, PI};
+    use std::collections::{HashMap, HashSet};
+
+    fn calc(expression: &str) -> f64 {
+        let context = Context::default();
+        evaluate_f64(expression, &context).unwrap()
+    }
+
+    /// Asserts that `evaluate`, `Expr::evaluate` and `Expression::evaluate`
+    /// agree on the result of `expression`.
+    fn assert_all_paths(expression: &str, expected: Result<f64, EvalError>, context: &Context) {
+        assert_eq!(
+            evaluate_f64(expression, context),
+            expected,
+            "{}",
+            expression
+        );
+        assert_eq!(
+            parse(expression, context).unwrap().evaluate(context),
+            expected,
+            "{}",
+            expression
+        );
+        assert_eq!(
+            Expression::compile(expression, context)
+                .unwrap()
+                .evaluate(context),
+            expected,
+            "{}",
+            expression
+        );
+    }
+
+    #[test]
+    fn addition() {
+        assert_eq!(calc("4 + 9"), 4.0 + 9.0);
+    }
+
+    #[test]
+    fn subtraction() {
+        assert_eq!(calc("25 - 3"), 25.0 - 3.0);
+    }
+
+    #[test]
+    fn multiplication() {
+        assert_eq!(calc("2 * 3"), 2.0 * 3.0);
+    }
+
+    #[test]
+    fn division() {
+        assert_eq!(calc("10 / 2"), 10.0 / 2.0);
+    }
+
+    #[test]
+    fn exponentiation() {
+        assert_eq!(calc("2^10"), f64::powf(2.0, 10.0));
+    }
+
+    #[test]
+    fn scientific_notation() {
+        assert_eq!(calc("1.5e3"), 1500.0);
+        assert_eq!(calc("2E-2 * 100"), 2.0);
+        assert_eq!(calc("1e+2"), 100.0);
+    }
+
+    #[test]
+    fn number_errors_quote_original_text() {
+        let context = Context64::default();
+
+        let error = evaluate("1e400 + 1", &context).unwrap_err();
+        assert_eq!(
+            error,
+            EvalError::Lex(LexError::NumberOutOfRange(String::from("1e400")))
+        );
+        if let EvalError::Lex(lex_error) = error {
+            let message = lex_error.to_string();
+            assert!(message.contains("1e400"));
+            assert!(!message.contains("inf"));
+        }
+
+        assert_eq!(
+            evaluate("1.2.3", &context),
+            Err(EvalError::Lex(LexError::MalformedNumber(String::from(
+                "1.2."
+            ))))
+        );
+    }
+
+    #[test]
+    fn hexadecimal_and_scientific_literals() {
+        let context = Context::default();
+        let malformed = |text: &str| Err(LexError::MalformedNumber(String::from(text)));
+        let number = |expression| match lex(expression) {
+            Ok(tokens) => match tokens.as_slice() {
+                [Token::Number(value, _)] => Ok(*value),
+                _ => panic!("{:?}", tokens),
+            },
+            Err(error) => Err(error),
+        };
+
+        assert_eq!(number("0xFF"), Ok(255.0));
+        assert_eq!(number("1e3"), Ok(1000.0));
+        assert_eq!(number("0xfe3"), Ok(4067.0));
+        assert_eq!(number("0x1E3"), Ok(483.0));
+        assert_eq!(number("0e3"), Ok(0.0));
+        assert_eq!(number("0x"), malformed("0x"));
+        assert_eq!(number("0x1p4"), malformed("0x1p"));
+        assert_eq!(number("0x1.5"), malformed("0x1."));
+        assert_eq!(number("0xffg"), malformed("0xffg"));
+        assert_eq!(evaluate_f64("0xe+1", &context), Ok(15.0));
+        assert_eq!(
+            evaluate_f64("max(0xa,2e1)", &Context::default_with_math_functions()),
+            Ok(20.0)
+        );
+    }
+
+    #[test]
+    fn variables() {
+        let mut context = Context::default();
+        context.set_variable("pi", PI);
+
+        assert_eq!(evaluate_f64("2 * pi", &context), Ok(2.0 * PI));
+        context.set_lazy_variable("tau", || 2.0 * PI);
+        assert_eq!(
+            context.variables().collect::<Vec<_>>(),
+            vec![("pi", &Value::Float(PI))]
+        );
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(calc("2 + 3 * 4"), 2.0 + 3.0 * 4.0);
+    }
+
+    #[test]
+    fn parentheses() {
+        assert_eq!(calc("(2 + 3) * 4"), (2.0 + 3.0) * 4.0);
+        assert_eq!(calc("((2) + 3) * 4"), (2.0 + 3.0) * 4.0);
+        assert_eq!(calc("(5 + 3) * (4 - 1)"), (5.0 + 3.0) * (4.0 - 1.0));
+        assert_eq!(calc("2^(9+1)"), f64::powf(2.0, 9.0 + 1.0));
+    }
+
+    #[test]
+    fn functions() {
+        let context = Context::default_with_math_functions();
+
+        assert_eq!(evaluate_f64("sqrt(16)", &context), Ok(4.0));
+        assert_eq!(evaluate_f64("max(2, 3) * 2", &context), Ok(6.0));
+        assert_eq!(evaluate_f64("sqrt(max(4, 9)) + 1", &context), Ok(4.0));
+    }
+
+    #[test]
+    fn variadic_functions() {
+        let context = Context::default_with_math_functions();
+
+        assert_eq!(evaluate_f64("max(1, 7, 3, 2)", &context), Ok(7.0));
+        assert_eq!(evaluate_f64("min(4)", &context), Ok(4.0));
+        assert_eq!(evaluate_f64("max(1, min(2, 3, 4), 5)", &context), Ok(5.0));
+        assert_eq!(
+            evaluate_f64("min(8, max(2, 3, 4), 5) * 2", &context),
+            Ok(8.0)
+        );
+        assert_eq!(
+            evaluate("max()", &context),
+            Err(EvalError::WrongNumberOfArguments {
+                name: String::from("max"),
+                expected: vec![Arity::AtLeast(1)],
+                found: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn fixed_arity_is_checked() {
+        let context = Context64::default_with_math_functions();
+
+        assert_eq!(
+            evaluate("sqrt(4, 9)", &context),
+            Err(EvalError::WrongNumberOfArguments {
+                name: String::from("sqrt"),
+                expected: vec![Arity::Exact(1)],
+                found: 2,
+            })
+        );
+        assert_eq!(
+            evaluate("exp()", &context),
+            Err(EvalError::WrongNumberOfArguments {
+                name: String::from("exp"),
+                expected: vec![Arity::Exact(1)],
+                found: 0,
+            })
+        );
+        assert_eq!(
+            evaluate("foo(1)", &context),
+            Err(EvalError::UnknownFunction(String::from("foo")))
+        );
+    }
+
+    #[test]
+    fn overloading() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("e", E);
+
+        assert_eq!(evaluate_f64("log(e)", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("log(8, 2)", &context), Ok(3.0));
+        assert_eq!(evaluate_f64("round(2.5)", &context), Ok(3.0));
+        assert_eq!(evaluate_f64("round(2.567, 2)", &context), Ok(2.57));
+        assert_eq!(
+            evaluate("round(1, 2, 3)", &context),
+            Err(EvalError::WrongNumberOfArguments {
+                name: String::from("round"),
+                expected: vec![Arity::Exact(1), Arity::Exact(2)],
+                found: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn overloads_are_replaced_by_arity() {
+        let mut context = Context::default();
+        context.add_function(Function::new("f", 1, |_, _| Ok(1.0)));
+        context.add_function(Function::new("f", 2, |_, _| Ok(2.0)));
+        context.add_function(Function::variadic("f", 1, |_, _| Ok(3.0)));
+        context.add_function(Function::new("f", 2, |_, _| Ok(4.0)));
+
+        assert_eq!(evaluate_f64("f(0)", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("f(0, 0)", &context), Ok(4.0));
+        assert_eq!(evaluate_f64("f(0, 0, 0)", &context), Ok(3.0));
+    }
+
+    #[test]
+    fn user_defined_functions() {
+        let mut context = Context::default_with_math_functions();
+        let square = Function::user_defined("square", &["x"], "x ^ 2", &context).unwrap();
+        context.add_function(square);
+        let hypot = Function::user_defined(
+            "hypot",
+            &["a", "b"],
+            "sqrt(square(a) + square(b))",
+            &context,
+        )
+        .unwrap();
+        context.add_function(hypot);
+
+        assert_eq!(evaluate_f64("square(3) + 1", &context), Ok(10.0));
+        assert_eq!(evaluate_f64("hypot(3, 4)", &context), Ok(5.0));
+        assert_eq!(context.current_depth(), 0);
+    }
+
+    #[test]
+    fn recursion_limit() {
+        let mut context = Context64::default();
+        let forever =
+            Function::user_defined("forever", &["x"], "forever(x + 1)", &context).unwrap();
+        context.add_function(forever);
+        let ping = Function::user_defined("ping", &["x"], "pong(x)", &context).unwrap();
+        context.add_function(ping);
+        let pong = Function::user_defined("pong", &["x"], "ping(x)", &context).unwrap();
+        context.add_function(pong);
+
+        assert_eq!(context.max_depth(), 256);
+        assert_eq!(
+            evaluate("forever(1)", &context),
+            Err(EvalError::RecursionLimitExceeded)
+        );
+        assert_eq!(
+            evaluate("ping(1)", &context),
+            Err(EvalError::RecursionLimitExceeded)
+        );
+        assert_eq!(context.current_depth(), 0);
+    }
+
+    #[test]
+    fn recursion_limit_is_configurable() {
+        let mut context = Context::default();
+        let one = Function::user_defined("one", &["x"], "x + 1", &context).unwrap();
+        context.add_function(one);
+        let two = Function::user_defined("two", &["x"], "one(x) + 1", &context).unwrap();
+        context.add_function(two);
+        let three = Function::user_defined("three", &["x"], "two(x) + 1", &context).unwrap();
+        context.add_function(three);
+
+        context.set_max_depth(3);
+        assert_eq!(evaluate_f64("three(0)", &context), Ok(3.0));
+
+        context.set_max_depth(2);
+        assert_eq!(
+            evaluate("three(0)", &context),
+            Err(EvalError::RecursionLimitExceeded)
+        );
+        assert_eq!(evaluate_f64("two(0)", &context), Ok(2.0));
+    }
+
+    #[test]
+    fn assignment() {
+        let mut context = Context::default();
+
+        assert_eq!(evaluate_mut("x = 2 + 3", &mut context), Ok(5.0));
+        assert_eq!(context.get_variable("x"), Some(&Value::Int(5)));
+        assert_eq!(evaluate_mut("y = x * 2", &mut context), Ok(10.0));
+        assert_eq!(evaluate_mut("(z = 4) + 1", &mut context), Ok(5.0));
+        assert_eq!(context.get_variable("z"), Some(&Value::Int(4)));
+        assert_eq!(evaluate_f64("x + y", &context), Ok(15.0));
+    }
+
+    #[test]
+    fn assignment_within_expression() {
+        let mut context = Context::default_with_math_functions();
+
+        assert_eq!(evaluate_mut("x = 5", &mut context), Ok(5.0));
+        assert_eq!(evaluate_f64("x", &context), Ok(5.0));
+        assert_eq!(evaluate_mut("(x = 5) + 1", &mut context), Ok(6.0));
+        assert_eq!(context.get_variable("x"), Some(&Value::Int(5)));
+        assert_eq!(
+            evaluate_mut("(y = 2) * y + max(z = 3, 1)", &mut context),
+            Ok(7.0)
+        );
+        assert_eq!(context.get_variable("y"), Some(&Value::Int(2)));
+        assert_eq!(context.get_variable("z"), Some(&Value::Int(3)));
+        assert_eq!(
+            evaluate_mut("(w = 1) + unknown", &mut context),
+            Err(EvalError::UnknownVariable(String::from("unknown")))
+        );
+        assert_eq!(context.get_variable("w"), None);
+    }
+
+    #[test]
+    fn chained_assignment() {
+        let mut context = Context::default();
+
+        assert_eq!(evaluate_mut("a = b = 4", &mut context), Ok(4.0));
+        assert_eq!(context.get_variable("a"), Some(&Value::Int(4)));
+        assert_eq!(context.get_variable("b"), Some(&Value::Int(4)));
+    }
+
+    #[test]
+    fn invalid_assignments() {
+        let mut context = Context::default_with_math_functions();
+
+        assert_eq!(
+            evaluate_mut("2 = 3", &mut context),
+            Err(EvalError::InvalidAssignmentTarget(String::from("2")))
+        );
+        assert_eq!(
+            evaluate_mut("max = 5", &mut context),
+            Err(EvalError::InvalidAssignmentTarget(String::from("max")))
+        );
+        assert_eq!(
+            evaluate_mut("1 + x = 5", &mut context),
+            Err(EvalError::InvalidAssignmentTarget(String::from("x")))
+        );
+        assert_eq!(
+            evaluate("x = 5", &context),
+            Err(EvalError::AssignmentNotAllowed(String::from("x")))
+        );
+        assert_eq!(
+            evaluate_mut("x = 1 / foo", &mut context),
+            Err(EvalError::UnknownVariable(String::from("foo")))
+        );
+        assert_eq!(context.get_variable("x"), None);
+    }
+
+    #[test]
+    fn arithmetic_only() {
+        let mut context = Context::arithmetic_only();
+        context.set_variable("x", 1.0);
+        context.add_function(Function::new("sin", 1, |args: &[f64], _| Ok(args[0].sin())));
+
+        assert_eq!(evaluate_f64("2+2", &context), Ok(4.0));
+        assert_eq!(evaluate_f64("(1 + 2) * 3 / 4 - 5", &context), Ok(-2.75));
+        assert_eq!(
+            evaluate("sin(0)", &context),
+            Err(EvalError::FeatureDisabled(String::from("sin")))
+        );
+        assert_eq!(
+            evaluate("x + 1", &context),
+            Err(EvalError::FeatureDisabled(String::from("x")))
+        );
+        assert_eq!(
+            evaluate_rpn("x 1 +", &context),
+            Err(EvalError::FeatureDisabled(String::from("x")))
+        );
+        assert_eq!(
+            evaluate("2 ^ 3", &context),
+            Err(EvalError::UnknownOperator(String::from("^")))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let context = Context64::default_with_math_functions();
+
+        assert_eq!(
+            evaluate("2 * foo", &context),
+            Err(EvalError::UnknownVariable(String::from("foo")))
+        );
+        assert_eq!(
+            evaluate("(2 + 3", &context),
+            Err(EvalError::UnclosedParenthesis { position: 0 })
+        );
+        assert_eq!(
+            evaluate("2 + 3)", &context),
+            Err(EvalError::UnexpectedClosingParenthesis { position: 5 })
+        );
+        assert_eq!(
+            evaluate("2 $ 3", &context),
+            Err(EvalError::UnknownOperator(String::from("$")))
+        );
+        assert_eq!(evaluate("", &context), Err(EvalError::EmptyExpression));
+    }
+
+    #[test]
+    fn rpn_operators() {
+        let context = Context::default();
+
+        assert_eq!(evaluate_rpn("3 4 + 2 *", &context), Ok(14.0));
+        assert_eq!(evaluate_rpn("2 3 4 * +", &context), Ok(14.0));
+        assert_eq!(evaluate_rpn("2 10 ^", &context), Ok(1024.0));
+    }
+
+    #[test]
+    fn rpn_variables() {
+        let mut context = Context::default();
+        context.set_variable("pi", PI);
+
+        assert_eq!(evaluate_rpn("2 pi *", &context), Ok(2.0 * PI));
+    }
+
+    #[test]
+    fn rpn_functions() {
+        let mut context = Context::default_with_math_functions();
+        context.add_function(Function::new("clamp", 3, |args: &[f64], _| {
+            Ok(args[0].max(args[1]).min(args[2]))
+        }));
+
+        assert_eq!(evaluate_rpn("16 sqrt 1 +", &context), Ok(5.0));
+        assert_eq!(evaluate_rpn("1 log 7 +", &context), Ok(7.0));
+        assert_eq!(evaluate_rpn("12 0 10 clamp", &context), Ok(10.0));
+        assert_eq!(
+            evaluate_rpn("2 clamp", &context),
+            Err(EvalError::WrongNumberOfArguments {
+                name: String::from("clamp"),
+                expected: vec![Arity::Exact(3)],
+                found: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn rpn_stack_validation() {
+        let context = Context::default();
+
+        assert_eq!(
+            evaluate_rpn("1 2", &context),
+            Err(EvalError::TooManyOperands(1))
+        );
+        assert_eq!(
+            evaluate_rpn("1 +", &context),
+            Err(EvalError::MissingOperand(String::from("+")))
+        );
+        assert_eq!(evaluate_rpn("", &context), Err(EvalError::EmptyExpression));
+        assert_eq!(
+            evaluate_rpn("1 ( 2 +", &context),
+            Err(EvalError::MismatchedParentheses)
+        );
+    }
+
+    #[test]
+    fn evaluate_queue_accepts_any_tokens() {
+        let context = Context::default();
+        let queue = shunting_yard(lex("2 * (3 + 4)").unwrap(), &context).unwrap();
+
+        assert_eq!(evaluate_queue(&queue, &context), Ok(Value::Int(14)));
+        let first = evaluate_queue(queue.iter().take(1), &context);
+        assert_eq!(first, Ok(Value::Int(2)));
+        assert_eq!(evaluate_queue(queue, &context), Ok(Value::Int(14)));
+
+        let tokens = vec![
+            Token::Number(6.0, String::from("6")),
+            Token::Identifier(String::from("x")),
+            Token::Symbol(String::from("/")),
+        ];
+        let mut bindings = HashMap::new();
+        bindings.insert(String::from("x"), 3.0);
+        let result = evaluate_queue_with(tokens, &context, &bindings);
+        assert_eq!(result, Ok(Value::Float(2.0)));
+    }
+
+    #[test]
+    fn commutative_operators() {
+        let mut context = Context::default();
+        let commutative =
+            |context: &Context, symbol| context.get_operator(symbol).map(Operator::is_commutative);
+
+        assert_eq!(commutative(&context, "+"), Some(true));
+        assert_eq!(commutative(&context, "*"), Some(true));
+        for &symbol in &["-", "/", "^", "**"] {
+            assert_eq!(commutative(&context, symbol), Some(false));
+        }
+
+        context.add_operator("<>", 10, Associativity::Left).unwrap();
+        assert_eq!(commutative(&context, "<>"), Some(false));
+        assert_eq!(context.set_operator_commutative("<>", true), Ok(()));
+        assert_eq!(commutative(&context, "<>"), Some(true));
+        assert_eq!(
+            context.set_operator_commutative("?", true),
+            Err(OperatorError::UnknownTarget(String::from("?")))
+        );
+    }
+
+    #[test]
+    fn popping_operators() {
+        let operator =
+            |precedence, associativity| Operator::<f64>::new("@", precedence, associativity);
+        let (left, right, none) = (
+            Associativity::Left,
+            Associativity::Right,
+            Associativity::None,
+        );
+
+        assert!(should_pop(&operator(11, left), &operator(10, left)));
+        assert!(should_pop(&operator(11, right), &operator(10, right)));
+        assert!(!should_pop(&operator(10, left), &operator(11, left)));
+        assert!(!should_pop(&operator(10, right), &operator(11, right)));
+        assert!(should_pop(&operator(10, left), &operator(10, left)));
+        assert!(!should_pop(&operator(10, right), &operator(10, right)));
+        assert!(!should_pop(&operator(10, none), &operator(10, none)));
+        assert!(!should_pop(&operator(10, none), &operator(10, left)));
+        assert!(should_pop(&operator(11, none), &operator(10, none)));
+    }
+
+    #[test]
+    fn explained_evaluation() {
+        let context = Context::default();
+        let (value, queue) = evaluate_explained("2 + 3 * 4", &context).unwrap();
+
+        assert_eq!(value, 14.0);
+        assert_eq!(
+            queue.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            vec!["2", "3", "4", "*", "+"]
+        );
+        assert_eq!(
+            evaluate_explained("2 +", &context),
+            Err(EvalError::MissingOperand(String::from("+")))
+        );
+    }
+
+    #[test]
+    fn us_locale() {
+        let mut context = Context::default_with_math_functions();
+        context.set_number_locale(NumberLocale::us());
+
+        assert_eq!(evaluate_f64("1,000.50", &context), Ok(1000.5));
+        assert_eq!(evaluate_f64("1,234,567 + 1", &context), Ok(1234568.0));
+        assert_eq!(evaluate_f64("max(1,2)", &context), Ok(2.0));
+        assert_eq!(evaluate_f64("max(1,2000)", &context), Ok(2000.0));
+        assert_eq!(evaluate_f64("max(1,000, 2)", &context), Ok(1000.0));
+        assert_eq!(
+            evaluate("1.000.5", &context),
+            Err(EvalError::Lex(LexError::MalformedNumber(String::from(
+                "1.000."
+            ))))
+        );
+    }
+
+    #[test]
+    fn german_locale() {
+        let mut context = Context::default_with_math_functions();
+        context.set_number_locale(NumberLocale::german());
+
+        assert_eq!(evaluate_f64("1.000,50", &context), Ok(1000.5));
+        assert_eq!(evaluate_f64("2,5 * 2", &context), Ok(5.0));
+        assert_eq!(evaluate_f64("max(1,5; 2)", &context), Ok(2.0));
+        assert_eq!(evaluate_f64("1,5e3", &context), Ok(1500.0));
+        assert_eq!(
+            evaluate("1,", &context),
+            Err(EvalError::Lex(LexError::MalformedNumber(String::from(
+                "1,"
+            ))))
+        );
+        assert_eq!(
+            evaluate("1.5", &context),
+            Err(EvalError::UnknownOperator(String::from(".")))
+        );
+    }
+
+    #[test]
+    fn lines() {
+        let context = Context::default();
+        let input: &[u8] = b"1 + 2\n3 * 4\n\n2 ^ 10\n1 +";
+        let results: Vec<_> = evaluate_lines(input, &context).collect();
+
+        assert_eq!(
+            results,
+            vec![
+                Ok(3.0),
+                Ok(12.0),
+                Err(EvalError::EmptyExpression),
+                Ok(1024.0),
+                Err(EvalError::MissingOperand(String::from("+"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn program() {
+        let mut context = Context::default();
+        context.set_variable("pi", PI);
+
+        assert_eq!(
+            evaluate_program("r = 2.5; area = pi * r ^ 2; area * 10", &mut context),
+            Ok(PI * 2.5 * 2.5 * 10.0)
+        );
+        assert_eq!(context.get_variable("r"), Some(&Value::Float(2.5)));
+        assert_eq!(evaluate_program("x = 1;; x + 1;", &mut context), Ok(2.0));
+        assert_eq!(
+            evaluate_program(" ; ", &mut context),
+            Err(EvalError::EmptyExpression)
+        );
+    }
+
+    #[test]
+    fn program_error_keeps_earlier_statements() {
+        let mut context = Context::default();
+
+        assert_eq!(
+            evaluate_program("a = 1; b = a + c; a + 1", &mut context),
+            Err(EvalError::UnknownVariable(String::from("c")))
+        );
+        assert_eq!(context.get_variable("a"), Some(&Value::Int(1)));
+        assert_eq!(context.get_variable("b"), None);
+    }
+
+    #[test]
+    fn program_with_semicolon_argument_separator() {
+        let mut context = Context::default_with_math_functions();
+        context.set_number_locale(NumberLocale::german());
+
+        assert_eq!(
+            evaluate_program("x = 1,5; max(x; 2) * 2", &mut context),
+            Ok(4.0)
+        );
+    }
+
+    #[test]
+    fn checked_functions() {
+        let mut context = Context::default();
+
+        let inverse = Function::new("inv", 1, |args, _| Ok(1.0 / args[0]));
+        assert_eq!(
+            context.add_function_checked(inverse, &[&[2.0], &[0.0]]),
+            Err(FunctionValidationError::ReturnedInfinity { inputs: vec![0.0] })
+        );
+        assert!(!context.function_exists("inv"));
+
+        let root = Function::new("root", 1, |args: &[f64], _| Ok(args[0].sqrt()));
+        assert_eq!(
+            context.add_function_checked(root, &[&[-1.0]]),
+            Err(FunctionValidationError::ReturnedNan { inputs: vec![-1.0] })
+        );
+
+        let square = Function::new("square", 1, |args, _| Ok(args[0] * args[0]));
+        assert_eq!(
+            context.add_function_checked(square, &[&[1.0, 2.0]]),
+            Err(FunctionValidationError::Failed {
+                inputs: vec![1.0, 2.0],
+                error: EvalError::WrongNumberOfArguments {
+                    name: String::from("square"),
+                    expected: vec![Arity::Exact(1)],
+                    found: 2,
+                },
+            })
+        );
+
+        let square = Function::new("square", 1, |args, _| Ok(args[0] * args[0]));
+        assert_eq!(
+            context.add_function_checked(square, &[&[3.0], &[-2.5]]),
+            Ok(())
+        );
+        assert_eq!(evaluate_f64("square(4)", &context), Ok(16.0));
+    }
+
+    #[test]
+    fn operator_alias() {
+        let mut context = Context::default();
+
+        assert_eq!(evaluate_f64("2 ** 10", &context), Ok(1024.0));
+        assert_eq!(evaluate_f64("2 ** 3 ** 2", &context), Ok(512.0));
+        assert_eq!(evaluate_f64("2**3*2", &context), Ok(16.0));
+        assert_eq!(evaluate_f64("2 * 3", &context), Ok(6.0));
+        assert_eq!(
+            evaluate("2 * * 3", &context),
+            Err(EvalError::MissingOperand(String::from("*")))
+        );
+
+        context.add_operator_alias("<>", "!=").unwrap();
+        let mut aliases: Vec<_> = context.operator_aliases().collect();
+        aliases.sort_unstable();
+        assert_eq!(aliases, vec![("**", "^"), ("<>", "!=")]);
+        assert!(context.operators().all(|op| op.symbol() != "**"));
+    }
+
+    #[test]
+    fn operator_introspection() {
+        let context = Context64::default();
+        let mut symbols: Vec<_> = context.operators().map(|op| op.symbol()).collect();
+        symbols.sort_unstable();
+        assert_eq!(
+            symbols,
+            vec![
+                "!=", "%", "&&", "*", "+", "-", "/", "//", "<", "<=", "=", "==", ">", ">=", "^",
+                "||"
+            ]
+        );
+
+        let power = context.get_operator("^").unwrap();
+        assert_eq!(power.precedence(), 12);
+        assert_eq!(power.associativity(), Associativity::Right);
+    }
+
+    #[test]
+    fn operator_validation() {
+        let mut context = Context64::default();
+
+        for &symbol in &["", "(", "a+", "1", "+ +", ",", ";"] {
+            assert_eq!(
+                context.add_operator(symbol, 5, Associativity::Left),
+                Err(OperatorError::InvalidSymbol(String::from(symbol)))
+            );
+        }
+        assert_eq!(context.add_operator("<>", 5, Associativity::Left), Ok(()));
+        assert_eq!(
+            context.add_operator("+", 5, Associativity::Left),
+            Err(OperatorError::AlreadyDefined(String::from("+")))
+        );
+        assert_eq!(context.get_operator("+").unwrap().precedence(), 10);
+
+        assert!(context.remove_operator("+").is_some());
+        assert_eq!(context.add_operator("+", 5, Associativity::Left), Ok(()));
+        assert_eq!(context.get_operator("+").unwrap().precedence(), 5);
     }
 
     #[test]
-    fn parentheses() {
-        assert_eq!(calc("(2 + 3) * 4"), (2.0 + 3.0) * 4.0);
-        assert_eq!(calc("((2) + 3) * 4"), (2.0 + 3.0) * 4.0);
-        assert_eq!(calc("(5 + 3) * (4 - 1)"), (5.0 + 3.0) * (4.0 - 1.0));
-        assert_eq!(calc("2^(9+1)"), f64::powf(2.0, 9.0 + 1.0));
+    fn operator_alias_validation() {
+        let mut context = Context64::default();
+
+        assert_eq!(
+            context.add_operator_alias("@@", "@"),
+            Err(OperatorError::UnknownTarget(String::from("@")))
+        );
+        assert_eq!(
+            context.add_operator_alias("*", "^"),
+            Err(OperatorError::AlreadyDefined(String::from("*")))
+        );
+        assert_eq!(
+            context.add_operator_alias("pow", "^"),
+            Err(OperatorError::InvalidSymbol(String::from("pow")))
+        );
+    }
+
+    #[test]
+    fn identifier_used_as_function() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", 2.0);
+
+        assert_eq!(
+            evaluate("x(3)", &context),
+            Err(EvalError::IdentifierUsedAsFunction(String::from("x")))
+        );
+        assert_eq!(
+            evaluate("y(3)", &context),
+            Err(EvalError::UnknownFunction(String::from("y")))
+        );
+        assert_eq!(evaluate_f64("sqrt(x * 8)", &context), Ok(4.0));
+
+        let f = Function::user_defined("f", &["a"], "a(1)", &context).unwrap();
+        context.add_function(f);
+        assert_eq!(
+            evaluate("f(1)", &context),
+            Err(EvalError::IdentifierUsedAsFunction(String::from("a")))
+        );
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        let context = Context::default_with_bitwise_operators();
+
+        assert_eq!(evaluate_f64("6 & 3", &context), Ok(2.0));
+        assert_eq!(evaluate_f64("6 | 3", &context), Ok(7.0));
+        assert_eq!(evaluate_f64("6 ^^ 3", &context), Ok(5.0));
+        assert_eq!(evaluate_f64("xor(6, 3)", &context), Ok(5.0));
+        assert_eq!(evaluate_f64("1 << 4", &context), Ok(16.0));
+        assert_eq!(evaluate_f64("256 >> 2 + 2", &context), Ok(16.0));
+        assert_eq!(evaluate_f64("~5", &context), Ok(-6.0));
+        assert_eq!(evaluate_f64("~~5 & ~0", &context), Ok(5.0));
+        assert_eq!(evaluate_f64("1 | 2 ^^ 3 & 6", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("2 ^ 3 & 12", &context), Ok(8.0));
+        assert_eq!(evaluate_f64("0xff & 0x0f", &context), Ok(15.0));
+        assert_eq!(evaluate_f64("0xFF - 0x10", &context), Ok(239.0));
+        assert_eq!(evaluate_f64("1 << 10", &context), Ok(1024.0));
+        assert_eq!(evaluate_f64("5 | 2 * 2", &context), Ok(5.0));
+        assert_eq!(evaluate_f64("1 < 2 & 3", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("bitnot(0)", &context), Ok(-1.0));
+    }
+
+    #[test]
+    fn bitwise_operand_errors() {
+        let context = Context::default_with_bitwise_operators();
+
+        assert_eq!(
+            evaluate("1.5 & 1", &context),
+            Err(EvalError::InvalidOperand {
+                operator: String::from("&"),
+                value: 1.5
+            })
+        );
+        assert_eq!(
+            evaluate("1 << 64", &context),
+            Err(EvalError::InvalidOperand {
+                operator: String::from("<<"),
+                value: 64.0
+            })
+        );
+        assert_eq!(
+            evaluate("~1e300", &context),
+            Err(EvalError::InvalidOperand {
+                operator: String::from("~"),
+                value: 1e300
+            })
+        );
+        assert_eq!(
+            evaluate("2 ^ 53 + 2 | 1", &context),
+            Err(EvalError::InvalidOperand {
+                operator: String::from("|"),
+                value: 9007199254740994.0
+            })
+        );
+        assert_eq!(
+            evaluate("1 << 60", &context),
+            Err(EvalError::ResultOutOfRange {
+                operator: String::from("<<"),
+                value: 1152921504606846976.0
+            })
+        );
+        assert_eq!(evaluate_f64("2 ^ 53 >> 53", &context), Ok(1.0));
+        assert_eq!(
+            evaluate("6 & 3", &Context64::default()),
+            Err(EvalError::UnknownOperator(String::from("&")))
+        );
+    }
+
+    #[test]
+    fn unary_minus() {
+        let mut context = Context::default();
+        context.set_variable("x", 3.0);
+
+        assert_eq!(evaluate_f64("-2", &context), Ok(-2.0));
+        assert_eq!(evaluate_f64("2 * -3", &context), Ok(-6.0));
+        assert_eq!(evaluate_f64("2 - -3", &context), Ok(5.0));
+        assert_eq!(evaluate_f64("-(1 + 2) * 2", &context), Ok(-6.0));
+        assert_eq!(evaluate_f64("2 ^ -1", &context), Ok(0.5));
+        assert_eq!(
+            evaluate_f64("max(-1, -2)", &Context::default_with_math_functions()),
+            Ok(-1.0)
+        );
+        assert_eq!(evaluate_f64("-2 ^ 2", &context), Ok(-4.0));
+        assert_eq!(evaluate_f64("-x ^ 2", &context), Ok(-9.0));
+        assert_eq!(evaluate_f64("(-2) ^ 2", &context), Ok(4.0));
+    }
+
+    #[test]
+    fn unary_minus_binding_tighter_than_power() {
+        let mut context = Context::default();
+        context.set_variable("x", 3.0);
+        context.set_unary_minus_binds_tighter_than_power(true);
+
+        assert_eq!(evaluate_f64("-2 ^ 2", &context), Ok(4.0));
+        assert_eq!(evaluate_f64("-x ^ 2", &context), Ok(9.0));
+        assert_eq!(evaluate_f64("(-2) ^ 2", &context), Ok(4.0));
+        assert_eq!(evaluate_f64("-2 * 3", &context), Ok(-6.0));
+        assert_eq!(evaluate_f64("2 ^ -1", &context), Ok(0.5));
+    }
+
+    #[test]
+    fn trig_mode() {
+        let mut context = Context::default_with_math_functions();
+        assert_eq!(context.trig_mode(), TrigMode::Radians);
+        assert_eq!(evaluate_f64("sin(0)", &context), Ok(0.0));
+
+        context.set_trig_mode(TrigMode::Degrees);
+        assert_eq!(evaluate_f64("sin(90)", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("cos(180)", &context), Ok(-1.0));
+        assert!((evaluate_f64("tan(45)", &context).unwrap() - 1.0).abs() < 1e-12);
+        assert_eq!(evaluate_f64("asin(1)", &context), Ok(90.0));
+        assert_eq!(evaluate_f64("acos(-1)", &context), Ok(180.0));
+        assert_eq!(evaluate_f64("atan(1)", &context), Ok(45.0));
+        assert_eq!(evaluate_f64("sqrt(16)", &context), Ok(4.0));
+
+        let context = Context::default_with_math_functions().with_trig_mode(TrigMode::Gradians);
+        assert_eq!(context.trig_mode(), TrigMode::Gradians);
+        assert_eq!(evaluate_f64("sin(100)", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("cos(200)", &context), Ok(-1.0));
+        assert!((evaluate_f64("tan(50)", &context).unwrap() - 1.0).abs() < 1e-12);
+        assert_eq!(evaluate_f64("asin(1)", &context), Ok(100.0));
+        assert_eq!(evaluate_f64("acos(-1)", &context), Ok(200.0));
+        assert_eq!(evaluate_f64("atan(1)", &context), Ok(50.0));
+    }
+
+    #[test]
+    fn non_associative_operators() {
+        let mut context = Context64::default();
+        context.add_operator("<>", 5, Associativity::None).unwrap();
+        context.add_operator("><", 5, Associativity::None).unwrap();
+
+        assert_eq!(
+            evaluate("1 <> 2 <> 3", &context),
+            Err(EvalError::ChainedNonAssociative {
+                first: String::from("<>"),
+                first_position: 2,
+                second: String::from("<>"),
+                second_position: 7,
+            })
+        );
+        assert_eq!(
+            evaluate("1 <> 2 >< 3", &context),
+            Err(EvalError::ChainedNonAssociative {
+                first: String::from("<>"),
+                first_position: 2,
+                second: String::from("><"),
+                second_position: 7,
+            })
+        );
+        assert_eq!(
+            evaluate("(1 <> 2) <> 3", &context),
+            Err(EvalError::UnknownOperator(String::from("<>")))
+        );
+    }
+
+    #[test]
+    fn associativity_regressions() {
+        assert_eq!(calc("2 - 3 - 4"), -5.0);
+        assert_eq!(calc("2 ^ 3 ^ 2"), 512.0);
+    }
+
+    #[test]
+    fn functions_see_context() {
+        let mut context = Context::default();
+        context.set_variable("rate", 0.25);
+        context.add_function(Function::new("tax", 1, |args, context| {
+            let rate = context
+                .get_variable("rate")
+                .ok_or_else(|| EvalError::UnknownVariable(String::from("rate")))?;
+            Ok(args[0] * rate.to_f64())
+        }));
+
+        assert_eq!(evaluate_f64("tax(100)", &context), Ok(25.0));
+        context.set_variable("rate", 0.5);
+        assert_eq!(evaluate_f64("tax(100) + 1", &context), Ok(51.0));
+    }
+
+    #[test]
+    fn lazy_if() {
+        let mut context = Context::default_with_math_functions();
+        context.add_function(Function::new("fail", 0, |_, _| {
+            Err(EvalError::UnknownVariable(String::from("fail")))
+        }));
+        context.set_variable("x", 0.0);
+
+        assert_eq!(evaluate_f64("if(x, 1 / x, 0)", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("if(1, 2, fail())", &context), Ok(2.0));
+        assert_eq!(evaluate_f64("if(0, fail(), 3)", &context), Ok(3.0));
+        assert_eq!(evaluate_f64("if(x, 1, if(x + 1, 2, 3))", &context), Ok(2.0));
+        assert_eq!(
+            evaluate_f64("max(if(1, 5, 6), if(0, 7, 8)) * 2", &context),
+            Ok(16.0)
+        );
+        assert_eq!(evaluate_f64("1 + if(-2, 2 + 3, 4) * 2", &context), Ok(11.0));
+        assert_eq!(
+            evaluate("if(1, 2)", &context),
+            Err(EvalError::WrongNumberOfArguments {
+                name: String::from("if"),
+                expected: vec![Arity::Exact(3)],
+                found: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn lazy_if_in_user_function() {
+        let mut context = Context::default();
+        let fact =
+            Function::user_defined("fact", &["n"], "if(n, n * fact(n - 1), 1)", &context).unwrap();
+        context.add_function(fact);
+
+        assert_eq!(evaluate_f64("fact(5)", &context), Ok(120.0));
+        assert_eq!(
+            evaluate_mut("y = if(0, 1, z = 4) + z", &mut context),
+            Ok(8.0)
+        );
+    }
+
+    #[test]
+    fn parentheses_balance() {
+        let check = |expression| {
+            let tokens: Vec<Token> = tokenize(expression).map(Result::unwrap).collect();
+            check_parentheses_balance(&tokens)
+        };
+
+        assert_eq!(check("max(1, (2 + 3)) * (4)"), Ok(()));
+        assert_eq!(
+            check("(1 + (2 * 3)"),
+            Err(EvalError::UnclosedParenthesis { position: 0 })
+        );
+        assert_eq!(
+            check("sqrt((4)"),
+            Err(EvalError::UnclosedParenthesis { position: 4 })
+        );
+        assert_eq!(
+            check("(1)) + (2"),
+            Err(EvalError::UnexpectedClosingParenthesis { position: 3 })
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", -2.0);
+
+        assert_eq!(evaluate_f64("1 < 2", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("2 < 1", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("2 <= 2", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("2 >= 3", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("1 + 1 == 2", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("1 != 1", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("1 < 2 == 2 > 1", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("(x > 0) * x", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("if(x < 0, -x, x)", &context), Ok(2.0));
+        assert_eq!(
+            evaluate("1 == 1 != 3", &context),
+            Err(EvalError::ChainedNonAssociative {
+                first: String::from("=="),
+                first_position: 2,
+                second: String::from("!="),
+                second_position: 7,
+            })
+        );
+    }
+
+    #[test]
+    fn equality_tolerance() {
+        let mut context = Context::default();
+        context.set_variable("x", 0.1);
+        let compiled = Expression::compile("x + 0.2 == 0.3", &context).unwrap();
+        assert_eq!(context.equality_tolerance(), None);
+        assert_eq!(evaluate_f64("0.1 + 0.2 == 0.3", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("0.1 + 0.2 != 0.3", &context), Ok(1.0));
+        assert_eq!(compiled.evaluate(&context), Ok(0.0));
+
+        context.set_equality_tolerance(Some(Tolerance {
+            relative: 1e-9,
+            absolute: 0.0,
+        }));
+        assert_eq!(evaluate_f64("0.1 + 0.2 == 0.3", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("0.1 + 0.2 != 0.3", &context), Ok(0.0));
+        assert_eq!(compiled.evaluate(&context), Ok(1.0));
+        assert_eq!(evaluate_f64("1e12 + 1 == 1e12", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("1 == 1.001", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("0.1 + 0.2 - 0.3 == 0", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("0 / 0 == 0 / 0", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("0.1 + 0.2 < 0.3 + 1e-12", &context), Ok(1.0));
+
+        context.set_equality_tolerance(Some(Tolerance {
+            relative: 1e-9,
+            absolute: 1e-12,
+        }));
+        for &(expression, expected) in &[
+            ("1 / 0 == 5", 0.0),
+            ("5 == -1 / 0", 0.0),
+            ("1 / 0 == 1 / 0", 1.0),
+            ("-1 / 0 == -1 / 0", 1.0),
+            ("1 / 0 == -1 / 0", 0.0),
+            ("1 / 0 != 1e308", 1.0),
+            ("0 / 0 == 1 / 0", 0.0),
+            ("0 / 0 != 0", 1.0),
+        ] {
+            assert_eq!(
+                evaluate_f64(expression, &context),
+                Ok(expected),
+                "{}",
+                expression
+            );
+        }
+
+        context.set_equality_tolerance(Some(Tolerance {
+            relative: 0.0,
+            absolute: 1e-12,
+        }));
+        assert_eq!(evaluate_f64("0.1 + 0.2 - 0.3 == 0", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("1e12 + 1 == 1e12", &context), Ok(0.0));
+
+        context.set_equality_tolerance(None);
+        assert_eq!(evaluate_f64("0.1 + 0.2 == 0.3", &context), Ok(0.0));
+    }
+
+    #[test]
+    fn paren_free_calls() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", 9.0);
+        assert_eq!(
+            evaluate("sqrt 16", &context),
+            Err(EvalError::WrongNumberOfArguments {
+                name: String::from("sqrt"),
+                expected: vec![Arity::Exact(1)],
+                found: 0,
+            })
+        );
+
+        context.set_paren_free_calls(true);
+        assert_eq!(evaluate_f64("sqrt 16", &context), Ok(4.0));
+        assert_eq!(evaluate_f64("sin 0", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("sqrt 16 + 1", &context), Ok(5.0));
+        assert_eq!(evaluate_f64("2 * sqrt x ^ 2", &context), Ok(18.0));
+        assert_eq!(evaluate_f64("sqrt (9 + 7) * 2", &context), Ok(8.0));
+        assert_eq!(evaluate_f64("abs -3", &context), Ok(3.0));
+        assert_eq!(evaluate_f64("sqrt sqrt 16", &context), Ok(2.0));
+        assert_eq!(evaluate_f64("max(sqrt 4, 1)", &context), Ok(2.0));
+    }
+
+    #[test]
+    fn percent_of() {
+        let mut context = Context::default_with_math_functions();
+        context.add_postfix_operator("%", 13).unwrap();
+        assert_eq!(evaluate_f64("50%", &context), Ok(0.5));
+        assert_eq!(evaluate_f64("200 + 10%", &context), Ok(200.1));
+        assert_eq!(evaluate_f64("7 % 4 + 50%", &context), Ok(3.5));
+        assert_eq!(evaluate_f64("50% - 5", &context), Ok(-4.5));
+
+        context.set_percent_of(true);
+        for &(expression, expected) in &[
+            ("200 + 10%", 220.0),
+            ("200 - 10%", 180.0),
+            ("(200 + 10%) + 5%", 231.0),
+            ("200 + 10% + 5%", 231.0),
+            ("200 + 10% - 5%", 209.0),
+            ("50% - 5", -4.5),
+            ("100 * 10%", 10.0),
+            ("200 + 10% * 2", 200.2),
+            ("200 + (10%)", 220.0),
+            ("(150 + 50) + 10%", 220.0),
+            ("2 * 100 + 10%", 220.0),
+            ("7 % 4 + 50%", 4.5),
+            ("50%", 0.5),
+            ("max(200 + 10%, 1)", 220.0),
+            ("if(1, 200 + 10%, 0)", 220.0),
+        ] {
+            assert_eq!(
+                evaluate_f64(expression, &context),
+                Ok(expected),
+                "{}",
+                expression
+            );
+            assert_eq!(
+                parse(expression, &context).unwrap().evaluate(&context),
+                Ok(expected),
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn implicit_multiplication() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", 4.0);
+        assert_eq!(evaluate("2x", &context), Err(EvalError::TooManyOperands(1)));
+        assert_eq!(
+            evaluate("x(2)", &context),
+            Err(EvalError::IdentifierUsedAsFunction(String::from("x")))
+        );
+
+        context.set_implicit_multiplication(true);
+        assert_eq!(evaluate_f64("2x", &context), Ok(8.0));
+        assert_eq!(evaluate_f64("1/2x", &context), Ok(2.0));
+        assert_eq!(evaluate_f64("2x^2", &context), Ok(32.0));
+        assert_eq!(evaluate_f64("-2x", &context), Ok(-8.0));
+        assert_eq!(evaluate_f64("2(3)", &context), Ok(6.0));
+        assert_eq!(evaluate_f64("(2)(3)", &context), Ok(6.0));
+        assert_eq!(evaluate_f64("x(x + 1)", &context), Ok(20.0));
+        assert_eq!(evaluate_f64("2 sqrt(x)", &context), Ok(4.0));
+        assert_eq!(evaluate_f64("max(1, 2)x", &context), Ok(8.0));
+        assert_eq!(evaluate_f64("3!x", &context), Ok(24.0));
+
+        context.set_implicit_mul_binds_tighter(true);
+        assert_eq!(evaluate_f64("1/2x", &context), Ok(0.125));
+        assert_eq!(evaluate_f64("1/2x*3", &context), Ok(0.375));
+        assert_eq!(evaluate_f64("2x^2", &context), Ok(32.0));
+        assert_eq!(evaluate_f64("8/2(1 + 1)", &context), Ok(2.0));
+    }
+
+    #[test]
+    fn collect_all_lex_errors() {
+        let (tokens, errors) = tokenize_collect_errors("1.2.3 + 4. * 1e400");
+
+        assert_eq!(
+            errors,
+            vec![
+                LexError::MalformedNumber(String::from("1.2.")),
+                LexError::MalformedNumber(String::from("4.")),
+                LexError::NumberOutOfRange(String::from("1e400")),
+            ]
+        );
+        let text: String = tokens.iter().map(Token::to_string).collect();
+        assert_eq!(text, "3 +  * ");
+    }
+
+    #[test]
+    fn logical_operators() {
+        let mut context = Context::default();
+
+        assert_eq!(evaluate_f64("1 && 2", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("1 && 0", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("0 || -3", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("0 || 0", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("not(0) + not(5)", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("1 || 0 && 0", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("(1 || 0) && 0", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("2 > 1 && 1 + 1 == 2", &context), Ok(1.0));
+
+        context.add_function(Function::new("div", 2, |args, _| {
+            if args[1] == 0.0 {
+                return Err(EvalError::InvalidOperand {
+                    operator: String::from("div"),
+                    value: args[1],
+                });
+            }
+            Ok(args[0] / args[1])
+        }));
+        context.set_variable("x", 0.0);
+        assert_eq!(evaluate_f64("x != 0 && div(1, x) > 2", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("x == 0 || div(1, x) > 2", &context), Ok(1.0));
+        assert_eq!(
+            evaluate_f64("if(1, x != 0 && div(1, x) > 2, 5)", &context),
+            Ok(0.0)
+        );
+        assert!(evaluate("x == 0 && div(1, x) > 2", &context).is_err());
+
+        context.set_variable("x", 0.25);
+        assert_eq!(evaluate_f64("x != 0 && div(1, x) > 2", &context), Ok(1.0));
+
+        let expr = parse("x == 0 || div(1, x) > 2", &context).unwrap();
+        context.set_variable("x", 0.0);
+        let queue = expr.to_postfix();
+        assert_eq!(evaluate_queue(&queue, &context), Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn function_aliases() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("e", E);
+        context.set_function_alias("log", "ln").unwrap();
+
+        assert_eq!(evaluate_f64("log(e)", &context), Ok(1.0));
+        assert_eq!(
+            evaluate("log(8, 2)", &context),
+            Err(EvalError::WrongNumberOfArguments {
+                name: String::from("log"),
+                expected: vec![Arity::Exact(1)],
+                found: 2,
+            })
+        );
+
+        let square = Function::user_defined("square", &["x"], "x * x", &context).unwrap();
+        context.add_function(square);
+        context.set_function_alias("sq", "square").unwrap();
+        assert_eq!(evaluate_f64("sq(3)", &context), Ok(9.0));
+        assert_eq!(
+            context.get_function("sq", 1).map(Function::name),
+            Some("sq")
+        );
+
+        assert_eq!(
+            context.set_function_alias("lg", "missing"),
+            Err(EvalError::UnknownFunction(String::from("missing")))
+        );
+    }
+
+    #[test]
+    fn modulo() {
+        let context = Context::default_with_math_functions();
+
+        assert_eq!(evaluate_f64("7 % 3", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("10 % 3", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("-7 % 3", &context), Ok(2.0));
+        assert_eq!(evaluate_f64("7 % -3", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("5.5 % 2", &context), Ok(1.5));
+        assert_eq!(evaluate_f64("2 * 7 % 4", &context), Ok(2.0));
+        assert_eq!(evaluate_f64("1 + 7 % 4", &context), Ok(4.0));
+        assert_eq!(evaluate_f64("rem(-7, 3)", &context), Ok(-1.0));
+        assert!(evaluate_f64("7 % 0", &context).unwrap().is_nan());
+
+        // With a postfix `%` as well, the token after it decides, regardless
+        // of spacing. Prefix operators that can be infix as well don't start
+        // an operand there.
+        let mut context = Context::default_with_math_functions();
+        context.add_postfix_operator("%", 13).unwrap();
+        for &(expression, expected) in &[
+            ("7 % -3", 0.07 - 3.0),
+            ("7 % +3", 3.07),
+            ("7 % !0", 0.0),
+            ("7 % - 3", 0.07 - 3.0),
+            ("7 % - -3", 3.07),
+            ("7 % (-3)", 1.0),
+            ("7 % (3)", 1.0),
+        ] {
+            assert_eq!(
+                evaluate_f64(expression, &context),
+                Ok(expected),
+                "{}",
+                expression
+            );
+            assert_eq!(
+                parse(expression, &context).unwrap().evaluate(&context),
+                Ok(expected),
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn exact_integer_powers() {
+        let context = Context::default_with_math_functions();
+
+        assert_eq!(evaluate_f64("10 ^ 23", &context), Ok(1e23));
+        assert_eq!(evaluate_f64("3 ^ 34", &context), Ok(16677181699666569.0));
+        assert_eq!(evaluate_f64("2 ^ 53", &context), Ok(9007199254740992.0));
+        assert_eq!(evaluate_f64("2 ^ 53 - 1", &context), Ok(9007199254740991.0));
+        // 2^53 + 1 has no f64, so it rounds to 2^53, while integer values
+        // keep it exact.
+        assert_eq!(evaluate_f64("2 ^ 53 + 1", &context), Ok(9007199254740992.0));
+        assert_eq!(
+            evaluate("2 ^ 53 + 1", &context),
+            Ok(Value::Int(9_007_199_254_740_993))
+        );
+        assert_eq!(evaluate_f64("pow(10, 23)", &context), Ok(1e23));
+        assert_eq!(evaluate_f64("2 ^ 62", &context), Ok(4611686018427387904.0));
+        assert_eq!(evaluate_f64("(-3) ^ 3", &context), Ok(-27.0));
+        assert_eq!(evaluate_f64("2 ^ -2", &context), Ok(0.25));
+        assert_eq!(evaluate_f64("4 ^ 0.5", &context), Ok(2.0));
+        assert_eq!(evaluate_f64("10 ^ 400", &context), Ok(f64::INFINITY));
+    }
+
+    #[test]
+    fn real_roots_of_negative_bases() {
+        let context = Context::default_with_math_functions();
+        let close = |expression, expected: f64| {
+            let value = evaluate_f64(expression, &context).unwrap();
+            assert!(
+                (value - expected).abs() < 1e-12,
+                "{} = {}",
+                expression,
+                value
+            );
+        };
+
+        close("(-8) ^ (1/3)", -2.0);
+        close("(-8) ^ (2/3)", 4.0);
+        close("(-32) ^ 0.2", -2.0);
+        close("(-27) ^ (-1/3)", -1.0 / 3.0);
+        close("pow(-8, 1/3)", -2.0);
+        close("(-2) ^ 3", -8.0);
+        assert!(evaluate_f64("(-4) ^ 0.5", &context).unwrap().is_nan());
+        assert!(evaluate_f64("(-8) ^ 0.3333", &context).unwrap().is_nan());
+        assert!(evaluate_f64("(-8) ^ (1/6)", &context).unwrap().is_nan());
+    }
+
+    #[test]
+    fn context_is_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Context>();
+
+        let mut context = Context::default_with_math_functions();
+        let fact = Function::user_defined("fact", &["n"], "if(n, n * fact(n - 1), 1)", &context);
+        context.add_function(fact.unwrap());
+        let context = std::sync::Arc::new(context);
+
+        let threads: Vec<_> = (1..=4)
+            .map(|n| {
+                let context = std::sync::Arc::clone(&context);
+                std::thread::spawn(move || evaluate_f64(&format!("fact({})", n), &context))
+            })
+            .collect();
+        let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(results, vec![Ok(1.0), Ok(2.0), Ok(6.0), Ok(24.0)]);
+    }
+
+    #[test]
+    fn integer_division() {
+        let mut context = Context::default();
+
+        assert_eq!(evaluate_f64("7 // 2", &context), Ok(3.0));
+        assert_eq!(evaluate_f64("-7 // 2", &context), Ok(-4.0));
+        assert_eq!(evaluate_f64("7.5 // 2.5", &context), Ok(3.0));
+        assert_eq!(evaluate_f64("1 + 9 // 2 * 2", &context), Ok(9.0));
+        assert_eq!(evaluate_f64("1 // 0", &context), Ok(f64::INFINITY));
+
+        for &a in &[7.0, -7.0, 6.0, -6.0, 0.0, 5.5, -5.5] {
+            for &b in &[2.0, -2.0, 3.0, -3.0, 1.5] {
+                context.set_variable("a", a);
+                context.set_variable("b", b);
+                assert_eq!(evaluate_f64("(a // b) * b + a % b", &context), Ok(a));
+            }
+        }
+    }
+
+    #[test]
+    fn evaluation_with_bindings() {
+        let mut context = Context::default();
+        context.set_variable("x", 1.0);
+        context.set_variable("y", 10.0);
+        let mut bindings = HashMap::new();
+
+        for &x in &[2.0, 3.0, -4.0] {
+            bindings.insert(String::from("x"), x);
+            assert_eq!(
+                evaluate_with("x ^ 2 + y", &context, &bindings),
+                Ok(x * x + 10.0)
+            );
+        }
+        assert_eq!(context.get_variable("x"), Some(&Value::Float(1.0)));
+        assert_eq!(evaluate_f64("x", &context), Ok(1.0));
+
+        let queue = shunting_yard(tokenize("x * y").map(Result::unwrap).collect(), &context);
+        assert_eq!(
+            evaluate_queue_with(queue.unwrap(), &context, &bindings),
+            Ok(Value::Float(-40.0))
+        );
+        assert_eq!(
+            evaluate_with("x = 2", &context, &bindings),
+            Err(EvalError::AssignmentNotAllowed(String::from("x")))
+        );
+    }
+
+    #[test]
+    fn validation() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", 2.0);
+
+        assert_eq!(context.validate("2 * x + sqrt(x)"), Ok(()));
+        assert_eq!(context.validate("y = x + 1"), Ok(()));
+        assert_eq!(context.validate("y = if(0, 1, z = 4) + z"), Ok(()));
+        assert_eq!(
+            context.validate("2 * foo"),
+            Err(EvalError::UnknownVariable(String::from("foo")))
+        );
+        assert_eq!(
+            context.validate("if(x, 1, foo)"),
+            Err(EvalError::UnknownVariable(String::from("foo")))
+        );
+        assert_eq!(
+            context.validate("sqrt(1, 2)"),
+            Err(EvalError::WrongNumberOfArguments {
+                name: String::from("sqrt"),
+                expected: vec![Arity::Exact(1)],
+                found: 2,
+            })
+        );
+        assert_eq!(
+            context.validate("foo(1)"),
+            Err(EvalError::UnknownFunction(String::from("foo")))
+        );
+        assert_eq!(
+            context.validate("x(1)"),
+            Err(EvalError::IdentifierUsedAsFunction(String::from("x")))
+        );
+
+        for expression in &["2 * sqrt", "sqrt + 1", "if(x, 1, sqrt)"] {
+            assert_eq!(
+                context.validate(expression),
+                Err(EvalError::WrongNumberOfArguments {
+                    name: String::from("sqrt"),
+                    expected: vec![Arity::Exact(1)],
+                    found: 0,
+                }),
+                "{}",
+                expression
+            );
+        }
+        context.add_function(Function::new("two", 0, |_, _| Ok(2.0)));
+        assert_eq!(context.validate("2 * two"), Ok(()));
+        assert_eq!(evaluate_f64("2 * two", &context), Ok(4.0));
+    }
+
+    #[test]
+    fn auto_close_parentheses() {
+        let mut context = Context::default_with_math_functions();
+
+        assert_eq!(
+            evaluate("(2 + 3) * (4 - 1", &context),
+            Err(EvalError::UnclosedParenthesis { position: 10 })
+        );
+
+        context.set_auto_close_parens(true);
+        assert_eq!(evaluate_f64("(2 + 3) * (4 - 1", &context), Ok(15.0));
+        assert_eq!(evaluate_f64("max(1, 2", &context), Ok(2.0));
+        assert_eq!(evaluate_f64("2 * (3 + max(1, (4 - 2", &context), Ok(10.0));
+        assert_eq!(evaluate_f64("sqrt(sqrt(sqrt(256", &context), Ok(2.0));
+        assert_eq!(
+            evaluate("(1 + 2)) * (3", &context),
+            Err(EvalError::UnexpectedClosingParenthesis { position: 7 })
+        );
+    }
+
+    #[test]
+    fn dependencies() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", 1.0);
+        let set = |names: &[&str]| names.iter().map(|&name| String::from(name)).collect();
+
+        let dependencies = identifiers("a * x + sqrt(a) - f(b, g(c)) + if(d, e, a)", &context);
+        assert_eq!(
+            dependencies,
+            Ok(Dependencies {
+                variables: set(&["a", "b", "c", "d", "e", "x"]),
+                functions: set(&["sqrt", "f", "g"]),
+            })
+        );
+        assert_eq!(
+            identifiers("y = 2 * z", &context).map(|d| d.variables),
+            Ok(set(&["z"]))
+        );
+        assert_eq!(
+            identifiers("(1", &context),
+            Err(EvalError::UnclosedParenthesis { position: 0 })
+        );
+    }
+
+    #[test]
+    fn chained_comparisons() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", 5.0);
+
+        assert_eq!(evaluate_f64("1 < 5 < 10", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("1 < 20 < 10", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("(1 < 20) < 10", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("0 <= x - 5 < 1 + 1", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("1 < x > 2 >= 2", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("1 < x > 2 >= 3", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("max(1 < x < 10, 0) + 1", &context), Ok(2.0));
+        assert_eq!(evaluate_f64("1 < 2 < 3 == 1", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("3 < 2 < 1 || 1 < x < 9", &context), Ok(1.0));
+
+        for expression in &["1 < 5 < 10", "1 < 20 < 10", "0 <= x - 5 < 1 > 0"] {
+            let queue = parse(expression, &context).unwrap().to_postfix();
+            assert_eq!(
+                evaluate_queue(&queue, &context),
+                evaluate(expression, &context),
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn operators_from_strings() {
+        let mut context = Context::default_with_math_functions();
+        context
+            .add_operator_binary_from_string(
+                "@@",
+                3,
+                Associativity::Left,
+                "max($0, $1) + min($0, $1)",
+            )
+            .unwrap();
+        context
+            .add_operator_binary_from_string("<+>", 10, Associativity::Left, "sqrt($0^2 + $1^2)")
+            .unwrap();
+
+        assert_eq!(evaluate_f64("2 @@ 3", &context), Ok(5.0));
+        assert_eq!(evaluate_f64("1 + 1 @@ 3 * 2", &context), Ok(8.0));
+        assert_eq!(evaluate_f64("3 <+> 4 * 1", &context), Ok(5.0));
+        assert_eq!(evaluate_f64("0 <+> 3 <+> 4", &context), Ok(5.0));
+
+        assert_eq!(
+            context.add_operator_binary_from_string("@@", 3, Associativity::Left, "$0"),
+            Err(EvalError::Operator(OperatorError::AlreadyDefined(
+                String::from("@@")
+            )))
+        );
+        assert_eq!(
+            context.add_operator_binary_from_string("%%", 3, Associativity::Left, "$2 + 1"),
+            Err(EvalError::UnknownOperator(String::from("$")))
+        );
+        assert!(context.get_operator("%%").is_none());
+    }
+
+    #[test]
+    fn memoized_user_functions() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let mut context = Context::default();
+        context.add_function(Function::new("tick", 1, move |args, _| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Ok(args[0])
+        }));
+        let fib = "if(n < 2, tick(n), fib(n - 1) + fib(n - 2))";
+        let fib = Function::user_defined("fib", &["n"], fib, &context).unwrap();
+        context.add_function(fib);
+
+        assert_eq!(evaluate_f64("fib(15)", &context), Ok(610.0));
+        assert_eq!(calls.swap(0, Ordering::Relaxed), 987);
+
+        context.set_memoize_user_functions(true);
+        context.set_max_depth(200);
+        assert_eq!(evaluate_f64("fib(80)", &context), Ok(23416728348467685.0));
+        assert_eq!(calls.swap(0, Ordering::Relaxed), 2);
+        assert_eq!(evaluate_f64("fib(15) + fib(15)", &context), Ok(1220.0));
+        assert_eq!(calls.swap(0, Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn postfix_operators() {
+        let context = Context::default_with_math_functions();
+
+        assert_eq!(evaluate_f64("5!", &context), Ok(120.0));
+        assert_eq!(evaluate_f64("-3!", &context), Ok(-6.0));
+        assert_eq!(evaluate_f64("2 ^ 3!", &context), Ok(64.0));
+        assert_eq!(evaluate_f64("3!! - 1", &context), Ok(719.0));
+        assert_eq!(evaluate_f64("5! != 120", &context), Ok(0.0));
+        assert_eq!(
+            evaluate("0.5!", &context),
+            Err(EvalError::InvalidOperand {
+                operator: String::from("!"),
+                value: 0.5
+            })
+        );
+        assert_eq!(
+            parse("2 ^ 3!", &context).unwrap().evaluate(&context),
+            Ok(64.0)
+        );
+
+        // A `%` that is both postfix and infix is only the remainder if the
+        // next token starts an operand, which `-` never does.
+        let mut context = Context::default_with_math_functions();
+        context.add_postfix_operator("%", 13).unwrap();
+        for &(expression, expected) in &[
+            ("10% - 3", -2.9),
+            ("10%-3", -2.9),
+            ("10 % -3", -2.9),
+            ("10 % (-3)", 1.0),
+        ] {
+            assert_eq!(
+                evaluate_f64(expression, &context),
+                Ok(expected),
+                "{}",
+                expression
+            );
+            assert_eq!(
+                parse(expression, &context).unwrap().evaluate(&context),
+                Ok(expected),
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn symbols_with_several_kinds() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", 4.0);
+
+        assert_eq!(evaluate_f64("-x - -2", &context), Ok(-2.0));
+        assert_eq!(evaluate_f64("3 - -x", &context), Ok(7.0));
+        assert_eq!(evaluate_f64("(-x) - (2 - 1)", &context), Ok(-5.0));
+        assert_eq!(evaluate_f64("!0 + !x", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("!(x > 3) || x == 4", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("3! + !3", &context), Ok(6.0));
+        assert_eq!(evaluate_f64("!3!", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("!0!", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("(!0)!", &context), Ok(1.0));
+        assert_eq!(evaluate_f64("x! != 24", &context), Ok(0.0));
+
+        for expression in &["!3! + 1", "-x - -2", "!(x > 3) || x == 4"] {
+            assert_all_paths(expression, evaluate_f64(expression, &context), &context);
+        }
+    }
+
+    #[test]
+    fn repeated_prefix_operators() {
+        let context = Context::default();
+
+        for &(expression, value) in &[
+            ("--5", 5.0),
+            ("- -5", 5.0),
+            ("-+-3", 3.0),
+            ("+5", 5.0),
+            ("!!1", 1.0),
+            ("!!0", 0.0),
+            ("-!0", -1.0),
+            ("2 - - -3", -1.0),
+            ("2 ^ --2", 4.0),
+        ] {
+            assert_all_paths(expression, Ok(value), &context);
+        }
+        assert_eq!(
+            evaluate("--", &context),
+            Err(EvalError::MissingOperand(String::from("-")))
+        );
+    }
+
+    #[test]
+    fn unary_operators_from_strings() {
+        let mut context = Context::default_with_math_functions();
+        context
+            .add_unary_operator_from_string("\u{221a}", OperatorKind::Prefix, 13, "sqrt($0)")
+            .unwrap();
+        context
+            .add_unary_operator_from_string("%", OperatorKind::Postfix, 13, "$0 / 100")
+            .unwrap();
+
+        assert_eq!(evaluate_f64("\u{221a}16 + 1", &context), Ok(5.0));
+        assert_eq!(evaluate_f64("50% * 4", &context), Ok(2.0));
+        assert_eq!(evaluate_f64("(50%) + 7 % 4", &context), Ok(3.5));
+        assert_eq!(
+            parse("7 % 4 + 50%", &context).unwrap().evaluate(&context),
+            Ok(3.5)
+        );
+        assert_eq!(
+            context
+                .get_operator_of_kind("%", OperatorKind::Postfix)
+                .map(|operator| operator.kind()),
+            Some(OperatorKind::Postfix)
+        );
+        assert_eq!(
+            context.add_unary_operator_from_string("@", OperatorKind::Infix, 1, "$0"),
+            Err(EvalError::Operator(OperatorError::NotUnary(String::from(
+                "@"
+            ))))
+        );
+        assert_eq!(
+            context.add_unary_operator_from_string("%", OperatorKind::Postfix, 1, "$0"),
+            Err(EvalError::Operator(OperatorError::AlreadyDefined(
+                String::from("%")
+            )))
+        );
+    }
+
+    #[test]
+    fn lazy_variables() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut context = Context::default();
+        let counter = Arc::clone(&calls);
+        context.set_lazy_variable("sensor", move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            21.5
+        });
+
+        assert_eq!(evaluate_f64("if(1, 2, sensor)", &context), Ok(2.0));
+        let branch = Expression::compile("if(1, 2, sensor)", &context).unwrap();
+        assert_eq!(branch.evaluate(&context), Ok(2.0));
+        assert_eq!(context.validate("sensor + 1"), Ok(()));
+        assert!(lint("sensor + 1", &context).is_empty());
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+        assert_eq!(evaluate_f64("sensor * 2", &context), Ok(43.0));
+        let expression = Expression::compile("sensor + 0.5", &context).unwrap();
+        assert_eq!(expression.evaluate(&context), Ok(22.0));
+        assert_eq!(context.get_variable("sensor"), Some(&Value::Float(21.5)));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        context.set_variable("sensor", 3.0);
+        assert_eq!(evaluate_f64("sensor", &context), Ok(3.0));
+        context.set_lazy_variable("sensor", || 4.0);
+        assert_eq!(evaluate_f64("sensor", &context), Ok(4.0));
+        assert!(context.has_variable("sensor"));
+        assert!(!context.has_variable("other"));
+    }
+
+    #[test]
+    fn scoped_variables() {
+        let mut context = Context::default();
+        context.set_variable("x", 1.0);
+
+        let inner = context.with_scope(|context| {
+            context.set_variable("x", 99.0);
+            context.set_variable("y", 2.0);
+            evaluate_f64("x + y", context)
+        });
+        assert_eq!(inner, Ok(101.0));
+        assert_eq!(context.get_variable("x"), Some(&Value::Float(1.0)));
+        assert_eq!(context.get_variable("y"), None);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            context.with_scope(|context| {
+                context.set_variable("x", 5.0);
+                panic!("inside scope");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(context.get_variable("x"), Some(&Value::Float(1.0)));
+    }
+
+    #[test]
+    fn lexing() {
+        assert_eq!(
+            lex("2+2"),
+            Ok(vec![
+                Token::Number(2.0, String::from("2")),
+                Token::Symbol(String::from("+")),
+                Token::Number(2.0, String::from("2")),
+            ])
+        );
+        assert_eq!(
+            lex("1 + 2.3.4"),
+            Err(LexError::MalformedNumber(String::from("2.3.")))
+        );
+
+        let tokens = lex("max(x, 1) * 2").unwrap();
+        let operands: Vec<String> = tokens
+            .iter()
+            .filter(|token| token.is_operand())
+            .map(Token::to_string)
+            .collect();
+        assert_eq!(operands, vec!["max", "x", "1", "2"]);
+        assert_eq!(
+            tokens
+                .iter()
+                .filter(|token| token.is_operator_symbol())
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn peeking_tokens() {
+        let mut tokens = tokenize("12 + x");
+        let number = Token::Number(12.0, String::from("12"));
+        assert_eq!(tokens.peek(), Some(Ok(number.clone())));
+        assert_eq!(tokens.peek(), Some(Ok(number.clone())));
+        assert_eq!(tokens.position(), 0);
+        assert_eq!(tokens.next(), Some(Ok(number)));
+        assert_eq!(tokens.position(), 2);
+
+        tokens.nth(2);
+        assert_eq!(tokens.position(), 5);
+        assert_eq!(
+            tokens.peek(),
+            Some(Ok(Token::Identifier(String::from("x"))))
+        );
+        tokens.next();
+        assert_eq!(tokens.peek(), None);
+        assert_eq!(tokens.position(), 6);
+
+        let mut tokens = tokenize("1.2.3 + 4");
+        let error = Some(Err(LexError::MalformedNumber(String::from("1.2."))));
+        assert_eq!(tokens.peek(), error);
+        assert_eq!(tokens.next(), error);
+        assert_eq!(tokens.peek(), None);
+
+        let mut tokens = itertools::multipeek(tokenize("a*b"));
+        assert_eq!(
+            tokens.peek(),
+            Some(&Ok(Token::Identifier(String::from("a"))))
+        );
+        assert_eq!(tokens.peek(), Some(&Ok(Token::Symbol(String::from("*")))));
+        assert_eq!(
+            tokens.next(),
+            Some(Ok(Token::Identifier(String::from("a"))))
+        );
+    }
+
+    #[test]
+    fn bindings_fan_out_between_threads() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("y", 1.0);
+        let expression = Expression::compile("max(x, y) * 2", &context).unwrap();
+
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|x| {
+                    let (context, expression) = (&context, &expression);
+                    scope.spawn(move || {
+                        let bindings = vec![(String::from("x"), f64::from(x))];
+                        expression.evaluate_with(context, &bindings.into_iter().collect())
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert_eq!(results, vec![Ok(2.0), Ok(2.0), Ok(4.0), Ok(6.0)]);
+        assert_eq!(
+            evaluate_with("z", &context, &HashMap::new()),
+            Err(EvalError::UnknownVariable(String::from("z")))
+        );
+    }
+
+    #[test]
+    fn tokens_from_elsewhere() {
+        let context = Context::default();
+        let number = |value: f64| Token::Number(value, value.to_string());
+        let symbol = |symbol: &str| Token::Symbol(String::from(symbol));
+        let tokens = vec![
+            number(3.0),
+            symbol("*"),
+            Token::LeftParenthesis,
+            number(4.0),
+            symbol("+"),
+            number(1.0),
+            Token::RightParenthesis,
+        ];
+
+        assert_eq!(evaluate_tokens(tokens, &context), Ok(15.0));
+        assert_eq!(
+            evaluate_tokens(vec![number(1.0), symbol("+")], &context),
+            Err(EvalError::MissingOperand(String::from("+")))
+        );
+        assert_eq!(
+            evaluate_tokens(Vec::new(), &context),
+            Err(EvalError::EmptyExpression)
+        );
+    }
+
+    #[test]
+    fn checked() {
+        let context = Context::default_with_math_functions();
+        assert_eq!(evaluate_checked("sqrt(16) / 2", &context), Ok(2.0));
+        assert_eq!(evaluate_checked("0 / -1", &context), Ok(-0.0));
+        assert_eq!(
+            evaluate_checked("1 / 0", &context),
+            Err(EvalError::NonFiniteResult {
+                value: f64::INFINITY,
+                expression: String::from("1 / 0"),
+            })
+        );
+        assert_eq!(
+            evaluate_checked("-1 / 0", &context)
+                .unwrap_err()
+                .to_string(),
+            "-1 / 0 evaluates to -inf"
+        );
+        match evaluate_checked("sqrt(-1)", &context) {
+            Err(EvalError::NonFiniteResult { value, expression }) => {
+                assert!(value.is_nan());
+                assert_eq!(expression, "sqrt(-1)");
+            }
+            result => panic!("{:?}", result),
+        }
+        assert_eq!(
+            evaluate_checked("1 +", &context),
+            Err(EvalError::MissingOperand(String::from("+")))
+        );
+        assert_eq!(evaluate_checked("if(1, 2, 1 / 0)", &context), Ok(2.0));
+    }
+
+    #[test]
+    fn series() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("k", 100.0);
+        context.set_variable("n", 4.0);
+
+        for &(expression, value) in &[
+            ("series(k, 1, 100, k^2)", 338350.0),
+            ("prod(k, 1, 5, k)", 120.0),
+            ("series(i, 1, 3, series(j, 1, i, j))", 10.0),
+            ("series(k, 1, 3, k) + k", 106.0),
+            ("series(i, n - 3, n * 2, i)", 36.0),
+            ("series(i, 1, n, if(i > 2, 1 / 0, i))", f64::INFINITY),
+            ("series(k, 3, 1, k)", 0.0),
+            ("prod(k, 3, 1, k)", 1.0),
+        ] {
+            assert_all_paths(expression, Ok(value), &context);
+        }
+
+        assert_eq!(
+            evaluate("series(k, 1, 2.5, k)", &context),
+            Err(EvalError::InvalidOperand {
+                operator: String::from("series"),
+                value: 2.5,
+            })
+        );
+        assert_eq!(
+            evaluate("series(k, 1, 3, x)", &context),
+            Err(EvalError::UnknownVariable(String::from("x")))
+        );
+        assert_eq!(
+            identifiers("series(i, 1, n, i * x)", &context),
+            Ok(Dependencies {
+                variables: ["n", "x"].iter().map(|name| String::from(*name)).collect(),
+                functions: HashSet::new(),
+            })
+        );
+        assert_eq!(
+            crate::postfix_to_infix(
+                &Expression::compile("2 * series(i, 1, n, i ^ 2)", &context).unwrap(),
+                &context
+            ),
+            Ok(String::from("2 * series(i, 1, n, i ^ 2)"))
+        );
+
+        let mut statistics = Context::default_with_statistical_functions();
+        assert_eq!(evaluate_f64("sum(1, 2, 3, 4)", &statistics), Ok(10.0));
+        for &(name, value) in &[("a", 1.0), ("b", 2.0), ("c", 3.0), ("d", 4.0)] {
+            statistics.set_variable(name, value);
+        }
+        assert_eq!(evaluate_f64("sum(a, b, c, d)", &statistics), Ok(10.0));
+        assert_eq!(
+            evaluate_f64("series(k, a, b, k * d)", &statistics),
+            Ok(12.0)
+        );
+    }
+
+    #[test]
+    fn iteration_limit() {
+        let mut context = Context::default();
+        context.set_max_iterations(10);
+
+        assert_eq!(context.max_iterations(), 10);
+        assert_eq!(evaluate_f64("series(k, 1, 10, k)", &context), Ok(55.0));
+        assert_eq!(
+            evaluate("series(k, 1, 11, k)", &context),
+            Err(EvalError::IterationLimitExceeded)
+        );
+        assert_eq!(
+            evaluate("series(k, 0, 1e300, k)", &Context64::default()),
+            Err(EvalError::IterationLimitExceeded)
+        );
+
+        // Nested and consecutive series share the limit of the evaluation.
+        assert_eq!(
+            evaluate_f64("series(i, 1, 2, series(j, 1, 4, j))", &context),
+            Ok(20.0)
+        );
+        for expression in &[
+            "series(i, 1, 3, series(j, 1, 4, j))",
+            "series(k, 1, 6, k) + prod(k, 1, 5, k)",
+            "series(i, 1, 3000, series(j, 1, 1000000, j))",
+        ] {
+            assert_all_paths(expression, Err(EvalError::IterationLimitExceeded), &context);
+        }
+        assert_eq!(evaluate_f64("series(k, 1, 10, k)", &context), Ok(55.0));
     }
 }
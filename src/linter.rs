@@ -0,0 +1,197 @@
+use crate::{Context, Token};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LintKind {
+    DivisionByZero,
+    FloatEquality,
+    NegativeSqrt,
+    PowerOverflow,
+    UndefinedVariable,
+}
+
+/// A likely mistake in an otherwise well-formed expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lint {
+    pub kind: LintKind,
+    /// The byte offset into the expression where the problem starts.
+    pub offset: usize,
+    pub message: String,
+    pub suggestion: Option<String>,
+}
+
+impl Lint {
+    fn new(kind: LintKind, offset: usize, message: String) -> Self {
+        Self {
+            kind,
+            offset,
+            message,
+            suggestion: None,
+        }
+    }
+
+    fn with_suggestion(mut self, suggestion: &str) -> Self {
+        self.suggestion = Some(String::from(suggestion));
+        self
+    }
+}
+
+/// Checks an expression for things that are valid, but probably not what the
+/// user meant, like dividing by a literal zero.
+///
+/// Unlike evaluation, this never fails. If the expression can't be tokenized,
+/// only the part before the error is checked.
+pub fn lint(expression: &str, context: &Context) -> Vec<Lint> {
+    let mut tokens: Vec<(usize, Token)> = Vec::new();
+    let mut offset = 0;
+    for token in context.tokenize(expression) {
+        let token = match token {
+            Ok(token) => token,
+            Err(_) => break,
+        };
+        let len = token.len();
+        if !matches!(token, Token::Whitespace(_)) {
+            tokens.push((offset, token));
+        }
+        offset += len;
+    }
+
+    let mut lints = Vec::new();
+    for (index, (offset, token)) in tokens.iter().enumerate() {
+        let previous = index.checked_sub(1).map(|index| &tokens[index]);
+        let next = tokens.get(index + 1).map(|(_, token)| token);
+        let after_next = tokens.get(index + 2).map(|(_, token)| token);
+
+        match token {
+            Token::Symbol(symbol) if symbol == "/" => {
+                if let Some(Token::Number(value, _)) = next {
+                    if *value == 0.0 {
+                        lints.push(Lint::new(
+                            LintKind::DivisionByZero,
+                            *offset,
+                            String::from("Division by zero"),
+                        ));
+                    }
+                }
+            }
+            Token::Symbol(symbol) if symbol == "==" => {
+                lints.push(
+                    Lint::new(
+                        LintKind::FloatEquality,
+                        *offset,
+                        String::from("Comparing floating point numbers with =="),
+                    )
+                    .with_suggestion("abs(a - b) < eps"),
+                );
+            }
+            Token::Symbol(symbol) if symbol == "^" => {
+                if let (
+                    Some((base_offset, Token::Number(base, _))),
+                    Some(Token::Number(exponent, _)),
+                ) = (previous, next)
+                {
+                    if base.powf(*exponent).is_infinite() {
+                        lints.push(Lint::new(
+                            LintKind::PowerOverflow,
+                            *base_offset,
+                            format!("{} ^ {} overflows", base, exponent),
+                        ));
+                    }
+                }
+            }
+            Token::Identifier(name) if name == "sqrt" => {
+                let argument = tokens.get(index + 3).map(|(_, token)| token);
+                if let (
+                    Some(Token::LeftParenthesis),
+                    Some(Token::Symbol(sign)),
+                    Some(Token::Number(..)),
+                ) = (next, after_next, argument)
+                {
+                    if sign == "-" {
+                        lints.push(
+                            Lint::new(
+                                LintKind::NegativeSqrt,
+                                *offset,
+                                String::from("Square root of a negative number"),
+                            )
+                            .with_suggestion("sqrt(abs(x))"),
+                        );
+                    }
+                }
+            }
+            Token::Identifier(name) => {
+                let is_call = matches!(next, Some(Token::LeftParenthesis));
+                if !is_call && !context.has_variable(name) {
+                    lints.push(Lint::new(
+                        LintKind::UndefinedVariable,
+                        *offset,
+                        format!("Variable {} is not defined", name),
+                    ));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    lints
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lint, Context, LintKind};
+
+    fn kinds(expression: &str) -> Vec<LintKind> {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("x", 1.0);
+        lint(expression, &context)
+            .into_iter()
+            .map(|lint| lint.kind)
+            .collect()
+    }
+
+    #[test]
+    fn clean_expression() {
+        assert_eq!(kinds("sqrt(x) / 2 + 10 ^ 3"), vec![]);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert_eq!(kinds("x / 0"), vec![LintKind::DivisionByZero]);
+        assert_eq!(kinds("x / 0.0 + 1"), vec![LintKind::DivisionByZero]);
+        assert_eq!(kinds("x / 0.5"), vec![]);
+    }
+
+    #[test]
+    fn float_equality() {
+        let context = Context::default();
+        let lints = lint("0.1 + 0.2 == 0.3", &context);
+
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].kind, LintKind::FloatEquality);
+        assert_eq!(lints[0].offset, 10);
+        assert_eq!(lints[0].suggestion, Some(String::from("abs(a - b) < eps")));
+    }
+
+    #[test]
+    fn negative_sqrt() {
+        assert_eq!(kinds("sqrt(-4)"), vec![LintKind::NegativeSqrt]);
+        assert_eq!(kinds("sqrt(4 - 1)"), vec![]);
+    }
+
+    #[test]
+    fn power_overflow() {
+        assert_eq!(kinds("10 ^ 400"), vec![LintKind::PowerOverflow]);
+        assert_eq!(kinds("10 ^ 300"), vec![]);
+    }
+
+    #[test]
+    fn undefined_variable() {
+        let context = Context::default();
+        let lints = lint("2 * foo + bar", &context);
+
+        assert_eq!(lints.len(), 2);
+        assert_eq!(lints[0].kind, LintKind::UndefinedVariable);
+        assert_eq!(lints[0].offset, 4);
+        assert_eq!(lints[1].offset, 10);
+        assert_eq!(kinds("max(x, 2)"), vec![]);
+    }
+}
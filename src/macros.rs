@@ -0,0 +1,234 @@
+use crate::{Context, EvalError, Number};
+use std::borrow::Cow;
+
+/// How deeply macros may expand to other macros. Anything deeper is left as
+/// it is, which stops macros that expand to themselves.
+const MAX_EXPANSION_DEPTH: usize = 16;
+
+/// A text substitution that is applied to expressions before they are
+/// tokenized.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Macro {
+    /// The parameter names, or `None` for a macro that is used without
+    /// parentheses.
+    params: Option<Vec<String>>,
+    replacement: String,
+}
+
+impl Macro {
+    /// Parses a pattern like `sq(x)` or `tau` into the macro's name and the
+    /// macro itself.
+    pub(crate) fn new(pattern: &str, replacement: &str) -> Result<(String, Self), EvalError> {
+        let invalid = || EvalError::InvalidMacroPattern(String::from(pattern));
+        let pattern = pattern.trim();
+        let name_length = identifier_length(pattern);
+        if name_length == 0 {
+            return Err(invalid());
+        }
+        let (name, rest) = pattern.split_at(name_length);
+
+        let params = if rest.is_empty() {
+            None
+        } else {
+            let list = rest
+                .strip_prefix('(')
+                .and_then(|rest| rest.strip_suffix(')'))
+                .ok_or_else(invalid)?;
+            let params: Vec<String> = if list.trim().is_empty() {
+                Vec::new()
+            } else {
+                list.split(',')
+                    .map(|param| String::from(param.trim()))
+                    .collect()
+            };
+            if params
+                .iter()
+                .any(|param| param.is_empty() || identifier_length(param) != param.len())
+            {
+                return Err(invalid());
+            }
+            Some(params)
+        };
+
+        let definition = Self {
+            params,
+            replacement: String::from(replacement),
+        };
+        Ok((String::from(name), definition))
+    }
+}
+
+/// Applies all macros of a context to an expression.
+///
+/// A macro with parameters is only expanded where its name is followed by
+/// parentheses with the right number of arguments. Each parameter in the
+/// replacement is substituted with the text of its argument as is, so
+/// replacements should parenthesize parameters, as in `((x)^2)`. Expansions
+/// are expanded again, up to a fixed depth.
+pub fn preprocess<N: Number>(expression: &str, context: &Context<N>) -> String {
+    expand(expression, context, 0)
+}
+
+/// Like `preprocess`, but doesn't copy the expression if the context has no
+/// macros.
+pub(crate) fn expand_macros<'a, N: Number>(
+    expression: &'a str,
+    context: &Context<N>,
+) -> Cow<'a, str> {
+    if context.macros.is_empty() {
+        return Cow::Borrowed(expression);
+    }
+    Cow::Owned(preprocess(expression, context))
+}
+
+fn expand<N: Number>(expression: &str, context: &Context<N>, depth: usize) -> String {
+    if depth >= MAX_EXPANSION_DEPTH {
+        return String::from(expression);
+    }
+
+    let mut result = String::new();
+    let mut rest = expression;
+    while let Some(c) = rest.chars().next() {
+        let length = identifier_length(rest);
+        if length == 0 {
+            result.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+
+        let (name, after) = rest.split_at(length);
+        rest = after;
+        let definition = match context.get_macro(name) {
+            Some(definition) => definition,
+            None => {
+                result.push_str(name);
+                continue;
+            }
+        };
+
+        match &definition.params {
+            None => result.push_str(&expand(&definition.replacement, context, depth + 1)),
+            Some(params) => match split_arguments(rest, context) {
+                Some((args, after)) if args.len() == params.len() => {
+                    let replacement = substitute(&definition.replacement, params, &args);
+                    result.push_str(&expand(&replacement, context, depth + 1));
+                    rest = after;
+                }
+                _ => result.push_str(name),
+            },
+        }
+    }
+    result
+}
+
+/// Reads a parenthesized argument list at the start of `expression`, skipping
+/// whitespace before it. Returns the arguments and the text after the list.
+fn split_arguments<'a, N: Number>(
+    expression: &'a str,
+    context: &Context<N>,
+) -> Option<(Vec<&'a str>, &'a str)> {
+    let list = expression.trim_start().strip_prefix('(')?;
+    let separator = context.number_locale().argument_separator();
+
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, c) in list.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => {
+                let last = list[start..index].trim();
+                if !(args.is_empty() && last.is_empty()) {
+                    args.push(last);
+                }
+                return Some((args, &list[index + 1..]));
+            }
+            ')' => depth -= 1,
+            c if c == separator && depth == 0 => {
+                args.push(list[start..index].trim());
+                start = index + c.len_utf8();
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Replaces each parameter name in `replacement` with its argument.
+fn substitute(replacement: &str, params: &[String], args: &[&str]) -> String {
+    let mut result = String::new();
+    let mut rest = replacement;
+    while let Some(c) = rest.chars().next() {
+        let length = identifier_length(rest);
+        if length == 0 {
+            result.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+
+        let (name, after) = rest.split_at(length);
+        match params.iter().position(|param| param == name) {
+            Some(index) => result.push_str(args[index]),
+            None => result.push_str(name),
+        }
+        rest = after;
+    }
+    result
+}
+
+/// The length of the identifier at the start of `text`, using the same
+/// letters as the lexer.
+fn identifier_length(text: &str) -> usize {
+    text.chars().take_while(|c| c.is_ascii_lowercase()).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{evaluate_f64, parse, parse_expression_list, preprocess};
+    use crate::{Context, Context64, EvalError};
+
+    #[test]
+    fn expansion() {
+        let mut context = Context::default();
+        context.add_macro("sq(x)", "((x)^2)").unwrap();
+        context.add_macro("avg(a, b)", "(((a) + (b)) / 2)").unwrap();
+        context.add_macro("tau", "6.28").unwrap();
+
+        assert_eq!(preprocess("sq(3+1)", &context), "((3+1)^2)");
+        assert_eq!(evaluate_f64("sq(3+1)", &context), Ok(16.0));
+        assert_eq!(evaluate_f64("avg(sq(2), 6) * 2", &context), Ok(10.0));
+        assert_eq!(evaluate_f64("sq(avg(1, (3)))", &context), Ok(4.0));
+        assert_eq!(preprocess("tau + taux", &context), "6.28 + taux");
+        assert_eq!(preprocess("sq + sq(1, 2)", &context), "sq + sq(1, 2)");
+
+        let expr = parse("avg(sq(2), 6) * 2", &context).unwrap();
+        assert_eq!(expr.evaluate(&context), Ok(10.0));
+        let values: Result<Vec<f64>, EvalError> =
+            parse_expression_list("sq(3), avg(1, 2)", &context)
+                .unwrap()
+                .iter()
+                .map(|expr| expr.evaluate(&context))
+                .collect();
+        assert_eq!(values, Ok(vec![9.0, 1.5]));
+    }
+
+    #[test]
+    fn recursive_macros_stop() {
+        let mut context = Context64::default();
+        context.add_macro("loop", "loop").unwrap();
+
+        assert_eq!(preprocess("loop + 1", &context), "loop + 1");
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        let mut context = Context64::default();
+
+        for &pattern in &["", "1x", "f(x", "f(x,)", "f(2)", "f(x)y"] {
+            assert_eq!(
+                context.add_macro(pattern, "1"),
+                Err(EvalError::InvalidMacroPattern(String::from(pattern)))
+            );
+        }
+    }
+}
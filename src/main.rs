@@ -1,10 +1,19 @@
-use clc::{evaluate, Context};
+use clc::{
+    evaluate, evaluate_mut, format_result, lex, Context, Function, NumberFormatOptions, Token,
+    TrigMode, Value,
+};
 use core::f64::consts::PI;
+use std::fs;
 use std::io::{self, Write};
 
+/// The number of significant digits results are rounded to for display.
+const DEFAULT_PRECISION: usize = 12;
+
 fn main() {
-    let mut context = Context::default();
+    let mut context = Context::default_with_math_functions();
     context.set_variable("pi", PI);
+    let format_options = NumberFormatOptions::default();
+    let mut precision = DEFAULT_PRECISION;
 
     loop {
         let mut input = String::new();
@@ -14,14 +23,194 @@ fn main() {
             .read_line(&mut input)
             .expect("Failed to read line");
 
-        if input == "" {
-            println!("");
+        if input.is_empty() {
+            println!();
             break;
-        } else if input.trim() == "" {
+        } else if input.trim().is_empty() {
             continue;
         }
 
-        let result = evaluate(input.trim(), &context);
-        println!("{}\n", result);
+        match input.trim() {
+            ":deg" => {
+                context.set_trig_mode(TrigMode::Degrees);
+                println!("Angles are in degrees\n");
+                continue;
+            }
+            ":grad" => {
+                context.set_trig_mode(TrigMode::Gradians);
+                println!("Angles are in gradians\n");
+                continue;
+            }
+            ":rad" => {
+                context.set_trig_mode(TrigMode::Radians);
+                println!("Angles are in radians\n");
+                continue;
+            }
+            command if command.starts_with(":load ") => {
+                let path = command[":load".len()..].trim();
+                match fs::read_to_string(path) {
+                    Ok(contents) => {
+                        for warning in load(&contents, &mut context) {
+                            println!("Warning: {}", warning);
+                        }
+                        println!("Loaded {}\n", path);
+                    }
+                    Err(error) => println!("Error: Can't read {}: {}\n", path, error),
+                }
+                continue;
+            }
+            command if command.starts_with(":save ") => {
+                let path = command[":save".len()..].trim();
+                match fs::write(path, save(&context)) {
+                    Ok(()) => println!("Saved variables to {}\n", path),
+                    Err(error) => println!("Error: Can't write {}: {}\n", path, error),
+                }
+                continue;
+            }
+            command if command.starts_with(":precision") => {
+                match command[":precision".len()..].trim().parse() {
+                    Ok(digits) if digits > 0 => {
+                        precision = digits;
+                        println!("Results are shown with {} significant digits\n", digits);
+                    }
+                    _ => println!("Usage: :precision N, where N is at least 1\n"),
+                }
+                continue;
+            }
+            _ => (),
+        }
+
+        match evaluate_mut(input.trim(), &mut context) {
+            Ok(result) => {
+                context.set_variable("ans", result);
+                let rounded = round_for_display(result, precision);
+                println!("{}\n", format_result(rounded, &format_options));
+            }
+            Err(error) => println!("Error: {}\n", error),
+        }
+    }
+}
+
+/// Reads definitions of variables and functions from the contents of a file
+/// written for `:load`, with one definition like `x = 3.14` or
+/// `f(a, b) = a ^ 2 + b` per line. Blank lines are skipped. Definitions apply
+/// in order, so later lines can use earlier ones. Returns a warning for each
+/// line that can't be read, which is skipped.
+fn load(contents: &str, context: &mut Context) -> Vec<String> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(|(index, line)| {
+            let error = define(line, context).err()?;
+            Some(format!("Line {} skipped: {}", index + 1, error))
+        })
+        .collect()
+}
+
+/// Adds the variable or function defined by one line of a file for `:load`.
+fn define(line: &str, context: &mut Context) -> Result<(), String> {
+    let (target, body) = line
+        .split_once('=')
+        .ok_or("expected a definition like `x = 1` or `f(x) = x + 1`")?;
+    let target = target.trim();
+    match target
+        .strip_suffix(')')
+        .and_then(|target| target.split_once('('))
+    {
+        Some((name, params)) => {
+            let params: Vec<&str> = params.split(',').map(str::trim).collect();
+            let params = if params == [""] { Vec::new() } else { params };
+            if !is_name(name.trim()) || !params.iter().all(|param| is_name(param)) {
+                return Err(format!("{} is not a valid function", target));
+            }
+            let function = Function::user_defined(name.trim(), &params, body, context)
+                .map_err(|error| error.to_string())?;
+            context.add_function(function);
+        }
+        None if is_name(target) => {
+            let value = evaluate(body, context).map_err(|error| error.to_string())?;
+            context.set_variable(target, value);
+        }
+        None => return Err(format!("{} is not a valid variable", target)),
+    }
+    Ok(())
+}
+
+fn is_name(text: &str) -> bool {
+    matches!(lex(text).as_deref(), Ok([Token::Identifier(_)]))
+}
+
+/// Writes the variables of the context in the format that `load` reads,
+/// sorted by name. Values are written with as many digits as it takes to
+/// read them back exactly. Infinite and NaN values can't be read back, so
+/// they are left out.
+fn save(context: &Context) -> String {
+    let mut variables: Vec<(&str, &Value)> = context
+        .variables()
+        .filter(|(_, value)| value.to_f64().is_finite())
+        .collect();
+    variables.sort_by(|a, b| a.0.cmp(b.0));
+    variables
+        .iter()
+        .map(|(name, value)| format!("{} = {}\n", name, value))
+        .collect()
+}
+
+/// Rounds a value to a number of significant digits, which hides artifacts
+/// like the `4` in `0.1 + 0.2 = 0.30000000000000004`.
+fn round_for_display(value: f64, digits: usize) -> f64 {
+    if !value.is_finite() || value == 0.0 {
+        return value;
+    }
+    let rounded = format!("{:.*e}", digits.max(1) - 1, value);
+    rounded.parse().unwrap_or(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, round_for_display, save};
+    use clc::{evaluate_f64, Context, Value};
+
+    #[test]
+    fn display_rounding() {
+        assert_eq!(round_for_display(0.1 + 0.2, 12), 0.3);
+        assert_eq!(round_for_display(1.1 + 2.2, 12), 3.3);
+        assert_eq!(round_for_display(0.7 + 0.1, 12), 0.8);
+        assert_eq!(round_for_display(3.0 * 1.1, 12), 3.3);
+        assert_eq!(round_for_display(1e20 / 3.0, 3), 3.33e19);
+        assert_eq!(round_for_display(-2.0 / 3.0, 2), -0.67);
+        assert_eq!(round_for_display(0.1 + 0.2, 17), 0.1 + 0.2);
+        assert!(round_for_display(f64::NAN, 12).is_nan());
+    }
+
+    #[test]
+    fn loading_and_saving() {
+        let mut context = Context::default_with_math_functions();
+        let contents = "x = 2.5\n\ny = x * 2\nsquare(a) = a ^ 2 + 1\n\
+                        hypot(a, b) = sqrt(a ^ 2 + b ^ 2)\nz = square(2)\n\
+                        2 = 3\nw\nbad( = 1\nv = 1 +\n";
+        let warnings = load(contents, &mut context);
+        assert_eq!(warnings.len(), 4, "{:?}", warnings);
+        assert!(warnings[0].starts_with("Line 7 skipped"));
+        assert!(warnings[1].starts_with("Line 8 skipped"));
+
+        assert_eq!(context.get_variable("y"), Some(&Value::Float(5.0)));
+        assert_eq!(context.get_variable("z"), Some(&Value::Int(5)));
+        assert_eq!(evaluate_f64("hypot(3, 4)", &context), Ok(5.0));
+        assert_eq!(context.get_variable("v"), None);
+
+        context.set_variable("third", 1.0 / 3.0);
+        context.set_variable("nan", f64::NAN);
+        let saved = save(&context);
+        assert_eq!(
+            saved,
+            format!("third = {}\nx = 2.5\ny = 5\nz = 5\n", 1.0 / 3.0)
+        );
+
+        let mut loaded = Context::default();
+        assert!(load(&saved, &mut loaded).is_empty());
+        assert_eq!(loaded.get_variable("third"), Some(&Value::Float(1.0 / 3.0)));
+        assert_eq!(save(&loaded), saved);
     }
 }
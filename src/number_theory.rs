@@ -0,0 +1,93 @@
+//! Functions on natural numbers, added by
+//! `Context::default_with_number_theory_functions`.
+
+use crate::{factorial, EvalError, INTEGER_LIMIT};
+
+/// Converts an argument of `function`, which has to be a non-negative integer
+/// of at most 2^53.
+fn natural(function: &str, value: f64) -> Result<u64, EvalError> {
+    if value.fract() != 0.0 || !(0.0..=INTEGER_LIMIT as f64).contains(&value) {
+        return Err(EvalError::InvalidOperand {
+            operator: String::from(function),
+            value,
+        });
+    }
+    Ok(value as u64)
+}
+
+fn greatest_common_divisor(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        let remainder = a % b;
+        a = b;
+        b = remainder;
+    }
+    a
+}
+
+pub(crate) fn gcd(a: f64, b: f64) -> Result<f64, EvalError> {
+    let divisor = greatest_common_divisor(natural("gcd", a)?, natural("gcd", b)?);
+    Ok(divisor as f64)
+}
+
+/// The least common multiple, which is 0 if either argument is 0. Fails if it
+/// is larger than 2^53.
+pub(crate) fn lcm(a: f64, b: f64) -> Result<f64, EvalError> {
+    let (a, b) = (natural("lcm", a)?, natural("lcm", b)?);
+    if a == 0 || b == 0 {
+        return Ok(0.0);
+    }
+    let multiple = u128::from(a / greatest_common_divisor(a, b)) * u128::from(b);
+    if multiple > INTEGER_LIMIT as u128 {
+        return Err(EvalError::ResultOutOfRange {
+            operator: String::from("lcm"),
+            value: multiple as f64,
+        });
+    }
+    Ok(multiple as f64)
+}
+
+pub(crate) fn fact(n: f64) -> Result<f64, EvalError> {
+    factorial("fact", natural("fact", n)? as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{evaluate, evaluate_f64, Context, Context64, EvalError};
+
+    #[test]
+    fn number_theory() {
+        let context = Context::default_with_number_theory_functions();
+
+        assert_eq!(evaluate_f64("gcd(12, 18)", &context), Ok(6.0));
+        assert_eq!(evaluate_f64("gcd(0, 5)", &context), Ok(5.0));
+        assert_eq!(evaluate_f64("lcm(4, 6)", &context), Ok(12.0));
+        assert_eq!(evaluate_f64("lcm(0, 6)", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("fact(5)", &context), Ok(120.0));
+        assert_eq!(evaluate_f64("fact(0)", &context), Ok(1.0));
+    }
+
+    #[test]
+    fn arguments_must_be_natural_numbers() {
+        let context = Context64::default_with_number_theory_functions();
+        let invalid = |function: &str, value| {
+            Err(EvalError::InvalidOperand {
+                operator: String::from(function),
+                value,
+            })
+        };
+
+        assert_eq!(evaluate("gcd(1.5, 3)", &context), invalid("gcd", 1.5));
+        assert_eq!(evaluate("lcm(4, -6)", &context), invalid("lcm", -6.0));
+        assert_eq!(
+            evaluate("fact(2^60)", &context),
+            invalid("fact", 2f64.powi(60))
+        );
+        assert_eq!(
+            evaluate("lcm(2^40, 2^40 - 1)", &context),
+            Err(EvalError::ResultOutOfRange {
+                operator: String::from("lcm"),
+                value: 2f64.powi(40) * (2f64.powi(40) - 1.0),
+            })
+        );
+    }
+}
@@ -1,28 +1,157 @@
-#[derive(Debug, Clone)]
+use crate::LexError;
+use std::collections::VecDeque;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
-    Number(String),
+    /// A number literal, holding its value and the text it was parsed from.
+    Number(f64, String),
     Identifier(String),
     Symbol(String),
     Whitespace(String),
     LeftParenthesis,
     RightParenthesis,
+    Separator,
+    /// A call to a function with the given number of arguments. The lexer
+    /// never produces these; they only appear in postfix queues.
+    Call(String, usize),
+    /// An assignment of the value on top of the stack to a variable. Like
+    /// calls, these only appear in postfix queues.
+    Assign(String),
+    /// A prefix operator applied to the value on top of the stack. Like
+    /// calls, these only appear in postfix queues.
+    Prefix(String),
+    /// A postfix operator applied to the value on top of the stack. Like
+    /// calls, these only appear in postfix queues.
+    Postfix(String),
+    /// The two branches of an `if`, of which only the one selected by the
+    /// value on top of the stack is evaluated. Like calls, these only appear
+    /// in postfix queues.
+    Branch(VecDeque<Token>, VecDeque<Token>),
+    /// A `series` or `prod` with its name, bound variable and body, which is
+    /// evaluated once for each integer from the second value on the stack to
+    /// the one on top. Like calls, these only appear in postfix queues.
+    Reduce(String, String, VecDeque<Token>),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(_, s) => write!(f, "{}", s),
+            Self::Identifier(s) => write!(f, "{}", s),
+            Self::Symbol(s) => write!(f, "{}", s),
+            Self::Whitespace(s) => write!(f, "{}", s),
+            Self::LeftParenthesis => write!(f, "("),
+            Self::RightParenthesis => write!(f, ")"),
+            Self::Separator => write!(f, ","),
+            Self::Call(name, _) => write!(f, "{}", name),
+            Self::Assign(name) => write!(f, "{} =", name),
+            Self::Prefix(symbol) | Self::Postfix(symbol) => write!(f, "{}", symbol),
+            Self::Branch(..) => write!(f, "if"),
+            Self::Reduce(name, ..) => write!(f, "{}", name),
+        }
+    }
 }
 
 impl Token {
     pub fn len(&self) -> usize {
         match self {
-            Self::Number(s) => s.len(),
+            Self::Number(_, s) => s.len(),
             Self::Identifier(s) => s.len(),
             Self::Symbol(s) => s.len(),
             Self::Whitespace(s) => s.len(),
             Self::LeftParenthesis => 1,
             Self::RightParenthesis => 1,
+            Self::Separator => 1,
+            Self::Call(..) => 0,
+            Self::Assign(_) => 0,
+            Self::Prefix(_) => 0,
+            Self::Postfix(_) => 0,
+            Self::Branch(..) => 0,
+            Self::Reduce(..) => 0,
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the token is a symbol, which the lexer produces for operators.
+    pub fn is_operator_symbol(&self) -> bool {
+        matches!(self, Self::Symbol(_))
+    }
+
+    /// Whether the token is a value on its own, i.e. a number or an
+    /// identifier.
+    pub fn is_operand(&self) -> bool {
+        matches!(self, Self::Number(..) | Self::Identifier(_))
+    }
 }
 
+/// How numbers are written: which character separates the decimals and which,
+/// if any, groups the digits of the integer part.
+///
+/// Grouping and argument separators can look alike, so a grouping separator
+/// only counts as part of a number if it is followed by exactly three digits.
+/// `1,000` is one thousand in the US locale, but `max(1,2)` and `max(1,2000)`
+/// are still calls with two arguments. Grouping separators aren't allowed in
+/// the decimals. If the decimal separator is `,`, arguments are separated by
+/// `;` instead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NumberLocale {
+    pub decimal_separator: char,
+    pub grouping_separator: Option<char>,
+}
+
+impl NumberLocale {
+    /// `1,000.5`
+    pub fn us() -> Self {
+        Self {
+            decimal_separator: '.',
+            grouping_separator: Some(','),
+        }
+    }
+
+    /// `1.000,5`
+    pub fn german() -> Self {
+        Self {
+            decimal_separator: ',',
+            grouping_separator: Some('.'),
+        }
+    }
+
+    /// The character that separates function arguments.
+    pub fn argument_separator(&self) -> char {
+        if self.decimal_separator == ',' {
+            ';'
+        } else {
+            ','
+        }
+    }
+}
+
+impl Default for NumberLocale {
+    /// `1000.5`, without grouping.
+    fn default() -> Self {
+        Self {
+            decimal_separator: '.',
+            grouping_separator: None,
+        }
+    }
+}
+
+/// An iterator over the tokens of an expression.
+///
+/// A token is read from the text that follows the previous one, without any
+/// other state, so `peek` reads the next token without a buffer. For more
+/// lookahead, clone the iterator, which is cheap, or wrap it in
+/// `itertools::multipeek`.
+#[derive(Clone)]
 pub struct Tokens<'a> {
     expression: &'a str,
+    locale: NumberLocale,
+    /// Symbols made of more than one character, longest first.
+    symbols: Vec<String>,
     pos: usize,
     error: bool,
 }
@@ -31,21 +160,47 @@ impl<'a> From<&'a str> for Tokens<'a> {
     fn from(expression: &'a str) -> Self {
         Self {
             expression,
+            locale: NumberLocale::default(),
+            symbols: Vec::new(),
             pos: 0,
             error: false,
         }
     }
 }
 
-impl Iterator for Tokens<'_> {
-    type Item = Result<Token, &'static str>;
+impl Tokens<'_> {
+    /// Makes the tokenizer read each of `symbols` as a single symbol token.
+    pub(crate) fn with_symbols(mut self, mut symbols: Vec<String>) -> Self {
+        symbols.sort_by_key(|symbol| std::cmp::Reverse(symbol.len()));
+        self.symbols = symbols;
+        self
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    /// Returns the next token without advancing, so `next` returns the same
+    /// token afterwards. Unlike `Peekable::peek`, the token is read again
+    /// each time rather than stored.
+    pub fn peek(&self) -> Option<Result<Token, LexError>> {
         if self.error || self.pos >= self.expression.len() {
             return None;
         }
+        Some(parse_token(
+            &self.expression[self.pos..],
+            &self.locale,
+            &self.symbols,
+        ))
+    }
 
-        let res = parse_token(&self.expression[self.pos..]);
+    /// The byte offset of the next token in the expression.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = Result<Token, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let res = self.peek()?;
         if let Ok(token) = &res {
             self.pos += token.len();
         } else {
@@ -87,29 +242,124 @@ fn parse_whitespace(expression: &str) -> &str {
     &expression[0..length]
 }
 
-fn parse_number(expression: &str) -> Result<&str, &'static str> {
-    let mut length = 0;
-    let mut has_decimals = false;
-    let mut last_char: Option<char> = None;
+/// The part of a number literal that is being read.
+#[derive(Clone, Copy, PartialEq)]
+enum NumberPart {
+    Integer,
+    Fraction,
+    /// The digits of a hexadecimal literal after its `0x` prefix.
+    Hexadecimal,
+}
 
-    for c in expression.chars() {
-        if c == '.' && has_decimals {
-            return Err("Malformed number");
-        } else if c == '.' && !has_decimals {
-            has_decimals = true;
-        } else if !is_digit(c) {
-            break;
+/// Reads a number literal at the start of `expression` and returns it with
+/// its value.
+///
+/// A literal that starts with `0x` is hexadecimal. Within it, `e` is always a
+/// hex digit rather than the start of an exponent, so `0xfe3` is 4067. Hex
+/// literals have no decimals or exponents, so a decimal separator or any
+/// other letter or digit right after the hex digits is an error, as is a
+/// `0x` without digits. Decimal literals may have decimals and an exponent
+/// like `e-5`.
+fn parse_number(expression: &str, locale: &NumberLocale) -> Result<Token, LexError> {
+    let chars: Vec<(usize, char)> = expression.char_indices().collect();
+    let offset = |index: usize| chars.get(index).map_or(expression.len(), |&(i, _)| i);
+    let malformed =
+        |end: usize| LexError::MalformedNumber(String::from(&expression[0..offset(end)]));
+
+    let (mut part, mut index) = if expression.starts_with("0x") {
+        (NumberPart::Hexadecimal, 2)
+    } else {
+        (NumberPart::Integer, 0)
+    };
+    while let Some(&(_, c)) = chars.get(index) {
+        match part {
+            NumberPart::Hexadecimal if c.is_ascii_hexdigit() => index += 1,
+            NumberPart::Hexadecimal if c.is_alphanumeric() || c == locale.decimal_separator => {
+                return Err(malformed(index + 1));
+            }
+            NumberPart::Integer | NumberPart::Fraction if is_digit(c) => index += 1,
+            NumberPart::Integer if c == locale.decimal_separator => {
+                part = NumberPart::Fraction;
+                index += 1;
+                if !matches!(chars.get(index), Some(&(_, c)) if is_digit(c)) {
+                    return Err(malformed(index));
+                }
+            }
+            NumberPart::Fraction if c == locale.decimal_separator => {
+                return Err(malformed(index + 1));
+            }
+            NumberPart::Integer
+                if Some(c) == locale.grouping_separator && is_digit_group(&chars[index + 1..]) =>
+            {
+                index += 4;
+            }
+            _ => break,
         }
+    }
 
-        last_char = Some(c);
-        length += 1;
+    let length = offset(index);
+    if part == NumberPart::Hexadecimal {
+        if index == 2 {
+            return Err(malformed(index));
+        }
+        let text = &expression[0..length];
+        let value = text[2..].chars().fold(0.0, |value, c| {
+            value * 16.0 + f64::from(c.to_digit(16).unwrap_or(0))
+        });
+        if value.is_infinite() {
+            return Err(LexError::NumberOutOfRange(String::from(text)));
+        }
+        return Ok(Token::Number(value, String::from(text)));
+    }
+
+    let text = &expression[0..length + parse_exponent(&expression[length..])];
+    let normalized: String = text
+        .chars()
+        .filter(|&c| Some(c) != locale.grouping_separator)
+        .map(|c| {
+            if c == locale.decimal_separator {
+                '.'
+            } else {
+                c
+            }
+        })
+        .collect();
+    let value: f64 = normalized
+        .parse()
+        .map_err(|_| LexError::MalformedNumber(String::from(text)))?;
+    if value.is_infinite() {
+        return Err(LexError::NumberOutOfRange(String::from(text)));
+    }
+    Ok(Token::Number(value, String::from(text)))
+}
+
+/// Whether `chars` starts with exactly three digits.
+fn is_digit_group(chars: &[(usize, char)]) -> bool {
+    let digits = chars.iter().take_while(|&&(_, c)| is_digit(c)).count();
+    digits == 3
+}
+
+/// Returns the length of an exponent like `e-5` at the start of `expression`,
+/// or 0 if there is none. An `e` that isn't followed by digits is left alone
+/// so it can still be read as an identifier.
+fn parse_exponent(expression: &str) -> usize {
+    let mut chars = expression.chars().peekable();
+    if !matches!(chars.next(), Some('e') | Some('E')) {
+        return 0;
     }
 
-    if let Some('.') = last_char {
-        return Err("Maldformed number");
+    let mut length = 1;
+    if let Some('+') | Some('-') = chars.peek() {
+        chars.next();
+        length += 1;
     }
 
-    Ok(&expression[0..length])
+    let digits = chars.take_while(|&c| is_digit(c)).count();
+    if digits == 0 {
+        0
+    } else {
+        length + digits
+    }
 }
 
 fn parse_identifier(expression: &str) -> &str {
@@ -124,24 +374,82 @@ fn parse_identifier(expression: &str) -> &str {
     &expression[0..length]
 }
 
-fn parse_token(expression: &str) -> Result<Token, &'static str> {
-    let current_char = expression.chars().nth(0).unwrap();
+fn parse_token(
+    expression: &str,
+    locale: &NumberLocale,
+    symbols: &[String],
+) -> Result<Token, LexError> {
+    let current_char = expression.chars().next().unwrap();
 
     if is_whitespace(current_char) {
-        Ok(Token::Whitespace(parse_whitespace(&expression).to_owned()))
+        Ok(Token::Whitespace(parse_whitespace(expression).to_owned()))
     } else if is_digit(current_char) {
-        Ok(Token::Number(parse_number(&expression)?.to_owned()))
+        parse_number(expression, locale)
     } else if is_letter(current_char) {
-        Ok(Token::Identifier(parse_identifier(&expression).to_owned()))
+        Ok(Token::Identifier(parse_identifier(expression).to_owned()))
     } else if is_left_parenthesis(current_char) {
         Ok(Token::LeftParenthesis)
     } else if is_right_parenthesis(current_char) {
         Ok(Token::RightParenthesis)
+    } else if current_char == locale.argument_separator() {
+        Ok(Token::Separator)
+    } else if let Some(symbol) = symbols
+        .iter()
+        .find(|&symbol| expression.starts_with(symbol.as_str()))
+    {
+        Ok(Token::Symbol(symbol.clone()))
     } else {
-        Ok(Token::Symbol(String::from(&expression[0..1])))
+        let length = current_char.len_utf8();
+        Ok(Token::Symbol(String::from(&expression[0..length])))
     }
 }
 
-pub fn tokenize(expression: &str) -> Tokens {
+pub fn tokenize(expression: &str) -> Tokens<'_> {
     Tokens::from(expression)
 }
+
+/// Tokenizes the whole expression at once, failing at the first lexical
+/// error. Whitespace is kept, so the text of the tokens adds up to the
+/// expression.
+pub fn lex(expression: &str) -> Result<Vec<Token>, LexError> {
+    tokenize(expression).collect()
+}
+
+/// Like `tokenize`, but reads numbers and argument separators according to
+/// `locale`.
+pub fn tokenize_with_locale(expression: &str, locale: NumberLocale) -> Tokens<'_> {
+    Tokens {
+        locale,
+        ..Tokens::from(expression)
+    }
+}
+
+/// Tokenizes the whole expression, even past lexical errors. A malformed
+/// number is skipped and reported, and tokenizing continues after it, so all
+/// errors in the expression are found at once.
+pub fn tokenize_collect_errors(expression: &str) -> (Vec<Token>, Vec<LexError>) {
+    let locale = NumberLocale::default();
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+    let mut pos = 0;
+
+    while pos < expression.len() {
+        match parse_token(&expression[pos..], &locale, &[]) {
+            Ok(token) => {
+                pos += token.len();
+                tokens.push(token);
+            }
+            Err(error) => {
+                let skipped = match &error {
+                    LexError::MalformedNumber(text) | LexError::NumberOutOfRange(text) => {
+                        text.len()
+                    }
+                };
+                pos += skipped.max(1);
+                errors.push(error);
+            }
+        }
+    }
+
+    (tokens, errors)
+}
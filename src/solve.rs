@@ -0,0 +1,247 @@
+//! Finding roots of expressions with Newton's method and bisection.
+
+use crate::{Context, Expression, SolveError};
+use std::collections::HashMap;
+
+/// How many steps in a row may be longer than the one before before `solve`
+/// gives up with `SolveError::Diverged`.
+const MAX_GROWING_STEPS: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SolveOptions {
+    /// The step size, relative to the root but at least absolute, below
+    /// which a root counts as found.
+    pub tolerance: f64,
+    pub max_iterations: usize,
+    /// Two values at which the expression has opposite signs. The root is
+    /// searched between them, and bisection is used whenever a Newton step
+    /// would leave them.
+    pub bracket: Option<(f64, f64)>,
+}
+
+impl Default for SolveOptions {
+    fn default() -> Self {
+        Self {
+            tolerance: 1e-12,
+            max_iterations: 100,
+            bracket: None,
+        }
+    }
+}
+
+/// Finds a value of `variable` close to `guess` at which `expression` is
+/// zero, see `solve_with`.
+pub fn solve(
+    expression: &Expression,
+    variable: &str,
+    guess: f64,
+    context: &Context,
+) -> Result<f64, SolveError> {
+    solve_with(
+        expression,
+        variable,
+        guess,
+        &SolveOptions::default(),
+        context,
+    )
+}
+
+/// Finds a value of `variable` at which `expression` is zero, starting at
+/// `guess`, with Newton's method and a numeric derivative.
+///
+/// Once two values with opposite signs are known, either from
+/// `options.bracket` or from the steps taken so far, the root is kept
+/// between them, bisecting whenever a Newton step would leave them. Without
+/// them, a zero derivative fails with `SolveError::ZeroDerivative`, and steps
+/// that keep getting longer with `SolveError::Diverged`. Errors,
+/// infinity and NaN at any of the values that are tried fail with
+/// `SolveError::Eval` holding an `EvalError::AtSample`.
+pub fn solve_with(
+    expression: &Expression,
+    variable: &str,
+    guess: f64,
+    options: &SolveOptions,
+    context: &Context,
+) -> Result<f64, SolveError> {
+    let mut objective = Objective {
+        expression,
+        variable,
+        context,
+        bindings: HashMap::new(),
+    };
+
+    let mut bracket = match options.bracket {
+        Some((start, end)) => {
+            let (f_start, f_end) = (objective.at(start)?, objective.at(end)?);
+            if f_start == 0.0 {
+                return Ok(start);
+            }
+            if f_end == 0.0 {
+                return Ok(end);
+            }
+            if f_start.signum() == f_end.signum() {
+                return Err(SolveError::InvalidBracket { start, end });
+            }
+            Some(((start, f_start), (end, f_end)))
+        }
+        None => None,
+    };
+
+    let mut x = match bracket {
+        Some((start, end)) if !is_between(guess, start.0, end.0) => (start.0 + end.0) / 2.0,
+        _ => guess,
+    };
+    let mut f_x = objective.at(x)?;
+    let mut last_step = f64::INFINITY;
+    let mut growing_steps = 0;
+    for _ in 0..options.max_iterations {
+        if f_x == 0.0 {
+            return Ok(x);
+        }
+
+        let newton = objective.derivative(x)?.map(|slope| x - f_x / slope);
+        let next = match (bracket, newton) {
+            (Some((start, end)), Some(next)) if is_between(next, start.0, end.0) => next,
+            (Some((start, end)), _) => (start.0 + end.0) / 2.0,
+            (None, Some(next)) if next.is_finite() => next,
+            (None, Some(_)) => return Err(SolveError::Diverged(x)),
+            (None, None) => return Err(SolveError::ZeroDerivative(x)),
+        };
+        let f_next = objective.at(next)?;
+
+        bracket = match bracket {
+            Some((start, end)) if f_next.signum() == start.1.signum() => {
+                Some(((next, f_next), end))
+            }
+            Some((start, _)) => Some((start, (next, f_next))),
+            None if f_next.signum() != f_x.signum() => Some(((x, f_x), (next, f_next))),
+            None => None,
+        };
+        let step = (next - x).abs();
+        growing_steps = match bracket {
+            None if step > last_step => growing_steps + 1,
+            _ => 0,
+        };
+        last_step = step;
+        if growing_steps >= MAX_GROWING_STEPS {
+            return Err(SolveError::Diverged(next));
+        }
+
+        let tolerance = options.tolerance * next.abs().max(1.0);
+        let converged = step <= tolerance
+            || bracket.is_some_and(|(start, end)| (end.0 - start.0).abs() <= tolerance);
+        x = next;
+        f_x = f_next;
+        if converged {
+            return Ok(x);
+        }
+    }
+    Err(SolveError::NotConverged {
+        value: x,
+        residual: f_x,
+    })
+}
+
+/// Whether `value` lies strictly between the bounds, in either order.
+fn is_between(value: f64, a: f64, b: f64) -> bool {
+    a.min(b) < value && value < a.max(b)
+}
+
+/// The expression as a function of one variable.
+struct Objective<'a> {
+    expression: &'a Expression,
+    variable: &'a str,
+    context: &'a Context,
+    bindings: HashMap<String, f64>,
+}
+
+impl Objective<'_> {
+    fn at(&mut self, value: f64) -> Result<f64, SolveError> {
+        self.expression
+            .evaluate_at(self.context, &mut self.bindings, self.variable, value)
+            .map_err(SolveError::Eval)
+    }
+
+    /// The slope at `value` by central differences, or `None` if it is zero.
+    fn derivative(&mut self, value: f64) -> Result<Option<f64>, SolveError> {
+        let step = f64::EPSILON.cbrt() * value.abs().max(1.0);
+        let slope = (self.at(value + step)? - self.at(value - step)?) / (2.0 * step);
+        Ok(Some(slope).filter(|&slope| slope != 0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{solve, solve_with, Context, EvalError, Expression, SolveError, SolveOptions};
+
+    #[test]
+    fn roots() {
+        let context = Context::default_with_math_functions();
+        let root = |expression, guess| {
+            let expression = Expression::compile(expression, &context).unwrap();
+            solve(&expression, "x", guess, &context).unwrap()
+        };
+
+        let rate = root("1000 * (1 + x)^12 - 1200", 0.01);
+        assert!((rate - (1.2f64.powf(1.0 / 12.0) - 1.0)).abs() < 1e-12);
+        assert!((root("x^2 - 2", 1.0) - 2f64.sqrt()).abs() < 1e-12);
+        assert!((root("cos(x) - x", 0.0) - 0.739_085_133_215_160_6).abs() < 1e-12);
+        assert_eq!(root("x - 3", 3.0), 3.0);
+        // The steps alternate in sign, so the root ends up bracketed.
+        assert!(root("atan(x)", 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn brackets() {
+        let context = Context::default_with_math_functions();
+        // Newton's method alone cycles between 0 and 1 here.
+        let expression = Expression::compile("x^3 - 2 * x + 2", &context).unwrap();
+        let options = |bracket| SolveOptions {
+            bracket,
+            ..SolveOptions::default()
+        };
+
+        assert!(matches!(
+            solve(&expression, "x", 0.0, &context),
+            Err(SolveError::NotConverged { .. })
+        ));
+        let root = solve_with(&expression, "x", 0.0, &options(Some((-3.0, 0.0))), &context);
+        assert!((root.unwrap() + 1.769_292_354_238_631).abs() < 1e-12);
+        assert_eq!(
+            solve_with(&expression, "x", 0.0, &options(Some((0.0, 1.0))), &context),
+            Err(SolveError::InvalidBracket {
+                start: 0.0,
+                end: 1.0
+            })
+        );
+    }
+
+    #[test]
+    fn failures() {
+        let context = Context::default_with_math_functions();
+        let solve = |expression, guess| {
+            let expression = Expression::compile(expression, &context).unwrap();
+            solve(&expression, "x", guess, &context)
+        };
+
+        assert!(matches!(solve("1 / x", 1.0), Err(SolveError::Diverged(_))));
+        assert_eq!(solve("x^2 - 1", 0.0), Err(SolveError::ZeroDerivative(0.0)));
+        assert_eq!(
+            solve("x! - 6", 2.5),
+            Err(SolveError::Eval(EvalError::AtSample {
+                value: 2.5,
+                error: Box::new(EvalError::InvalidOperand {
+                    operator: String::from("!"),
+                    value: 2.5,
+                }),
+            }))
+        );
+        assert_eq!(
+            solve("x + y", 1.0),
+            Err(SolveError::Eval(EvalError::AtSample {
+                value: 1.0,
+                error: Box::new(EvalError::UnknownVariable(String::from("y"))),
+            }))
+        );
+    }
+}
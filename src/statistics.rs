@@ -0,0 +1,86 @@
+//! Functions of a whole list of numbers, added by
+//! `Context::default_with_statistical_functions`.
+
+use crate::Number;
+
+pub(crate) fn sum<N: Number>(values: &[N]) -> N {
+    values.iter().copied().sum()
+}
+
+pub(crate) fn product<N: Number>(values: &[N]) -> N {
+    values.iter().copied().product()
+}
+
+pub(crate) fn mean<N: Number>(values: &[N]) -> N {
+    sum(values) / N::from_f64(values.len() as f64)
+}
+
+/// The middle value, or the mean of the two middle values if there is an
+/// even number of values.
+pub(crate) fn median<N: Number>(values: &[N]) -> N {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(N::total_cmp);
+    let middle = sorted.len() / 2;
+    if sorted.len() % 2 == 1 {
+        sorted[middle]
+    } else {
+        (sorted[middle - 1] + sorted[middle]) / N::from_f64(2.0)
+    }
+}
+
+/// The sample variance, which divides by `n - 1`.
+pub(crate) fn variance<N: Number>(values: &[N]) -> N {
+    let mean = mean(values);
+    let squares: N = values.iter().map(|&value| (value - mean).powi(2)).sum();
+    squares / N::from_f64((values.len() - 1) as f64)
+}
+
+/// The sample standard deviation.
+pub(crate) fn stddev<N: Number>(values: &[N]) -> N {
+    variance(values).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{evaluate, evaluate_f64, Arity, Context, Context64, EvalError};
+
+    #[test]
+    fn statistics() {
+        let context = Context::default_with_statistical_functions();
+
+        assert_eq!(evaluate_f64("mean(1, 2, 3, 4, 5)", &context), Ok(3.0));
+        assert_eq!(evaluate_f64("sum(1, 2, 3)", &context), Ok(6.0));
+        assert_eq!(evaluate_f64("sum()", &context), Ok(0.0));
+        assert_eq!(evaluate_f64("product(2, 3, 4)", &context), Ok(24.0));
+        assert_eq!(evaluate_f64("median(5, 1, 3)", &context), Ok(3.0));
+        assert_eq!(evaluate_f64("median(1, 2)", &context), Ok(1.5));
+        assert_eq!(evaluate_f64("median(7)", &context), Ok(7.0));
+        assert_eq!(
+            evaluate_f64("variance(2, 4, 4, 4, 5, 5, 7, 9)", &context),
+            Ok(32.0 / 7.0)
+        );
+        assert_eq!(evaluate_f64("stddev(1, 3)", &context), Ok(2f64.sqrt()));
+    }
+
+    #[test]
+    fn too_few_arguments() {
+        let context = Context64::default_with_statistical_functions();
+
+        assert_eq!(
+            evaluate("mean()", &context),
+            Err(EvalError::WrongNumberOfArguments {
+                name: String::from("mean"),
+                expected: vec![Arity::AtLeast(1)],
+                found: 0,
+            })
+        );
+        assert_eq!(
+            evaluate("stddev(1)", &context),
+            Err(EvalError::WrongNumberOfArguments {
+                name: String::from("stddev"),
+                expected: vec![Arity::AtLeast(2)],
+                found: 1,
+            })
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Evaluating an expression for a range of values of one variable.
+
+use crate::{Context, EvalError, Expression};
+use std::collections::HashMap;
+
+/// Evaluates `expression` with `variable` bound to each value from `start` to
+/// `stop`, both inclusive, in increments of `step`, and returns the pairs of
+/// value and result. The expression is compiled once.
+///
+/// `step` has to be non-zero and go from `start` towards `stop`, otherwise
+/// this fails with `EvalError::InvalidStep`. An error at one of the values is
+/// returned as `EvalError::AtSample`.
+pub fn table(
+    expression: &str,
+    variable: &str,
+    start: f64,
+    stop: f64,
+    step: f64,
+    context: &Context,
+) -> Result<Vec<(f64, f64)>, EvalError> {
+    sample(
+        expression,
+        variable,
+        (start, stop, step),
+        context,
+        |value, error| {
+            Err(EvalError::AtSample {
+                value,
+                error: Box::new(error),
+            })
+        },
+    )
+}
+
+/// Like `table`, but a value at which the expression fails to evaluate gets
+/// NaN as its result.
+pub fn table_or_nan(
+    expression: &str,
+    variable: &str,
+    start: f64,
+    stop: f64,
+    step: f64,
+    context: &Context,
+) -> Result<Vec<(f64, f64)>, EvalError> {
+    sample(
+        expression,
+        variable,
+        (start, stop, step),
+        context,
+        |_, _| Ok(f64::NAN),
+    )
+}
+
+fn sample(
+    expression: &str,
+    variable: &str,
+    (start, stop, step): (f64, f64, f64),
+    context: &Context,
+    on_error: impl Fn(f64, EvalError) -> Result<f64, EvalError>,
+) -> Result<Vec<(f64, f64)>, EvalError> {
+    let steps = (stop - start) / step;
+    if step == 0.0 || !steps.is_finite() || steps < 0.0 {
+        return Err(EvalError::InvalidStep(step));
+    }
+    // Allow for rounding errors so that `stop` is included, e.g. when going
+    // from 0 to 1 in steps of 0.1.
+    let count = (steps + 1e-9).floor() as usize + 1;
+
+    let expression = Expression::compile(expression, context)?;
+    let mut bindings = HashMap::new();
+    (0..count)
+        .map(|index| {
+            let value = start + index as f64 * step;
+            bindings.insert(String::from(variable), value);
+            let result = expression
+                .evaluate_with(context, &bindings)
+                .or_else(|error| on_error(value, error))?;
+            Ok((value, result))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{table, table_or_nan, Context, EvalError, LexError};
+
+    #[test]
+    fn samples() {
+        let context = Context::default();
+
+        assert_eq!(
+            table("x * x", "x", 0.0, 2.0, 0.5, &context),
+            Ok(vec![
+                (0.0, 0.0),
+                (0.5, 0.25),
+                (1.0, 1.0),
+                (1.5, 2.25),
+                (2.0, 4.0)
+            ])
+        );
+        assert_eq!(
+            table("x + 1", "x", 1.0, -1.0, -1.0, &context),
+            Ok(vec![(1.0, 2.0), (0.0, 1.0), (-1.0, 0.0)])
+        );
+        assert_eq!(
+            table("x", "x", 0.0, 1.0, 0.4, &context),
+            Ok(vec![(0.0, 0.0), (0.4, 0.4), (0.8, 0.8)])
+        );
+        assert_eq!(table("x", "x", 0.0, 1.0, 0.1, &context).unwrap().len(), 11);
+        assert_eq!(
+            table("2", "x", 3.0, 3.0, 1.0, &context),
+            Ok(vec![(3.0, 2.0)])
+        );
+    }
+
+    #[test]
+    fn invalid_steps() {
+        let context = Context::default();
+
+        assert_eq!(
+            table("x", "x", 0.0, 1.0, 0.0, &context),
+            Err(EvalError::InvalidStep(0.0))
+        );
+        assert_eq!(
+            table("x", "x", 0.0, 1.0, -0.5, &context),
+            Err(EvalError::InvalidStep(-0.5))
+        );
+        assert_eq!(
+            table("x", "x", 0.0, f64::INFINITY, 1.0, &context),
+            Err(EvalError::InvalidStep(1.0))
+        );
+    }
+
+    #[test]
+    fn errors_at_samples() {
+        let context = Context::default_with_math_functions();
+
+        assert_eq!(
+            table("(x - 1)!", "x", 0.0, 2.0, 1.0, &context),
+            Err(EvalError::AtSample {
+                value: 0.0,
+                error: Box::new(EvalError::InvalidOperand {
+                    operator: String::from("!"),
+                    value: -1.0,
+                }),
+            })
+        );
+
+        let rows = table_or_nan("(x - 1)!", "x", 0.0, 2.0, 1.0, &context).unwrap();
+        assert!(rows[0].1.is_nan());
+        assert_eq!(rows[1..], [(1.0, 1.0), (2.0, 1.0)]);
+
+        assert_eq!(
+            table("x + 0x", "x", 0.0, 1.0, 1.0, &context),
+            Err(EvalError::Lex(LexError::MalformedNumber(String::from(
+                "0x"
+            ))))
+        );
+    }
+}
@@ -0,0 +1,1071 @@
+use crate::bytecode::{OpCode, UnaryOp};
+use crate::{is_logical_operator, power, Context, EvalError};
+#[cfg(feature = "bigint")]
+use num_bigint::BigInt;
+#[cfg(feature = "bigint")]
+use num_traits::{Euclid, One, Signed, ToPrimitive, Zero};
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter;
+use std::str::FromStr;
+
+/// The floating point type that a `Context` computes with, `f64` or `f32`.
+/// Integers and truth values are the same whatever it is, see `Value`.
+pub trait Number:
+    num_traits::Float
+    + num_traits::float::TotalOrder
+    + FromStr
+    + fmt::Display
+    + fmt::Debug
+    + Default
+    + iter::Sum
+    + iter::Product
+    + Send
+    + Sync
+    + 'static
+{
+    /// The nearest number to an `f64`.
+    fn from_f64(value: f64) -> Self;
+
+    /// The number as an `f64`, which holds every `f32` exactly.
+    fn into_f64(self) -> f64;
+}
+
+impl Number for f64 {
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+
+    fn into_f64(self) -> f64 {
+        self
+    }
+}
+
+impl Number for f32 {
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+
+    fn into_f64(self) -> f64 {
+        f64::from(self)
+    }
+}
+
+/// The nearest number to an integer, which `as` rounds only once, unlike a
+/// detour through `f64`.
+fn cast<N: Number, T: num_traits::NumCast>(value: T) -> N {
+    num_traits::cast(value).unwrap_or_else(N::nan)
+}
+
+/// A value of an expression, which keeps integers and truth values apart
+/// from floating point numbers of the type `N`, see `evaluate`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value<N = f64> {
+    Float(N),
+    Int(i64),
+    Bool(bool),
+    /// An integer that doesn't fit into an `i64`. Integers that do are always
+    /// `Int`.
+    #[cfg(feature = "bigint")]
+    Big(BigInt),
+}
+
+/// What evaluation does if an operation on two integers has no exact integer
+/// result, see `Context::set_integer_mode`. The default is `Promote`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IntegerMode {
+    /// The result is a float, e.g. `10 / 4` is 2.5 and `2 ^ 63` is about
+    /// 9.2e18.
+    ///
+    /// With the `bigint` feature, integers that overflow an `i64` become a
+    /// `Value::Big` instead, unless they have more than `BIGINT_MAX_BITS`
+    /// bits.
+    #[default]
+    Promote,
+    /// Overflow, like in `2 ^ 63`, fails with `EvalError::ResultOutOfRange`.
+    /// Inexact results still give a float, so `10 / 4` is 2.5. `//` and `%`
+    /// by zero fail with `EvalError::InvalidOperand`.
+    ///
+    /// With the `bigint` feature, only integers with more than
+    /// `BIGINT_MAX_BITS` bits overflow.
+    Checked,
+    /// Like `Checked`, but inexact results fail as well, with
+    /// `EvalError::NotAnInteger`. That includes division by zero and
+    /// negative exponents, so `10 / 4` and `2 ^ -1` are errors.
+    ///
+    /// With the `bigint` feature, combining a `Value::Big` with a float fails
+    /// with `EvalError::PrecisionLoss`.
+    Strict,
+}
+
+/// The largest magnitude of a number literal that is read as an `Int`.
+/// Literals are parsed as `f64`, which represents every integer up to 2^53
+/// exactly. With the `bigint` feature, larger literals are read from their
+/// digits instead.
+const LITERAL_LIMIT: f64 = (1u64 << 53) as f64;
+
+/// The largest number of bits of a `Value::Big`. Larger results overflow,
+/// which also keeps operations like `9 ^ 9 ^ 9` from running out of memory.
+#[cfg(feature = "bigint")]
+pub const BIGINT_MAX_BITS: u64 = 1 << 16;
+
+impl<N: Number> Value<N> {
+    /// The value of a number literal, which is told apart from a float by the
+    /// `text` it was parsed from. The constants that `logical_branch` adds
+    /// for `&&` and `||` are written `true` and `false`. Floats are parsed
+    /// from the text as an `N` if they can be, rather than rounded from
+    /// `value`.
+    pub(crate) fn literal(value: f64, text: &str, context: &Context<N>) -> Self {
+        match text {
+            "true" => return Self::Bool(true),
+            "false" => return Self::Bool(false),
+            _ => (),
+        }
+        let float = || Self::Float(text.parse().unwrap_or_else(|_| N::from_f64(value)));
+        if is_float_literal(text, context) {
+            return float();
+        }
+        #[cfg(feature = "bigint")]
+        if value.abs() >= LITERAL_LIMIT {
+            let grouping_separator = context.number_locale().grouping_separator;
+            let digits: String = text
+                .chars()
+                .filter(|&c| Some(c) != grouping_separator)
+                .collect();
+            // Hex literals aren't decimal digits, so they stay floats.
+            return match digits.parse::<BigInt>() {
+                Ok(big) => Self::from(big),
+                Err(_) => float(),
+            };
+        }
+        if value.fract() == 0.0 && value.abs() <= LITERAL_LIMIT {
+            Self::Int(value as i64)
+        } else {
+            float()
+        }
+    }
+
+    /// The value as a number, with `true` as 1 and `false` as 0. A
+    /// `Value::Big` becomes the nearest float, or an infinity beyond
+    /// `f64::MAX`.
+    pub fn to_f64(&self) -> f64 {
+        self.to_number().into_f64()
+    }
+
+    /// Like `to_f64`, as an `N`.
+    pub fn to_number(&self) -> N {
+        match *self {
+            Self::Float(value) => value,
+            Self::Int(value) => cast(value),
+            Self::Bool(value) => cast(u8::from(value)),
+            #[cfg(feature = "bigint")]
+            Self::Big(ref value) => N::from_f64(big_to_f64(value)),
+        }
+    }
+
+    /// The value with its float as an `f64`, as errors hold it.
+    pub(crate) fn into_f64_value(self) -> Value {
+        match self {
+            Self::Float(value) => Value::Float(value.into_f64()),
+            Self::Int(value) => Value::Int(value),
+            Self::Bool(value) => Value::Bool(value),
+            #[cfg(feature = "bigint")]
+            Self::Big(value) => Value::Big(value),
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Float(_) => "float",
+            Self::Int(_) => "int",
+            Self::Bool(_) => "bool",
+            #[cfg(feature = "bigint")]
+            Self::Big(_) => "int",
+        }
+    }
+
+    /// Applies one of the built-in infix operators, see `evaluate` for the
+    /// rules. Unlike in an evaluation, truth values are never numbers and
+    /// `==` always compares exactly. Other operators fail with
+    /// `EvalError::UnknownOperator`.
+    pub fn binary(
+        symbol: &str,
+        left: Self,
+        right: Self,
+        mode: IntegerMode,
+    ) -> Result<Self, EvalError> {
+        let mismatch = |value: Self| EvalError::TypeMismatch {
+            operator: String::from(symbol),
+            value: value.into_f64_value(),
+        };
+        match (symbol, &left, &right) {
+            ("==", Self::Bool(a), Self::Bool(b)) => return Ok(Self::Bool(a == b)),
+            ("!=", Self::Bool(a), Self::Bool(b)) => return Ok(Self::Bool(a != b)),
+            (_, Self::Bool(_), _) => return Err(mismatch(left)),
+            (_, _, Self::Bool(_)) => return Err(mismatch(right)),
+            _ => (),
+        }
+
+        let integers = match (&left, &right) {
+            (Self::Int(a), Self::Int(b)) => Some((*a, *b)),
+            _ => None,
+        };
+        if let Some((a, b)) = integers {
+            if let Some(result) = compare(symbol, a.cmp(&b)) {
+                return Ok(Self::Bool(result));
+            }
+            if let Some(result) = integer_operation(symbol, a, b) {
+                return Ok(Self::Int(result));
+            }
+        }
+        #[cfg(feature = "bigint")]
+        if let Some(result) = big_binary(symbol, &left, &right, mode) {
+            return result;
+        }
+
+        let (a, b) = (left.to_number(), right.to_number());
+        if let Some(ordering) = a.partial_cmp(&b) {
+            if let Some(result) = compare(symbol, ordering) {
+                return Ok(Self::Bool(result));
+            }
+        } else if compare(symbol, std::cmp::Ordering::Equal).is_some() {
+            // NaN is neither equal to, less or greater than anything.
+            return Ok(Self::Bool(symbol == "!="));
+        }
+        let result = match float_operation(symbol, a, b) {
+            Some(result) => result,
+            // Operators like the bitwise ones take numbers and give a float.
+            None => {
+                let opcode = OpCode::from_symbol(symbol)
+                    .ok_or_else(|| EvalError::UnknownOperator(String::from(symbol)))?;
+                let result = opcode.apply(a.into_f64(), b.into_f64())?;
+                return Ok(Self::Float(N::from_f64(result)));
+            }
+        };
+        match integers {
+            Some((a, b)) => {
+                let inexact = match symbol {
+                    "/" => b == 0 || a.checked_rem(b).is_some_and(|r| r != 0),
+                    "^" => b < 0,
+                    _ => false,
+                };
+                integer_fallback(symbol, inexact, b == 0, result, mode)
+            }
+            None => Ok(Self::Float(result)),
+        }
+    }
+
+    /// Applies one of the built-in prefix operators, like `binary`. `-`, `+`
+    /// and `!` are applied as described in `evaluate`, others to the value
+    /// as a number, which gives a float.
+    pub fn prefix(symbol: &str, value: Self, mode: IntegerMode) -> Result<Self, EvalError> {
+        match (symbol, &value) {
+            ("!", &Self::Bool(value)) => Ok(Self::Bool(!value)),
+            // Like `0 - value`, which overflows for `i64::MIN`.
+            ("-", &Self::Int(value)) => Self::binary(symbol, Self::Int(0), Self::Int(value), mode),
+            ("-", &Self::Float(value)) => Ok(Self::Float(-value)),
+            #[cfg(feature = "bigint")]
+            ("-", Self::Big(value)) => Ok(Self::from(-value)),
+            ("!", _) | (_, Self::Bool(_)) => Err(EvalError::TypeMismatch {
+                operator: String::from(symbol),
+                value: value.into_f64_value(),
+            }),
+            ("+", _) => Ok(value),
+            _ => match UnaryOp::prefix(symbol) {
+                Some(op) => Ok(Self::Float(N::from_f64(op.apply(value.to_f64())?))),
+                None => Err(EvalError::UnknownOperator(String::from(symbol))),
+            },
+        }
+    }
+
+    /// Applies one of the built-in postfix operators, like `prefix`. The
+    /// factorial `!` of an integer gives an integer unless it overflows.
+    pub fn postfix(symbol: &str, value: Self, mode: IntegerMode) -> Result<Self, EvalError> {
+        let op = UnaryOp::postfix(symbol)
+            .ok_or_else(|| EvalError::UnknownOperator(String::from(symbol)))?;
+        match value {
+            Self::Bool(_) => Err(EvalError::TypeMismatch {
+                operator: String::from(symbol),
+                value: value.into_f64_value(),
+            }),
+            Self::Int(n) if symbol == "!" && n >= 0 => {
+                let float = op.apply(n as f64)?;
+                factorial(n, float, mode)
+            }
+            _ => Ok(Self::Float(N::from_f64(op.apply(value.to_f64())?))),
+        }
+    }
+}
+
+/// The result of an arithmetic operator on two integers, if it is an integer
+/// that doesn't overflow.
+fn integer_operation(symbol: &str, a: i64, b: i64) -> Option<i64> {
+    match symbol {
+        "+" => a.checked_add(b),
+        "-" => a.checked_sub(b),
+        "*" => a.checked_mul(b),
+        "/" => a.checked_rem(b).filter(|&r| r == 0).and(a.checked_div(b)),
+        "//" => a.checked_div_euclid(b),
+        "%" => a.checked_rem_euclid(b),
+        "^" if b >= 0 => {
+            // Exponents this large overflow unless the base is 0, 1 or -1,
+            // for which only their parity matters.
+            let parity = u32::from(b % 2 == 1);
+            a.checked_pow(u32::try_from(b).unwrap_or(u32::MAX - 1 + parity))
+        }
+        _ => None,
+    }
+}
+
+fn float_operation<N: Number>(symbol: &str, a: N, b: N) -> Option<N> {
+    Some(match symbol {
+        "+" => a + b,
+        "-" => a - b,
+        "*" => a * b,
+        "/" => a / b,
+        "//" => div_euclid(a, b),
+        "%" => rem_euclid(a, b),
+        "^" => power(a, b),
+        _ => return None,
+    })
+}
+
+/// The Euclidean quotient, like `f64::div_euclid`.
+pub(crate) fn div_euclid<N: Number>(a: N, b: N) -> N {
+    let quotient = (a / b).trunc();
+    if a % b >= N::zero() {
+        quotient
+    } else if b > N::zero() {
+        quotient - N::one()
+    } else {
+        quotient + N::one()
+    }
+}
+
+/// The Euclidean remainder, which is never negative, like `f64::rem_euclid`.
+pub(crate) fn rem_euclid<N: Number>(a: N, b: N) -> N {
+    let remainder = a % b;
+    if remainder < N::zero() {
+        remainder + b.abs()
+    } else {
+        remainder
+    }
+}
+
+/// The float `result` of an operator on two integers that has no integer
+/// result, because it is `inexact` or overflows, unless `mode` makes that an
+/// error.
+fn integer_fallback<N: Number>(
+    symbol: &str,
+    inexact: bool,
+    by_zero: bool,
+    result: N,
+    mode: IntegerMode,
+) -> Result<Value<N>, EvalError> {
+    let error = match mode {
+        IntegerMode::Promote => None,
+        IntegerMode::Checked | IntegerMode::Strict if by_zero && symbol != "/" => {
+            Some(EvalError::InvalidOperand {
+                operator: String::from(symbol),
+                value: 0.0,
+            })
+        }
+        IntegerMode::Checked if inexact => None,
+        IntegerMode::Strict if inexact => Some(EvalError::NotAnInteger {
+            operator: String::from(symbol),
+            value: result.into_f64(),
+        }),
+        IntegerMode::Checked | IntegerMode::Strict => Some(EvalError::ResultOutOfRange {
+            operator: String::from(symbol),
+            value: result.into_f64(),
+        }),
+    };
+    match error {
+        Some(error) => Err(error),
+        None => Ok(Value::Float(result)),
+    }
+}
+
+/// Applies an operator to two integers of which at least one is a
+/// `Value::Big` or whose result overflows an `i64`. `None` if the operands
+/// aren't both integers and can be combined as floats.
+#[cfg(feature = "bigint")]
+fn big_binary<N: Number>(
+    symbol: &str,
+    left: &Value<N>,
+    right: &Value<N>,
+    mode: IntegerMode,
+) -> Option<Result<Value<N>, EvalError>> {
+    let big = |value: &Value<N>| match value {
+        Value::Int(value) => Some(BigInt::from(*value)),
+        Value::Big(value) => Some(value.clone()),
+        _ => None,
+    };
+    let (a, b) = match (big(left), big(right)) {
+        (Some(a), Some(b)) => (a, b),
+        (Some(_), None) | (None, Some(_)) if mode == IntegerMode::Strict => {
+            let value = match (left, right) {
+                (Value::Big(_), _) => left,
+                (_, Value::Big(_)) => right,
+                _ => return None,
+            };
+            return Some(Err(EvalError::PrecisionLoss {
+                operator: String::from(symbol),
+                value: value.clone().into_f64_value(),
+            }));
+        }
+        _ => return None,
+    };
+    if let Some(result) = compare(symbol, a.cmp(&b)) {
+        return Some(Ok(Value::Bool(result)));
+    }
+
+    let result = match symbol {
+        "+" => Some(&a + &b),
+        "-" => Some(&a - &b),
+        "*" if a.bits() + b.bits() <= BIGINT_MAX_BITS + 1 => Some(&a * &b),
+        "*" => None,
+        "/" => div_rem_euclid(&a, &b)
+            .filter(|(_, remainder)| remainder.is_zero())
+            .map(|(quotient, _)| quotient),
+        "//" => div_rem_euclid(&a, &b).map(|(quotient, _)| quotient),
+        "%" => div_rem_euclid(&a, &b).map(|(_, remainder)| remainder),
+        "^" => big_power(&a, &b),
+        _ => return None,
+    };
+    match result {
+        Some(result) if result.bits() <= BIGINT_MAX_BITS => Some(Ok(Value::from(result))),
+        _ => {
+            let inexact = symbol == "/" || (symbol == "^" && b.is_negative());
+            let (a, b) = (N::from_f64(big_to_f64(&a)), N::from_f64(big_to_f64(&b)));
+            let result = float_operation(symbol, a, b)?;
+            Some(integer_fallback(symbol, inexact, b.is_zero(), result, mode))
+        }
+    }
+}
+
+/// `base ^ exponent`, if the exponent isn't negative and the result has at
+/// most about `BIGINT_MAX_BITS` bits.
+#[cfg(feature = "bigint")]
+fn big_power(base: &BigInt, exponent: &BigInt) -> Option<BigInt> {
+    if exponent.is_negative() {
+        return None;
+    }
+    if base.bits() <= 1 {
+        // 0, 1 and -1, for which only whether the exponent is 0 or odd
+        // matters, however large it is.
+        let odd = exponent.bit(0);
+        return Some(match (base.is_zero(), base.is_negative()) {
+            (true, _) if exponent.is_zero() => BigInt::one(),
+            (true, _) => BigInt::zero(),
+            (false, true) if odd => -BigInt::one(),
+            (false, _) => BigInt::one(),
+        });
+    }
+    let exponent = exponent.to_u32()?;
+    if (base.bits() - 1).saturating_mul(u64::from(exponent)) > BIGINT_MAX_BITS {
+        return None;
+    }
+    Some(base.pow(exponent))
+}
+
+/// The nearest `f64`, or an infinity beyond `f64::MAX`. `num-bigint` never
+/// fails this conversion.
+#[cfg(feature = "bigint")]
+fn big_to_f64(value: &BigInt) -> f64 {
+    value.to_f64().unwrap_or(f64::NAN)
+}
+
+/// The Euclidean quotient and remainder, or `None` for a zero divisor.
+#[cfg(feature = "bigint")]
+fn div_rem_euclid(dividend: &BigInt, divisor: &BigInt) -> Option<(BigInt, BigInt)> {
+    if divisor.is_zero() {
+        return None;
+    }
+    Some((dividend.div_euclid(divisor), dividend.rem_euclid(divisor)))
+}
+
+fn compare(symbol: &str, ordering: std::cmp::Ordering) -> Option<bool> {
+    use std::cmp::Ordering::*;
+    Some(match symbol {
+        "==" => ordering == Equal,
+        "!=" => ordering != Equal,
+        "<" => ordering == Less,
+        "<=" => ordering != Greater,
+        ">" => ordering == Greater,
+        ">=" => ordering != Less,
+        _ => return None,
+    })
+}
+
+impl<N: Number> From<N> for Value<N> {
+    fn from(value: N) -> Self {
+        Self::Float(value)
+    }
+}
+
+impl<N: Number> From<i64> for Value<N> {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+
+impl<N: Number> From<bool> for Value<N> {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+/// An `Int` if the value fits into an `i64`, a `Big` otherwise.
+#[cfg(feature = "bigint")]
+impl<N: Number> From<BigInt> for Value<N> {
+    fn from(value: BigInt) -> Self {
+        match value.to_i64() {
+            Some(value) => Self::Int(value),
+            None => Self::Big(value),
+        }
+    }
+}
+
+impl<N: Number> fmt::Display for Value<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Float(value) => fmt::Display::fmt(value, f),
+            Self::Int(value) => value.fmt(f),
+            Self::Bool(value) => value.fmt(f),
+            #[cfg(feature = "bigint")]
+            Self::Big(value) => value.fmt(f),
+        }
+    }
+}
+
+/// Applies a built-in infix operator in a context. `&&` and `||` take truth
+/// values, see `condition`, other operators take truth values as the
+/// integers 1 and 0, unless the context has strict truth values. `==` and
+/// `!=` compare numbers with the equality tolerance of the context, if it has
+/// one.
+pub(crate) fn apply_binary<N: Number>(
+    symbol: &str,
+    left: Value<N>,
+    right: Value<N>,
+    context: &Context<N>,
+) -> Result<Value<N>, EvalError> {
+    if is_logical_operator(symbol) {
+        let left = condition(symbol, left, context)?;
+        let right = condition(symbol, right, context)?;
+        return Ok(Value::Bool(if symbol == "&&" {
+            left && right
+        } else {
+            left || right
+        }));
+    }
+
+    let (left, right) = (operand(left, context), operand(right, context));
+    let is_number = |value: &Value<N>| !matches!(value, Value::Bool(_));
+    if let Some(tolerance) = context.equality_tolerance() {
+        if (symbol == "==" || symbol == "!=") && is_number(&left) && is_number(&right) {
+            let equal = tolerance.is_close(left.to_f64(), right.to_f64());
+            return Ok(Value::Bool(equal == (symbol == "==")));
+        }
+    }
+    Value::binary(symbol, left, right, context.integer_mode())
+}
+
+/// Like `apply_binary`, for a built-in prefix operator. `!` takes a truth
+/// value like `&&`.
+pub(crate) fn apply_prefix<N: Number>(
+    symbol: &str,
+    value: Value<N>,
+    context: &Context<N>,
+) -> Result<Value<N>, EvalError> {
+    if symbol == "!" && !context.strict_truth_values() {
+        return condition(symbol, value, context).map(|value| Value::Bool(!value));
+    }
+    Value::prefix(symbol, operand(value, context), context.integer_mode())
+}
+
+/// Like `apply_binary`, for a built-in postfix operator.
+pub(crate) fn apply_postfix<N: Number>(
+    symbol: &str,
+    value: Value<N>,
+    context: &Context<N>,
+) -> Result<Value<N>, EvalError> {
+    Value::postfix(symbol, operand(value, context), context.integer_mode())
+}
+
+/// A truth value as the integer 1 or 0, unless the context has strict truth
+/// values.
+fn operand<N: Number>(value: Value<N>, context: &Context<N>) -> Value<N> {
+    match value {
+        Value::Bool(value) if !context.strict_truth_values() => Value::Int(i64::from(value)),
+        value => value,
+    }
+}
+
+/// The value of a number for a function, with truth values as 1 and 0. Fails
+/// for truth values if the context has strict truth values.
+pub(crate) fn number<N: Number>(
+    operator: &str,
+    value: &Value<N>,
+    context: &Context<N>,
+) -> Result<N, EvalError> {
+    match value {
+        Value::Bool(_) if context.strict_truth_values() => Err(EvalError::TypeMismatch {
+            operator: String::from(operator),
+            value: value.clone().into_f64_value(),
+        }),
+        _ => Ok(value.to_number()),
+    }
+}
+
+/// The truth value of a condition or an operand of `&&`, `||` and `!`.
+/// Numbers are true unless they are 0, or fail if the context has strict
+/// truth values.
+pub(crate) fn condition<N: Number>(
+    operator: &str,
+    value: Value<N>,
+    context: &Context<N>,
+) -> Result<bool, EvalError> {
+    match value {
+        Value::Bool(value) => Ok(value),
+        value if !context.strict_truth_values() => Ok(value.to_f64() != 0.0),
+        value => Err(EvalError::TypeMismatch {
+            operator: String::from(operator),
+            value: value.into_f64_value(),
+        }),
+    }
+}
+
+/// `n!` for an integer `n`, computed exactly unless it overflows, in which
+/// case it is the `float` result of the factorial operator unless `mode`
+/// makes that an error.
+fn factorial<N: Number>(n: i64, float: f64, mode: IntegerMode) -> Result<Value<N>, EvalError> {
+    let mut result = Value::Int(1);
+    for k in 2..=n {
+        result = match Value::binary("*", result, Value::Int(k), mode) {
+            Ok(Value::Float(_)) | Err(_) => {
+                return integer_fallback("!", false, false, N::from_f64(float), mode)
+            }
+            Ok(product) => product,
+        };
+    }
+    Ok(result)
+}
+
+/// Whether a number literal has decimals or an exponent, like `4.0` or `1e3`.
+fn is_float_literal<N: Number>(text: &str, context: &Context<N>) -> bool {
+    let decimal_separator = context.number_locale().decimal_separator;
+    !text.starts_with("0x") && text.contains([decimal_separator, 'e', 'E'])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{evaluate, Context, Context64, EvalError, IntegerMode, Value};
+
+    fn check(cases: &[(&str, Value)], context: &Context) {
+        for (expression, expected) in cases {
+            assert_eq!(
+                evaluate(expression, context).as_ref(),
+                Ok(expected),
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn integers() {
+        let mut context = Context::default();
+        context.set_variable("max", i64::MAX);
+        context.set_variable("min", i64::MIN);
+
+        check(
+            &[
+                ("3", Value::Int(3)),
+                ("1e3", Value::Float(1000.0)),
+                ("3.0", Value::Float(3.0)),
+                ("0x10", Value::Int(16)),
+                ("1e20", Value::Float(1e20)),
+                ("2 + 3 * 4", Value::Int(14)),
+                ("10 / 2", Value::Int(5)),
+                ("10 / 4", Value::Float(2.5)),
+                ("-7 // 2", Value::Int(-4)),
+                ("7 // -2", Value::Int(-3)),
+                ("-7 % 2", Value::Int(1)),
+                ("2 ^ 10", Value::Int(1024)),
+                ("2 ^ -1", Value::Float(0.5)),
+                ("max - 1", Value::Int(i64::MAX - 1)),
+                ("1 / 0", Value::Float(f64::INFINITY)),
+            ],
+            &context,
+        );
+        #[cfg(not(feature = "bigint"))]
+        check(
+            &[
+                ("max + 1", Value::Float(i64::MAX as f64 + 1.0)),
+                ("min - 1", Value::Float(i64::MIN as f64 - 1.0)),
+                ("max * 2", Value::Float(i64::MAX as f64 * 2.0)),
+                ("2 ^ 63", Value::Float(2f64.powi(63))),
+                ("-min", Value::Float(-(i64::MIN as f64))),
+                ("min / -1", Value::Float(-(i64::MIN as f64))),
+            ],
+            &context,
+        );
+        assert_eq!(
+            evaluate("5 // 0", &context).map(|value| value.to_f64().is_infinite()),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn floats() {
+        let mut context = Context::default();
+        context.set_variable("x", 2.0);
+
+        check(
+            &[
+                ("1 + 0.5", Value::Float(1.5)),
+                ("2 * 1.5", Value::Float(3.0)),
+                ("10 / 2.0", Value::Float(5.0)),
+                ("x + 1", Value::Float(3.0)),
+                ("x ^ 2", Value::Float(4.0)),
+                ("7.5 // 2", Value::Float(3.0)),
+                ("-x", Value::Float(-2.0)),
+                ("(-8) ^ (1 / 3)", Value::Float(-2.0)),
+            ],
+            &context,
+        );
+    }
+
+    #[test]
+    fn booleans() {
+        let mut context = Context::default();
+        context.set_variable("flag", true);
+        context.set_variable("nan", f64::NAN);
+
+        check(
+            &[
+                ("1 < 2", Value::Bool(true)),
+                ("1 == 1.0", Value::Bool(true)),
+                ("2 >= 2.5", Value::Bool(false)),
+                ("1 < 2 < 3", Value::Bool(true)),
+                ("3 < 2 < 1", Value::Bool(false)),
+                ("!(1 > 2)", Value::Bool(true)),
+                ("flag != (1 < 2)", Value::Bool(false)),
+                ("1 > 2 || flag", Value::Bool(true)),
+                ("flag || 1", Value::Bool(true)),
+                ("!flag && 1", Value::Bool(false)),
+                ("if(flag, 1, 2.5)", Value::Int(1)),
+                ("if(!flag, 1, 2.5)", Value::Float(2.5)),
+                ("if(flag, 2, 2.0)", Value::Int(2)),
+                ("nan == nan", Value::Bool(false)),
+                ("nan != nan", Value::Bool(true)),
+            ],
+            &context,
+        );
+    }
+
+    #[test]
+    fn loose_truth_values() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("flag", false);
+        assert!(!context.strict_truth_values());
+
+        check(
+            &[
+                ("flag + 1", Value::Int(1)),
+                ("1 * (2 < 3)", Value::Int(1)),
+                ("flag == 0", Value::Bool(true)),
+                ("1 && flag", Value::Bool(false)),
+                ("flag || 0.5", Value::Bool(true)),
+                ("!2", Value::Bool(false)),
+                ("-flag", Value::Int(0)),
+                ("if(1, 2, 3)", Value::Int(2)),
+                ("sqrt(flag)", Value::Float(0.0)),
+            ],
+            &context,
+        );
+    }
+
+    #[test]
+    fn type_mismatches() {
+        let mut context = Context64::default_with_math_functions();
+        context.set_strict_truth_values(true);
+        context.set_variable("flag", false);
+        let mismatch = |operator: &str, value| {
+            Err(EvalError::TypeMismatch {
+                operator: String::from(operator),
+                value,
+            })
+        };
+
+        assert_eq!(
+            evaluate("flag + 1", &context),
+            mismatch("+", Value::Bool(false))
+        );
+        assert_eq!(
+            evaluate("1 * (2 < 3)", &context),
+            mismatch("*", Value::Bool(true))
+        );
+        assert_eq!(
+            evaluate("flag < flag", &context),
+            mismatch("<", Value::Bool(false))
+        );
+        assert_eq!(
+            evaluate("flag == 0", &context),
+            mismatch("==", Value::Bool(false))
+        );
+        assert_eq!(
+            evaluate("1 && flag", &context),
+            mismatch("&&", Value::Int(1))
+        );
+        assert_eq!(
+            evaluate("flag || 0.5", &context),
+            mismatch("||", Value::Float(0.5))
+        );
+        assert_eq!(evaluate("!2", &context), mismatch("!", Value::Int(2)));
+        assert_eq!(
+            evaluate("-flag", &context),
+            mismatch("-", Value::Bool(false))
+        );
+        assert_eq!(
+            evaluate("if(1, 2, 3)", &context),
+            mismatch("if", Value::Int(1))
+        );
+        assert_eq!(
+            evaluate("sqrt(flag)", &context),
+            mismatch("sqrt", Value::Bool(false))
+        );
+        assert_eq!(
+            evaluate("flag + 1", &context).unwrap_err().to_string(),
+            "+ can't be applied to bool false"
+        );
+    }
+
+    #[test]
+    fn functions_and_operators() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("y", 3.0);
+        check(
+            &[
+                ("sqrt(16)", Value::Float(4.0)),
+                ("sqrt(16) == 4", Value::Bool(true)),
+                ("max(1, 2) + 1", Value::Float(3.0)),
+                ("y * 2", Value::Float(6.0)),
+                ("3!", Value::Int(6)),
+                ("20!", Value::Int(2_432_902_008_176_640_000)),
+                ("series(k, 1, 10, k)", Value::Int(55)),
+                ("series(k, 1, 4, 12 / k)", Value::Int(25)),
+                ("series(k, 1, 2, 1 / k)", Value::Float(1.5)),
+                ("prod(k, 1, 20, k)", Value::Int(2_432_902_008_176_640_000)),
+                ("series(k, 3, 1, k)", Value::Int(0)),
+            ],
+            &context,
+        );
+        #[cfg(not(feature = "bigint"))]
+        check(
+            &[
+                ("21!", Value::Float(51_090_942_171_709_440_000.0)),
+                (
+                    "prod(k, 1, 21, k)",
+                    Value::Float(51_090_942_171_709_440_000.0),
+                ),
+            ],
+            &context,
+        );
+        assert_eq!(
+            evaluate("series(k, 1, 2.5, k)", &context),
+            Err(EvalError::InvalidOperand {
+                operator: String::from("series"),
+                value: 2.5,
+            })
+        );
+        assert_eq!(
+            evaluate("x = 1", &context),
+            Err(EvalError::AssignmentNotAllowed(String::from("x")))
+        );
+
+        let context = Context::default_with_bitwise_operators();
+        check(&[("6 & 3", Value::Float(2.0))], &context);
+    }
+
+    #[test]
+    fn integer_modes() {
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("smallest", i64::MIN);
+        assert_eq!(context.integer_mode(), IntegerMode::Promote);
+        let out_of_range = |operator: &str, value| {
+            Err(EvalError::ResultOutOfRange {
+                operator: String::from(operator),
+                value,
+            })
+        };
+
+        for &mode in &[
+            IntegerMode::Promote,
+            IntegerMode::Checked,
+            IntegerMode::Strict,
+        ] {
+            context.set_integer_mode(mode);
+            check(
+                &[
+                    ("2^62 + 1", Value::Int((1 << 62) + 1)),
+                    ("10 / 2", Value::Int(5)),
+                    ("1 ^ 5000000000", Value::Int(1)),
+                    ("(-1) ^ 5000000001", Value::Int(-1)),
+                    ("10 / 4.0", Value::Float(2.5)),
+                ],
+                &context,
+            );
+        }
+
+        context.set_integer_mode(IntegerMode::Promote);
+        check(&[("10 / 4", Value::Float(2.5))], &context);
+        #[cfg(not(feature = "bigint"))]
+        check(
+            &[
+                ("2 ^ 63", Value::Float(2f64.powi(63))),
+                ("-smallest", Value::Float(2f64.powi(63))),
+            ],
+            &context,
+        );
+        assert!(evaluate("1 % 0", &context).unwrap().to_f64().is_nan());
+
+        context.set_integer_mode(IntegerMode::Checked);
+        check(
+            &[
+                ("10 / 4", Value::Float(2.5)),
+                ("2 ^ -1", Value::Float(0.5)),
+                ("1 / 0", Value::Float(f64::INFINITY)),
+            ],
+            &context,
+        );
+        #[cfg(not(feature = "bigint"))]
+        {
+            assert_eq!(
+                evaluate("2 ^ 63", &context),
+                out_of_range("^", 2f64.powi(63))
+            );
+            assert_eq!(
+                evaluate("2^62 * 2 + 2.0", &context),
+                out_of_range("*", 2f64.powi(63))
+            );
+            assert_eq!(
+                evaluate("3037000500 * 3037000500", &context),
+                out_of_range("*", 3037000500f64 * 3037000500f64)
+            );
+            assert_eq!(
+                evaluate("-smallest", &context),
+                out_of_range("-", 2f64.powi(63))
+            );
+            assert_eq!(
+                evaluate("smallest - 1", &context),
+                out_of_range("-", i64::MIN as f64)
+            );
+            assert_eq!(
+                evaluate("prod(k, 1, 21, k)", &context),
+                out_of_range("*", 51_090_942_171_709_440_000.0)
+            );
+            assert_eq!(
+                evaluate("21!", &context),
+                out_of_range("!", 51_090_942_171_709_440_000.0)
+            );
+        }
+        #[cfg(feature = "bigint")]
+        assert_eq!(
+            evaluate("2 ^ 65536", &context),
+            out_of_range("^", f64::INFINITY)
+        );
+        assert_eq!(
+            evaluate("7 // 0", &context),
+            Err(EvalError::InvalidOperand {
+                operator: String::from("//"),
+                value: 0.0,
+            })
+        );
+
+        context.set_integer_mode(IntegerMode::Strict);
+        let not_an_integer = |operator: &str, value| {
+            Err(EvalError::NotAnInteger {
+                operator: String::from(operator),
+                value,
+            })
+        };
+        assert_eq!(evaluate("10 / 4", &context), not_an_integer("/", 2.5));
+        assert_eq!(evaluate("10 / 4 + 4.0", &context), not_an_integer("/", 2.5));
+        assert_eq!(evaluate("2 ^ -1", &context), not_an_integer("^", 0.5));
+        assert_eq!(
+            evaluate("1 / 0", &context),
+            not_an_integer("/", f64::INFINITY)
+        );
+        #[cfg(not(feature = "bigint"))]
+        assert_eq!(
+            evaluate("2 ^ 63", &context),
+            out_of_range("^", 2f64.powi(63))
+        );
+        assert_eq!(
+            evaluate("10 / 4", &context).unwrap_err().to_string(),
+            "Result 2.5 of / is not an integer"
+        );
+    }
+
+    #[cfg(feature = "bigint")]
+    #[test]
+    fn big_integers() {
+        let big = |text: &str| Value::Big(text.parse().unwrap());
+        let mut context = Context::default_with_math_functions();
+        context.set_variable("largest", i64::MAX);
+        context.set_variable("smallest", i64::MIN);
+
+        check(
+            &[
+                (
+                    "2 ^ 200",
+                    big("1606938044258990275541962092341162602522202993782792835301376"),
+                ),
+                (
+                    "50!",
+                    big("30414093201713378043612608166064768844377641568960512000000000000"),
+                ),
+                ("largest + 1", big("9223372036854775808")),
+                ("-smallest", big("9223372036854775808")),
+                ("(largest + 1) - 1", Value::Int(i64::MAX)),
+                ("series(k, 1, 3, 2 ^ 64)", big("55340232221128654848")),
+                ("2 ^ 64 // 3", Value::Int(6_148_914_691_236_517_205)),
+                ("(0 - 2 ^ 64) % 3", Value::Int(2)),
+                ("2 ^ 64 / 2 ^ 32", Value::Int(1 << 32)),
+                ("2 ^ 64 / 3", Value::Float(2f64.powi(64) / 3.0)),
+                ("2 ^ 64 > largest", Value::Bool(true)),
+                ("2 ^ 64 + 0.5", Value::Float(2f64.powi(64))),
+                ("(-1) ^ (2 ^ 100 + 1)", Value::Int(-1)),
+                ("2 ^ 65536", Value::Float(f64::INFINITY)),
+                (
+                    "123456789012345678901234567890 + 1",
+                    big("123456789012345678901234567891"),
+                ),
+                ("9007199254740993", Value::Int(9_007_199_254_740_993)),
+                ("9007199254740993 - 9007199254740992", Value::Int(1)),
+            ],
+            &context,
+        );
+        assert_eq!(
+            evaluate("2 ^ 200", &context).unwrap().to_string(),
+            "1606938044258990275541962092341162602522202993782792835301376"
+        );
+
+        context.set_integer_mode(IntegerMode::Strict);
+        check(&[("2 ^ 64 + 1", big("18446744073709551617"))], &context);
+        let error = evaluate("2 ^ 64 + 0.5", &context).unwrap_err();
+        assert_eq!(
+            error,
+            EvalError::PrecisionLoss {
+                operator: String::from("+"),
+                value: big("18446744073709551616"),
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "+ would round 18446744073709551616 to a float"
+        );
+    }
+}
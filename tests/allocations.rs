@@ -0,0 +1,54 @@
+//! Counts the allocations of `Evaluator` with a global allocator, which would
+//! count those of every other test in the same binary. This test gets a binary
+//! of its own and runs without the test harness, on a single thread.
+
+use clc::{Context, Evaluator, Expression};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+fn no_allocations_after_warm_up() {
+    let mut context = Context::default_with_math_functions();
+    let expression =
+        Expression::compile("if(x > 1, max(x, 2, y) * 3, -x) + sqrt(abs(x))", &context).unwrap();
+    context.set_variable("y", 1.0);
+    let mut evaluator = Evaluator::new();
+    context.set_variable("x", 4.0);
+    assert_eq!(evaluator.eval_compiled(&expression, &context), Ok(14.0));
+
+    let mut count = 0;
+    for x in 0..100 {
+        context.set_variable("x", f64::from(x));
+        let before = allocations();
+        let result = evaluator.eval_compiled(&expression, &context);
+        count += allocations() - before;
+        assert!(result.is_ok());
+    }
+    assert_eq!(count, 0);
+}
+
+fn main() {
+    no_allocations_after_warm_up();
+    println!("test no_allocations_after_warm_up ... ok");
+}